@@ -25,6 +25,21 @@ struct Args {
     /// Enable debug output
     #[arg(long)]
     debug: bool,
+
+    /// Symbol file (see roms/vt420/symbols.txt) whose ROM names replace the
+    /// generated labels
+    #[arg(long, value_name = "PATH")]
+    symbols: Option<PathBuf>,
+
+    /// Prefix each line with its address and raw bytes, like an assembler
+    /// listing
+    #[arg(long)]
+    listing: bool,
+
+    /// Also write a Graphviz control-flow graph of the basic blocks in each
+    /// bank, with jump, call and fall-through edges
+    #[arg(long, value_name = "PATH")]
+    cfg: Option<PathBuf>,
 }
 
 /// Simple context for disassembly that only provides ROM access
@@ -63,8 +78,119 @@ pub fn main() {
     let args = Args::parse();
     let rom = fs::read(&args.rom).unwrap();
     fs::create_dir_all(&args.output).unwrap();
-    disassemble(&rom[0..0x10000], &args.output.join("bank0.asm"), args.debug).unwrap();
-    // disassemble(&rom[0x10000..], &args.output.join("bank1.asm")).unwrap();
+
+    let symbols = match &args.symbols {
+        Some(path) => parse_symbols(&fs::read_to_string(path).unwrap()),
+        None => BTreeMap::new(),
+    };
+    let labels = Labels {
+        symbols,
+        dispatches: find_bank_dispatch(&rom),
+    };
+
+    let mut cfg = args.cfg.as_ref().map(|path| {
+        let mut file = io::BufWriter::new(fs::File::create(path).unwrap());
+        writeln!(file, "digraph rom {{").unwrap();
+        writeln!(file, "  node [shape=box, fontname=monospace];").unwrap();
+        file
+    });
+
+    for (bank, bank_rom) in rom.chunks(0x10000).enumerate() {
+        disassemble(
+            bank_rom,
+            bank as u32,
+            &labels,
+            &args.output.join(format!("bank{bank}.asm")),
+            args.debug,
+            args.listing,
+            cfg.as_mut().map(|file| file as &mut dyn Write),
+        )
+        .unwrap();
+    }
+
+    if let Some(mut file) = cfg {
+        writeln!(file, "}}").unwrap();
+        file.flush().unwrap();
+    }
+}
+
+/// Parse the `ROM:` section of a symbol file into a map of full (bank-qualified)
+/// addresses to names. Lines are of the form `0x10030: name`.
+fn parse_symbols(text: &str) -> BTreeMap<u32, String> {
+    let mut symbols = BTreeMap::new();
+    let mut in_rom = false;
+    for line in text.lines() {
+        let line = line.split('#').next().unwrap_or_default();
+        if !line.starts_with(char::is_whitespace) {
+            in_rom = line.trim() == "ROM:";
+            continue;
+        }
+        if !in_rom {
+            continue;
+        }
+        let Some((addr, name)) = line.trim().split_once(':') else {
+            continue;
+        };
+        let (Some(addr), name) = (addr.strip_prefix("0x"), name.trim()) else {
+            continue;
+        };
+        if let Ok(addr) = u32::from_str_radix(addr, 16)
+            && !name.is_empty()
+        {
+            symbols.insert(addr, name.to_string());
+        }
+    }
+    symbols
+}
+
+/// Locate the cross-bank thunks (`MOV A, #id; LJMP 00xx`) in each bank and
+/// resolve them through the other bank's jump table at 0x100. Mirrors
+/// `ROM::find_bank_dispatch` in the emulator, returning a map of thunk address
+/// to target address, both bank-qualified.
+fn find_bank_dispatch(rom: &[u8]) -> BTreeMap<u32, u32> {
+    const BANK_SEARCH_LENGTH: usize = 0x250;
+    let banks = rom.chunks(0x10000).collect::<Vec<_>>();
+    let mut dispatches = BTreeMap::new();
+    if banks.len() < 2 || banks[1].len() < BANK_SEARCH_LENGTH {
+        return dispatches;
+    }
+
+    for (offset, bank, other_offset, other) in [
+        (0, banks[0], 0x10000, banks[1]),
+        (0x10000, banks[1], 0, banks[0]),
+    ] {
+        for (dispatch_addr, window) in bank[..BANK_SEARCH_LENGTH].windows(5).enumerate() {
+            if window[0] == 0x74 && window[2] == 0x02 && window[3] == 0x00 {
+                let target = 0x100 + 2 * window[1] as usize;
+                let Some(&[lo, hi]) = other.get(target..target + 2) else {
+                    continue;
+                };
+                let addr = (hi as u32) << 8 | lo as u32;
+                dispatches.insert(dispatch_addr as u32 + offset, addr + other_offset);
+            }
+        }
+    }
+
+    dispatches
+}
+
+/// Names for addresses in the listing. Generated labels include the bank as the
+/// top digit of a five-digit address, ie: `label_1ABCD` is 0xABCD in bank 1.
+struct Labels {
+    symbols: BTreeMap<u32, String>,
+    dispatches: BTreeMap<u32, u32>,
+}
+
+impl Labels {
+    fn name(&self, addr: u32, root: bool) -> String {
+        if let Some(name) = self.symbols.get(&addr) {
+            name.clone()
+        } else if root {
+            format!("root_{addr:05X}")
+        } else {
+            format!("label_{addr:05X}")
+        }
+    }
 }
 
 #[derive(Debug, Clone, Default)]
@@ -87,8 +213,17 @@ enum Flow {
     Jump,
 }
 
-fn disassemble(rom: &[u8], output: &Path, debug: bool) -> io::Result<()> {
+fn disassemble(
+    rom: &[u8],
+    bank: u32,
+    labels: &Labels,
+    output: &Path,
+    debug: bool,
+    listing: bool,
+    cfg: Option<&mut dyn Write>,
+) -> io::Result<()> {
     let mut file = fs::File::create(output)?;
+    let bank_base = bank << 16;
     let mut roots: Vec<(Flow, u16, u16)> = vec![];
 
     let mut address_state = Vec::with_capacity(65536);
@@ -379,28 +514,63 @@ fn disassemble(rom: &[u8], output: &Path, debug: bool) -> io::Result<()> {
         }
     }
 
+    if let Some(cfg) = cfg {
+        write_cfg(cfg, &address_state, &cpu, &ctx, bank, labels)?;
+    }
+
     let mut pc = 0_u16;
     loop {
         match address_state[pc as usize] {
             AddressState::Unknown | AddressState::Data => {
-                writeln!(
-                    file,
-                    "  DATA {:02X}",
-                    ctx.rom.read(&(&cpu, &ctx), pc as u32)
-                )?;
+                let byte = ctx.rom.read(&(&cpu, &ctx), pc as u32);
+                if listing {
+                    writeln!(
+                        file,
+                        "{}DATA",
+                        listing_prefix(bank_base | pc as u32, &[byte])
+                    )?;
+                } else {
+                    writeln!(file, "  DATA {:02X}", byte)?;
+                }
                 pc = pc.wrapping_add(1);
             }
             AddressState::InstructionStart {
                 jump_target, root, ..
             } => {
+                let addr = bank_base | pc as u32;
                 let instruction = cpu.decode(&ctx, pc as u32);
-                if jump_target {
-                    writeln!(file, "label_{pc:04X}:")?;
-                } else if root {
-                    writeln!(file, "root_{pc:04X}:")?;
+                if jump_target || root || labels.symbols.contains_key(&addr) {
+                    writeln!(file, "{}:", labels.name(addr, !jump_target && root))?;
+                }
+                if let Some(&target) = labels.dispatches.get(&addr) {
+                    writeln!(
+                        file,
+                        "  ; cross-bank thunk to bank {}: {}",
+                        target >> 16,
+                        labels.name(target, true)
+                    )?;
+                }
+                let next = pc.wrapping_add(instruction.len() as u16);
+                let prefix = if listing {
+                    let bytes = (0..instruction.len())
+                        .map(|i| rom[(pc as usize + i) & 0xffff])
+                        .collect::<Vec<_>>();
+                    listing_prefix(addr, &bytes)
+                } else {
+                    "  ".to_string()
+                };
+                match jump_destination(instruction.control_flow(), pc, next) {
+                    Some(target) if labels.symbols.contains_key(&(bank_base | target as u32)) => {
+                        writeln!(
+                            file,
+                            "{prefix}{} ; {}",
+                            instruction,
+                            labels.name(bank_base | target as u32, false)
+                        )?;
+                    }
+                    _ => writeln!(file, "{prefix}{}", instruction)?,
                 }
-                writeln!(file, "  {}", instruction)?;
-                pc = pc.wrapping_add(instruction.len() as u16);
+                pc = next;
             }
             _ => {}
         }
@@ -411,3 +581,121 @@ fn disassemble(rom: &[u8], output: &Path, debug: bool) -> io::Result<()> {
 
     Ok(())
 }
+
+/// Write the basic blocks of a bank as a Graphviz cluster. Blocks start at
+/// roots, jump targets and after any instruction that doesn't simply fall
+/// through, and are joined by jump (solid), call (dashed) and fall-through
+/// (dotted) edges.
+fn write_cfg(
+    cfg: &mut dyn Write,
+    address_state: &[AddressState],
+    cpu: &Cpu,
+    ctx: &DisassemblyContext,
+    bank: u32,
+    labels: &Labels,
+) -> io::Result<()> {
+    let bank_base = bank << 16;
+    let instructions = address_state
+        .iter()
+        .enumerate()
+        .filter_map(|(pc, state)| match state {
+            AddressState::InstructionStart {
+                root, jump_target, ..
+            } => {
+                let instruction = cpu.decode(ctx, pc as u32);
+                let next = (pc as u16).wrapping_add(instruction.len() as u16);
+                Some((
+                    pc as u16,
+                    next,
+                    instruction.control_flow(),
+                    *root,
+                    *jump_target,
+                ))
+            }
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+    let starts = instructions
+        .iter()
+        .map(|&(pc, ..)| pc)
+        .collect::<BTreeSet<_>>();
+
+    let mut leaders = BTreeSet::new();
+    for &(pc, next, ref flow, root, jump_target) in &instructions {
+        if root || jump_target {
+            leaders.insert(pc);
+        }
+        match *flow {
+            ControlFlow::Continue(target) if target == next => {}
+            ControlFlow::Continue(target) => {
+                leaders.extend([target, next]);
+            }
+            ControlFlow::Call(ret, target) | ControlFlow::Choice(ret, target) => {
+                leaders.extend([target, ret]);
+            }
+            ControlFlow::Diverge => {
+                leaders.insert(next);
+            }
+        }
+    }
+    leaders.retain(|pc| starts.contains(pc));
+
+    let node = |pc: u16| format!("n{:05X}", bank_base | pc as u32);
+    writeln!(cfg, "  subgraph cluster_bank{bank} {{")?;
+    writeln!(cfg, "    label=\"bank {bank}\";")?;
+    let mut block = None;
+    for &(pc, next, ref flow, root, jump_target) in &instructions {
+        if leaders.contains(&pc) {
+            let addr = bank_base | pc as u32;
+            writeln!(
+                cfg,
+                "    {} [label=\"{}\"];",
+                node(pc),
+                labels.name(addr, !jump_target && root)
+            )?;
+            block = Some(pc);
+        }
+        let Some(from) = block else {
+            continue;
+        };
+        let edges = match *flow {
+            ControlFlow::Continue(target) if target == next => vec![(target, "dotted")],
+            ControlFlow::Continue(target) => vec![(target, "solid")],
+            ControlFlow::Call(ret, target) => vec![(target, "dashed"), (ret, "dotted")],
+            ControlFlow::Choice(step, target) => vec![
+                (step, if step == next { "dotted" } else { "solid" }),
+                (target, "solid"),
+            ],
+            ControlFlow::Diverge => vec![],
+        };
+        for (to, style) in edges {
+            // Fall-through within a block isn't an edge
+            if leaders.contains(&to) {
+                writeln!(cfg, "    {} -> {} [style={style}];", node(from), node(to))?;
+            }
+        }
+    }
+    writeln!(cfg, "  }}")?;
+    Ok(())
+}
+
+/// The `AAAAA: BB BB BB    ` prefix of a listing line, with the bank-qualified
+/// address and the raw bytes padded to the longest (3-byte) instruction.
+fn listing_prefix(addr: u32, bytes: &[u8]) -> String {
+    let bytes = bytes
+        .iter()
+        .map(|b| format!("{b:02X}"))
+        .collect::<Vec<_>>()
+        .join(" ");
+    format!("{addr:05X}: {bytes:<8}    ")
+}
+
+/// The non-fallthrough destination of a jump or call, if any.
+fn jump_destination(flow: ControlFlow, pc: u16, next: u16) -> Option<u16> {
+    match flow {
+        ControlFlow::Continue(target) if target != next && target != pc => Some(target),
+        ControlFlow::Call(_, target) => Some(target),
+        ControlFlow::Choice(_, target) if target != pc => Some(target),
+        _ => None,
+    }
+}
@@ -0,0 +1,38 @@
+//! Errors returned when setting up the emulator.
+
+use std::io;
+use std::path::PathBuf;
+
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum BlazeError {
+    /// The ROM file couldn't be read.
+    #[error("Failed to load ROM {path:?}: {source}")]
+    RomLoad {
+        path: PathBuf,
+        #[source]
+        source: io::Error,
+    },
+    /// The ROM image isn't one or two whole 64kB banks.
+    #[error("ROM is {0} bytes, expected 64kB or 128kB")]
+    RomSize(usize),
+    #[error("Failed to access NVR file {path:?}: {source}")]
+    Nvr {
+        path: PathBuf,
+        #[source]
+        source: io::Error,
+    },
+    #[error("Failed to connect comm port: {0}")]
+    Comm(#[source] io::Error),
+    /// An output file, such as a comm trace or recording, couldn't be created.
+    #[error("Failed to create {path:?}: {source}")]
+    FileCreate {
+        path: PathBuf,
+        #[source]
+        source: io::Error,
+    },
+    #[cfg(feature = "graphics")]
+    #[error("Graphics error: {0}")]
+    Graphics(#[from] pixels::Error),
+}
@@ -1,15 +1,21 @@
-use std::cell::Cell;
-use std::fs::{File, OpenOptions};
-use std::io::{Read, Write};
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::fs::File;
+#[cfg(not(target_arch = "wasm32"))]
+use std::fs::OpenOptions;
+use std::io::{self, Read, Write};
 use std::path::PathBuf;
+#[cfg(not(target_arch = "wasm32"))]
 use std::process::Stdio;
 use std::rc::Rc;
-use std::sync::Arc;
+use std::sync::Mutex;
 use std::sync::atomic::{AtomicBool, Ordering};
-use std::thread;
-use std::time::Duration;
-use tracing::{debug, error, info, trace};
+use std::sync::{Arc, mpsc};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+use tracing::{debug, error, info, trace, warn};
 
+use crate::error::BlazeError;
 use crate::machine::generic::duart::DUARTChannel;
 
 /// Communication configuration for a DUART channel
@@ -25,29 +31,240 @@ pub enum CommConfig {
     Pipe(PathBuf),
     /// Separate read and write pipes
     Pipes { rx: PathBuf, tx: PathBuf },
-    /// Execute a command and connect to its pty
-    Exec(String),
-    /// Execute a command and connect to its pty
+    /// Execute a command and connect to its stdin/stdout, running it again up
+    /// to `restarts` times when it exits
+    Exec { command: String, restarts: u32 },
+    /// Execute a command and connect to its pty, running it again up to
+    /// `restarts` times when it exits
     #[cfg(feature = "pty")]
-    ExecPty(String),
+    ExecPty { command: String, restarts: u32 },
+    /// Serial port device
+    #[cfg(feature = "serial")]
+    Serial { path: PathBuf, baud: u32 },
+    /// Connect to a WebSocket URL from the browser
+    #[cfg(target_arch = "wasm32")]
+    WebSocket(String),
+    /// The channel's transmitter looped straight back into its receiver inside
+    /// the DUART, standing in for the loopback connector used by the
+    /// firmware's comm port tests. The firmware can't switch this off.
+    SelfTest,
+    /// Another connection, behind a link that delays and drops bytes
+    Impaired(Box<CommConfig>, LinkImpairment),
+    /// Another connection, with a copy of every byte written to stdout
+    Echo(Box<CommConfig>, EchoFormat),
+    /// Write everything transmitted to a file and never receive anything, as
+    /// a printer would
+    Capture(PathBuf),
+    /// Another connection, with every byte in both directions recorded to a
+    /// file with its time
+    Recorded(Box<CommConfig>, PathBuf),
+    /// Receive the bytes of a recording made by [`CommConfig::Recorded`] with
+    /// their original timing, discarding everything transmitted
+    Playback(PathBuf),
+}
+
+/// A description of one kind of [`CommConfig`], for `--list-comm-modes`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CommMode {
+    pub name: &'static str,
+    pub description: &'static str,
+    /// The command-line options that select it.
+    pub example: &'static str,
+}
+
+/// How the bytes on an echoed connection are written to stdout.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum EchoFormat {
+    /// The bytes exactly as they were sent and received
+    #[default]
+    Raw,
+    /// A hex dump, one line per direction and at most 16 bytes
+    Hex,
+}
+
+/// Which way an echoed byte was travelling.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EchoDirection {
+    /// From the host to the terminal.
+    Received,
+    /// From the terminal to the host.
+    Sent,
+}
+
+/// Simulated faults on the link between a DUART channel and its host, applied
+/// in both directions.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct LinkImpairment {
+    /// How long each byte takes to arrive.
+    pub latency: Duration,
+    /// The fraction of bytes lost, from 0.0 to 1.0.
+    pub drop_rate: f64,
+    /// Seed for choosing which bytes are lost, so that runs are reproducible.
+    pub seed: u64,
+}
+
+// The drop rate is checked to be within 0.0..=1.0, so is never NaN
+impl Eq for LinkImpairment {}
+
+impl LinkImpairment {
+    pub fn is_active(&self) -> bool {
+        !self.latency.is_zero() || self.drop_rate > 0.0
+    }
 }
 
 impl CommConfig {
+    /// An example of every kind of connection compiled into this build, in
+    /// the order that `--list-comm-modes` lists them.
+    pub fn examples() -> Vec<CommConfig> {
+        vec![
+            CommConfig::Loopback,
+            #[cfg(feature = "demo")]
+            CommConfig::Demo,
+            CommConfig::Pipe(PathBuf::from("/tmp/comm1")),
+            CommConfig::Pipes {
+                rx: PathBuf::from("/tmp/rx"),
+                tx: PathBuf::from("/tmp/tx"),
+            },
+            CommConfig::Exec {
+                command: "cat".to_owned(),
+                restarts: 0,
+            },
+            #[cfg(feature = "pty")]
+            CommConfig::ExecPty {
+                command: "/bin/sh".to_owned(),
+                restarts: 0,
+            },
+            #[cfg(feature = "serial")]
+            CommConfig::Serial {
+                path: PathBuf::from("/dev/ttyUSB0"),
+                baud: 19200,
+            },
+            #[cfg(target_arch = "wasm32")]
+            CommConfig::WebSocket("ws://localhost:8080".to_owned()),
+            CommConfig::SelfTest,
+            CommConfig::Loopback.impaired(LinkImpairment {
+                latency: Duration::from_millis(20),
+                ..Default::default()
+            }),
+            CommConfig::Echo(Box::new(CommConfig::Loopback), EchoFormat::Hex),
+            CommConfig::Capture(PathBuf::from("print.txt")),
+            CommConfig::Recorded(Box::new(CommConfig::Loopback), PathBuf::from("session.rec")),
+            CommConfig::Playback(PathBuf::from("session.rec")),
+        ]
+    }
+
+    /// Describe this kind of connection. Every variant must be described
+    /// here, and given an example in [`CommConfig::examples`], to be listed
+    /// by `--list-comm-modes`.
+    pub fn mode(&self) -> CommMode {
+        let (name, description, example) = match self {
+            CommConfig::Loopback => (
+                "loopback",
+                "Everything the terminal sends is received again",
+                "--comm1-loopback",
+            ),
+            #[cfg(feature = "demo")]
+            CommConfig::Demo => (
+                "demo",
+                "A built-in demo host, used when no comm1 option is given",
+                "",
+            ),
+            CommConfig::Pipe(_) => (
+                "pipe",
+                "A single named pipe, read and written",
+                "--comm1-pipe /tmp/comm1",
+            ),
+            CommConfig::Pipes { .. } => (
+                "pipes",
+                "Separate named pipes to read and write",
+                "--comm1-pipes /tmp/rx /tmp/tx",
+            ),
+            CommConfig::Exec { .. } => (
+                "exec-raw",
+                "A command's stdin and stdout",
+                "--comm1-exec-raw cat",
+            ),
+            #[cfg(feature = "pty")]
+            CommConfig::ExecPty { .. } => {
+                ("exec", "A command running in a pty", "--comm1-exec /bin/sh")
+            }
+            #[cfg(feature = "serial")]
+            CommConfig::Serial { .. } => (
+                "serial",
+                "A serial port, at 9600 baud unless given",
+                "--comm1-serial /dev/ttyUSB0@19200",
+            ),
+            #[cfg(target_arch = "wasm32")]
+            CommConfig::WebSocket(_) => (
+                "websocket",
+                "A WebSocket, connected from the browser",
+                "set_comm1_websocket(url)",
+            ),
+            CommConfig::SelfTest => (
+                "selftest",
+                "The DUART's local loopback, for the firmware's port tests",
+                "--selftest",
+            ),
+            CommConfig::Impaired(..) => (
+                "impaired",
+                "Another connection with bytes delayed and dropped",
+                "--comm1-latency 20 --comm1-drop-rate 0.01",
+            ),
+            CommConfig::Echo(..) => (
+                "echo",
+                "Another connection, copied to stdout",
+                "--echo-comm=hex",
+            ),
+            CommConfig::Capture(_) => (
+                "capture",
+                "Everything sent written to a file, as a printer",
+                "--printer-file print.txt",
+            ),
+            CommConfig::Recorded(..) => (
+                "record",
+                "Another connection, recorded to a file for playback",
+                "--comm1-record session.rec",
+            ),
+            CommConfig::Playback(_) => (
+                "playback",
+                "The received bytes of a recording, with their timing",
+                "--comm1-playback session.rec",
+            ),
+        };
+        CommMode {
+            name,
+            description,
+            example,
+        }
+    }
+
     /// Parse command-line arguments into CommConfig
     pub fn from_args(
         pipe: Option<PathBuf>,
         pipes: Option<(PathBuf, PathBuf)>,
         exec: Option<String>,
         exec_pty: Option<String>,
+        serial: Option<(PathBuf, u32)>,
         loopback: bool,
     ) -> Self {
         #[cfg(feature = "pty")]
         if let Some(exec_pty_cmd) = exec_pty {
-            return CommConfig::ExecPty(exec_pty_cmd);
+            return CommConfig::ExecPty {
+                command: exec_pty_cmd,
+                restarts: 0,
+            };
+        }
+
+        #[cfg(feature = "serial")]
+        if let Some((path, baud)) = serial {
+            return CommConfig::Serial { path, baud };
         }
 
         if let Some(exec_cmd) = exec {
-            CommConfig::Exec(exec_cmd)
+            CommConfig::Exec {
+                command: exec_cmd,
+                restarts: 0,
+            }
         } else if let Some((rx, tx)) = pipes {
             CommConfig::Pipes { rx, tx }
         } else if let Some(pipe) = pipe {
@@ -61,32 +278,840 @@ impl CommConfig {
             return CommConfig::Loopback;
         }
     }
+
+    /// Run the command again up to `restarts` times when it exits. Only
+    /// affects the exec connections.
+    pub fn restarting(self, restarts: u32) -> Self {
+        match self {
+            CommConfig::Exec { command, .. } => CommConfig::Exec { command, restarts },
+            #[cfg(feature = "pty")]
+            CommConfig::ExecPty { command, .. } => CommConfig::ExecPty { command, restarts },
+            config => {
+                if restarts > 0 {
+                    warn!("Restarting only applies to exec connections, ignoring");
+                }
+                config
+            }
+        }
+    }
+
+    /// Put this connection behind `impairment`, if it does anything.
+    pub fn impaired(self, impairment: LinkImpairment) -> Self {
+        if !impairment.is_active() {
+            return self;
+        }
+        match self {
+            // Neither is connected by `connect_duart`, so there's no link to
+            // impair
+            #[cfg(feature = "demo")]
+            CommConfig::Demo => {
+                warn!("Link impairment doesn't apply to the demo, ignoring");
+                self
+            }
+            CommConfig::SelfTest => {
+                warn!("Link impairment doesn't apply to the self test loopback, ignoring");
+                self
+            }
+            config => CommConfig::Impaired(Box::new(config), impairment),
+        }
+    }
+
+    /// Write a copy of every byte on this connection to stdout.
+    pub fn echoed(self, format: EchoFormat) -> Self {
+        match self {
+            // The demo is connected inside the emulator, not by `connect_duart`
+            #[cfg(feature = "demo")]
+            CommConfig::Demo => {
+                warn!("Echo doesn't apply to the demo, ignoring");
+                self
+            }
+            config => CommConfig::Echo(Box::new(config), format),
+        }
+    }
+
+    /// Record every byte on this connection to a file at `path`.
+    pub fn recorded(self, path: PathBuf) -> Self {
+        match self {
+            #[cfg(feature = "demo")]
+            CommConfig::Demo => {
+                warn!("Recording doesn't apply to the demo, ignoring");
+                self
+            }
+            config => CommConfig::Recorded(Box::new(config), path),
+        }
+    }
+}
+
+/// The host side of a connected DUART channel.
+pub struct CommConnection {
+    /// Data terminal ready, driven by the emulator.
+    pub dtr: Arc<AtomicBool>,
+    /// The thread delivering terminal output to the connected sink, if any.
+    writer: Option<JoinHandle<()>>,
+}
+
+impl CommConnection {
+    pub fn new(dtr: Arc<AtomicBool>, writer: Option<JoinHandle<()>>) -> Self {
+        Self { dtr, writer }
+    }
+
+    /// Wait for the writer thread to deliver everything queued for it. The
+    /// DUART side of the channel must have been disconnected first, otherwise
+    /// this waits until the deadline. Returns false if the deadline passed.
+    pub fn join(&mut self, deadline: Instant) -> bool {
+        let Some(writer) = self.writer.take() else {
+            return true;
+        };
+        while !writer.is_finished() {
+            if Instant::now() >= deadline {
+                self.writer = Some(writer);
+                return false;
+            }
+            thread::sleep(Duration::from_millis(1));
+        }
+        _ = writer.join();
+        true
+    }
 }
 
 /// Connect a DUART channel to the configured communication method
 pub fn connect_duart(
     channel: DUARTChannel,
     config: CommConfig,
-) -> Result<Rc<Cell<bool>>, std::io::Error> {
-    if cfg!(target_arch = "wasm32") {
-        return Ok(Rc::new(Cell::new(true)));
+) -> Result<CommConnection, BlazeError> {
+    #[cfg(target_arch = "wasm32")]
+    return match config {
+        CommConfig::WebSocket(url) => crate::host::websocket::connect_websocket(channel, &url),
+        _ => Ok(CommConnection::new(Arc::new(AtomicBool::new(true)), None)),
     }
+    .map_err(BlazeError::Comm);
 
+    #[cfg(not(target_arch = "wasm32"))]
     match config {
         CommConfig::Loopback => connect_loopback(channel),
+        // The DUART never uses the channel, see `DUART::force_local_loopback`
+        CommConfig::SelfTest => connect_loopback(channel),
         CommConfig::Pipe(path) => connect_single_pipe(channel, path),
         CommConfig::Pipes { rx, tx } => connect_dual_pipes(channel, rx, tx),
-        CommConfig::Exec(cmd) => connect_exec(channel, cmd),
+        CommConfig::Exec { command, restarts } => connect_exec(channel, command, restarts),
         #[cfg(feature = "pty")]
-        CommConfig::ExecPty(cmd) => connect_exec_pty(channel, cmd),
+        CommConfig::ExecPty { command, restarts } => connect_exec_pty(channel, command, restarts),
+        #[cfg(feature = "serial")]
+        CommConfig::Serial { path, baud } => connect_serial(channel, path, baud),
         #[cfg(feature = "demo")]
         CommConfig::Demo => connect_loopback(channel),
+        CommConfig::Capture(path) => connect_capture(channel, path),
+        CommConfig::Playback(path) => connect_playback(channel, path),
+        CommConfig::Impaired(config, impairment) => {
+            return connect_duart(impair_duart(channel, impairment), *config);
+        }
+        CommConfig::Echo(config, format) => {
+            let echo = CommEcho::new(format, Arc::new(Mutex::new(io::stdout())));
+            return connect_duart(echo_duart(channel, echo), *config);
+        }
+        CommConfig::Recorded(config, path) => {
+            info!("Recording DUART channel to {:?}", path);
+            let file = File::create(&path).map_err(|source| BlazeError::FileCreate {
+                path: path.clone(),
+                source,
+            })?;
+            return connect_duart(record_duart(channel, io::BufWriter::new(file)), *config);
+        }
     }
+    .map_err(BlazeError::Comm)
 }
 
-fn connect_loopback(channel: DUARTChannel) -> Result<Rc<Cell<bool>>, std::io::Error> {
-    info!("Connecting DUART loopback");
+/// A small, seedable PRNG (SplitMix64) for choosing which bytes an impaired
+/// link drops.
+#[cfg(not(target_arch = "wasm32"))]
+struct LinkRng(u64);
+
+#[cfg(not(target_arch = "wasm32"))]
+impl LinkRng {
+    fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    /// A uniformly distributed value in `0.0..1.0`.
+    fn next_f64(&mut self) -> f64 {
+        (self.next_u64() >> 11) as f64 / (1_u64 << 53) as f64
+    }
+}
+
+/// Insert a simulated link between the host side of a DUART channel and the
+/// DUART that delays and drops bytes in both directions.
+#[cfg(not(target_arch = "wasm32"))]
+pub fn impair_duart(channel: DUARTChannel, impairment: LinkImpairment) -> DUARTChannel {
+    info!(
+        "Impairing DUART link: {:?} latency, {:.1}% dropped",
+        impairment.latency,
+        impairment.drop_rate * 100.0
+    );
+    let (to_duart_tx, to_duart_rx) = mpsc::sync_channel(16);
+    let (from_duart_tx, from_duart_rx) = mpsc::sync_channel(16);
+    let seed = impairment.seed;
+    let impairment_clone = impairment.clone();
+    thread::spawn(move || {
+        impaired_relay(to_duart_rx, channel.tx, &impairment_clone, LinkRng(seed));
+        trace!("DUART impaired receive thread exited");
+    });
+    thread::spawn(move || {
+        impaired_relay(channel.rx, from_duart_tx, &impairment, LinkRng(!seed));
+        trace!("DUART impaired transmit thread exited");
+    });
+    DUARTChannel {
+        rx: from_duart_rx,
+        tx: to_duart_tx,
+        dtr: channel.dtr,
+    }
+}
+
+/// Relay bytes from `rx` to `tx`, dropping some and delivering the rest once
+/// they are `latency` old. Bytes in flight are still delivered after `rx`
+/// disconnects.
+#[cfg(not(target_arch = "wasm32"))]
+fn impaired_relay(
+    rx: mpsc::Receiver<u8>,
+    tx: mpsc::SyncSender<u8>,
+    impairment: &LinkImpairment,
+    mut rng: LinkRng,
+) {
+    let mut in_flight = VecDeque::<(Instant, u8)>::new();
+    let mut connected = true;
+    while connected || !in_flight.is_empty() {
+        let received = match in_flight.front() {
+            Some(&(due, _)) => {
+                let wait = due.saturating_duration_since(Instant::now());
+                match rx.recv_timeout(wait) {
+                    Ok(b) => Some(b),
+                    Err(mpsc::RecvTimeoutError::Timeout) => None,
+                    Err(mpsc::RecvTimeoutError::Disconnected) => {
+                        connected = false;
+                        thread::sleep(wait);
+                        None
+                    }
+                }
+            }
+            None if connected => rx.recv().ok().or_else(|| {
+                connected = false;
+                None
+            }),
+            None => None,
+        };
+        if let Some(b) = received {
+            if rng.next_f64() < impairment.drop_rate {
+                trace!("DUART impaired link dropped {b:02X}");
+            } else {
+                in_flight.push_back((Instant::now() + impairment.latency, b));
+            }
+        }
+        while let Some(&(due, b)) = in_flight.front() {
+            if due > Instant::now() {
+                break;
+            }
+            in_flight.pop_front();
+            if tx.send(b).is_err() {
+                return;
+            }
+        }
+    }
+}
+
+/// Writes a copy of the bytes on a channel to a shared writer, either as they
+/// are or as a hex dump.
+pub struct CommEcho<W: Write> {
+    format: EchoFormat,
+    writer: Arc<Mutex<W>>,
+    /// The hex dump line being built, and its direction.
+    line: Option<(EchoDirection, Vec<u8>)>,
+}
+
+impl<W: Write> CommEcho<W> {
+    pub fn new(format: EchoFormat, writer: Arc<Mutex<W>>) -> Self {
+        Self {
+            format,
+            writer,
+            line: None,
+        }
+    }
+
+    pub fn feed(&mut self, direction: EchoDirection, b: u8) -> io::Result<()> {
+        match self.format {
+            EchoFormat::Raw => self.lock()?.write_all(&[b]),
+            EchoFormat::Hex => {
+                match &mut self.line {
+                    Some((d, line)) if *d == direction && line.len() < 16 => line.push(b),
+                    _ => {
+                        self.write_line()?;
+                        self.line = Some((direction, vec![b]));
+                    }
+                }
+                Ok(())
+            }
+        }
+    }
+
+    /// Write out any partial hex dump line and flush the writer.
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.write_line()?;
+        self.lock()?.flush()
+    }
+
+    fn write_line(&mut self) -> io::Result<()> {
+        let Some((direction, line)) = self.line.take() else {
+            return Ok(());
+        };
+        let hex = line
+            .iter()
+            .map(|b| format!("{b:02x}"))
+            .collect::<Vec<_>>()
+            .join(" ");
+        let ascii = line
+            .iter()
+            .map(|&b| match b {
+                0x20..=0x7e => b as char,
+                _ => '.',
+            })
+            .collect::<String>();
+        let arrow = match direction {
+            EchoDirection::Received => "<",
+            EchoDirection::Sent => ">",
+        };
+        writeln!(self.lock()?, "{arrow} {hex:<47}  {ascii}")
+    }
+
+    fn lock(&self) -> io::Result<std::sync::MutexGuard<'_, W>> {
+        self.writer
+            .lock()
+            .map_err(|_| io::Error::other("echo writer poisoned"))
+    }
+}
+
+/// Insert a [`CommEcho`] tap between the host side of a DUART channel and the
+/// DUART. Bytes are delivered unchanged and are never held up by the echo:
+/// copies go to a writer thread through a bounded queue, and are dropped if
+/// the writer falls behind (eg: stdout is a pipe that nobody is reading).
+#[cfg(not(target_arch = "wasm32"))]
+pub fn echo_duart<W: Write + Send + 'static>(
+    channel: DUARTChannel,
+    mut echo: CommEcho<W>,
+) -> DUARTChannel {
+    info!("Echoing DUART channel to stdout");
+    let (echo_tx, echo_rx) = mpsc::sync_channel::<(EchoDirection, u8)>(4096);
+    thread::spawn(move || {
+        while let Ok((direction, b)) = echo_rx.recv() {
+            let mut result = echo.feed(direction, b);
+            // Flush once the burst is over, so that output appears promptly
+            while result.is_ok() {
+                let Ok((direction, b)) = echo_rx.try_recv() else {
+                    break;
+                };
+                result = echo.feed(direction, b);
+            }
+            if let Err(e) = result.and_then(|()| echo.flush()) {
+                warn!("Stopped echoing DUART channel: {e}");
+                break;
+            }
+        }
+        trace!("DUART echo thread exited");
+    });
+
+    let relay = |rx: mpsc::Receiver<u8>, tx: mpsc::SyncSender<u8>, direction| {
+        let echo_tx = echo_tx.clone();
+        thread::spawn(move || {
+            let mut dropped = false;
+            while let Ok(b) = rx.recv() {
+                if let Err(mpsc::TrySendError::Full(_)) = echo_tx.try_send((direction, b)) {
+                    if !dropped {
+                        warn!("DUART echo can't keep up, dropping echoed bytes");
+                        dropped = true;
+                    }
+                }
+                if tx.send(b).is_err() {
+                    break;
+                }
+            }
+            trace!("DUART echo relay thread exited");
+        });
+    };
+    let (to_duart_tx, to_duart_rx) = mpsc::sync_channel(16);
+    let (from_duart_tx, from_duart_rx) = mpsc::sync_channel(16);
+    relay(to_duart_rx, channel.tx, EchoDirection::Received);
+    relay(channel.rx, from_duart_tx, EchoDirection::Sent);
+    DUARTChannel {
+        rx: from_duart_rx,
+        tx: to_duart_tx,
+        dtr: channel.dtr,
+    }
+}
+
+/// One byte of a recorded session, and when it passed through the recorder
+/// relative to the start of the recording.
+///
+/// Recordings have one line per byte: the time in microseconds, `<` for a byte
+/// received by the terminal or `>` for one it sent, then the byte in hex (eg:
+/// `1500 < 1b`).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CommRecord {
+    pub time: Duration,
+    pub direction: EchoDirection,
+    pub byte: u8,
+}
+
+impl CommRecord {
+    pub fn parse(line: &str) -> Result<Self, String> {
+        let mut fields = line.split_whitespace();
+        let (Some(time), Some(direction), Some(byte), None) =
+            (fields.next(), fields.next(), fields.next(), fields.next())
+        else {
+            return Err(format!("expected TIME DIRECTION BYTE, got {line:?}"));
+        };
+        let time = time
+            .parse()
+            .map(Duration::from_micros)
+            .map_err(|_| format!("invalid time {time:?}"))?;
+        let direction = match direction {
+            "<" => EchoDirection::Received,
+            ">" => EchoDirection::Sent,
+            _ => return Err(format!("invalid direction {direction:?}")),
+        };
+        let byte = u8::from_str_radix(byte, 16).map_err(|_| format!("invalid byte {byte:?}"))?;
+        Ok(Self {
+            time,
+            direction,
+            byte,
+        })
+    }
+
+    /// Read every record of a recording, in order.
+    pub fn read_all(reader: impl io::BufRead) -> io::Result<Vec<Self>> {
+        let mut records = vec![];
+        for (i, line) in reader.lines().enumerate() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            let record = Self::parse(&line).map_err(|e| {
+                io::Error::new(io::ErrorKind::InvalidData, format!("line {}: {e}", i + 1))
+            })?;
+            records.push(record);
+        }
+        Ok(records)
+    }
+
+    pub fn write(&self, mut writer: impl Write) -> io::Result<()> {
+        let arrow = match self.direction {
+            EchoDirection::Received => "<",
+            EchoDirection::Sent => ">",
+        };
+        writeln!(
+            writer,
+            "{} {arrow} {:02x}",
+            self.time.as_micros(),
+            self.byte
+        )
+    }
+}
+
+/// Insert a recorder between the host side of a DUART channel and the DUART,
+/// writing a [`CommRecord`] for every byte in either direction. Unlike the
+/// echo, the recorder never drops bytes, so a slow writer holds up the
+/// connection.
+#[cfg(not(target_arch = "wasm32"))]
+pub fn record_duart<W: Write + Send + 'static>(
+    channel: DUARTChannel,
+    mut writer: W,
+) -> DUARTChannel {
+    let (record_tx, record_rx) = mpsc::channel::<(Instant, EchoDirection, u8)>();
+    let start = Instant::now();
     thread::spawn(move || {
+        let write = |writer: &mut W, (at, direction, byte): (Instant, EchoDirection, u8)| {
+            CommRecord {
+                time: at.saturating_duration_since(start),
+                direction,
+                byte,
+            }
+            .write(writer)
+        };
+        while let Ok(record) = record_rx.recv() {
+            let mut result = write(&mut writer, record);
+            // Flush once the burst is over, so the file is usable while running
+            while result.is_ok() {
+                let Ok(record) = record_rx.try_recv() else {
+                    break;
+                };
+                result = write(&mut writer, record);
+            }
+            if let Err(e) = result.and_then(|()| writer.flush()) {
+                warn!("Stopped recording DUART channel: {e}");
+                break;
+            }
+        }
+        trace!("DUART record thread exited");
+    });
+
+    let relay = |rx: mpsc::Receiver<u8>, tx: mpsc::SyncSender<u8>, direction| {
+        let record_tx = record_tx.clone();
+        thread::spawn(move || {
+            while let Ok(b) = rx.recv() {
+                _ = record_tx.send((Instant::now(), direction, b));
+                if tx.send(b).is_err() {
+                    break;
+                }
+            }
+            trace!("DUART record relay thread exited");
+        });
+    };
+    let (to_duart_tx, to_duart_rx) = mpsc::sync_channel(16);
+    let (from_duart_tx, from_duart_rx) = mpsc::sync_channel(16);
+    relay(to_duart_rx, channel.tx, EchoDirection::Received);
+    relay(channel.rx, from_duart_tx, EchoDirection::Sent);
+    DUARTChannel {
+        rx: from_duart_rx,
+        tx: to_duart_tx,
+        dtr: channel.dtr,
+    }
+}
+
+/// Observes the bytes received by the terminal on a channel and appends one
+/// JSON line per parsed escape sequence event to a shared writer.
+#[cfg(feature = "comm-trace")]
+pub struct CommTrace<W: Write> {
+    channel: &'static str,
+    parser: vt_push_parser::VTPushParser,
+    writer: Arc<Mutex<W>>,
+    start: Instant,
+}
+
+#[cfg(feature = "comm-trace")]
+impl<W: Write> CommTrace<W> {
+    pub fn new(channel: &'static str, writer: Arc<Mutex<W>>) -> Self {
+        Self {
+            channel,
+            parser: vt_push_parser::VTPushParser::new(),
+            writer,
+            start: Instant::now(),
+        }
+    }
+
+    pub fn feed(&mut self, bytes: &[u8]) {
+        let Self {
+            channel,
+            parser,
+            writer,
+            start,
+        } = self;
+        parser.feed_with(bytes, &mut |event: vt_push_parser::event::VTEvent<'_>| {
+            let event = format!("{event:?}");
+            let kind = event
+                .split(|c: char| !c.is_ascii_alphanumeric())
+                .next()
+                .unwrap_or_default();
+            let Ok(mut writer) = writer.lock() else {
+                return;
+            };
+            _ = writeln!(
+                writer,
+                r#"{{"ts":{:.6},"channel":"{}","kind":"{}","event":"{}"}}"#,
+                start.elapsed().as_secs_f64(),
+                json_escape(channel),
+                json_escape(kind),
+                json_escape(&event)
+            );
+        });
+    }
+}
+
+#[cfg(feature = "comm-trace")]
+fn json_escape(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+/// Insert a [`CommTrace`] tap between the host side of a DUART channel and
+/// the DUART. Bytes are delivered unchanged; the tap only observes them.
+#[cfg(feature = "comm-trace")]
+pub fn trace_duart(channel: DUARTChannel, mut trace: CommTrace<File>) -> DUARTChannel {
+    info!("Tracing DUART {} received sequences", trace.channel);
+    let (relay_tx, relay_rx) = mpsc::sync_channel(16);
+    let tx = channel.tx;
+    thread::spawn(move || {
+        while let Ok(b) = relay_rx.recv() {
+            trace.feed(&[b]);
+            if tx.send(b).is_err() {
+                break;
+            }
+        }
+        trace!("DUART trace thread exited");
+    });
+    DUARTChannel {
+        rx: channel.rx,
+        tx: relay_tx,
+        dtr: channel.dtr,
+    }
+}
+
+/// DEC Locator reporting mode, set by the host with DECELR.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum LocatorMode {
+    #[default]
+    Off,
+    On,
+    /// Send one report, then turn reporting off
+    OneShot,
+}
+
+/// A mouse button, as reported by DECLRP.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LocatorButton {
+    Left,
+    Middle,
+    Right,
+}
+
+impl LocatorButton {
+    /// The button's bit in the DECLRP button mask.
+    fn mask(self) -> u8 {
+        match self {
+            LocatorButton::Right => 1,
+            LocatorButton::Middle => 2,
+            LocatorButton::Left => 4,
+        }
+    }
+
+    /// The DECLRP event code for the button going down or up.
+    fn event(self, pressed: bool) -> u8 {
+        let down = match self {
+            LocatorButton::Left => 2,
+            LocatorButton::Middle => 4,
+            LocatorButton::Right => 6,
+        };
+        if pressed { down } else { down + 1 }
+    }
+}
+
+/// DECLRP event code for a report requested with DECRQLP.
+const LOCATOR_REQUESTED: u8 = 1;
+
+/// Longest control sequence the locator bothers to parse.
+const LOCATOR_MAX_SEQUENCE: usize = 32;
+
+/// DEC Locator support for a mouse on the host. The VT420 firmware has no
+/// locator, so the host's DECELR, DECSLE and DECRQLP sequences are picked out
+/// of the bytes it sends, and DECLRP reports are added to the terminal's
+/// output. Positions are always reported in character cells.
+#[derive(Clone, Default)]
+pub struct Locator(Arc<Mutex<LocatorState>>);
+
+#[derive(Default)]
+struct LocatorState {
+    mode: LocatorMode,
+    /// DECSLE: report buttons going down
+    report_down: bool,
+    /// DECSLE: report buttons going up
+    report_up: bool,
+    /// 1-based row and column of the mouse, if it's over the text
+    position: Option<(u16, u16)>,
+    buttons: u8,
+    escape: bool,
+    /// Parameter and intermediate bytes of the control sequence being parsed
+    sequence: Option<Vec<u8>>,
+    to_host: Option<mpsc::SyncSender<u8>>,
+}
+
+impl Locator {
+    /// Record the cell under the mouse, or `None` if it's not over the text.
+    pub fn move_to(&self, position: Option<(u16, u16)>) {
+        self.0.lock().unwrap().position = position;
+    }
+
+    /// Record a mouse button going down or up, and report it if the host
+    /// asked for that event.
+    pub fn button(&self, button: LocatorButton, pressed: bool) {
+        let report = {
+            let mut state = self.0.lock().unwrap();
+            if pressed {
+                state.buttons |= button.mask();
+            } else {
+                state.buttons &= !button.mask();
+            }
+            if pressed {
+                state.report_down
+            } else {
+                state.report_up
+            }
+        };
+        if report {
+            self.send_locator_report(button.event(pressed));
+        }
+    }
+
+    /// Send a DECLRP report of the current position to the host, unless
+    /// locator reports are off.
+    pub fn send_locator_report(&self, event: u8) {
+        self.0.lock().unwrap().send_report(event);
+    }
+
+    /// Watch a byte sent by the host to the terminal.
+    fn feed(&self, b: u8) {
+        self.0.lock().unwrap().feed(b);
+    }
+}
+
+impl LocatorState {
+    fn send_report(&mut self, event: u8) {
+        if self.mode == LocatorMode::Off {
+            return;
+        }
+        if self.mode == LocatorMode::OneShot {
+            self.mode = LocatorMode::Off;
+        }
+        let report = locator_report(event, self.buttons, self.position);
+        trace!("DEC Locator report {:?}", String::from_utf8_lossy(&report));
+        let Some(to_host) = &self.to_host else {
+            return;
+        };
+        for b in report {
+            if to_host.try_send(b).is_err() {
+                warn!("Dropped DEC Locator report, host isn't reading");
+                break;
+            }
+        }
+    }
+
+    fn feed(&mut self, b: u8) {
+        match b {
+            0x1b => {
+                self.escape = true;
+                self.sequence = None;
+                return;
+            }
+            b'[' if self.escape => self.sequence = Some(vec![]),
+            0x9b => self.sequence = Some(vec![]),
+            // CAN and SUB abort a sequence
+            0x18 | 0x1a => self.sequence = None,
+            _ => {
+                if let Some(sequence) = &mut self.sequence {
+                    match b {
+                        0x20..=0x3f if sequence.len() < LOCATOR_MAX_SEQUENCE => sequence.push(b),
+                        0x20..=0x3f => self.sequence = None,
+                        0x40..=0x7e => {
+                            let sequence = self.sequence.take().unwrap_or_default();
+                            self.control_sequence(&sequence, b);
+                        }
+                        _ => {}
+                    }
+                }
+            }
+        }
+        self.escape = false;
+    }
+
+    fn control_sequence(&mut self, sequence: &[u8], final_byte: u8) {
+        let Some(params) = sequence.strip_suffix(b"'") else {
+            return;
+        };
+        let params = params
+            .split(|&b| b == b';')
+            .map(|param| {
+                std::str::from_utf8(param)
+                    .ok()
+                    .and_then(|param| param.parse::<u16>().ok())
+                    .unwrap_or(0)
+            })
+            .collect::<Vec<_>>();
+        match final_byte {
+            // DECELR: enable locator reports
+            b'z' => {
+                self.mode = match params[0] {
+                    1 => LocatorMode::On,
+                    2 => LocatorMode::OneShot,
+                    _ => LocatorMode::Off,
+                };
+                debug!("DEC Locator mode {:?}", self.mode);
+            }
+            // DECSLE: select locator events
+            b'{' => {
+                for param in params {
+                    match param {
+                        0 => (self.report_down, self.report_up) = (false, false),
+                        1 => self.report_down = true,
+                        2 => self.report_down = false,
+                        3 => self.report_up = true,
+                        4 => self.report_up = false,
+                        _ => {}
+                    }
+                }
+            }
+            // DECRQLP: request locator position
+            b'|' => self.send_report(LOCATOR_REQUESTED),
+            _ => {}
+        }
+    }
+}
+
+/// A DECLRP report. Without a position, the locator is reported unavailable.
+fn locator_report(event: u8, buttons: u8, position: Option<(u16, u16)>) -> Vec<u8> {
+    match position {
+        Some((row, column)) => format!("\x1b[{event};{buttons};{row};{column};1&w").into_bytes(),
+        None => b"\x1b[0&w".to_vec(),
+    }
+}
+
+/// Insert a [`Locator`] between the host side of a DUART channel and the
+/// DUART, so that it can watch the host's bytes and add its reports to the
+/// terminal's output.
+#[cfg(not(target_arch = "wasm32"))]
+pub fn locator_duart(channel: DUARTChannel, locator: Locator) -> DUARTChannel {
+    // Leave room for a few reports on top of the terminal's own output
+    let (to_host_tx, to_host_rx) = mpsc::sync_channel(256);
+    let (relay_tx, relay_rx) = mpsc::sync_channel::<u8>(16);
+    locator.0.lock().unwrap().to_host = Some(to_host_tx.clone());
+    let tx = channel.tx;
+    thread::spawn(move || {
+        while let Ok(b) = relay_rx.recv() {
+            locator.feed(b);
+            if tx.send(b).is_err() {
+                break;
+            }
+        }
+        trace!("DUART locator receive thread exited");
+    });
+    let rx = channel.rx;
+    thread::spawn(move || {
+        while let Ok(b) = rx.recv() {
+            if to_host_tx.send(b).is_err() {
+                break;
+            }
+        }
+        trace!("DUART locator transmit thread exited");
+    });
+    DUARTChannel {
+        rx: to_host_rx,
+        tx: relay_tx,
+        dtr: channel.dtr,
+    }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+fn connect_loopback(channel: DUARTChannel) -> Result<CommConnection, std::io::Error> {
+    info!("Connecting DUART loopback");
+    let writer = thread::spawn(move || {
         loop {
             match channel.rx.recv() {
                 Ok(b) => {
@@ -98,27 +1123,204 @@ fn connect_loopback(channel: DUARTChannel) -> Result<Rc<Cell<bool>>, std::io::Er
                 _ => break,
             }
         }
-        trace!("DUART pipe loopback thread exited");
-    });
-    Ok(channel.dtr)
+        trace!("DUART pipe loopback thread exited");
+    });
+    Ok(CommConnection::new(channel.dtr, Some(writer)))
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+fn connect_capture(channel: DUARTChannel, path: PathBuf) -> Result<CommConnection, std::io::Error> {
+    info!("Capturing DUART output to {:?}", path);
+    let mut file = File::create(&path)?;
+    let dtr = channel.dtr.clone();
+    let writer = thread::spawn(move || {
+        // Unbuffered, so the file is complete whenever the emulator stops
+        while let Ok(b) = channel.rx.recv() {
+            if let Err(e) = file.write_all(&[b]) {
+                warn!("Failed to write DUART capture to {:?}: {e}", path);
+                break;
+            }
+        }
+        trace!("DUART capture thread exited");
+    });
+    Ok(CommConnection::new(dtr, Some(writer)))
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+fn connect_playback(
+    channel: DUARTChannel,
+    path: PathBuf,
+) -> Result<CommConnection, std::io::Error> {
+    info!("Playing back DUART recording from {:?}", path);
+    let records = CommRecord::read_all(io::BufReader::new(File::open(&path)?))?;
+    let tx = channel.tx;
+    thread::spawn(move || {
+        let start = Instant::now();
+        for record in records {
+            if record.direction != EchoDirection::Received {
+                continue;
+            }
+            thread::sleep((start + record.time).saturating_duration_since(Instant::now()));
+            if tx.send(record.byte).is_err() {
+                break;
+            }
+        }
+        trace!("DUART playback thread exited");
+    });
+    let writer = thread::spawn(move || {
+        while let Ok(b) = channel.rx.recv() {
+            trace!("DUART playback discarded {b:02X}");
+        }
+    });
+    Ok(CommConnection::new(channel.dtr, Some(writer)))
+}
+
+/// A host connection that is polled from the step loop rather than serviced by
+/// threads, so that it works where threads aren't available and delivers
+/// bytes deterministically.
+pub trait CommBackend {
+    /// The next byte from the host for the terminal, if one is ready.
+    fn poll_read(&mut self) -> Option<u8>;
+    /// Offer a byte sent by the terminal. Returns false if the backend can't
+    /// accept it yet, in which case it is offered again on the next poll.
+    fn poll_write(&mut self, byte: u8) -> bool;
+}
+
+/// Connects a DUART channel to a [`CommBackend`] on the emulator thread. Must
+/// be ticked from the step loop.
+pub struct PolledComm {
+    channel: DUARTChannel,
+    backend: Box<dyn CommBackend>,
+    write_pending: Option<u8>,
+    read_pending: Option<u8>,
+}
+
+impl PolledComm {
+    pub fn new(channel: DUARTChannel, backend: Box<dyn CommBackend>) -> Self {
+        info!("Connecting DUART to polled backend");
+        Self {
+            channel,
+            backend,
+            write_pending: None,
+            read_pending: None,
+        }
+    }
+
+    pub fn dtr(&self) -> Arc<AtomicBool> {
+        self.channel.dtr.clone()
+    }
+
+    pub fn tick(&mut self) {
+        while let Some(b) = self
+            .write_pending
+            .take()
+            .or_else(|| self.channel.rx.try_recv().ok())
+        {
+            if !self.backend.poll_write(b) {
+                self.write_pending = Some(b);
+                break;
+            }
+        }
+        while let Some(b) = self
+            .read_pending
+            .take()
+            .or_else(|| self.backend.poll_read())
+        {
+            if self.channel.tx.try_send(b).is_err() {
+                self.read_pending = Some(b);
+                break;
+            }
+        }
+    }
+}
+
+/// Polled loopback: everything the terminal sends is echoed back to it.
+#[derive(Default)]
+pub struct LoopbackBackend {
+    pending: VecDeque<u8>,
+}
+
+impl CommBackend for LoopbackBackend {
+    fn poll_read(&mut self) -> Option<u8> {
+        self.pending.pop_front()
+    }
+
+    fn poll_write(&mut self, byte: u8) -> bool {
+        trace!("DUART polled loopback char {byte:02X} {:?}", byte as char);
+        self.pending.push_back(byte);
+        true
+    }
+}
+
+/// An in-memory host. Bytes queued with [`MemoryBackend::send`] are delivered
+/// to the terminal, honouring XON/XOFF, and everything else the terminal sends
+/// is collected. Clones share the same buffers, so a clone can be kept to
+/// drive the host side while the system owns the backend.
+#[allow(dead_code)]
+#[derive(Clone, Default)]
+pub struct MemoryBackend {
+    state: Rc<RefCell<MemoryBackendState>>,
+}
+
+#[derive(Default)]
+struct MemoryBackendState {
+    input: VecDeque<u8>,
+    output: Vec<u8>,
+    xoff: bool,
+}
+
+#[allow(dead_code)]
+impl MemoryBackend {
+    /// Queue bytes to be received by the terminal.
+    pub fn send(&self, bytes: &[u8]) {
+        self.state.borrow_mut().input.extend(bytes);
+    }
+
+    /// Take everything the terminal has sent so far.
+    pub fn take_output(&self) -> Vec<u8> {
+        std::mem::take(&mut self.state.borrow_mut().output)
+    }
+}
+
+impl CommBackend for MemoryBackend {
+    fn poll_read(&mut self) -> Option<u8> {
+        let mut state = self.state.borrow_mut();
+        if state.xoff {
+            return None;
+        }
+        state.input.pop_front()
+    }
+
+    fn poll_write(&mut self, byte: u8) -> bool {
+        let mut state = self.state.borrow_mut();
+        match byte {
+            // XON
+            0x11 => state.xoff = false,
+            // XOFF
+            0x13 => state.xoff = true,
+            byte => state.output.push(byte),
+        }
+        true
+    }
 }
 
+#[cfg(not(target_arch = "wasm32"))]
 fn connect_single_pipe(
     channel: DUARTChannel,
     path: PathBuf,
-) -> Result<Rc<Cell<bool>>, std::io::Error> {
+) -> Result<CommConnection, std::io::Error> {
     info!("Connecting DUART single pipe to {:?}", path);
     let software_flow_control = Arc::new(AtomicBool::new(true));
     let rx = channel.rx;
     let tx = channel.tx;
 
     debug!("Opening {:?} as read/write", path);
-    let mut pipe_r = OpenOptions::new().read(true).write(true).open(&path)?;
+    let pipe_r = OpenOptions::new().read(true).write(true).open(&path)?;
     let mut pipe_w = pipe_r.try_clone()?;
     debug!("Opened!");
 
     let software_flow_control_clone = software_flow_control.clone();
-    thread::spawn(move || {
+    let writer = thread::spawn(move || {
         loop {
             match rx.recv() {
                 Ok(b) => {
@@ -142,33 +1344,20 @@ fn connect_single_pipe(
         debug!("DUART pipe write thread exited");
     });
 
+    let dtr = channel.dtr.clone();
     thread::spawn(move || {
-        loop {
-            if !software_flow_control.load(Ordering::Relaxed) {
-                std::thread::sleep(Duration::from_millis(10));
-                continue;
-            }
-            let mut buf = [0; 1];
-            match pipe_r.read(&mut buf) {
-                Ok(1) => {
-                    if !tx.send(buf[0]).is_ok() {
-                        break;
-                    }
-                }
-                _ => break,
-            }
-        }
-        trace!("DUART pipe read thread exited");
+        pipe_read_loop(pipe_r, tx, software_flow_control, dtr);
     });
 
-    Ok(channel.dtr)
+    Ok(CommConnection::new(channel.dtr, Some(writer)))
 }
 
+#[cfg(not(target_arch = "wasm32"))]
 fn connect_dual_pipes(
     channel: DUARTChannel,
     pipe_r_path: PathBuf,
     pipe_w_path: PathBuf,
-) -> Result<Rc<Cell<bool>>, std::io::Error> {
+) -> Result<CommConnection, std::io::Error> {
     info!(
         "Connecting DUART dual pipes to {:?} and {:?}",
         pipe_r_path, pipe_w_path
@@ -178,7 +1367,7 @@ fn connect_dual_pipes(
     let tx = channel.tx;
 
     let software_flow_control_clone = software_flow_control.clone();
-    thread::spawn(move || {
+    let writer = thread::spawn(move || {
         let Ok(mut pipe_w) = OpenOptions::new().write(true).open(&pipe_w_path) else {
             error!("Failed to open pipe_w: {:?}", pipe_w_path);
             return;
@@ -206,76 +1395,86 @@ fn connect_dual_pipes(
         trace!("DUART pipe write thread exited");
     });
 
+    let dtr = channel.dtr.clone();
     thread::spawn(move || {
-        let Ok(mut pipe_r) = OpenOptions::new().read(true).open(&pipe_r_path) else {
+        let Ok(pipe_r) = OpenOptions::new().read(true).open(&pipe_r_path) else {
             error!("Failed to open pipe_r: {:?}", pipe_r_path);
             return;
         };
-        loop {
-            if !software_flow_control.load(Ordering::Relaxed) {
-                std::thread::sleep(Duration::from_millis(10));
-                continue;
-            }
-            let mut buf = [0; 1];
-            match pipe_r.read(&mut buf) {
-                Ok(1) => {
-                    if !tx.send(buf[0]).is_ok() {
-                        break;
-                    }
+        pipe_read_loop(pipe_r, tx, software_flow_control, dtr);
+    });
+
+    Ok(CommConnection::new(channel.dtr, Some(writer)))
+}
+
+/// Read bytes from a pipe and deliver them to the DUART, pausing while either
+/// the host has sent XOFF (software flow control) or the terminal has dropped
+/// DTR (hardware flow control).
+#[cfg(not(target_arch = "wasm32"))]
+fn pipe_read_loop(
+    mut pipe_r: impl Read,
+    tx: mpsc::SyncSender<u8>,
+    software_flow_control: Arc<AtomicBool>,
+    dtr: Arc<AtomicBool>,
+) {
+    loop {
+        if !software_flow_control.load(Ordering::Relaxed) || !dtr.load(Ordering::Relaxed) {
+            std::thread::sleep(Duration::from_millis(10));
+            continue;
+        }
+        let mut buf = [0; 1];
+        match pipe_r.read(&mut buf) {
+            Ok(1) => {
+                if !tx.send(buf[0]).is_ok() {
+                    break;
                 }
-                _ => break,
             }
+            _ => break,
         }
-        trace!("DUART pipe read thread exited");
-    });
-
-    Ok(channel.dtr)
+    }
+    trace!("DUART pipe read thread exited");
 }
 
-fn connect_exec(
+#[cfg(all(feature = "serial", not(target_arch = "wasm32")))]
+fn connect_serial(
     channel: DUARTChannel,
-    cmd_string: String,
-) -> Result<Rc<Cell<bool>>, std::io::Error> {
-    info!("Connecting DUART to shell process {:?}", cmd_string);
+    path: PathBuf,
+    baud: u32,
+) -> Result<CommConnection, std::io::Error> {
+    info!(
+        "Connecting DUART to serial port {:?} at {} baud",
+        path, baud
+    );
     let software_flow_control = Arc::new(AtomicBool::new(true));
     let rx = channel.rx;
     let tx = channel.tx;
 
-    if cmd_string.is_empty() {
-        return Err(std::io::Error::new(
-            std::io::ErrorKind::InvalidInput,
-            "Empty command string",
-        ));
-    }
-
-    // Spawn command via shell
-    let mut child = std::process::Command::new("/bin/sh")
-        .arg("-c")
-        .arg(&cmd_string)
-        .stderr(Stdio::null())
-        .stdout(Stdio::piped())
-        .stdin(Stdio::piped())
-        .spawn()
-        .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?;
-
-    let mut stdin = child.stdin.take().unwrap();
-    let mut stdout = child.stdout.take().unwrap();
+    let open_error = |e| {
+        std::io::Error::other(format!(
+            "Failed to open serial port {path:?} at {baud} baud: {e}"
+        ))
+    };
+    let mut port_r = serialport::new(path.to_string_lossy(), baud)
+        .timeout(Duration::from_millis(100))
+        .open()
+        .map_err(open_error)?;
+    let mut port_w = port_r.try_clone().map_err(open_error)?;
 
     let software_flow_control_clone = software_flow_control.clone();
-    thread::spawn(move || {
+    let writer = thread::spawn(move || {
         loop {
             match rx.recv() {
                 Ok(b) => {
                     if b == 0x11 {
                         // XON
-                        trace!("DUART exec XON");
+                        trace!("DUART serial XON");
                         software_flow_control_clone.store(true, Ordering::Relaxed);
                     } else if b == 0x13 {
                         // XOFF
-                        trace!("DUART exec XOFF");
+                        trace!("DUART serial XOFF");
                         software_flow_control_clone.store(false, Ordering::Relaxed);
                     } else {
-                        if !stdin.write_all(&[b]).is_ok() {
+                        if !port_w.write_all(&[b]).is_ok() {
                             break;
                         }
                     }
@@ -283,42 +1482,149 @@ fn connect_exec(
                 _ => break,
             }
         }
-        trace!("DUART write thread exited");
+        trace!("DUART serial write thread exited");
     });
 
+    let dtr = channel.dtr.clone();
     thread::spawn(move || {
         loop {
-            if !software_flow_control.load(Ordering::Relaxed) {
+            if !software_flow_control.load(Ordering::Relaxed) || !dtr.load(Ordering::Relaxed) {
                 std::thread::sleep(Duration::from_millis(10));
                 continue;
             }
             let mut buf = [0; 1];
-            let read_result = { stdout.read(&mut buf) };
-            match read_result {
-                Ok(n) if n > 0 => {
+            match port_r.read(&mut buf) {
+                Ok(1) => {
                     if !tx.send(buf[0]).is_ok() {
                         break;
                     }
                 }
-                Ok(_) => break, // EOF (read 0 bytes)
-                Err(_) => break,
+                // The port times out reads so that flow control is rechecked
+                Err(e) if e.kind() == std::io::ErrorKind::TimedOut => {}
+                _ => break,
             }
         }
-        trace!("DUART read thread exited");
+        trace!("DUART serial read thread exited");
     });
 
-    Ok(channel.dtr)
+    Ok(CommConnection::new(channel.dtr, Some(writer)))
 }
 
-#[cfg(feature = "pty")]
-fn connect_exec_pty(
+/// Write the terminal's output to the current process, tracking XON/XOFF.
+/// Output is discarded while no process is running, so the thread outlives
+/// restarts.
+#[cfg(not(target_arch = "wasm32"))]
+fn exec_write_loop<W: Write>(
+    rx: mpsc::Receiver<u8>,
+    input: Arc<Mutex<Option<W>>>,
+    software_flow_control: Arc<AtomicBool>,
+) {
+    loop {
+        match rx.recv() {
+            Ok(b) => {
+                if b == 0x11 {
+                    // XON
+                    trace!("DUART exec XON");
+                    software_flow_control.store(true, Ordering::Relaxed);
+                } else if b == 0x13 {
+                    // XOFF
+                    trace!("DUART exec XOFF");
+                    software_flow_control.store(false, Ordering::Relaxed);
+                } else {
+                    let mut input = input.lock().unwrap();
+                    if let Some(writer) = input.as_mut() {
+                        if !writer.write_all(&[b]).is_ok() {
+                            *input = None;
+                        }
+                    }
+                }
+            }
+            _ => break,
+        }
+    }
+    trace!("DUART write thread exited");
+}
+
+/// Copy a process's output to the terminal until it closes. Returns false if
+/// the terminal side has gone away.
+#[cfg(not(target_arch = "wasm32"))]
+fn exec_read_loop(
+    mut output: impl Read,
+    tx: &mpsc::SyncSender<u8>,
+    software_flow_control: &AtomicBool,
+) -> bool {
+    loop {
+        if !software_flow_control.load(Ordering::Relaxed) {
+            std::thread::sleep(Duration::from_millis(10));
+            continue;
+        }
+        let mut buf = [0; 1];
+        match output.read(&mut buf) {
+            Ok(n) if n > 0 => {
+                if !tx.send(buf[0]).is_ok() {
+                    return false;
+                }
+            }
+            // EOF (read 0 bytes), or EIO from a pty whose process has exited
+            Ok(_) | Err(_) => return true,
+        }
+    }
+}
+
+/// Reap a process that has closed its output and tell the terminal it has
+/// gone. Returns false if the terminal side has gone away.
+#[cfg(not(target_arch = "wasm32"))]
+fn exec_exited(
+    child: &mut std::process::Child,
+    tx: &mpsc::SyncSender<u8>,
+    restarting: bool,
+) -> bool {
+    let status = match child.wait() {
+        Ok(status) => status.to_string(),
+        Err(e) => e.to_string(),
+    };
+    info!("Process exited ({status})");
+    let message = if restarting {
+        format!("\r\n[Process exited ({status}), restarting]\r\n")
+    } else {
+        format!("\r\n[Process exited ({status})]\r\n")
+    };
+    message.bytes().all(|b| tx.send(b).is_ok())
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+fn spawn_exec(
+    cmd_string: &str,
+) -> Result<
+    (
+        std::process::Child,
+        std::process::ChildStdin,
+        std::process::ChildStdout,
+    ),
+    std::io::Error,
+> {
+    // Spawn command via shell
+    let mut child = std::process::Command::new("/bin/sh")
+        .arg("-c")
+        .arg(cmd_string)
+        .stderr(Stdio::null())
+        .stdout(Stdio::piped())
+        .stdin(Stdio::piped())
+        .spawn()
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?;
+
+    let stdin = child.stdin.take().unwrap();
+    let stdout = child.stdout.take().unwrap();
+    Ok((child, stdin, stdout))
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+fn connect_exec(
     channel: DUARTChannel,
     cmd_string: String,
-) -> Result<Rc<Cell<bool>>, std::io::Error> {
-    use pty_process::blocking::Command;
-    use std::os::fd::OwnedFd;
-
-    info!("Connecting DUART to shell process PTY {:?}", cmd_string);
+    restarts: u32,
+) -> Result<CommConnection, std::io::Error> {
+    info!("Connecting DUART to shell process {:?}", cmd_string);
     let software_flow_control = Arc::new(AtomicBool::new(true));
     let rx = channel.rx;
     let tx = channel.tx;
@@ -330,6 +1636,47 @@ fn connect_exec_pty(
         ));
     }
 
+    let (mut child, stdin, mut stdout) = spawn_exec(&cmd_string)?;
+    let stdin = Arc::new(Mutex::new(Some(stdin)));
+
+    let writer = thread::spawn({
+        let stdin = stdin.clone();
+        let software_flow_control = software_flow_control.clone();
+        move || exec_write_loop(rx, stdin, software_flow_control)
+    });
+
+    thread::spawn(move || {
+        let mut restarts_left = restarts;
+        while exec_read_loop(&mut stdout, &tx, &software_flow_control) {
+            *stdin.lock().unwrap() = None;
+            if !exec_exited(&mut child, &tx, restarts_left > 0) || restarts_left == 0 {
+                break;
+            }
+            restarts_left -= 1;
+            info!("Restarting shell process {:?}", cmd_string);
+            match spawn_exec(&cmd_string) {
+                Ok((new_child, new_stdin, new_stdout)) => {
+                    child = new_child;
+                    *stdin.lock().unwrap() = Some(new_stdin);
+                    stdout = new_stdout;
+                }
+                Err(e) => {
+                    error!("Failed to restart shell process: {e}");
+                    break;
+                }
+            }
+        }
+        trace!("DUART read thread exited");
+    });
+
+    Ok(CommConnection::new(channel.dtr, Some(writer)))
+}
+
+#[cfg(all(feature = "pty", not(target_arch = "wasm32")))]
+fn spawn_exec_pty(cmd_string: &str) -> Result<(std::process::Child, File), std::io::Error> {
+    use pty_process::blocking::Command;
+    use std::os::fd::OwnedFd;
+
     // Open PTY
     let (pty, pts) = pty_process::blocking::open()
         .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?;
@@ -337,60 +1684,552 @@ fn connect_exec_pty(
         .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?;
 
     // Spawn command via shell
-    let _child = Command::new("/bin/sh")
+    let child = Command::new("/bin/sh")
         .arg("-c")
-        .arg(&cmd_string)
+        .arg(cmd_string)
         .spawn(pts)
         .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?;
 
-    let mut pty = File::from(OwnedFd::from(pty));
-    let mut pty_read: File = pty.try_clone()?;
+    Ok((child, File::from(OwnedFd::from(pty))))
+}
+
+#[cfg(all(feature = "pty", not(target_arch = "wasm32")))]
+fn connect_exec_pty(
+    channel: DUARTChannel,
+    cmd_string: String,
+    restarts: u32,
+) -> Result<CommConnection, std::io::Error> {
+    info!("Connecting DUART to shell process PTY {:?}", cmd_string);
+    let software_flow_control = Arc::new(AtomicBool::new(true));
+    let rx = channel.rx;
+    let tx = channel.tx;
+
+    if cmd_string.is_empty() {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            "Empty command string",
+        ));
+    }
+
+    let (mut child, pty) = spawn_exec_pty(&cmd_string)?;
+    let mut pty_read = pty.try_clone()?;
+    let pty = Arc::new(Mutex::new(Some(pty)));
+
+    let writer = thread::spawn({
+        let pty = pty.clone();
+        let software_flow_control = software_flow_control.clone();
+        move || exec_write_loop(rx, pty, software_flow_control)
+    });
 
-    let software_flow_control_clone = software_flow_control.clone();
     thread::spawn(move || {
-        loop {
-            match rx.recv() {
-                Ok(b) => {
-                    if b == 0x11 {
-                        // XON
-                        trace!("DUART pty XON");
-                        software_flow_control_clone.store(true, Ordering::Relaxed);
-                    } else if b == 0x13 {
-                        // XOFF
-                        trace!("DUART pty XOFF");
-                        software_flow_control_clone.store(false, Ordering::Relaxed);
-                    } else {
-                        if !pty.write_all(&[b]).is_ok() {
-                            break;
-                        }
-                    }
+        let mut restarts_left = restarts;
+        while exec_read_loop(&pty_read, &tx, &software_flow_control) {
+            *pty.lock().unwrap() = None;
+            if !exec_exited(&mut child, &tx, restarts_left > 0) || restarts_left == 0 {
+                break;
+            }
+            restarts_left -= 1;
+            info!("Restarting shell process PTY {:?}", cmd_string);
+            let restarted = spawn_exec_pty(&cmd_string)
+                .and_then(|(child, pty)| Ok((child, pty.try_clone()?, pty)));
+            match restarted {
+                Ok((new_child, new_pty_read, new_pty)) => {
+                    child = new_child;
+                    pty_read = new_pty_read;
+                    *pty.lock().unwrap() = Some(new_pty);
+                }
+                Err(e) => {
+                    error!("Failed to restart shell process: {e}");
+                    break;
                 }
-                _ => break,
             }
         }
-        trace!("DUART pty write thread exited");
+        trace!("DUART pty read thread exited");
     });
 
-    thread::spawn(move || {
-        loop {
-            if !software_flow_control.load(Ordering::Relaxed) {
-                std::thread::sleep(Duration::from_millis(10));
-                continue;
+    Ok(CommConnection::new(channel.dtr, Some(writer)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pipe_read_loop_dtr() {
+        let (tx, rx) = mpsc::sync_channel(16);
+        let software_flow_control = Arc::new(AtomicBool::new(true));
+        let dtr = Arc::new(AtomicBool::new(false));
+
+        let dtr_clone = dtr.clone();
+        thread::spawn(move || {
+            pipe_read_loop(&b"abc"[..], tx, software_flow_control, dtr_clone);
+        });
+
+        // DTR deasserted: nothing is delivered
+        assert!(rx.recv_timeout(Duration::from_millis(100)).is_err());
+
+        // DTR asserted: bytes flow
+        dtr.store(true, Ordering::Relaxed);
+        assert_eq!(rx.recv_timeout(Duration::from_secs(5)), Ok(b'a'));
+        assert_eq!(rx.recv_timeout(Duration::from_secs(5)), Ok(b'b'));
+        assert_eq!(rx.recv_timeout(Duration::from_secs(5)), Ok(b'c'));
+    }
+
+    #[test]
+    fn test_drain_loopback() {
+        let (duart_side, host_side) = DUARTChannel::new();
+        let mut connection = connect_loopback(host_side).unwrap();
+
+        // A full queue's worth of output written right before shutdown
+        let burst = (0..16).collect::<Vec<u8>>();
+        for &b in &burst {
+            duart_side.tx.send(b).unwrap();
+        }
+        drop(duart_side.tx);
+
+        assert!(connection.join(Instant::now() + Duration::from_secs(5)));
+        assert_eq!(duart_side.rx.try_iter().collect::<Vec<_>>(), burst);
+    }
+
+    #[test]
+    fn test_exec_restart() {
+        let (duart_side, host_side) = DUARTChannel::new();
+        let config = CommConfig::from_args(None, None, Some("echo hi".into()), None, None, false)
+            .restarting(2);
+        let _connection = connect_duart(host_side, config).unwrap();
+
+        let mut output = vec![];
+        while let Ok(b) = duart_side.rx.recv_timeout(Duration::from_secs(5)) {
+            output.push(b);
+        }
+        let output = String::from_utf8(output).unwrap();
+        assert_eq!(output.matches("hi\n").count(), 3, "{output:?}");
+        assert_eq!(output.matches("restarting]").count(), 2, "{output:?}");
+        assert!(
+            output.ends_with("[Process exited (exit status: 0)]\r\n"),
+            "{output:?}"
+        );
+    }
+
+    #[test]
+    fn test_impaired_drop_all() {
+        let (duart_side, host_side) = DUARTChannel::new();
+        let impairment = LinkImpairment {
+            drop_rate: 1.0,
+            seed: 1,
+            ..Default::default()
+        };
+        let mut connection =
+            connect_duart(host_side, CommConfig::Loopback.impaired(impairment)).unwrap();
+
+        for b in 0..16 {
+            duart_side.tx.send(b).unwrap();
+        }
+        drop(duart_side.tx);
+        assert!(connection.join(Instant::now() + Duration::from_secs(5)));
+
+        // Every byte is lost on the way out, and the link closes without
+        // delivering anything
+        assert_eq!(
+            duart_side.rx.recv_timeout(Duration::from_secs(5)),
+            Err(mpsc::RecvTimeoutError::Disconnected)
+        );
+    }
+
+    #[test]
+    fn test_impaired_passthrough() {
+        let impairment = LinkImpairment {
+            latency: Duration::from_millis(10),
+            ..Default::default()
+        };
+        assert_eq!(
+            CommConfig::SelfTest.impaired(impairment.clone()),
+            CommConfig::SelfTest
+        );
+        #[cfg(feature = "demo")]
+        assert_eq!(
+            CommConfig::Demo.impaired(impairment.clone()),
+            CommConfig::Demo
+        );
+        assert_eq!(
+            CommConfig::Loopback.impaired(LinkImpairment::default()),
+            CommConfig::Loopback
+        );
+        assert_eq!(
+            CommConfig::Loopback.impaired(impairment.clone()),
+            CommConfig::Impaired(Box::new(CommConfig::Loopback), impairment)
+        );
+    }
+
+    #[test]
+    fn test_echo_hex() {
+        let writer = Arc::new(Mutex::new(Vec::<u8>::new()));
+        let mut echo = CommEcho::new(EchoFormat::Hex, writer.clone());
+        for &b in b"\x1b[0cabcdefghijklmnopq" {
+            echo.feed(EchoDirection::Received, b).unwrap();
+        }
+        for &b in b"\x1b[?64c" {
+            echo.feed(EchoDirection::Sent, b).unwrap();
+        }
+        echo.flush().unwrap();
+
+        let output = String::from_utf8(writer.lock().unwrap().clone()).unwrap();
+        assert_eq!(
+            output.lines().collect::<Vec<_>>(),
+            [
+                "< 1b 5b 30 63 61 62 63 64 65 66 67 68 69 6a 6b 6c  .[0cabcdefghijkl",
+                "< 6d 6e 6f 70 71                                   mnopq",
+                "> 1b 5b 3f 36 34 63                                .[?64c",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_echo_duart() {
+        let (duart_side, host_side) = DUARTChannel::new();
+        let writer = Arc::new(Mutex::new(Vec::<u8>::new()));
+        let echo = CommEcho::new(EchoFormat::Raw, writer.clone());
+        let host_side = echo_duart(host_side, echo);
+
+        // Bytes pass through unchanged in both directions
+        host_side.tx.send(b'a').unwrap();
+        assert_eq!(duart_side.rx.recv_timeout(Duration::from_secs(5)), Ok(b'a'));
+        duart_side.tx.send(b'b').unwrap();
+        assert_eq!(host_side.rx.recv_timeout(Duration::from_secs(5)), Ok(b'b'));
+
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while writer.lock().unwrap().len() < 2 && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(1));
+        }
+        assert_eq!(*writer.lock().unwrap(), b"ab");
+    }
+
+    #[test]
+    fn test_echo_blocked_writer() {
+        /// A writer that never finishes a write, like a full pipe.
+        struct Blocked;
+        impl Write for Blocked {
+            fn write(&mut self, _: &[u8]) -> io::Result<usize> {
+                thread::park();
+                Ok(0)
             }
-            let mut buf = [0; 1];
-            let read_result = { pty_read.read(&mut buf) };
-            match read_result {
-                Ok(n) if n > 0 => {
-                    if !tx.send(buf[0]).is_ok() {
-                        break;
-                    }
+            fn flush(&mut self) -> io::Result<()> {
+                Ok(())
+            }
+        }
+
+        let (duart_side, host_side) = DUARTChannel::new();
+        let echo = CommEcho::new(EchoFormat::Raw, Arc::new(Mutex::new(Blocked)));
+        let host_side = echo_duart(host_side, echo);
+
+        // Far more than the echo queue holds still flows through
+        thread::spawn(move || {
+            for i in 0..10_000 {
+                if host_side.tx.send(i as u8).is_err() {
+                    break;
                 }
-                Ok(_) => break, // EOF (read 0 bytes)
-                Err(_) => break,
             }
+        });
+        for i in 0..10_000 {
+            assert_eq!(
+                duart_side.rx.recv_timeout(Duration::from_secs(5)),
+                Ok(i as u8)
+            );
         }
-        trace!("DUART pty read thread exited");
-    });
+    }
+
+    #[test]
+    fn test_impaired_latency() {
+        let (duart_side, host_side) = DUARTChannel::new();
+        let impairment = LinkImpairment {
+            latency: Duration::from_millis(50),
+            ..Default::default()
+        };
+        let _connection =
+            connect_duart(host_side, CommConfig::Loopback.impaired(impairment)).unwrap();
+
+        let start = Instant::now();
+        for &b in b"abc" {
+            duart_side.tx.send(b).unwrap();
+        }
+        let received = (0..3)
+            .map(|_| duart_side.rx.recv_timeout(Duration::from_secs(5)).unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(received, b"abc");
+        // Delayed on the way out and on the way back
+        assert!(start.elapsed() >= Duration::from_millis(100));
+    }
+
+    #[test]
+    fn test_link_rng_is_seeded() {
+        let sample = |seed| {
+            let mut rng = LinkRng(seed);
+            (0..8).map(|_| rng.next_f64()).collect::<Vec<_>>()
+        };
+        assert_eq!(sample(42), sample(42));
+        assert_ne!(sample(42), sample(43));
+        assert!(sample(0).iter().all(|x| (0.0..1.0).contains(x)));
+    }
+
+    #[test]
+    fn test_polled_memory_backend() {
+        let (duart_side, host_side) = DUARTChannel::new();
+        let backend = MemoryBackend::default();
+        let mut comm = PolledComm::new(host_side, Box::new(backend.clone()));
+
+        backend.send(b"hi");
+        comm.tick();
+        assert_eq!(duart_side.rx.try_iter().collect::<Vec<_>>(), b"hi");
+
+        // XOFF holds back input until XON, and neither reaches the host
+        duart_side.tx.send(b'a').unwrap();
+        duart_side.tx.send(0x13).unwrap();
+        backend.send(b"there");
+        comm.tick();
+        assert_eq!(duart_side.rx.try_iter().count(), 0);
+        duart_side.tx.send(0x11).unwrap();
+        duart_side.tx.send(b'b').unwrap();
+        comm.tick();
+        assert_eq!(duart_side.rx.try_iter().collect::<Vec<_>>(), b"there");
+        assert_eq!(backend.take_output(), b"ab");
+        assert!(backend.take_output().is_empty());
+    }
+
+    #[test]
+    fn test_polled_loopback_backpressure() {
+        let (duart_side, host_side) = DUARTChannel::new();
+        let mut comm = PolledComm::new(host_side, Box::new(LoopbackBackend::default()));
+
+        // More than the channel holds: the rest waits in the backend
+        for b in 0..16 {
+            duart_side.tx.send(b).unwrap();
+        }
+        comm.tick();
+        for b in 16..20 {
+            duart_side.tx.send(b).unwrap();
+        }
+        comm.tick();
+        assert_eq!(
+            duart_side.rx.try_iter().collect::<Vec<_>>(),
+            (0..16).collect::<Vec<_>>()
+        );
+        comm.tick();
+        assert_eq!(
+            duart_side.rx.try_iter().collect::<Vec<_>>(),
+            (16..20).collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn test_locator() {
+        let (duart_side, host_side) = DUARTChannel::new();
+        let locator = Locator::default();
+        let host_side = locator_duart(host_side, locator.clone());
+        let send = |bytes: &[u8]| {
+            for &b in bytes {
+                host_side.tx.send(b).unwrap();
+                assert_eq!(duart_side.rx.recv_timeout(Duration::from_secs(5)), Ok(b));
+            }
+        };
+        let report = || {
+            let mut report = vec![];
+            while let Ok(b) = host_side.rx.recv_timeout(Duration::from_millis(100)) {
+                report.push(b);
+            }
+            String::from_utf8(report).unwrap()
+        };
+        let enabled = || locator.0.lock().unwrap().mode != LocatorMode::Off;
+
+        // Nothing is reported until the host enables the locator
+        locator.move_to(Some((3, 10)));
+        locator.button(LocatorButton::Left, true);
+        assert!(!enabled());
+        assert_eq!(report(), "");
+
+        send(b"\x1b[1;0'z\x1b[1;3'{");
+        assert!(enabled());
+        locator.button(LocatorButton::Right, true);
+        assert_eq!(report(), "\x1b[6;5;3;10;1&w");
+        locator.move_to(Some((4, 1)));
+        locator.button(LocatorButton::Left, false);
+        assert_eq!(report(), "\x1b[3;1;4;1;1&w");
+
+        // Terminal output is still delivered
+        duart_side.tx.send(b'x').unwrap();
+        assert_eq!(report(), "x");
+
+        // One shot, requested
+        send(b"\x1b[2'z\x1b['|");
+        assert_eq!(report(), "\x1b[1;1;4;1;1&w");
+        assert!(!enabled());
+
+        send(b"\x1b[1'z");
+        locator.move_to(None);
+        locator.send_locator_report(LOCATOR_REQUESTED);
+        assert_eq!(report(), "\x1b[0&w");
+
+        send(b"\x1b[0'z");
+        locator.button(LocatorButton::Right, false);
+        assert_eq!(report(), "");
+    }
+
+    #[cfg(feature = "comm-trace")]
+    #[test]
+    fn test_comm_trace() {
+        let writer = Arc::new(Mutex::new(Vec::<u8>::new()));
+        let mut trace = CommTrace::new("comm1", writer.clone());
+        trace.feed(b"\x1b[2J\x1b[12;40H");
+
+        let output = String::from_utf8(writer.lock().unwrap().clone()).unwrap();
+        let lines = output.lines().collect::<Vec<_>>();
+        assert_eq!(lines.len(), 2, "{output}");
+        for line in &lines {
+            assert!(line.starts_with(r#"{"ts":"#), "{line}");
+            assert!(line.contains(r#""channel":"comm1""#), "{line}");
+            assert!(line.contains(r#""kind":"Csi""#), "{line}");
+            assert!(line.ends_with("\"}"), "{line}");
+        }
+        assert!(
+            lines[0].contains("'J'") || lines[0].contains("74"),
+            "{}",
+            lines[0]
+        );
+        assert!(
+            lines[1].contains("'H'") || lines[1].contains("72"),
+            "{}",
+            lines[1]
+        );
+    }
+
+    #[test]
+    fn test_record_playback() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("comm1.rec");
+
+        let (duart_side, host_side) = DUARTChannel::new();
+        let config = CommConfig::Loopback.recorded(path.clone());
+        let mut connection = connect_duart(host_side, config).unwrap();
+        for &b in b"hello" {
+            duart_side.tx.send(b).unwrap();
+            assert_eq!(duart_side.rx.recv_timeout(Duration::from_secs(5)), Ok(b));
+            thread::sleep(Duration::from_millis(20));
+        }
+        drop(duart_side.tx);
+        assert!(connection.join(Instant::now() + Duration::from_secs(5)));
 
-    Ok(channel.dtr)
+        let read = || CommRecord::read_all(io::BufReader::new(File::open(&path).unwrap())).unwrap();
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while read().len() < 10 && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(1));
+        }
+        let records = read();
+        let bytes = |direction| {
+            records
+                .iter()
+                .filter(|r| r.direction == direction)
+                .map(|r| r.byte)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(bytes(EchoDirection::Sent), b"hello");
+        assert_eq!(bytes(EchoDirection::Received), b"hello");
+        assert!(records.windows(2).all(|w| w[0].time <= w[1].time));
+        let received = records
+            .iter()
+            .filter(|r| r.direction == EchoDirection::Received)
+            .collect::<Vec<_>>();
+        let recorded_span = received[4].time - received[0].time;
+        assert!(recorded_span >= Duration::from_millis(80));
+
+        // Only the received bytes are played back, in order and about as far
+        // apart as they were recorded
+        let (duart_side, host_side) = DUARTChannel::new();
+        let _connection = connect_duart(host_side, CommConfig::Playback(path.clone())).unwrap();
+        let mut played = vec![];
+        let mut first = None;
+        while let Ok(b) = duart_side.rx.recv_timeout(Duration::from_secs(5)) {
+            first.get_or_insert_with(Instant::now);
+            played.push(b);
+        }
+        assert_eq!(played, b"hello");
+        assert!(first.unwrap().elapsed() >= recorded_span - Duration::from_millis(10));
+    }
+
+    #[test]
+    fn test_comm_record_format() {
+        let record = CommRecord {
+            time: Duration::from_micros(1500),
+            direction: EchoDirection::Received,
+            byte: 0x1b,
+        };
+        let mut line = vec![];
+        record.write(&mut line).unwrap();
+        assert_eq!(line, b"1500 < 1b\n");
+        assert_eq!(CommRecord::parse("1500 < 1b"), Ok(record));
+        assert!(CommRecord::parse("1500 ? 1b").is_err());
+        assert!(CommRecord::parse("1500 > 1b 1c").is_err());
+        assert!(CommRecord::read_all(&b"0 > 41\n\nx < 41\n"[..]).is_err());
+    }
+
+    #[test]
+    fn test_comm_modes() {
+        let modes = CommConfig::examples()
+            .iter()
+            .map(CommConfig::mode)
+            .collect::<Vec<_>>();
+        let names = modes
+            .iter()
+            .map(|mode| mode.name)
+            .collect::<std::collections::HashSet<_>>();
+        assert_eq!(names.len(), modes.len(), "{modes:?}");
+        assert!(modes.iter().all(|mode| !mode.description.is_empty()));
+        assert_eq!(modes[0].name, "loopback");
+        assert!(names.contains("playback"));
+        assert_eq!(names.contains("exec"), cfg!(feature = "pty"));
+    }
+
+    #[test]
+    fn test_every_mode_has_example() {
+        // One bit for each variant. The match is exhaustive, so a new variant
+        // won't compile until it has a bit here, and then fails this test
+        // until it has an example.
+        let bit = |config: &CommConfig| match config {
+            CommConfig::Loopback => 1 << 0,
+            #[cfg(feature = "demo")]
+            CommConfig::Demo => 1 << 1,
+            CommConfig::Pipe(_) => 1 << 2,
+            CommConfig::Pipes { .. } => 1 << 3,
+            CommConfig::Exec { .. } => 1 << 4,
+            #[cfg(feature = "pty")]
+            CommConfig::ExecPty { .. } => 1 << 5,
+            #[cfg(feature = "serial")]
+            CommConfig::Serial { .. } => 1 << 6,
+            #[cfg(target_arch = "wasm32")]
+            CommConfig::WebSocket(_) => 1 << 7,
+            CommConfig::SelfTest => 1 << 8,
+            CommConfig::Impaired(..) => 1 << 9,
+            CommConfig::Echo(..) => 1 << 10,
+            CommConfig::Capture(_) => 1 << 11,
+            CommConfig::Recorded(..) => 1 << 12,
+            CommConfig::Playback(_) => 1 << 13,
+        };
+        let mut expected: u32 = (1 << 14) - 1;
+        for (enabled, variant) in [
+            (cfg!(feature = "demo"), 1),
+            (cfg!(feature = "pty"), 5),
+            (cfg!(feature = "serial"), 6),
+            (cfg!(target_arch = "wasm32"), 7),
+        ] {
+            if !enabled {
+                expected &= !(1 << variant);
+            }
+        }
+        let examples = CommConfig::examples();
+        let covered = examples.iter().fold(0, |bits, config| bits | bit(config));
+        assert_eq!(
+            covered,
+            expected,
+            "{:?}",
+            examples.iter().map(CommConfig::mode).collect::<Vec<_>>()
+        );
+    }
 }
@@ -11,6 +11,8 @@ use ratatui::{
 };
 use tracing::trace;
 
+use crate::host::vt_writer::VtWriter;
+
 const VT420_BORDER_SET: border::Set = border::Set {
     top_left: "|",
     top_right: "|",
@@ -22,18 +24,61 @@ const VT420_BORDER_SET: border::Set = border::Set {
     horizontal_bottom: "-",
 };
 
-const PAGE_MENU_ITEMS: [&str; 11] = [
-    "Set 80 columns",
-    "Set 132 columns",
-    "", //
-    "Set 24 rows",
-    "Set 36 rows",
-    "Set 48 rows",
-    "", //
-    "Page size 24",
-    "Page size 36",
-    "Page size 48",
-    "Page size 72",
+/// A menu entry: its label and a function writing the sequence sent to the
+/// terminal when it is chosen. Entries with an empty label are separators.
+type MenuItem = (&'static str, fn(&mut VtWriter));
+
+const SEPARATOR: MenuItem = ("", |_| {});
+
+const PAGE_INTRO: u8 = 0;
+const PAGE_DISPLAY: u8 = 1;
+const PAGE_KEYBOARD: u8 = 2;
+const PAGE_COMM: u8 = 3;
+const LAST_PAGE: u8 = PAGE_COMM;
+
+/// Number of received bytes shown on the keyboard test page.
+const KEY_HISTORY: usize = 16;
+
+/// The most bytes queued for the terminal before replies are dropped. Frames
+/// are only drawn once the queue is empty, so only replies can back it up,
+/// eg: when the terminal sends XOFF and never sends XON.
+const MAX_PENDING: usize = 4096;
+
+/// The menu for each page, indexed by page number.
+const PAGE_MENU_ITEMS: [&[MenuItem]; 4] = [
+    // PAGE_INTRO
+    &[],
+    // PAGE_DISPLAY
+    &[
+        ("Set 80 columns", |w| _ = w.decscpp(80)),
+        ("Set 132 columns", |w| _ = w.decscpp(132)),
+        SEPARATOR,
+        ("Set 24 rows", |w| _ = w.set_rows(24)),
+        ("Set 36 rows", |w| _ = w.set_rows(36)),
+        ("Set 48 rows", |w| _ = w.set_rows(48)),
+        SEPARATOR,
+        ("Page size 24", |w| _ = w.set_page_size(24)),
+        ("Page size 36", |w| _ = w.set_page_size(36)),
+        ("Page size 48", |w| _ = w.set_page_size(48)),
+        ("Page size 72", |w| _ = w.set_page_size(72)),
+    ],
+    // PAGE_KEYBOARD
+    &[],
+    // PAGE_COMM
+    &[
+        ("Auto wrap on", |w| _ = w.set_private_mode(7)),
+        ("Auto wrap off", |w| _ = w.reset_private_mode(7)),
+        SEPARATOR,
+        ("Auto repeat on", |w| _ = w.set_private_mode(8)),
+        ("Auto repeat off", |w| _ = w.reset_private_mode(8)),
+        SEPARATOR,
+        ("New line mode on", |w| _ = w.set_mode(20)),
+        ("New line mode off", |w| _ = w.reset_mode(20)),
+        SEPARATOR,
+        ("Report cursor position", |w| _ = w.device_status(6)),
+        ("Report device attributes", |w| _ = w.device_attributes()),
+        ("Report auto wrap mode", |w| _ = w.request_private_mode(7)),
+    ],
 ];
 
 #[derive(Clone)]
@@ -76,17 +121,36 @@ impl Pending {
         self.pending.borrow_mut().extend(s.as_bytes());
     }
 
-    fn write_csi(&self, params: &str, final_byte: u8) {
-        self.write_bytes(b"\x1b[");
-        self.write_str(params);
-        self.write_bytes(&[final_byte]);
+    fn write_vt(&self, f: impl FnOnce(&mut VtWriter) -> &mut VtWriter) {
+        let mut writer = VtWriter::new();
+        f(&mut writer);
+        self.write_bytes(writer.as_bytes());
+    }
+
+    /// Queue a reply to the terminal, unless that would back the queue up
+    /// past [`MAX_PENDING`]. A reply is dropped whole, so the terminal never
+    /// sees part of a sequence.
+    fn write_reply(&self, f: impl FnOnce(&mut VtWriter)) {
+        let mut writer = VtWriter::new();
+        f(&mut writer);
+        let bytes = writer.as_bytes();
+        let mut pending = self.pending.borrow_mut();
+        if pending.len() + bytes.len() > MAX_PENDING {
+            trace!(
+                "Dropping a {} byte reply, {} bytes are queued",
+                bytes.len(),
+                pending.len()
+            );
+            return;
+        }
+        pending.extend(bytes);
     }
 
     fn set_cursor_pos(&self, x: u16, y: u16) {
         let mut pos = self.cursor_pos.borrow_mut();
         if pos.x != x || pos.y != y {
-            // VT420 uses 1-based indexing, and format is ESC [ row ; col H
-            self.write_csi(&format!("{};{}", y + 1, x + 1), b'H');
+            // VT420 uses 1-based indexing
+            self.write_vt(|w| w.cursor_position(y + 1, x + 1));
             pos.x = x;
             pos.y = y;
         }
@@ -204,10 +268,8 @@ impl Pending {
             codes.push(29);
         }
 
-        // Write SGR sequence: ESC [ codes... m
         if !codes.is_empty() {
-            let params: Vec<String> = codes.iter().map(|c| c.to_string()).collect();
-            self.write_csi(&params.join(";"), b'm');
+            self.write_vt(|w| w.sgr(&codes));
         }
 
         *current = *style;
@@ -243,8 +305,7 @@ impl ratatui::backend::Backend for Pending {
     fn hide_cursor(&mut self) -> Result<(), Self::Error> {
         let mut visible = self.cursor_visible.borrow_mut();
         if *visible {
-            // ESC [ ? 25 l - Hide cursor
-            self.write_csi("?25", b'l');
+            self.write_vt(|w| w.reset_private_mode(25));
             *visible = false;
         }
         Ok(())
@@ -253,8 +314,7 @@ impl ratatui::backend::Backend for Pending {
     fn show_cursor(&mut self) -> Result<(), Self::Error> {
         let mut visible = self.cursor_visible.borrow_mut();
         if !*visible {
-            // ESC [ ? 25 h - Show cursor
-            self.write_csi("?25", b'h');
+            self.write_vt(|w| w.set_private_mode(25));
             *visible = true;
         }
         Ok(())
@@ -271,39 +331,23 @@ impl ratatui::backend::Backend for Pending {
     }
 
     fn clear(&mut self) -> Result<(), Self::Error> {
-        // ESC [ 2 J - Clear entire screen
-        self.write_csi("2", b'J');
+        self.write_vt(|w| w.erase_display(2));
         // Reset cursor to top-left
         self.set_cursor_pos(0, 0);
         // Reset style
         *self.current_style.borrow_mut() = ratatui::style::Style::default();
-        self.write_csi("0", b'm');
+        self.write_vt(|w| w.sgr(&[0]));
         Ok(())
     }
 
     fn clear_region(&mut self, clear_type: ClearType) -> Result<(), Self::Error> {
         // VT420 clear operations
         match clear_type {
-            ClearType::All => {
-                // ESC [ 2 J - Clear entire screen
-                self.write_csi("2", b'J');
-            }
-            ClearType::CurrentLine => {
-                // ESC [ 2 K - Clear entire line
-                self.write_csi("2", b'K');
-            }
-            ClearType::AfterCursor => {
-                // ESC [ 0 J - Clear from cursor to end of screen
-                self.write_csi("0", b'J');
-            }
-            ClearType::BeforeCursor => {
-                // ESC [ 1 J - Clear from beginning to cursor
-                self.write_csi("1", b'J');
-            }
-            ClearType::UntilNewLine => {
-                // ESC [ 0 K - Clear from cursor to end of line
-                self.write_csi("0", b'K');
-            }
+            ClearType::All => self.write_vt(|w| w.erase_display(2)),
+            ClearType::CurrentLine => self.write_vt(|w| w.erase_line(2)),
+            ClearType::AfterCursor => self.write_vt(|w| w.erase_display(0)),
+            ClearType::BeforeCursor => self.write_vt(|w| w.erase_display(1)),
+            ClearType::UntilNewLine => self.write_vt(|w| w.erase_line(0)),
         }
         Ok(())
     }
@@ -332,6 +376,10 @@ pub struct DemoComm {
     xon: bool,
     input: bool,
     page: u8,
+    /// Bytes received while on the keyboard test page, oldest first
+    keys: VecDeque<u8>,
+    /// The last report the terminal sent in response to a comm settings request
+    report: Option<String>,
 
     screen: ratatui::Terminal<Pending>,
     list_state: ListState,
@@ -350,7 +398,9 @@ impl DemoComm {
             pending,
             xon: false,
             input: false,
-            page: 0,
+            page: PAGE_INTRO,
+            keys: VecDeque::with_capacity(KEY_HISTORY),
+            report: None,
             list_state: ListState::default(),
         }
     }
@@ -358,6 +408,13 @@ impl DemoComm {
     pub fn tick(&mut self) {
         loop {
             if let Ok(byte) = self.rx.try_recv() {
+                if self.page == PAGE_KEYBOARD && byte != 0x11 && byte != 0x13 {
+                    if self.keys.len() == KEY_HISTORY {
+                        self.keys.pop_front();
+                    }
+                    self.keys.push_back(byte);
+                    self.input = true;
+                }
                 if byte == 0x11 {
                     self.xon = true;
                     if self.pending.pending.borrow().is_empty() {
@@ -373,37 +430,12 @@ impl DemoComm {
                     self.input = true;
                 } else if byte == 0x0d {
                     self.input = true;
-                    if self.page == 1 {
-                        match self.list_state.selected() {
-                            Some(0) => {
-                                self.pending.pending.borrow_mut().extend(b"\x1b[80$|");
-                            }
-                            Some(1) => {
-                                self.pending.pending.borrow_mut().extend(b"\x1b[132$|");
-                            }
-                            Some(3) => {
-                                self.pending.pending.borrow_mut().extend(b"\x1b[24*|");
-                            }
-                            Some(4) => {
-                                self.pending.pending.borrow_mut().extend(b"\x1b[36*|");
-                            }
-                            Some(5) => {
-                                self.pending.pending.borrow_mut().extend(b"\x1b[48*|");
-                            }
-                            Some(7) => {
-                                self.pending.pending.borrow_mut().extend(b"\x1b[24t");
-                            }
-                            Some(8) => {
-                                self.pending.pending.borrow_mut().extend(b"\x1b[36t");
-                            }
-                            Some(9) => {
-                                self.pending.pending.borrow_mut().extend(b"\x1b[48t");
-                            }
-                            Some(10) => {
-                                self.pending.pending.borrow_mut().extend(b"\x1b[72t");
-                            }
-                            _ => (),
-                        }
+                    if let Some((_, sequence)) = self
+                        .list_state
+                        .selected()
+                        .and_then(|index| menu_items(self.page).get(index))
+                    {
+                        self.pending.write_reply(sequence);
                     }
                 } else {
                     self.input_queue.feed_with(
@@ -425,34 +457,68 @@ impl DemoComm {
                                         }
                                     }
                                 } else if csi.final_byte == b'C' {
-                                    self.page = 1;
-                                    self.input = true;
-                                    self.list_state.select(Some(0));
+                                    if self.page < LAST_PAGE {
+                                        self.page += 1;
+                                        self.input = true;
+                                        self.list_state.select(first_item(self.page));
+                                    }
                                 } else if csi.final_byte == b'D' {
-                                    self.page = 0;
-                                    self.input = true;
+                                    if self.page > PAGE_INTRO {
+                                        self.page -= 1;
+                                        self.input = true;
+                                        self.list_state.select(first_item(self.page));
+                                    }
                                 } else if csi.final_byte == b'A' {
                                     self.list_state.select_previous();
-                                    if PAGE_MENU_ITEMS
-                                        .get(self.list_state.selected().unwrap_or_default())
-                                        .cloned()
-                                        .unwrap_or_default()
-                                        == ""
-                                    {
+                                    if is_separator(self.page, self.list_state.selected()) {
                                         self.list_state.select_previous();
                                     }
                                     self.input = true;
                                 } else if csi.final_byte == b'B' {
                                     self.list_state.select_next();
-                                    if PAGE_MENU_ITEMS
-                                        .get(self.list_state.selected().unwrap_or_default())
-                                        .cloned()
-                                        .unwrap_or_default()
-                                        == ""
-                                    {
+                                    if is_separator(self.page, self.list_state.selected()) {
                                         self.list_state.select_next();
                                     }
                                     self.input = true;
+                                } else if csi.final_byte == b'R' && csi.params.len() == 2 {
+                                    // CPR: CSI row ; column R
+                                    let row = csi.params.try_parse(0).unwrap_or(1_u16);
+                                    let column = csi.params.try_parse(1).unwrap_or(1_u16);
+                                    self.report =
+                                        Some(format!("Cursor at row {row}, column {column}"));
+                                    self.input = true;
+                                } else if csi.final_byte == b'n' {
+                                    match csi.params.try_parse(0).unwrap_or(0_u16) {
+                                        // DSR: CSI 5 n -> CSI 0 n (no malfunction)
+                                        5 => self.pending.write_reply(|w| _ = w.device_status(0)),
+                                        // CPR: CSI 6 n -> CSI row ; column R
+                                        6 => {
+                                            let pos = *self.pending.cursor_pos.borrow();
+                                            self.pending.write_reply(|w| {
+                                                _ = w.cursor_position_report(pos.y + 1, pos.x + 1)
+                                            });
+                                        }
+                                        _ => trace!("CSI: {:?}", csi),
+                                    }
+                                } else if csi.final_byte == b'c' {
+                                    // DA1: CSI ? 64 ; Ps ... c
+                                    let params = (0..csi.params.len())
+                                        .map(|i| csi.params.try_parse(i).unwrap_or(0_u16))
+                                        .map(|param| param.to_string())
+                                        .collect::<Vec<_>>();
+                                    self.report =
+                                        Some(format!("Device attributes: {}", params.join(";")));
+                                    self.input = true;
+                                } else if csi.final_byte == b'y' && csi.intermediates.has(b'$') {
+                                    // DECRPM: CSI ? mode ; Ps $ y
+                                    let mode = csi.params.try_parse(0).unwrap_or(0_u16);
+                                    let state = match csi.params.try_parse(1).unwrap_or(0_u16) {
+                                        1 | 3 => "set",
+                                        2 | 4 => "reset",
+                                        _ => "not recognized",
+                                    };
+                                    self.report = Some(format!("Mode {mode} is {state}"));
+                                    self.input = true;
                                 } else {
                                     trace!("CSI: {:?}", csi);
                                 }
@@ -477,6 +543,8 @@ impl DemoComm {
                 }
                 return;
             } else {
+                // A frame is only drawn once the last one has been sent, and
+                // every change since then is coalesced into this one redraw
                 if !self.input {
                     return;
                 }
@@ -485,8 +553,8 @@ impl DemoComm {
                 // Move cursor to top-left corner and set double width line for
                 // our title (we do this before and after because Ratatui
                 // doesn't _really_ support it)
-                self.pending.pending.borrow_mut().extend(b"\x1b[0;0H");
-                self.pending.pending.borrow_mut().extend(b"\x1b#6");
+                self.pending
+                    .write_vt(|w| w.cursor_position(1, 1).double_width_line());
 
                 _ = self.screen.draw(|f| {
                     let layout = ratatui::layout::Layout::vertical(vec![
@@ -504,32 +572,52 @@ impl DemoComm {
                         .border_style(Style::default())
                         .padding(Padding::symmetric(1, 0));
 
-                    if self.page == 0 {
-                        let paragraph = create_demo_text().wrap(Wrap { trim: true }).block(block);
-                        f.render_widget(paragraph, areas[1]);
-                    } else if self.page == 1 {
-                        let list = List::new(PAGE_MENU_ITEMS)
-                            .block(
+                    match self.page {
+                        PAGE_INTRO => {
+                            let paragraph =
+                                create_demo_text().wrap(Wrap { trim: true }).block(block);
+                            f.render_widget(paragraph, areas[1]);
+                        }
+                        PAGE_KEYBOARD => {
+                            let paragraph = create_keyboard_text(&self.keys).block(
                                 block
-                                    .title("Display tests")
+                                    .title("Keyboard test")
                                     .title_alignment(HorizontalAlignment::Center),
-                            )
-                            .style(Style::default())
-                            .highlight_style(Style::new().reversed())
-                            .highlight_symbol(">>")
-                            .repeat_highlight_symbol(true)
-                            .direction(ListDirection::TopToBottom);
-
-                        f.render_stateful_widget(list, areas[1], &mut self.list_state);
+                            );
+                            f.render_widget(paragraph, areas[1]);
+                        }
+                        _ => {
+                            let title = if self.page == PAGE_DISPLAY {
+                                "Display tests"
+                            } else {
+                                "Comm settings"
+                            };
+                            let mut block = block
+                                .title(title)
+                                .title_alignment(HorizontalAlignment::Center);
+                            if let (PAGE_COMM, Some(report)) = (self.page, &self.report) {
+                                block = block.title_bottom(Line::from(report.as_str()));
+                            }
+                            let list =
+                                List::new(menu_items(self.page).iter().map(|(label, _)| *label))
+                                    .block(block)
+                                    .style(Style::default())
+                                    .highlight_style(Style::new().reversed())
+                                    .highlight_symbol(">>")
+                                    .repeat_highlight_symbol(true)
+                                    .direction(ListDirection::TopToBottom);
+
+                            f.render_stateful_widget(list, areas[1], &mut self.list_state);
+                        }
                     }
                 });
 
-                self.pending.pending.borrow_mut().extend(b"\x1b[\"v");
+                self.pending.write_vt(|w| w.request_displayed_extent());
 
                 // Move cursor to top-left corner and set double width line for
                 // our title
-                self.pending.pending.borrow_mut().extend(b"\x1b[0;0H");
-                self.pending.pending.borrow_mut().extend(b"\x1b#6");
+                self.pending
+                    .write_vt(|w| w.cursor_position(1, 1).double_width_line());
 
                 break;
             }
@@ -537,6 +625,24 @@ impl DemoComm {
     }
 }
 
+fn menu_items(page: u8) -> &'static [MenuItem] {
+    PAGE_MENU_ITEMS
+        .get(page as usize)
+        .copied()
+        .unwrap_or_default()
+}
+
+/// The item selected when a page is first shown.
+fn first_item(page: u8) -> Option<usize> {
+    (!menu_items(page).is_empty()).then_some(0)
+}
+
+fn is_separator(page: u8, selected: Option<usize>) -> bool {
+    selected
+        .and_then(|index| menu_items(page).get(index))
+        .is_some_and(|(label, _)| label.is_empty())
+}
+
 fn blank_line<'a>() -> Line<'a> {
     Line::from(vec![])
 }
@@ -615,3 +721,84 @@ fn create_demo_text<'a>() -> Paragraph<'a> {
     lines.push(line(&[reversed("[ Press the right arrow key --> ]")]).centered());
     Paragraph::new(lines)
 }
+
+fn create_keyboard_text<'a>(keys: &VecDeque<u8>) -> Paragraph<'a> {
+    let mut lines = vec![];
+    lines.push(line(&[span(
+        "Press keys to see the bytes the terminal sends. Left/right arrows change pages.",
+    )]));
+    lines.push(blank_line());
+    for &byte in keys {
+        let name = match byte {
+            0x00..=0x1f | 0x7f => C0_NAMES
+                .get(byte as usize)
+                .copied()
+                .unwrap_or("DEL")
+                .to_string(),
+            _ => (byte as char).to_string(),
+        };
+        lines.push(Line::from(format!(" {byte:02X}  {name}")));
+    }
+    Paragraph::new(lines)
+}
+
+const C0_NAMES: [&str; 32] = [
+    "NUL", "SOH", "STX", "ETX", "EOT", "ENQ", "ACK", "BEL", "BS", "HT", "LF", "VT", "FF", "CR",
+    "SO", "SI", "DLE", "DC1", "DC2", "DC3", "DC4", "NAK", "SYN", "ETB", "CAN", "EM", "SUB", "ESC",
+    "FS", "GS", "RS", "US",
+];
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn demo() -> (DemoComm, mpsc::SyncSender<u8>) {
+        let (to_demo, rx) = mpsc::sync_channel(16);
+        let (tx, _) = mpsc::sync_channel(16);
+        (DemoComm::new(tx, rx), to_demo)
+    }
+
+    fn send(demo: &mut DemoComm, to_demo: &mpsc::SyncSender<u8>, bytes: &[u8]) {
+        for &byte in bytes {
+            to_demo.send(byte).unwrap();
+            demo.tick();
+        }
+    }
+
+    fn replies(demo: &DemoComm) -> Vec<u8> {
+        demo.pending.pending.borrow_mut().drain(..).collect()
+    }
+
+    #[test]
+    fn test_cursor_position_report() {
+        let (mut demo, to_demo) = demo();
+        *demo.pending.cursor_pos.borrow_mut() = Position::new(4, 2);
+        send(&mut demo, &to_demo, b"\x1b[6n");
+        assert_eq!(replies(&demo), b"\x1b[3;5R");
+    }
+
+    #[test]
+    fn test_pending_bounded_under_xoff() {
+        let (mut demo, to_demo) = demo();
+        // The terminal sends XOFF and never sends XON, but keeps asking for
+        // reports and moving around the menus
+        send(&mut demo, &to_demo, b"\x13");
+        for _ in 0..2000 {
+            send(&mut demo, &to_demo, b"\x1b[5n\x1b[6n\x1b[C\x1b[B\r");
+        }
+        let queued = demo.pending.pending.borrow().len();
+        assert!(queued <= MAX_PENDING, "{queued} bytes queued");
+        assert!(queued > MAX_PENDING - 16, "{queued} bytes queued");
+
+        // Whole replies are kept, so the queue still parses
+        let replies = replies(&demo);
+        assert!(replies.starts_with(b"\x1b[0n\x1b[1;1R"), "{replies:?}");
+    }
+
+    #[test]
+    fn test_device_status_report() {
+        let (mut demo, to_demo) = demo();
+        send(&mut demo, &to_demo, b"\x1b[5n");
+        assert_eq!(replies(&demo), b"\x1b[0n");
+    }
+}
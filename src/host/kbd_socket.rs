@@ -0,0 +1,191 @@
+//! A Unix domain socket for typing on the emulated keyboard from scripts.
+//!
+//! Each connection sends commands, one per line, and gets a line back for
+//! each: `ok`, or `error: ` followed by the reason. The commands are:
+//!
+//! - `char:A`: type a character, with a compose sequence if it isn't on the
+//!   keyboard (eg: `char:é`)
+//! - `key:F3`: press a special key by its [`SpecialKey`] name, ignoring case
+//!   (eg: `key:Return`, `key:KpEnter`)
+//! - `raw:AE C2 B3`: send LK201 keycodes unchanged, in hex
+//! - `text:hello`: type the rest of the line, as if it were pasted
+//!
+//! Raw keycodes are written in hex rather than as bytes since many of them
+//! are also printable ASCII (eg: 0x58 is both F3 and `X`).
+
+use std::fs;
+use std::io::{self, BufRead, BufReader, Write};
+use std::os::unix::fs::FileTypeExt;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::Path;
+use std::thread;
+
+use tracing::{info, trace, warn};
+
+use crate::machine::generic::lk201::{LK201Sender, SpecialKey};
+
+/// A command received on the keyboard socket.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Command {
+    Char(char),
+    Key(SpecialKey),
+    Raw(Vec<u8>),
+    Text(String),
+}
+
+impl Command {
+    pub fn parse(line: &str) -> Result<Self, String> {
+        let line = line.trim_end_matches(['\r', '\n']);
+        let (kind, arg) = line
+            .split_once(':')
+            .ok_or_else(|| format!("expected COMMAND:ARGUMENT, got {line:?}"))?;
+        match kind.trim() {
+            "char" => {
+                let mut chars = arg.chars();
+                match (chars.next(), chars.next()) {
+                    (Some(c), None) => Ok(Self::Char(c)),
+                    _ => Err(format!("expected a single character, got {arg:?}")),
+                }
+            }
+            "key" => SpecialKey::from_name(arg.trim())
+                .map(Self::Key)
+                .ok_or_else(|| format!("unknown key {:?}", arg.trim())),
+            "raw" => {
+                let keycodes = arg
+                    .split_whitespace()
+                    .map(|b| u8::from_str_radix(b, 16))
+                    .collect::<Result<Vec<_>, _>>()
+                    .map_err(|_| format!("expected hex keycodes, got {arg:?}"))?;
+                Ok(Self::Raw(keycodes))
+            }
+            "text" => Ok(Self::Text(arg.to_owned())),
+            kind => Err(format!("unknown command {kind:?}")),
+        }
+    }
+
+    pub fn send(&self, sender: &LK201Sender) -> Result<(), String> {
+        match self {
+            Self::Char(c) => sender
+                .send_char(*c)
+                .or_else(|()| sender.send_composed_char(*c))
+                .map_err(|()| format!("can't type {c:?}")),
+            Self::Key(key) => {
+                sender.send_special_key(*key);
+                Ok(())
+            }
+            Self::Raw(keycodes) => {
+                sender.send_keycodes(keycodes);
+                Ok(())
+            }
+            Self::Text(text) => {
+                sender.paste(text);
+                Ok(())
+            }
+        }
+    }
+}
+
+/// Listen for connections on a Unix domain socket at `path`, typing the
+/// commands received on `sender`. A stale socket left at `path` is replaced.
+pub fn listen(path: &Path, sender: LK201Sender) -> io::Result<()> {
+    if fs::symlink_metadata(path).is_ok_and(|metadata| metadata.file_type().is_socket()) {
+        fs::remove_file(path)?;
+    }
+    let listener = UnixListener::bind(path)?;
+    info!("Listening for keyboard input on {path:?}");
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            match stream {
+                Ok(stream) => {
+                    let sender = sender.clone();
+                    thread::spawn(move || {
+                        if let Err(e) = serve(stream, &sender) {
+                            warn!("Keyboard socket connection failed: {e}");
+                        }
+                    });
+                }
+                Err(e) => warn!("Keyboard socket accept failed: {e}"),
+            }
+        }
+        trace!("Keyboard socket thread exited");
+    });
+    Ok(())
+}
+
+fn serve(stream: UnixStream, sender: &LK201Sender) -> io::Result<()> {
+    let mut writer = stream.try_clone()?;
+    for line in BufReader::new(stream).lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        match Command::parse(&line).and_then(|command| command.send(sender)) {
+            Ok(()) => writeln!(writer, "ok")?,
+            Err(e) => {
+                trace!("Keyboard socket: {e}");
+                writeln!(writer, "error: {e}")?
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::machine::generic::lk201::LK201;
+    use std::sync::mpsc;
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(Command::parse("key:F3"), Ok(Command::Key(SpecialKey::F3)));
+        assert_eq!(SpecialKey::F3 as u8, 0x58);
+        assert_eq!(
+            Command::parse("key:kpenter\r\n"),
+            Ok(Command::Key(SpecialKey::KpEnter))
+        );
+        assert_eq!(Command::parse("char:A"), Ok(Command::Char('A')));
+        assert_eq!(Command::parse("char::"), Ok(Command::Char(':')));
+        assert_eq!(
+            Command::parse("raw:ae c2 B3"),
+            Ok(Command::Raw(vec![0xae, 0xc2, 0xb3]))
+        );
+        assert_eq!(
+            Command::parse("text:a b"),
+            Ok(Command::Text("a b".to_owned()))
+        );
+        assert!(Command::parse("key:F99").is_err());
+        assert!(Command::parse("char:AB").is_err());
+        assert!(Command::parse("raw:GG").is_err());
+        assert!(Command::parse("F3").is_err());
+    }
+
+    #[test]
+    fn test_socket() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("kbd.sock");
+        let (in_tx, in_rx) = mpsc::channel();
+        let (_out_tx, out_rx) = mpsc::channel();
+        let lk201 = LK201::new(in_tx, out_rx);
+        listen(&path, lk201.sender()).unwrap();
+
+        let stream = UnixStream::connect(&path).unwrap();
+        let mut reader = BufReader::new(stream.try_clone().unwrap());
+        let mut reply = |line: &str| {
+            writeln!(&stream, "{line}").unwrap();
+            let mut response = String::new();
+            reader.read_line(&mut response).unwrap();
+            response
+        };
+        assert_eq!(reply("key:F3"), "ok\n");
+        assert_eq!(reply("char:A"), "ok\n");
+        assert_eq!(reply("raw:b3"), "ok\n");
+        assert!(reply("bogus").starts_with("error: "));
+
+        // F3, then shift down, A, all up, then all up
+        assert_eq!(
+            in_rx.try_iter().collect::<Vec<_>>(),
+            [0x58, 0xae, 0xc2, 0xb3, 0xb3]
+        );
+    }
+}
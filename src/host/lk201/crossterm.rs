@@ -1,16 +1,28 @@
-use ratatui::crossterm::event::{Event, KeyCode, KeyModifiers};
+use ratatui::crossterm::event::{
+    Event, KeyCode, KeyEvent, KeyEventKind, KeyModifiers, ModifierKeyCode,
+};
 
+use crate::host::lk201::{Compose, Modifiers};
 use crate::machine::generic::lk201::{LK201Sender, SpecialKey};
 
 #[derive(Default)]
 pub struct CrosstermKeyboard {
     compose_special_key: bool,
+    compose: Compose,
+    modifiers: Modifiers,
+    /// Set once the terminal reports the modifier keys themselves, which
+    /// needs the kitty keyboard protocol.
+    modifier_events: bool,
 }
 
 pub enum KeyboardCommand {
     ToggleRun,
     ToggleHexMode,
     DumpVRAM,
+    ToggleRecording,
+    ToggleVideoFreeze,
+    ToggleMemoryViewer,
+    Reset,
     #[cfg(feature = "pc-trace")]
     TogglePCTrace,
     Quit,
@@ -22,170 +34,302 @@ impl CrosstermKeyboard {
         event: &Event,
         sender: &LK201Sender,
     ) -> Option<KeyboardCommand> {
+        if let Event::Paste(text) = event {
+            self.modifiers.release(sender);
+            sender.paste(text);
+            return None;
+        }
         if let Event::Key(key) = event {
-            if self.compose_special_key {
-                self.compose_special_key = false;
-                if key.modifiers.is_empty() {
-                    match key.code {
-                        KeyCode::Char('1') => {
-                            _ = sender.send_special_key(SpecialKey::F1);
-                        }
-                        KeyCode::Char('2') => {
-                            _ = sender.send_special_key(SpecialKey::F2);
-                        }
-                        KeyCode::Char('3') => {
-                            _ = sender.send_special_key(SpecialKey::F3);
-                        }
-                        KeyCode::Char('4') => {
-                            _ = sender.send_special_key(SpecialKey::F4);
-                        }
-                        KeyCode::Char('5') => {
-                            _ = sender.send_special_key(SpecialKey::F5);
-                        }
-                        KeyCode::Char('c') => {
-                            _ = sender.send_special_key(SpecialKey::Lock);
-                        }
-                        KeyCode::Char('q') => {
-                            return Some(KeyboardCommand::Quit);
-                        }
-                        KeyCode::Char(' ') => {
-                            return Some(KeyboardCommand::ToggleRun);
-                        }
-                        KeyCode::Char('h') => {
-                            return Some(KeyboardCommand::ToggleHexMode);
-                        }
-                        KeyCode::Char('d') => {
-                            return Some(KeyboardCommand::DumpVRAM);
-                        }
-                        #[cfg(feature = "pc-trace")]
-                        KeyCode::Char('p') => {
-                            return Some(KeyboardCommand::TogglePCTrace);
-                        }
-                        _ => {}
-                    }
+            return sender.log_key(
+                || (format!("{:?}", key.code), format!("{:?}", key.modifiers)),
+                |sender| self.update_key(key, sender),
+            );
+        }
+        None
+    }
+
+    fn update_key(&mut self, key: &KeyEvent, sender: &LK201Sender) -> Option<KeyboardCommand> {
+        let command = self.press_key(key, sender);
+        // Without events for the modifiers, there's no telling when they are
+        // released, so don't leave them held until the next key
+        if !self.modifier_events {
+            self.modifiers.release(sender);
+        }
+        command
+    }
+
+    fn press_key(&mut self, key: &KeyEvent, sender: &LK201Sender) -> Option<KeyboardCommand> {
+        let shift = key.modifiers.contains(KeyModifiers::SHIFT);
+        let ctrl = key.modifiers.contains(KeyModifiers::CONTROL);
+        if let KeyCode::Modifier(modifier) = key.code {
+            // Only reported by terminals with the kitty keyboard protocol
+            self.modifier_events = true;
+            let pressed = key.kind != KeyEventKind::Release;
+            let (shift, ctrl) = match modifier {
+                ModifierKeyCode::LeftShift | ModifierKeyCode::RightShift => (pressed, ctrl),
+                ModifierKeyCode::LeftControl | ModifierKeyCode::RightControl => (shift, pressed),
+                _ => (shift, ctrl),
+            };
+            self.modifiers.update(shift, ctrl, sender);
+            return None;
+        }
+        if key.kind == KeyEventKind::Release {
+            return None;
+        }
+        if self.compose.is_active() {
+            match key.code {
+                KeyCode::Char(c)
+                    if key.modifiers.is_empty() || key.modifiers == KeyModifiers::SHIFT =>
+                {
+                    self.modifiers.release(sender);
+                    self.compose.key(c, sender);
                 }
+                _ => self.compose.cancel(),
             }
-            if key.modifiers == KeyModifiers::CONTROL {
+            return None;
+        }
+        if self.compose_special_key {
+            self.compose_special_key = false;
+            if key.modifiers.is_empty() {
+                self.modifiers.release(sender);
                 match key.code {
-                    KeyCode::Char('g') => {
-                        self.compose_special_key = true;
-                    }
-                    KeyCode::Char(c) => {
-                        _ = sender.send_ctrl_char(c);
-                    }
-                    KeyCode::F(1) => {
-                        _ = sender.send_ctrl_special_key(SpecialKey::F1);
-                    }
-                    KeyCode::F(2) => {
-                        _ = sender.send_ctrl_special_key(SpecialKey::F2);
-                    }
-                    KeyCode::F(3) => {
-                        _ = sender.send_ctrl_special_key(SpecialKey::F3);
+                    KeyCode::Char('1') => {
+                        _ = sender.send_special_key(SpecialKey::F1);
                     }
-                    KeyCode::F(4) => {
-                        _ = sender.send_ctrl_special_key(SpecialKey::F4);
+                    KeyCode::Char('2') => {
+                        _ = sender.send_special_key(SpecialKey::F2);
                     }
-                    KeyCode::F(5) => {
-                        _ = sender.send_ctrl_special_key(SpecialKey::F5);
+                    KeyCode::Char('3') => {
+                        _ = sender.send_special_key(SpecialKey::F3);
                     }
-                    KeyCode::Up => {
-                        _ = sender.send_ctrl_special_key(SpecialKey::Up);
+                    KeyCode::Char('4') => {
+                        _ = sender.send_special_key(SpecialKey::F4);
                     }
-                    KeyCode::Down => {
-                        _ = sender.send_ctrl_special_key(SpecialKey::Down);
+                    KeyCode::Char('5') => {
+                        _ = sender.send_special_key(SpecialKey::F5);
                     }
-                    KeyCode::Left => {
-                        _ = sender.send_ctrl_special_key(SpecialKey::Left);
+                    KeyCode::Char('c') => {
+                        _ = sender.send_special_key(SpecialKey::Lock);
                     }
-                    KeyCode::Right => {
-                        _ = sender.send_ctrl_special_key(SpecialKey::Right);
+                    KeyCode::Char('m') => {
+                        self.compose.start();
+                        return None;
                     }
-                    _ => {}
-                }
-            }
-            if key.modifiers == KeyModifiers::SHIFT | KeyModifiers::CONTROL {
-                match key.code {
-                    KeyCode::Up => {
-                        _ = sender.send_shift_ctrl_special_key(SpecialKey::Up);
+                    KeyCode::Char('q') => {
+                        return Some(KeyboardCommand::Quit);
                     }
-                    KeyCode::Down => {
-                        _ = sender.send_shift_ctrl_special_key(SpecialKey::Down);
+                    KeyCode::Char(' ') => {
+                        return Some(KeyboardCommand::ToggleRun);
                     }
-                    KeyCode::Left => {
-                        _ = sender.send_shift_ctrl_special_key(SpecialKey::Left);
+                    KeyCode::Char('h') => {
+                        return Some(KeyboardCommand::ToggleHexMode);
                     }
-                    KeyCode::Right => {
-                        _ = sender.send_shift_ctrl_special_key(SpecialKey::Right);
+                    KeyCode::Char('d') => {
+                        return Some(KeyboardCommand::DumpVRAM);
                     }
-                    _ => {}
-                }
-            }
-            if key.modifiers == KeyModifiers::SHIFT {
-                match key.code {
-                    KeyCode::Char(c) => {
-                        _ = sender.send_char(c);
+                    KeyCode::Char('r') => {
+                        return Some(KeyboardCommand::ToggleRecording);
                     }
-                    KeyCode::Up => {
-                        _ = sender.send_shift_special_key(SpecialKey::Up);
+                    KeyCode::Char('v') => {
+                        return Some(KeyboardCommand::ToggleVideoFreeze);
                     }
-                    KeyCode::Down => {
-                        _ = sender.send_shift_special_key(SpecialKey::Down);
+                    KeyCode::Char('x') => {
+                        return Some(KeyboardCommand::ToggleMemoryViewer);
                     }
-                    KeyCode::Left => {
-                        _ = sender.send_shift_special_key(SpecialKey::Left);
+                    KeyCode::Char('b') => {
+                        return Some(KeyboardCommand::Reset);
                     }
-                    KeyCode::Right => {
-                        _ = sender.send_shift_special_key(SpecialKey::Right);
+                    #[cfg(feature = "pc-trace")]
+                    KeyCode::Char('p') => {
+                        return Some(KeyboardCommand::TogglePCTrace);
                     }
                     _ => {}
                 }
             }
-            if key.modifiers.is_empty() {
-                match key.code {
-                    KeyCode::Char(c) => {
-                        _ = sender.send_char(c);
-                    }
-                    KeyCode::Left => {
-                        _ = sender.send_special_key(SpecialKey::Left);
-                    }
-                    KeyCode::Right => {
-                        _ = sender.send_special_key(SpecialKey::Right);
-                    }
-                    KeyCode::Up => {
-                        _ = sender.send_special_key(SpecialKey::Up);
-                    }
-                    KeyCode::Down => {
-                        _ = sender.send_special_key(SpecialKey::Down);
-                    }
-                    KeyCode::Backspace => {
-                        _ = sender.send_special_key(SpecialKey::Delete);
-                    }
-                    KeyCode::Enter => {
-                        _ = sender.send_special_key(SpecialKey::Return);
-                    }
-                    KeyCode::Esc => {
-                        sender.send_escape();
-                    }
-
-                    KeyCode::F(1) => {
-                        _ = sender.send_special_key(SpecialKey::F1);
-                    }
-                    KeyCode::F(2) => {
-                        _ = sender.send_special_key(SpecialKey::F2);
-                    }
-                    KeyCode::F(3) => {
-                        _ = sender.send_special_key(SpecialKey::F3);
-                    }
-                    KeyCode::F(4) => {
-                        _ = sender.send_special_key(SpecialKey::F4);
-                    }
-                    KeyCode::F(5) => {
-                        _ = sender.send_special_key(SpecialKey::F5);
-                    }
-                    _ => {}
+        }
+        if key.modifiers == KeyModifiers::CONTROL && key.code == KeyCode::Char('g') {
+            self.compose_special_key = true;
+            return None;
+        }
+        // Alt and the other host modifiers have no LK201 key
+        if !(KeyModifiers::SHIFT | KeyModifiers::CONTROL).contains(key.modifiers) {
+            return None;
+        }
+        self.modifiers.update(shift, ctrl, sender);
+        let special_key = match key.code {
+            KeyCode::Char(c) => {
+                if self.modifiers.send_char(c, sender).is_err() {
+                    self.modifiers.release(sender);
+                    _ = sender.send_composed_char(c);
                 }
+                return None;
             }
-        }
+            KeyCode::Esc => {
+                self.modifiers.send_escape(sender);
+                return None;
+            }
+            KeyCode::Left => SpecialKey::Left,
+            KeyCode::Right => SpecialKey::Right,
+            KeyCode::Up => SpecialKey::Up,
+            KeyCode::Down => SpecialKey::Down,
+            KeyCode::Backspace => SpecialKey::Delete,
+            KeyCode::Enter => SpecialKey::Return,
+            KeyCode::F(1) => SpecialKey::F1,
+            KeyCode::F(2) => SpecialKey::F2,
+            KeyCode::F(3) => SpecialKey::F3,
+            KeyCode::F(4) => SpecialKey::F4,
+            KeyCode::F(5) => SpecialKey::F5,
+            _ => return None,
+        };
+        sender.send_special_key(special_key);
         None
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::sync::mpsc;
+
+    use super::*;
+    use crate::machine::generic::lk201::LK201;
+
+    fn key(code: KeyCode, modifiers: KeyModifiers) -> Event {
+        Event::Key(KeyEvent::new(code, modifiers))
+    }
+
+    #[test]
+    fn test_compose() {
+        let (in_tx, in_rx) = mpsc::channel();
+        let (_out_tx, out_rx) = mpsc::channel();
+        let lk201 = LK201::new(in_tx, out_rx);
+        let sender = lk201.sender();
+        let mut keyboard = CrosstermKeyboard::default();
+
+        for event in [
+            key(KeyCode::Char('g'), KeyModifiers::CONTROL),
+            key(KeyCode::Char('m'), KeyModifiers::NONE),
+            key(KeyCode::Char('\''), KeyModifiers::NONE),
+            key(KeyCode::Char('e'), KeyModifiers::NONE),
+            // An accented character from the host is composed too
+            key(KeyCode::Char('\u{f1}'), KeyModifiers::NONE),
+            // Invalid sequences are dropped
+            key(KeyCode::Char('g'), KeyModifiers::CONTROL),
+            key(KeyCode::Char('m'), KeyModifiers::NONE),
+            key(KeyCode::Char('q'), KeyModifiers::NONE),
+            key(KeyCode::Char('q'), KeyModifiers::NONE),
+            key(KeyCode::Char('x'), KeyModifiers::NONE),
+        ] {
+            assert!(keyboard.update_keyboard(&event, &sender).is_none());
+        }
+
+        assert_eq!(
+            in_rx.try_iter().collect::<Vec<_>>(),
+            [
+                0xb1, 0xfb, 0xcc, // Compose ' e
+                0xb1, 0xae, 0xbf, 0xb3, 0xde, // Compose ~ n
+                0xc8, // x
+            ]
+        );
+    }
+
+    #[test]
+    fn test_key_log() {
+        let (in_tx, in_rx) = mpsc::channel();
+        let (_out_tx, out_rx) = mpsc::channel();
+        let mut lk201 = LK201::new(in_tx, out_rx);
+        lk201.enable_key_log();
+        let sender = lk201.sender();
+        let mut keyboard = CrosstermKeyboard::default();
+
+        keyboard.update_keyboard(&key(KeyCode::Char('A'), KeyModifiers::SHIFT), &sender);
+        keyboard.update_keyboard(&key(KeyCode::Char('a'), KeyModifiers::NONE), &sender);
+
+        // Shift down, A, all up, then a
+        assert_eq!(
+            in_rx.try_iter().collect::<Vec<_>>(),
+            [0xae, 0xc2, 0xb3, 0xc2]
+        );
+        let log = lk201.key_log();
+        assert_eq!(log.len(), 2);
+        assert_eq!(log[0].key, "Char('A')");
+        assert!(log[0].modifiers.contains("SHIFT"), "{}", log[0].modifiers);
+        assert_eq!(log[0].keycodes, [0xae, 0xc2, 0xb3]);
+        assert_eq!(log[1].keycodes, [0xc2]);
+
+        let mut text = vec![];
+        lk201.write_key_log(&mut text).unwrap();
+        let text = String::from_utf8(text).unwrap();
+        assert!(text.starts_with("Char('A') ["), "{text}");
+        assert!(text.contains("] -> AE C2 B3\n"), "{text}");
+    }
+
+    /// Without events for the modifier keys, they are released after each key
+    /// rather than held until the next one.
+    #[test]
+    fn test_modifiers_released() {
+        let (in_tx, in_rx) = mpsc::channel();
+        let (_out_tx, out_rx) = mpsc::channel();
+        let lk201 = LK201::new(in_tx, out_rx);
+        let sender = lk201.sender();
+        let mut keyboard = CrosstermKeyboard::default();
+
+        keyboard.update_keyboard(&key(KeyCode::Char('x'), KeyModifiers::CONTROL), &sender);
+        let x = sender.char_key('x').unwrap().0;
+        assert_eq!(in_rx.try_iter().collect::<Vec<_>>(), [0xaf, x, 0xb3]);
+
+        keyboard.update_keyboard(&key(KeyCode::Left, KeyModifiers::SHIFT), &sender);
+        assert_eq!(
+            in_rx.try_iter().collect::<Vec<_>>(),
+            [0xae, SpecialKey::Left as u8, 0xb3]
+        );
+    }
+
+    #[test]
+    fn test_held_shift() {
+        let (in_tx, in_rx) = mpsc::channel();
+        let (_out_tx, out_rx) = mpsc::channel();
+        let lk201 = LK201::new(in_tx, out_rx);
+        let sender = lk201.sender();
+        let mut keyboard = CrosstermKeyboard::default();
+        let shift = |kind| {
+            Event::Key(KeyEvent::new_with_kind(
+                KeyCode::Modifier(ModifierKeyCode::LeftShift),
+                KeyModifiers::SHIFT,
+                kind,
+            ))
+        };
+
+        for event in [
+            shift(KeyEventKind::Press),
+            key(KeyCode::Char('A'), KeyModifiers::SHIFT),
+            key(KeyCode::Char('B'), KeyModifiers::SHIFT),
+            shift(KeyEventKind::Release),
+        ] {
+            keyboard.update_keyboard(&event, &sender);
+        }
+        let a = sender.char_key('a').unwrap().0;
+        let b = sender.char_key('b').unwrap().0;
+        assert_eq!(in_rx.try_iter().collect::<Vec<_>>(), [0xae, a, b, 0xb3]);
+
+        // A terminal that doesn't report shift still gets a shifted key
+        keyboard.update_keyboard(&key(KeyCode::Char('A'), KeyModifiers::NONE), &sender);
+        assert_eq!(in_rx.try_iter().collect::<Vec<_>>(), [0xae, a, 0xb3]);
+
+        // Ctrl stays down across keys, and shift is released without it
+        for event in [
+            key(
+                KeyCode::Char('X'),
+                KeyModifiers::CONTROL | KeyModifiers::SHIFT,
+            ),
+            key(KeyCode::Char('x'), KeyModifiers::CONTROL),
+            key(KeyCode::Char('x'), KeyModifiers::NONE),
+        ] {
+            keyboard.update_keyboard(&event, &sender);
+        }
+        let x = sender.char_key('x').unwrap().0;
+        assert_eq!(
+            in_rx.try_iter().collect::<Vec<_>>(),
+            [0xaf, 0xae, x, 0xb3, 0xaf, x, 0xb3, x]
+        );
+    }
+}
@@ -2,3 +2,110 @@
 pub mod crossterm;
 #[cfg(feature = "graphics")]
 pub mod winit;
+
+use tracing::warn;
+
+use crate::machine::generic::lk201::{LK201Sender, SpecialKey};
+
+/// The shift and ctrl keys held down on the emulated keyboard, kept in step
+/// with the host's modifiers. Modifier keycodes are only sent when the host's
+/// modifiers change, so a key held across several keystrokes is pressed and
+/// released once, as on a real keyboard.
+#[derive(Default)]
+pub struct Modifiers {
+    shift: bool,
+    ctrl: bool,
+}
+
+impl Modifiers {
+    /// Press or release shift and ctrl to match the host. The LK201 can't
+    /// release a single key, so releasing either one releases both and
+    /// presses the other again if it is still held.
+    pub fn update(&mut self, shift: bool, ctrl: bool, sender: &LK201Sender) {
+        if (self.shift && !shift) || (self.ctrl && !ctrl) {
+            sender.send_all_up();
+            self.shift = false;
+            self.ctrl = false;
+        }
+        if ctrl && !self.ctrl {
+            sender.send_special_key(SpecialKey::Ctrl);
+            self.ctrl = true;
+        }
+        if shift && !self.shift {
+            sender.send_special_key(SpecialKey::Shift);
+            self.shift = true;
+        }
+    }
+
+    /// Release shift and ctrl, before keys that are sent with their own
+    /// modifiers (eg: compose sequences and pasted text).
+    pub fn release(&mut self, sender: &LK201Sender) {
+        self.update(false, false, sender);
+    }
+
+    /// Press `keycode` with shift and ctrl as given, then return them to
+    /// what the host holds.
+    pub fn press(&mut self, keycode: u8, shift: bool, ctrl: bool, sender: &LK201Sender) {
+        let held = (self.shift, self.ctrl);
+        self.update(shift, ctrl, sender);
+        sender.send_keycodes(&[keycode]);
+        self.update(held.0, held.1, sender);
+    }
+
+    /// Type a character with ctrl as held on the host. Shift is pressed or
+    /// released around the key if the keyboard layout needs it to be
+    /// different, eg: for a character that is shifted on the host but not on
+    /// the LK201.
+    pub fn send_char(&mut self, c: char, sender: &LK201Sender) -> Result<(), ()> {
+        let (keycode, shift) = sender.char_key(c).ok_or(())?;
+        self.press(keycode, shift, self.ctrl, sender);
+        if !self.ctrl {
+            sender.echo(c);
+        }
+        Ok(())
+    }
+
+    /// Send Escape, which the LK201 types as Ctrl+3 whatever the host holds.
+    /// Every key is released afterwards, so the host's modifiers are pressed
+    /// again on the next update.
+    pub fn send_escape(&mut self, sender: &LK201Sender) {
+        self.release(sender);
+        sender.send_escape();
+    }
+}
+
+/// Collects the two keys typed after the host's compose key and sends them to
+/// the terminal as a compose sequence.
+#[derive(Default)]
+pub struct Compose {
+    active: bool,
+    first: Option<char>,
+}
+
+impl Compose {
+    pub fn start(&mut self) {
+        self.active = true;
+        self.first = None;
+    }
+
+    pub fn cancel(&mut self) {
+        self.active = false;
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.active
+    }
+
+    /// Take a key typed while composing. Once two keys have been collected the
+    /// sequence is sent, or dropped if the terminal wouldn't understand it.
+    pub fn key(&mut self, c: char, sender: &LK201Sender) {
+        let Some(first) = self.first.take() else {
+            self.first = Some(c);
+            return;
+        };
+        self.active = false;
+        if sender.send_compose(first, c).is_err() {
+            warn!("KBD: No compose sequence for {first:?} {c:?}");
+        }
+    }
+}
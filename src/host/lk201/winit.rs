@@ -1,95 +1,182 @@
 use game_loop::winit::keyboard::{Key, KeyCode};
 use winit_input_helper::WinitInputHelper;
 
+use crate::host::lk201::{Compose, Modifiers};
 use crate::machine::generic::lk201::{LK201Sender, SpecialKey};
 
-pub fn update_keyboard(input: &WinitInputHelper, sender: &LK201Sender) {
-    for (key, mapping) in [
-        (KeyCode::F1, SpecialKey::F1),
-        (KeyCode::F2, SpecialKey::F2),
-        (KeyCode::F3, SpecialKey::F3),
-        (KeyCode::F4, SpecialKey::F4),
-        (KeyCode::F5, SpecialKey::F5),
-        (KeyCode::F6, SpecialKey::F6),
-        (KeyCode::F7, SpecialKey::F7),
-        (KeyCode::F8, SpecialKey::F8),
-        (KeyCode::F9, SpecialKey::F9),
-        (KeyCode::F10, SpecialKey::F10),
-        (KeyCode::F11, SpecialKey::F11),
-        (KeyCode::F12, SpecialKey::F12),
-        (KeyCode::F13, SpecialKey::F13),
-        (KeyCode::F14, SpecialKey::F14),
-        (KeyCode::F15, SpecialKey::Help),
-        (KeyCode::F16, SpecialKey::Menu),
-        (KeyCode::F17, SpecialKey::F17),
-        (KeyCode::F18, SpecialKey::F18),
-        (KeyCode::F19, SpecialKey::F19),
-        (KeyCode::F20, SpecialKey::F20),
-        (KeyCode::ArrowUp, SpecialKey::Up),
-        (KeyCode::ArrowDown, SpecialKey::Down),
-        (KeyCode::ArrowLeft, SpecialKey::Left),
-        (KeyCode::ArrowRight, SpecialKey::Right),
-        (KeyCode::Enter, SpecialKey::Return),
-        (KeyCode::Backspace, SpecialKey::Delete),
-        (KeyCode::Tab, SpecialKey::Tab),
-        (KeyCode::Home, SpecialKey::Find),
-        (KeyCode::End, SpecialKey::Select),
-        (KeyCode::Insert, SpecialKey::InsertHere),
-        (KeyCode::Delete, SpecialKey::Remove),
-        (KeyCode::PageUp, SpecialKey::PrevScreen),
-        (KeyCode::PageDown, SpecialKey::NextScreen),
-        (KeyCode::NumLock, SpecialKey::KpPf1),
-        (KeyCode::NumpadDivide, SpecialKey::KpPf2),
-        (KeyCode::NumpadMultiply, SpecialKey::KpPf3),
-        (KeyCode::NumpadSubtract, SpecialKey::KpPf4),
-        (KeyCode::Numpad0, SpecialKey::Kp0),
-        (KeyCode::Numpad1, SpecialKey::Kp1),
-        (KeyCode::Numpad2, SpecialKey::Kp2),
-        (KeyCode::Numpad3, SpecialKey::Kp3),
-        (KeyCode::Numpad4, SpecialKey::Kp4),
-        (KeyCode::Numpad5, SpecialKey::Kp5),
-        (KeyCode::Numpad6, SpecialKey::Kp6),
-        (KeyCode::Numpad7, SpecialKey::Kp7),
-        (KeyCode::Numpad8, SpecialKey::Kp8),
-        (KeyCode::Numpad9, SpecialKey::Kp9),
-        (KeyCode::NumpadAdd, SpecialKey::KpHyphen),
-        (KeyCode::NumpadDecimal, SpecialKey::KpPeriod),
-        (KeyCode::NumpadEnter, SpecialKey::KpEnter),
-    ] {
-        if input.key_pressed(key) {
-            if input.held_control() {
-                if input.held_shift() {
-                    sender.send_shift_ctrl_special_key(mapping);
-                } else {
-                    sender.send_ctrl_special_key(mapping);
-                }
-            } else if input.held_shift() {
-                sender.send_shift_special_key(mapping);
-            } else {
-                sender.send_special_key(mapping);
+/// The host key that starts a compose sequence.
+const COMPOSE_KEY: KeyCode = KeyCode::AltRight;
+
+/// An emulator command, typed as Ctrl+G then a key.
+pub enum KeyboardCommand {
+    Reset,
+    /// Ctrl+Shift+V: type the contents of the host clipboard.
+    Paste,
+}
+
+/// The host keyboard in the graphical display.
+#[derive(Default)]
+pub struct WinitKeyboard {
+    compose: Compose,
+    /// Set after Ctrl+G, while waiting for a command key.
+    command_mode: bool,
+    modifiers: Modifiers,
+}
+
+impl WinitKeyboard {
+    /// Send the keys pressed since the last update, or return the command
+    /// typed after Ctrl+G. Any key other than a command leaves command mode
+    /// and is typed as usual.
+    pub fn update(
+        &mut self,
+        input: &WinitInputHelper,
+        sender: &LK201Sender,
+    ) -> Option<KeyboardCommand> {
+        if input.key_pressed(COMPOSE_KEY) {
+            self.compose.start();
+            return None;
+        }
+        if self.compose.is_active() && input.key_pressed(KeyCode::Escape) {
+            self.compose.cancel();
+            return None;
+        }
+        if input.held_control() && input.held_shift() && input.key_pressed(KeyCode::KeyV) {
+            self.command_mode = false;
+            return Some(KeyboardCommand::Paste);
+        }
+        if input.held_control() && input.key_pressed_logical(Key::Character("g")) {
+            self.command_mode = true;
+            return None;
+        }
+        if self.command_mode && input.key_pressed_logical(Key::Character("b")) {
+            self.command_mode = false;
+            return Some(KeyboardCommand::Reset);
+        }
+        // Compose sequences press their own modifiers
+        if self.compose.is_active() {
+            self.modifiers.release(sender);
+        } else {
+            self.modifiers
+                .update(input.held_shift(), input.held_control(), sender);
+        }
+
+        for (key, mapping) in [
+            (KeyCode::F1, SpecialKey::F1),
+            (KeyCode::F2, SpecialKey::F2),
+            (KeyCode::F3, SpecialKey::F3),
+            (KeyCode::F4, SpecialKey::F4),
+            (KeyCode::F5, SpecialKey::F5),
+            (KeyCode::F6, SpecialKey::F6),
+            (KeyCode::F7, SpecialKey::F7),
+            (KeyCode::F8, SpecialKey::F8),
+            (KeyCode::F9, SpecialKey::F9),
+            (KeyCode::F10, SpecialKey::F10),
+            (KeyCode::F11, SpecialKey::F11),
+            (KeyCode::F12, SpecialKey::F12),
+            (KeyCode::F13, SpecialKey::F13),
+            (KeyCode::F14, SpecialKey::F14),
+            (KeyCode::F15, SpecialKey::Help),
+            (KeyCode::F16, SpecialKey::Menu),
+            (KeyCode::F17, SpecialKey::F17),
+            (KeyCode::F18, SpecialKey::F18),
+            (KeyCode::F19, SpecialKey::F19),
+            (KeyCode::F20, SpecialKey::F20),
+            (KeyCode::ArrowUp, SpecialKey::Up),
+            (KeyCode::ArrowDown, SpecialKey::Down),
+            (KeyCode::ArrowLeft, SpecialKey::Left),
+            (KeyCode::ArrowRight, SpecialKey::Right),
+            (KeyCode::Enter, SpecialKey::Return),
+            (KeyCode::Backspace, SpecialKey::Delete),
+            (KeyCode::Tab, SpecialKey::Tab),
+            (KeyCode::Home, SpecialKey::Find),
+            (KeyCode::End, SpecialKey::Select),
+            (KeyCode::Insert, SpecialKey::InsertHere),
+            (KeyCode::Delete, SpecialKey::Remove),
+            (KeyCode::PageUp, SpecialKey::PrevScreen),
+            (KeyCode::PageDown, SpecialKey::NextScreen),
+            (KeyCode::NumLock, SpecialKey::KpPf1),
+            (KeyCode::NumpadDivide, SpecialKey::KpPf2),
+            (KeyCode::NumpadMultiply, SpecialKey::KpPf3),
+            (KeyCode::NumpadSubtract, SpecialKey::KpPf4),
+            (KeyCode::Numpad0, SpecialKey::Kp0),
+            (KeyCode::Numpad1, SpecialKey::Kp1),
+            (KeyCode::Numpad2, SpecialKey::Kp2),
+            (KeyCode::Numpad3, SpecialKey::Kp3),
+            (KeyCode::Numpad4, SpecialKey::Kp4),
+            (KeyCode::Numpad5, SpecialKey::Kp5),
+            (KeyCode::Numpad6, SpecialKey::Kp6),
+            (KeyCode::Numpad7, SpecialKey::Kp7),
+            (KeyCode::Numpad8, SpecialKey::Kp8),
+            (KeyCode::Numpad9, SpecialKey::Kp9),
+            (KeyCode::NumpadAdd, SpecialKey::KpHyphen),
+            (KeyCode::NumpadDecimal, SpecialKey::KpPeriod),
+            (KeyCode::NumpadEnter, SpecialKey::KpEnter),
+        ] {
+            if input.key_pressed(key) {
+                self.compose.cancel();
+                self.command_mode = false;
+                sender.log_key(
+                    || (format!("{key:?}"), modifiers(input)),
+                    |sender| sender.send_special_key(mapping),
+                );
+                return None;
             }
-            return;
         }
-    }
 
-    br#"!"$#%&'()*+,-./0123456789:;<=>?@ABCDEFGHIJKLMNOPQRSTUVWXYZ[\]^_`abcdefghijklmnopqrstuvwxyz{|}~"#        .iter()
-        .for_each(|&c| {
+        for &c in br#"!"$#%&'()*+,-./0123456789:;<=>?@ABCDEFGHIJKLMNOPQRSTUVWXYZ[\]^_`abcdefghijklmnopqrstuvwxyz{|}~"# {
             let s = &[c];
             let s = str::from_utf8(s).unwrap();
             if input.key_pressed_logical(Key::Character(s)) {
-                if input.held_control() {
-                    sender.send_ctrl_char(c as char);
-                } else {
-                    sender.send_char(c as char);
-                }
+                self.command_mode = false;
+                sender.log_key(
+                    || (format!("{:?}", Key::Character(s)), modifiers(input)),
+                    |sender| self.send_char(c as char, sender),
+                );
             }
-        });
+        }
+
+        if input.key_pressed(KeyCode::Space) {
+            self.command_mode = false;
+            sender.log_key(
+                || (format!("{:?}", KeyCode::Space), modifiers(input)),
+                |sender| self.send_char(' ', sender),
+            );
+        }
 
-    if input.key_pressed(KeyCode::Space) {
-        sender.send_char(' ');
+        if input.key_pressed(KeyCode::Escape) {
+            self.command_mode = false;
+            sender.log_key(
+                || (format!("{:?}", KeyCode::Escape), modifiers(input)),
+                |sender| self.modifiers.send_escape(sender),
+            );
+        }
+        None
     }
 
-    if input.key_pressed(KeyCode::Escape) {
-        sender.send_escape();
+    fn send_char(&mut self, c: char, sender: &LK201Sender) {
+        if self.compose.is_active() {
+            self.compose.key(c, sender);
+        } else {
+            _ = self.modifiers.send_char(c, sender);
+        }
+    }
+}
+
+/// The modifiers held on the host, for the key log.
+fn modifiers(input: &WinitInputHelper) -> String {
+    let held = [
+        (input.held_shift(), "SHIFT"),
+        (input.held_control(), "CONTROL"),
+        (input.held_alt(), "ALT"),
+    ];
+    let names = held
+        .iter()
+        .filter(|(held, _)| *held)
+        .map(|(_, name)| *name)
+        .collect::<Vec<_>>();
+    if names.is_empty() {
+        "NONE".to_string()
+    } else {
+        names.join(" | ")
     }
 }
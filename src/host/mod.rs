@@ -1,9 +1,16 @@
 pub mod comm;
 #[cfg(feature = "demo")]
 pub mod demo_comm;
+#[cfg(unix)]
+pub mod kbd_socket;
 pub mod lk201;
 pub mod logging;
+#[cfg(feature = "graphics")]
+pub mod png;
 pub mod screen;
 pub mod ssu;
+pub mod vt_writer;
+#[cfg(target_arch = "wasm32")]
+pub mod websocket;
 #[cfg(feature = "graphics")]
 pub mod wgpu;
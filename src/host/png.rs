@@ -0,0 +1,137 @@
+//! A minimal PNG writer for frame buffer snapshots.
+//!
+//! The image data is stored without compression, which keeps this free of
+//! dependencies. Snapshots are mostly black, so they are still small enough
+//! for CI artifacts.
+
+use std::io::{self, Write};
+
+use crate::machine::vt420::memory::crc32;
+
+const SIGNATURE: &[u8] = b"\x89PNG\r\n\x1a\n";
+/// The most bytes a stored deflate block can hold.
+const STORED_BLOCK_MAX: usize = 0xffff;
+
+/// Write an RGBA frame buffer of `width` by `height` pixels as an RGB PNG. The
+/// alpha channel is dropped, since the renderer leaves parts of the frame
+/// with an alpha of zero.
+pub fn write_rgba(mut writer: impl Write, width: u32, height: u32, rgba: &[u8]) -> io::Result<()> {
+    let row_bytes = width as usize * 4;
+    if rgba.len() < row_bytes * height as usize {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "frame buffer is smaller than the image",
+        ));
+    }
+
+    // Each row starts with filter type 0 (none)
+    let mut raw = Vec::with_capacity((width as usize * 3 + 1) * height as usize);
+    for row in rgba.chunks_exact(row_bytes).take(height as usize) {
+        raw.push(0);
+        for pixel in row.chunks_exact(4) {
+            raw.extend(&pixel[..3]);
+        }
+    }
+
+    let mut header = Vec::with_capacity(13);
+    header.extend(width.to_be_bytes());
+    header.extend(height.to_be_bytes());
+    // 8 bits per channel, RGB, deflate, adaptive filtering, no interlace
+    header.extend([8, 2, 0, 0, 0]);
+
+    writer.write_all(SIGNATURE)?;
+    write_chunk(&mut writer, b"IHDR", &header)?;
+    write_chunk(&mut writer, b"IDAT", &zlib_stored(&raw))?;
+    write_chunk(&mut writer, b"IEND", &[])?;
+    writer.flush()
+}
+
+fn write_chunk(writer: &mut impl Write, kind: &[u8; 4], data: &[u8]) -> io::Result<()> {
+    writer.write_all(&(data.len() as u32).to_be_bytes())?;
+    writer.write_all(kind)?;
+    writer.write_all(data)?;
+    let crc = crc32(kind.iter().chain(data));
+    writer.write_all(&crc.to_be_bytes())
+}
+
+/// A zlib stream holding `data` in uncompressed deflate blocks.
+fn zlib_stored(data: &[u8]) -> Vec<u8> {
+    let blocks = data.len().div_ceil(STORED_BLOCK_MAX).max(1);
+    let mut out = Vec::with_capacity(data.len() + blocks * 5 + 6);
+    // 32kB window, no preset dictionary, check bits for the default level
+    out.extend([0x78, 0x01]);
+    let mut chunks = data.chunks(STORED_BLOCK_MAX).peekable();
+    if chunks.peek().is_none() {
+        out.extend([1, 0, 0, 0xff, 0xff]);
+    }
+    while let Some(chunk) = chunks.next() {
+        let last = chunks.peek().is_none();
+        let len = chunk.len() as u16;
+        out.push(last as u8);
+        out.extend(len.to_le_bytes());
+        out.extend((!len).to_le_bytes());
+        out.extend(chunk);
+    }
+    out.extend(adler32(data).to_be_bytes());
+    out
+}
+
+fn adler32(data: &[u8]) -> u32 {
+    let (mut a, mut b) = (1_u32, 0_u32);
+    for &byte in data {
+        a = (a + byte as u32) % 65521;
+        b = (b + a) % 65521;
+    }
+    b << 16 | a
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_checksums() {
+        assert_eq!(crc32(b"IEND"), 0xae42_6082);
+        assert_eq!(crc32(b"123456789"), 0xcbf4_3926);
+        assert_eq!(adler32(b"Wikipedia"), 0x11e6_0398);
+    }
+
+    #[test]
+    fn test_write_rgba() {
+        // Two by two: red, green / blue, white, with varying alpha
+        let rgba = [
+            0xff, 0, 0, 0xff, 0, 0xff, 0, 0, //
+            0, 0, 0xff, 0xff, 0xff, 0xff, 0xff, 0x80,
+        ];
+        let mut png = vec![];
+        write_rgba(&mut png, 2, 2, &rgba).unwrap();
+
+        assert_eq!(&png[..8], SIGNATURE);
+        assert_eq!(&png[8..16], b"\0\0\0\x0dIHDR");
+        assert_eq!(&png[16..29], [0, 0, 0, 2, 0, 0, 0, 2, 8, 2, 0, 0, 0]);
+        assert!(png.ends_with(b"\0\0\0\0IEND\xae\x42\x60\x82"));
+
+        // The stored block holds each row behind its filter byte
+        let idat = &png[33..];
+        assert_eq!(&idat[4..8], b"IDAT");
+        let raw = [
+            0, 0xff, 0, 0, 0, 0xff, 0, //
+            0, 0, 0, 0xff, 0xff, 0xff, 0xff,
+        ];
+        assert_eq!(&idat[8..15], [0x78, 0x01, 1, 14, 0, !14, 0xff]);
+        assert_eq!(&idat[15..29], raw);
+        assert_eq!(&idat[29..33], adler32(&raw).to_be_bytes());
+
+        assert!(write_rgba(&mut vec![], 3, 2, &rgba).is_err());
+    }
+
+    #[test]
+    fn test_stored_blocks() {
+        let data = vec![0x55; STORED_BLOCK_MAX + 1];
+        let zlib = zlib_stored(&data);
+        assert_eq!(zlib.len(), 2 + 5 + STORED_BLOCK_MAX + 5 + 1 + 4);
+        // Only the second block is marked as the last
+        assert_eq!(zlib[2], 0);
+        assert_eq!(zlib[2 + 5 + STORED_BLOCK_MAX], 1);
+    }
+}
@@ -1,14 +1,451 @@
+use std::collections::BTreeSet;
+use std::fs;
+use std::io::{self, BufRead, Write};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::time::Duration;
 
 use i8051::Cpu;
+use i8051::breakpoint::Action;
 #[cfg(feature = "tui")]
 use i8051_debug_tui::Debugger;
+use tracing::{Level, info};
 
 use crate::System;
 
+/// How long to wait for queued comm output to be delivered at shutdown.
+#[cfg(not(target_arch = "wasm32"))]
+const DRAIN_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// The most steps to wait for the display to leave vertical sync before
+/// writing a PNG, a little over a frame.
+#[cfg(feature = "graphics")]
+const VSYNC_MAX_STEPS: usize = crate::machine::vt420::STEPS_PER_SECOND as usize / 50;
+
+/// Set by SIGINT/SIGTERM to request a clean shutdown.
+static SHUTDOWN: AtomicBool = AtomicBool::new(false);
+
+#[cfg(not(target_arch = "wasm32"))]
+extern "C" fn request_shutdown(_signal: libc::c_int) {
+    SHUTDOWN.store(true, Ordering::Relaxed);
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+fn install_shutdown_handler() {
+    let handler = request_shutdown as extern "C" fn(libc::c_int) as libc::sighandler_t;
+    // SAFETY: the handler only stores to an atomic, which is async-signal-safe.
+    unsafe {
+        libc::signal(libc::SIGINT, handler);
+        libc::signal(libc::SIGTERM, handler);
+    }
+}
+
+#[cfg(target_arch = "wasm32")]
+fn install_shutdown_handler() {}
+
+/// Deliver any output the terminal has queued, then save the NVR and write
+/// the logs before exiting.
+fn shutdown(system: &mut System) {
+    info!("Shutting down, draining comm output");
+    #[cfg(not(target_arch = "wasm32"))]
+    system.drain_comm(DRAIN_TIMEOUT);
+    system.shutdown();
+}
+
+/// Watches for the text screen to contain a pattern, optionally dumping it
+/// the first time it does.
+pub struct ScreenMatch {
+    pattern: String,
+    /// Whether to dump the screen when it matches
+    dump: bool,
+    /// Where to write the screen, or stdout if unset
+    output: Option<PathBuf>,
+    /// Whether to stop the emulator once the screen matches
+    exit: bool,
+    matched: bool,
+}
+
+impl ScreenMatch {
+    pub fn new(pattern: String, output: Option<PathBuf>, exit: bool) -> Self {
+        Self {
+            pattern,
+            dump: true,
+            output,
+            exit,
+            matched: false,
+        }
+    }
+
+    /// Stop the emulator once the screen contains `pattern`, without dumping
+    /// it.
+    pub fn expect(pattern: String) -> Self {
+        Self {
+            pattern,
+            dump: false,
+            output: None,
+            exit: true,
+            matched: false,
+        }
+    }
+
+    /// Check the screen, dumping it if this is the first match. Returns true
+    /// if the screen matched for the first time.
+    pub fn check(&mut self, system: &System) -> io::Result<bool> {
+        if self.matched {
+            return Ok(false);
+        }
+        let screen = system.dump_screen_text();
+        if !screen.contains(&self.pattern) {
+            return Ok(false);
+        }
+        self.matched = true;
+        info!("Screen matched {:?}", self.pattern);
+        if self.dump {
+            match &self.output {
+                Some(output) => fs::write(output, &screen)?,
+                None => println!("{screen}"),
+            }
+        }
+        Ok(true)
+    }
+
+    /// Whether the screen has ever matched, checking it now if it hasn't yet.
+    fn matched(&mut self, system: &System) -> io::Result<bool> {
+        Ok(self.check(system)? || self.matched)
+    }
+}
+
+/// Bounds on a headless run, so it can be used as a self-checking test.
+#[derive(Default)]
+pub struct RunLimit {
+    /// Stop after this many steps
+    pub max_steps: Option<usize>,
+    /// Text the screen must contain by the time the run stops. A
+    /// free-running emulator stops as soon as it appears.
+    pub expect: Option<ScreenMatch>,
+    /// Stop with an error once the watchdog finds the firmware hung
+    pub exit_on_hang: bool,
+}
+
+impl RunLimit {
+    fn hung(&self, system: &System) -> bool {
+        self.exit_on_hang && system.hang().is_some()
+    }
+
+    /// Check that the screen contained the expected text, if any.
+    pub fn check(
+        &mut self,
+        system: &System,
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        if let Some(hang) = system.hang().filter(|_| self.exit_on_hang) {
+            return Err(format!("Firmware hung at {hang}").into());
+        }
+        let Some(expect) = &mut self.expect else {
+            return Ok(());
+        };
+        if expect.matched(system)? {
+            return Ok(());
+        }
+        Err(format!(
+            "Screen didn't contain {:?} after {} steps. The screen was:\n{}",
+            expect.pattern,
+            system.instruction_count,
+            system.dump_screen_text()
+        )
+        .into())
+    }
+}
+
+/// Files to write with the final state of the screen when a run stops.
+#[derive(Default)]
+pub struct Snapshots {
+    /// The screen as styled HTML
+    pub html: Option<PathBuf>,
+    /// The display rendered as a PNG
+    #[cfg(feature = "graphics")]
+    pub png: Option<PathBuf>,
+}
+
+impl Snapshots {
+    #[cfg_attr(not(feature = "graphics"), allow(unused_variables))]
+    fn write(&self, system: &mut System, cpu: &mut Cpu) -> io::Result<()> {
+        if let Some(path) = &self.html {
+            write_html(system, path)?;
+        }
+        #[cfg(feature = "graphics")]
+        if let Some(path) = &self.png {
+            write_png(system, cpu, path)?;
+        }
+        Ok(())
+    }
+}
+
+/// Special function registers shown by the REPL's `r` command.
+const REPL_REGISTERS: &[(&str, u8)] = &[
+    ("A", 0xe0),
+    ("B", 0xf0),
+    ("PSW", 0xd0),
+    ("SP", 0x81),
+    ("DPH", 0x83),
+    ("DPL", 0x82),
+    ("P1", 0x90),
+    ("P2", 0xa0),
+    ("P3", 0xb0),
+];
+
+const REPL_HELP: &str = "\
+s [N]         step one (or N) instructions
+c [N]         continue for N instructions, or until a breakpoint
+f [N]         run until one (or N) video frames have completed
+b ADDR        toggle a breakpoint at a hex address
+r             dump registers
+x ADDR [LEN]  dump LEN bytes of XDATA at a hex address
+w ADDR BYTE.. write hex bytes to XDATA at a hex address
+p             dump the named peripheral registers
+m [N]         list the last 16 (or N) video mapper writes
+screen        print the decoded screen
+v             freeze or unfreeze the video sync generator
+q             quit";
+
+/// A line-oriented debugger for when the TUI debugger isn't available.
+#[derive(Default)]
+pub struct Repl {
+    breakpoints: BTreeSet<u32>,
+}
+
+impl Repl {
+    pub fn new(breakpoints: impl IntoIterator<Item = u32>) -> Self {
+        Self {
+            breakpoints: breakpoints.into_iter().collect(),
+        }
+    }
+
+    /// Read commands until end of input or `q`.
+    pub fn run(
+        &mut self,
+        system: &mut System,
+        cpu: &mut Cpu,
+        input: impl BufRead,
+        mut output: impl Write,
+    ) -> io::Result<()> {
+        for &addr in &self.breakpoints {
+            add_breakpoint(system, addr);
+        }
+        write!(output, "{:05X}> ", cpu.pc_ext(system))?;
+        output.flush()?;
+        for line in input.lines() {
+            if !self.execute(line?.trim(), system, cpu, &mut output)? {
+                break;
+            }
+            write!(output, "{:05X}> ", cpu.pc_ext(system))?;
+            output.flush()?;
+        }
+        Ok(())
+    }
+
+    /// Execute one command, returning false to quit.
+    pub fn execute(
+        &mut self,
+        line: &str,
+        system: &mut System,
+        cpu: &mut Cpu,
+        output: &mut impl Write,
+    ) -> io::Result<bool> {
+        let mut words = line.split_whitespace();
+        let Some(command) = words.next() else {
+            return Ok(true);
+        };
+        let args = words.collect::<Vec<_>>();
+        let count = |index: usize| args.get(index).map(|arg| arg.parse::<usize>());
+        let hex = |index: usize| args.get(index).map(|arg| parse_hex(arg));
+
+        match (command, args.len()) {
+            ("s", 0 | 1) => {
+                let Ok(steps) = count(0).unwrap_or(Ok(1)) else {
+                    writeln!(output, "Invalid count")?;
+                    return Ok(true);
+                };
+                for _ in 0..steps {
+                    system.step(cpu);
+                }
+                writeln!(output, "{}", self.state(system, cpu))?;
+            }
+            ("c", 0 | 1) => {
+                let steps = match count(0) {
+                    Some(Ok(steps)) => Some(steps),
+                    Some(Err(_)) => {
+                        writeln!(output, "Invalid count")?;
+                        return Ok(true);
+                    }
+                    None => None,
+                };
+                let mut executed = 0;
+                while steps.is_none_or(|steps| executed < steps)
+                    && !SHUTDOWN.load(Ordering::Relaxed)
+                {
+                    system.step(cpu);
+                    executed += 1;
+                    if system.watch_hit {
+                        writeln!(output, "Watchpoint hit")?;
+                        break;
+                    }
+                    let pc = cpu.pc_ext(system);
+                    if self.breakpoints.contains(&pc) {
+                        writeln!(output, "Breakpoint at {pc:05X}")?;
+                        break;
+                    }
+                }
+                writeln!(output, "{}", self.state(system, cpu))?;
+            }
+            ("f", 0 | 1) => {
+                let Ok(frames) = count(0).unwrap_or(Ok(1)) else {
+                    writeln!(output, "Invalid count")?;
+                    return Ok(true);
+                };
+                let mut completed = 0;
+                while completed < frames && !SHUTDOWN.load(Ordering::Relaxed) {
+                    system.step(cpu);
+                    if system.frame_ready() {
+                        completed += 1;
+                    }
+                }
+                writeln!(
+                    output,
+                    "{} frame={}",
+                    self.state(system, cpu),
+                    system.frame_count()
+                )?;
+            }
+            ("b", 1) => {
+                let Some(Ok(addr)) = hex(0) else {
+                    writeln!(output, "Invalid address")?;
+                    return Ok(true);
+                };
+                if self.breakpoints.insert(addr) {
+                    add_breakpoint(system, addr);
+                    writeln!(output, "Breakpoint set at {addr:05X}")?;
+                } else {
+                    self.breakpoints.remove(&addr);
+                    writeln!(output, "Breakpoint cleared at {addr:05X}")?;
+                }
+            }
+            ("r", 0) => {
+                writeln!(output, "{}", self.state(system, cpu))?;
+                let registers = REPL_REGISTERS
+                    .iter()
+                    .map(|&(name, sfr)| format!("{name}={:02X}", cpu.sfr(sfr, system)))
+                    .collect::<Vec<_>>();
+                writeln!(output, "{}", registers.join(" "))?;
+            }
+            ("x", 1 | 2) => {
+                let (Some(Ok(addr)), Ok(len)) = (hex(0), count(1).unwrap_or(Ok(16))) else {
+                    writeln!(output, "Invalid address or length")?;
+                    return Ok(true);
+                };
+                let bytes = (0..len)
+                    .map(|i| system.memory.peek_xdata((addr as usize + i) as u16))
+                    .collect::<Vec<_>>();
+                for (i, chunk) in bytes.chunks(16).enumerate() {
+                    let chunk = chunk.iter().map(|b| format!("{b:02X}")).collect::<Vec<_>>();
+                    writeln!(
+                        output,
+                        "{:04X}: {}",
+                        (addr as usize + i * 16) as u16,
+                        chunk.join(" ")
+                    )?;
+                }
+            }
+            ("w", 2..) => {
+                let Some(Ok(addr)) = hex(0) else {
+                    writeln!(output, "Invalid address")?;
+                    return Ok(true);
+                };
+                let Ok(bytes) = args[1..]
+                    .iter()
+                    .map(|arg| u8::from_str_radix(arg, 16))
+                    .collect::<Result<Vec<_>, _>>()
+                else {
+                    writeln!(output, "Invalid byte")?;
+                    return Ok(true);
+                };
+                for (i, &byte) in bytes.iter().enumerate() {
+                    system.write_xdata((addr as usize + i) as u16, byte);
+                }
+                writeln!(output, "Wrote {} bytes at {addr:04X}", bytes.len())?;
+            }
+            ("p", 0) => {
+                for (addr, name, value) in system.peripheral_snapshot().named() {
+                    writeln!(output, "{addr:04X} {value:02X}  {name}")?;
+                }
+            }
+            ("m", 0 | 1) => {
+                let Ok(count) = count(0).unwrap_or(Ok(16)) else {
+                    writeln!(output, "Invalid count")?;
+                    return Ok(true);
+                };
+                let history = system.mapper_history();
+                let skip = history.len().saturating_sub(count);
+                for write in history.skip(skip) {
+                    writeln!(
+                        output,
+                        "{:.6} 0x{:04X} = 0x{:02X} -> 0x{:02X} @ {:05X}",
+                        write.time.as_secs_f64(),
+                        0x7ff0 | write.offset as u16,
+                        write.old,
+                        write.new,
+                        write.pc
+                    )?;
+                }
+            }
+            ("screen", 0) => writeln!(output, "{}", system.dump_screen_text())?,
+            ("v", 0) => {
+                system.freeze_video(!system.is_video_frozen());
+                let (x, y) = system.raster_position();
+                let state = if system.is_video_frozen() {
+                    "frozen"
+                } else {
+                    "running"
+                };
+                writeln!(output, "Video {state} at x={x} y={y}")?;
+            }
+            ("q", 0) => return Ok(false),
+            _ => writeln!(output, "{REPL_HELP}")?,
+        }
+        Ok(true)
+    }
+
+    fn state(&self, system: &System, cpu: &Cpu) -> String {
+        format!(
+            "PC={:05X} steps={}",
+            cpu.pc_ext(system),
+            system.instruction_count
+        )
+    }
+}
+
+/// Breakpoints are also registered with the system so that hitting one is
+/// logged. The system can't remove them, so the log remains after the REPL
+/// clears a breakpoint.
+fn add_breakpoint(system: &mut System, addr: u32) {
+    system.breakpoints.add(
+        true,
+        addr,
+        Action::Log(Level::INFO, format!("REPL breakpoint {addr:05X}").into()),
+    );
+}
+
+fn parse_hex(s: &str) -> Result<u32, std::num::ParseIntError> {
+    let s = s.trim_start_matches("0x").trim_end_matches(['h', 'H']);
+    u32::from_str_radix(s, 16)
+}
+
 pub fn run(
     mut system: System,
     mut cpu: Cpu,
+    mut screen_match: Option<ScreenMatch>,
+    repl: Option<Repl>,
+    mut limit: RunLimit,
+    snapshots: Snapshots,
     #[cfg(feature = "tui")] debugger: Option<Debugger>,
 ) -> Result<usize, Box<dyn std::error::Error + Send + Sync>> {
     #[cfg(feature = "tui")]
@@ -19,6 +456,7 @@ pub fn run(
             match debugger.debugger_state() {
                 DebuggerState::Quit => {
                     debugger.exit()?;
+                    shutdown(&mut system);
                     break;
                 }
                 DebuggerState::Paused => {
@@ -44,7 +482,7 @@ pub fn run(
                         }
                     }
                     system.step(&mut cpu);
-                    if debugger.breakpoints().contains(&cpu.pc_ext(&system)) {
+                    if system.watch_hit || debugger.breakpoints().contains(&cpu.pc_ext(&system)) {
                         debugger.pause();
                     }
                 }
@@ -53,8 +491,176 @@ pub fn run(
         return Ok(system.instruction_count);
     }
 
-    loop {
-        system.step(&mut cpu);
+    install_shutdown_handler();
+    if let Some(mut repl) = repl {
+        repl.run(&mut system, &mut cpu, io::stdin().lock(), io::stdout())?;
+        snapshots.write(&mut system, &mut cpu)?;
+        shutdown(&mut system);
+        limit.check(&system)?;
+        return Ok(system.instruction_count);
     }
+    run_free(&mut system, &mut cpu, screen_match.as_mut(), &mut limit)?;
+    snapshots.write(&mut system, &mut cpu)?;
+    shutdown(&mut system);
+    limit.check(&system)?;
     Ok(system.instruction_count)
 }
+
+/// Write the screen to `path` as styled HTML.
+fn write_html(system: &System, path: &std::path::Path) -> io::Result<()> {
+    std::fs::write(path, system.screen_html())?;
+    info!("Wrote the screen to {:?}", path);
+    Ok(())
+}
+
+/// Write the display to `path` as a PNG, running until it is out of vertical
+/// sync if need be.
+#[cfg(feature = "graphics")]
+fn write_png(system: &mut System, cpu: &mut Cpu, path: &std::path::Path) -> io::Result<()> {
+    use super::wgpu::in_vsync;
+
+    for _ in 0..VSYNC_MAX_STEPS {
+        if !in_vsync(system) {
+            break;
+        }
+        system.step(cpu);
+    }
+    if !super::wgpu::write_png(system, path)? {
+        return Err(io::Error::other("the display never left vertical sync"));
+    }
+    info!("Wrote the display to {:?}", path);
+    Ok(())
+}
+
+/// Run until asked to shut down, the screen matches, or the limit is reached.
+fn run_free(
+    system: &mut System,
+    cpu: &mut Cpu,
+    mut screen_match: Option<&mut ScreenMatch>,
+    limit: &mut RunLimit,
+) -> io::Result<()> {
+    while !SHUTDOWN.load(Ordering::Relaxed) {
+        let steps = match limit.max_steps {
+            Some(max_steps) => max_steps
+                .saturating_sub(system.instruction_count)
+                .min(0x1000),
+            None => 0x1000,
+        };
+        if steps == 0 {
+            info!("Stopping after {} steps", system.instruction_count);
+            break;
+        }
+        for _ in 0..steps {
+            system.step(cpu);
+        }
+        let mut stop = limit.hung(system);
+        for screen_match in screen_match
+            .as_deref_mut()
+            .into_iter()
+            .chain(&mut limit.expect)
+        {
+            stop |= screen_match.check(system)? && screen_match.exit;
+        }
+        if stop {
+            break;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::machine::vt420::harness;
+
+    #[test]
+    fn test_screen_match() {
+        let rom = harness::rom();
+        let mut system = System::new_deterministic(rom, None).unwrap();
+        let mut cpu = Cpu::new();
+
+        let output = std::env::temp_dir().join(format!("blaze-screen-{}.txt", std::process::id()));
+        let mut screen_match = ScreenMatch::new("VT420 OK".to_string(), Some(output.clone()), true);
+        let mut matched = false;
+        for _ in 0..20_000_000 / 0x1000 {
+            for _ in 0..0x1000 {
+                system.step(&mut cpu);
+            }
+            if screen_match.check(&system).unwrap() {
+                matched = true;
+                break;
+            }
+        }
+        assert!(matched);
+        // Only the first match is reported
+        assert!(!screen_match.check(&system).unwrap());
+
+        let screen = fs::read_to_string(&output).unwrap();
+        _ = fs::remove_file(&output);
+        assert!(screen.contains("VT420 OK"), "{screen}");
+    }
+
+    #[test]
+    fn test_run_limit() {
+        let rom = harness::rom();
+        let mut system = System::new_deterministic(rom, None).unwrap();
+        let mut cpu = Cpu::new();
+
+        let mut limit = RunLimit {
+            max_steps: Some(0x1800),
+            expect: Some(ScreenMatch::expect("VT420 OK".to_string())),
+            exit_on_hang: false,
+        };
+        run_free(&mut system, &mut cpu, None, &mut limit).unwrap();
+        assert_eq!(system.instruction_count, 0x1800);
+        let error = limit.check(&system).unwrap_err().to_string();
+        assert!(
+            error.starts_with("Screen didn't contain \"VT420 OK\" after 6144 steps"),
+            "{error}"
+        );
+
+        // Stops as soon as the screen matches
+        let mut limit = RunLimit {
+            max_steps: Some(20_000_000),
+            expect: Some(ScreenMatch::expect("VT420 OK".to_string())),
+            exit_on_hang: false,
+        };
+        run_free(&mut system, &mut cpu, None, &mut limit).unwrap();
+        assert!(system.instruction_count < 20_000_000);
+        limit.check(&system).unwrap();
+    }
+
+    #[test]
+    fn test_repl() {
+        let rom = harness::rom();
+        let mut system = System::new_deterministic(rom, None).unwrap();
+        let mut cpu = Cpu::new();
+        system.memory.peripheral[0x10] = 0x5a;
+
+        let input = "s\ns 3\nb 1234\nb 1234\nc 100\n\
+                     w 7e11 a5\nx 7e10 2\np\nw 7ff0 00\nw 7ff0 00\nm 2\nf\n\
+                     r\nbogus\nq\ns\n";
+        let mut output = vec![];
+        Repl::default()
+            .run(&mut system, &mut cpu, input.as_bytes(), &mut output)
+            .unwrap();
+        let output = String::from_utf8(output).unwrap();
+
+        // The command after q is never run
+        let steps = system.instruction_count;
+        assert!(
+            output.contains(&format!("steps={steps} frame=1\n")),
+            "{output}"
+        );
+        assert!(output.contains("steps=1\n"), "{output}");
+        assert!(output.contains("steps=4\n"), "{output}");
+        assert!(output.contains("Breakpoint set at 01234"), "{output}");
+        assert!(output.contains("Breakpoint cleared at 01234"), "{output}");
+        assert!(output.contains("Wrote 1 bytes at 7E11"), "{output}");
+        assert!(output.contains("7E10: 5A A5"), "{output}");
+        assert!(output.contains("7EE4 "), "{output}");
+        assert_eq!(output.matches("0x7FF0 = ").count(), 2, "{output}");
+        assert!(output.contains("SP="), "{output}");
+        assert!(output.contains("screen        print"), "{output}");
+    }
+}
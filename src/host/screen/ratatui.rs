@@ -6,23 +6,31 @@ use i8051::Cpu;
 use i8051_debug_tui::Debugger;
 use ratatui::buffer::Buffer;
 use ratatui::crossterm;
+use ratatui::crossterm::event::{Event, KeyCode, KeyEvent};
 use ratatui::layout::Offset;
 use ratatui::layout::Rect;
 use ratatui::prelude::CrosstermBackend;
 use ratatui::style::{Color, Style, Stylize};
 use ratatui::text::{Line, Span};
-use ratatui::widgets::Widget;
+use ratatui::widgets::{Clear, Widget};
 
 use i8051::sfr::{SFR_P1, SFR_P2, SFR_P3};
-use tracing::warn;
+use tracing::{info, warn};
 
 use crate::host::lk201::crossterm::{CrosstermKeyboard, KeyboardCommand};
-use crate::{System, machine::vt420::video::Mapper};
+use crate::{
+    System,
+    machine::generic::lk201::Led,
+    machine::vt420::charset::line_drawing_glyph,
+    machine::vt420::video::{BLINK_PHASE, CellAttr, Mapper, RowFlags, decode_vram},
+};
 
 pub struct Screen<'a> {
     vram: &'a [u8],
     mapper: &'a Mapper,
     display_mode: DisplayMode,
+    show_protection: bool,
+    blink_off: bool,
 }
 
 #[derive(Copy, Clone, Debug, PartialEq, Eq)]
@@ -30,6 +38,9 @@ pub enum DisplayMode {
     Normal,
     NibbleTriplet,
     Bytes,
+    /// The low nibble of each cell's attribute in hex, styled by the attribute
+    /// bits so the upper bits are visible too.
+    Attributes,
 }
 
 impl<'a> Screen<'a> {
@@ -38,6 +49,8 @@ impl<'a> Screen<'a> {
             vram,
             mapper,
             display_mode: DisplayMode::Normal,
+            show_protection: false,
+            blink_off: false,
         }
     }
 
@@ -45,227 +58,373 @@ impl<'a> Screen<'a> {
         self.display_mode = mode;
         self
     }
+
+    /// Highlight cells protected from selective erase (DECSCA) with a blue
+    /// background. Protected cells otherwise render normally.
+    pub fn show_protection(mut self, show_protection: bool) -> Self {
+        self.show_protection = show_protection;
+        self
+    }
+
+    /// Blank blinking cells, for the off phase of the blink cycle.
+    pub fn blink_off(mut self, blink_off: bool) -> Self {
+        self.blink_off = blink_off;
+        self
+    }
+}
+
+/// Where the next decoded cell is written.
+struct Cursor<'b> {
+    buf: &'b mut Buffer,
+    area: Rect,
+    x: u16,
+    y: u16,
+    flags: RowFlags,
+}
+
+impl Cursor<'_> {
+    fn put(&mut self, symbol: &str, style: Style) {
+        if self.x < self.area.width && self.y < self.area.bottom() {
+            if let Some(cell) = self.buf.cell_mut((self.area.left() + self.x, self.y)) {
+                cell.set_symbol(symbol);
+                cell.set_style(style);
+            }
+        }
+        self.x += 1;
+    }
+
+    /// Put one decoded cell, which takes up two cells on a double-width row
+    /// so that it lines up with single-width rows.
+    fn put_cell(&mut self, symbol: &str, style: Style) {
+        self.put(symbol, style);
+        if self.flags.double_width {
+            self.put(" ", style);
+        }
+    }
+
+    fn put_str(&mut self, s: &str, style: Style) {
+        for ch in s.chars() {
+            self.put(&ch.to_string(), style);
+        }
+    }
 }
 
 impl<'a> Widget for Screen<'a> {
     fn render(self, area: Rect, buf: &mut Buffer) {
         let vram = self.vram;
-        let vram_base = 0;
-
-        let mut line = [0_u16; 256];
-        let mut attr = [0_u8; 256];
-
-        let Some(rows) = self.mapper.row_count(&vram) else {
-            return;
+        let cursor = Cursor {
+            buf,
+            area,
+            x: 0,
+            y: area.top(),
+            flags: RowFlags::default(),
         };
 
-        for row_idx in 0..rows as u16 {
-            let row = ((vram[vram_base + row_idx as usize * 2] as u16) >> 1) << 8;
-            if row == 0 {
-                continue;
-            }
-            // Bit 2: double width
-            // Bit 1: swap between screen 0 and screen 1 attributes
-            let row_attrs = vram[vram_base + row_idx as usize * 2 + 1];
-            let is_double_width = (row_attrs >> 2) & 3 != 0;
-            // If true, force 132 characters per line
-            let row_is_132 = vram[vram_base + row_idx as usize * 2] & 1 != 0;
-
-            // Decode 12-bit character codes from packed 3-byte sequences
-            let mut b = 0;
-            let mut j = 0;
-
-            // First segment: 72 chars, bytes 0-107
-            for i in 0..108 {
-                let char = vram[row as usize + i];
-                match i % 3 {
-                    0 => b = char as u16,
-                    1 => {
-                        b |= ((char & 0xf) as u16) << 8;
-                        line[j] = b;
-                        j += 1;
-                        b = ((char & 0xf0) as u16) >> 4;
+        decode_vram(
+            vram,
+            self.mapper,
+            |cursor, row_idx, row, flags| {
+                cursor.x = 0;
+                cursor.y = area.top() + row_idx as u16;
+                cursor.flags = flags;
+                match self.display_mode {
+                    DisplayMode::Bytes => {
+                        let offset = row.vram_offset() as usize;
+                        for (i, b) in vram[offset..offset + 256].iter().enumerate() {
+                            cursor.put_str(&format!("{:02X}", b), alternate_bold(i));
+                        }
                     }
-                    _ => {
-                        b |= (char as u16) << 4;
-                        line[j] = b;
-                        j += 1;
+                    DisplayMode::NibbleTriplet => {
+                        let row_header = format!(
+                            "{:02X}{:02X}|",
+                            vram[row_idx as usize * 2],
+                            vram[row_idx as usize * 2 + 1]
+                        );
+                        cursor.put_str(&row_header, Style::default());
                     }
+                    DisplayMode::Normal | DisplayMode::Attributes => {}
                 }
-            }
-            // Second segment: bytes 128-220
-            for i in 128..221 {
-                let char = vram[row as usize + i];
-                let i = i + 1;
-                match i % 3 {
-                    0 => b = char as u16,
-                    1 => {
-                        b |= ((char & 0xf) as u16) << 8;
-                        line[j] = b;
-                        j += 1;
-                        b = ((char & 0xf0) as u16) >> 4;
-                    }
-                    _ => {
-                        b |= (char as u16) << 4;
-                        line[j] = b;
-                        j += 1;
-                    }
+            },
+            |cursor, col, char_code, attr| match self.display_mode {
+                DisplayMode::Bytes => {}
+                DisplayMode::NibbleTriplet => {
+                    let code = (attr.code_nibble() as u16) << 8 | char_code as u16;
+                    cursor.put_str(&format!("{:03X}", code), alternate_bold(col as usize));
                 }
-            }
+                DisplayMode::Normal => self.render_cell(cursor, char_code, attr),
+                DisplayMode::Attributes => render_attribute(cursor, attr),
+            },
+            cursor,
+        );
+    }
+}
 
-            // Extract attributes
-            for i in 1..133 {
-                let bit = ((i % 4) * 2) as u8;
-                attr[i - 1] = (vram[row as usize + 0xdd + (i / 4)] >> bit) & 0x3;
-                let cell_attr = ((line[i - 1] & 0xf00) >> 8) as u8;
-                attr[i - 1] |= cell_attr << 2;
+impl<'a> Screen<'a> {
+    /// Render one decoded cell.
+    fn render_cell(&self, cursor: &mut Cursor, char_code: u8, attr: CellAttr) {
+        let flags = cursor.flags;
+        let ch = if attr.alternate_glyph() {
+            match char_code {
+                0x9c => 'S',
+                0x0d => 'H',
+                0x54 => 'e',
+                0x09 => 's',
+                0x52 => 'd',
+                0x55 => 'i',
+                0x6d => 'l',
+                0x7f => 'o',
+                0x75 => 'n',
+                0x20 => '1',
+                0x38 => '2',
+                _ => '.',
             }
+        } else if char_code == 0 || char_code == 0x98 {
+            ' '
+        } else if char_code < 0x20 || char_code > 0x7e {
+            match char_code {
+                0xa9 => '©', // copyright symbol
+                _ => line_drawing_glyph(char_code).unwrap_or('.'),
+            }
+        } else {
+            char::from(char_code)
+        };
 
-            // Render the line
-            match self.display_mode {
-                DisplayMode::Bytes => {
-                    let row_header = format!("{:02X}|", row >> 8);
-                    let mut col = 0;
-                    for (i, b) in vram[row as usize..row as usize + 256].iter().enumerate() {
-                        if col < area.width {
-                            let hex_str = format!("{:02X}", b);
-                            for ch in hex_str.chars() {
-                                if let Some(cell) =
-                                    buf.cell_mut((area.left() + col, area.top() + row_idx))
-                                {
-                                    cell.set_symbol(&ch.to_string());
-                                    cell.set_style(if i % 2 == 0 {
-                                        Style::default()
-                                    } else {
-                                        Style::default().bold()
-                                    });
-                                }
-                                col += 1;
-                            }
-                        }
-                    }
+        if char_code == 0 && attr.is_fill() {
+            cursor.put_cell(" ", Style::default());
+            return;
+        }
+
+        let mut style = attr_style(attr, self.show_protection);
+        let mut symbol = ch.to_string();
+        if attr.blink() && self.blink_off && !flags.status_row {
+            // The status row doesn't blink
+            symbol = " ".to_string();
+            style = style.not_underlined();
+        }
+        if flags.double_height_bottom {
+            // We can't draw a glyph across two rows, so the top half shows the
+            // text and the bottom half only carries its attributes
+            symbol = " ".to_string();
+        }
+        cursor.put_cell(&symbol, style);
+    }
+}
+
+/// The style for a cell's attribute bits, other than blink.
+fn attr_style(attr: CellAttr, show_protection: bool) -> Style {
+    let mut style = Style::default();
+    if attr.underline() {
+        style = style.underlined();
+    }
+    if attr.protected() && show_protection {
+        // selective erase protection mode
+        style = style.bg(Color::Blue);
+    }
+    if attr.bold() {
+        style = style.bold();
+    }
+    if attr.reverse() {
+        style = style.reversed();
+    }
+    style
+}
+
+/// Render a cell's attribute for [`DisplayMode::Attributes`]: the low nibble
+/// as a hex digit, styled as normal mode would style the cell, with protected
+/// cells always highlighted and blinking cells in magenta.
+fn render_attribute(cursor: &mut Cursor, attr: CellAttr) {
+    let mut style = attr_style(attr, true);
+    if attr.blink() {
+        style = style.fg(Color::Magenta);
+    }
+    cursor.put_cell(&format!("{:X}", attr.cell_bits() & 0xf), style);
+}
+
+fn alternate_bold(i: usize) -> Style {
+    if i % 2 == 0 {
+        Style::default()
+    } else {
+        Style::default().bold()
+    }
+}
+
+/// A right-aligned line showing the keyboard LEDs, with the lit ones
+/// highlighted.
+fn led_line(leds: Led) -> Line<'static> {
+    let mut line = Line::default().right_aligned();
+    for (name, lit) in leds.indicators() {
+        let style = if lit {
+            Style::default().fg(Color::Black).bg(Color::LightGreen)
+        } else {
+            Style::default().fg(Color::DarkGray)
+        };
+        line.push_span(Span::styled(format!(" {name} "), style));
+    }
+    line
+}
+
+/// Memory that the memory viewer can show.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum MemoryTarget {
+    /// XDATA as the CPU sees it, including the mapper and peripherals
+    Xdata,
+    Sram,
+    Vram,
+}
+
+impl MemoryTarget {
+    fn name(self) -> &'static str {
+        match self {
+            Self::Xdata => "XDATA",
+            Self::Sram => "SRAM",
+            Self::Vram => "VRAM",
+        }
+    }
+
+    fn next(self) -> Self {
+        match self {
+            Self::Xdata => Self::Sram,
+            Self::Sram => Self::Vram,
+            Self::Vram => Self::Xdata,
+        }
+    }
+
+    fn len(self, system: &System) -> u32 {
+        match self {
+            Self::Xdata => 0x10000,
+            Self::Sram => system.memory.sram.len() as u32,
+            Self::Vram => system.memory.vram.len() as u32,
+        }
+    }
+
+    fn read(self, system: &System, addr: u32) -> u8 {
+        match self {
+            Self::Xdata => system.read_xdata(addr as u16),
+            Self::Sram => system.read_sram(addr as u16),
+            Self::Vram => system.read_vram(addr),
+        }
+    }
+}
+
+/// A hexdump of SRAM, VRAM or XDATA, shown over the screen.
+struct MemoryViewer {
+    target: MemoryTarget,
+    /// The address of the first line shown
+    address: u32,
+    /// The hex address being typed, if any
+    input: Option<String>,
+}
+
+impl MemoryViewer {
+    const BYTES_PER_LINE: u32 = 16;
+
+    fn new() -> Self {
+        Self {
+            target: MemoryTarget::Xdata,
+            address: 0,
+            input: None,
+        }
+    }
+
+    /// Handle a key with `lines` lines of the dump visible. Returns false when
+    /// the viewer should be closed.
+    fn key(&mut self, key: &KeyEvent, system: &System, lines: u32) -> bool {
+        let page = lines.max(1) * Self::BYTES_PER_LINE;
+        let last = self
+            .target
+            .len(system)
+            .saturating_sub(page)
+            .next_multiple_of(Self::BYTES_PER_LINE);
+        if let Some(input) = &mut self.input {
+            match key.code {
+                KeyCode::Char(c) if c.is_ascii_hexdigit() && input.len() < 5 => {
+                    input.push(c.to_ascii_uppercase());
                 }
-                DisplayMode::NibbleTriplet => {
-                    let row_header = format!(
-                        "{:02X}{:02X}|",
-                        vram[vram_base + row_idx as usize * 2],
-                        vram[vram_base + row_idx as usize * 2 + 1]
-                    );
-                    let mut col = 0;
-                    for ch in row_header.chars() {
-                        if col < area.width {
-                            if let Some(cell) =
-                                buf.cell_mut((area.left() + col, area.top() + row_idx))
-                            {
-                                cell.set_symbol(&ch.to_string());
-                                cell.set_style(Style::default());
-                            }
-                            col += 1;
-                        }
-                    }
-                    for (i, char_code) in line.iter().take(132).enumerate() {
-                        let hex_str = format!("{:03X}", char_code);
-                        for ch in hex_str.chars() {
-                            if col < area.width {
-                                if let Some(cell) =
-                                    buf.cell_mut((area.left() + col, area.top() + row_idx))
-                                {
-                                    cell.set_symbol(&ch.to_string());
-                                    cell.set_style(if i % 2 == 0 {
-                                        Style::default()
-                                    } else {
-                                        Style::default().bold()
-                                    });
-                                }
-                                col += 1;
-                            }
-                        }
-                    }
+                KeyCode::Backspace => {
+                    input.pop();
                 }
-                DisplayMode::Normal => {
-                    // Render characters
-                    let mut col = 0;
-                    for i in 0..132.min((area.width - col) as usize) {
-                        let char_code = line[i] & 0xff;
-                        let ch = if line[i] & 0x100 != 0 {
-                            match char_code {
-                                0x9c => 'S',
-                                0x0d => 'H',
-                                0x54 => 'e',
-                                0x09 => 's',
-                                0x52 => 'd',
-                                0x55 => 'i',
-                                0x6d => 'l',
-                                0x7f => 'o',
-                                0x75 => 'n',
-                                0x20 => '1',
-                                0x38 => '2',
-                                _ => '.',
-                            }
-                        } else if char_code == 0 || char_code == 0x98 {
-                            ' '
-                        } else if char_code < 0x20 || char_code > 0x7e {
-                            match char_code {
-                                0x0d => '╭', // unicode box corner
-                                0x0c => '╮', // unicode box corner
-                                0x0e => '╰', // unicode box corner
-                                0x0b => '╯', // unicode box corner
-                                0x12 => '─', // unicode box horizontal
-                                0x19 => '│', // unicode box vertical
-                                0xa9 => '©', // copyright symbol
-                                _ => '.',
-                            }
-                        } else {
-                            char::from(char_code as u8)
-                        };
-
-                        let mut style = Style::default();
-                        if let Some(cell) = buf.cell_mut((area.left() + col, area.top() + row_idx))
-                        {
-                            if char_code == 0 && attr[i] >> 2 == 0xe {
-                                cell.set_symbol(" ");
-                                cell.set_style(Style::default());
-                                col += 1;
-                                continue;
-                            }
-                            cell.set_symbol(&ch.to_string());
-                            if attr[i] & 1 != 0 {
-                                style = style.underlined();
-                            }
-                            if attr[i] & 2 != 0 {
-                                // selective erase protection mode
-                                style = style.bg(Color::Blue);
-                            }
-                            if attr[i] & 8 != 0 {
-                                style = style.bold();
-                            }
-                            if attr[i] & 16 != 0 {
-                                style = style.reversed();
-                            }
-                            if attr[i] & 32 != 0 {
-                                // This doesn't seem quite right: the status bar shouldn't blink and
-                                // the setup screen's header shouldn't either.
-                                // if !self.mapper.is_blink() {
-                                //     cell.set_symbol(" ");
-                                // }
-                            }
-                            cell.set_style(style);
-                        }
-                        col += 1;
-                        if is_double_width {
-                            if let Some(cell) =
-                                buf.cell_mut((area.left() + col, area.top() + row_idx))
-                            {
-                                cell.set_symbol(" ");
-                                cell.set_style(style);
-                            }
-                            col += 1;
-                        }
+                KeyCode::Enter => {
+                    if let Ok(address) = u32::from_str_radix(input, 16) {
+                        self.address = address / Self::BYTES_PER_LINE * Self::BYTES_PER_LINE;
                     }
+                    self.input = None;
                 }
+                KeyCode::Esc => self.input = None,
+                _ => {}
+            }
+            self.address = self.address.min(last);
+            return true;
+        }
+        match key.code {
+            KeyCode::Esc => return false,
+            KeyCode::Tab => {
+                self.target = self.target.next();
+                self.address = 0;
+            }
+            KeyCode::Char(c) if c.is_ascii_hexdigit() => {
+                self.input = Some(c.to_ascii_uppercase().to_string());
             }
+            KeyCode::Up => self.address = self.address.saturating_sub(Self::BYTES_PER_LINE),
+            KeyCode::Down => self.address += Self::BYTES_PER_LINE,
+            KeyCode::PageUp => self.address = self.address.saturating_sub(page),
+            KeyCode::PageDown => self.address += page,
+            KeyCode::Home => self.address = 0,
+            KeyCode::End => self.address = last,
+            _ => {}
+        }
+        self.address = self.address.min(last);
+        true
+    }
+
+    fn header(&self, system: &System) -> Line<'static> {
+        let end = self.target.len(system).saturating_sub(1);
+        let mut header = format!("{} {:05X}-{end:05X}", self.target.name(), self.address);
+        match &self.input {
+            Some(input) => header.push_str(&format!("  Go to: {input}_")),
+            None => header.push_str("  Tab: memory  0-F: go to  PgUp/PgDn: page  Esc: close"),
+        }
+        Line::styled(header, Style::default().reversed())
+    }
+
+    fn lines(&self, system: &System, count: u32) -> Vec<Line<'static>> {
+        let len = self.target.len(system);
+        (0..count)
+            .map(|line| self.address + line * Self::BYTES_PER_LINE)
+            .take_while(|&address| address < len)
+            .map(|address| {
+                let end = (address + Self::BYTES_PER_LINE).min(len);
+                let bytes = (address..end)
+                    .map(|addr| self.target.read(system, addr))
+                    .collect::<Vec<_>>();
+                Line::raw(hexdump_line(address, &bytes))
+            })
+            .collect()
+    }
+}
+
+/// One line of a hexdump: the address, the bytes in hex and the printable
+/// ones as ASCII.
+fn hexdump_line(address: u32, bytes: &[u8]) -> String {
+    let mut line = format!("{address:05X} ");
+    for i in 0..MemoryViewer::BYTES_PER_LINE as usize {
+        if i % 8 == 0 {
+            line.push(' ');
+        }
+        match bytes.get(i) {
+            Some(b) => line.push_str(&format!("{b:02X} ")),
+            None => line.push_str("   "),
         }
     }
+    line.push_str(" |");
+    line.extend(bytes.iter().map(|&b| {
+        if b.is_ascii_graphic() || b == b' ' {
+            b as char
+        } else {
+            '.'
+        }
+    }));
+    line.push('|');
+    line
 }
 
 pub fn run(
@@ -274,18 +433,52 @@ pub fn run(
     debugger: Option<Debugger>,
     show_mapper: bool,
     show_vram: bool,
+    show_protection: bool,
+    start_paused: bool,
 ) -> Result<usize, Box<dyn std::error::Error + Send + Sync>> {
     crossterm::terminal::enable_raw_mode()?;
-    crossterm::execute!(io::stdout(), crossterm::terminal::EnterAlternateScreen,)?;
+    crossterm::execute!(
+        io::stdout(),
+        crossterm::terminal::EnterAlternateScreen,
+        crossterm::event::EnableBracketedPaste,
+    )?;
+    // Report presses and releases of the modifier keys, where the terminal
+    // supports it, so shift and ctrl are held on the LK201 as on the host
+    let enhanced_keyboard = crossterm::terminal::supports_keyboard_enhancement().unwrap_or(false);
+    if enhanced_keyboard {
+        use crossterm::event::KeyboardEnhancementFlags;
+        crossterm::execute!(
+            io::stdout(),
+            crossterm::event::PushKeyboardEnhancementFlags(
+                KeyboardEnhancementFlags::REPORT_EVENT_TYPES
+                    | KeyboardEnhancementFlags::REPORT_ALL_KEYS_AS_ESCAPE_CODES
+            ),
+        )?;
+    }
     crossterm::execute!(
         io::stdout(),
         crossterm::terminal::Clear(crossterm::terminal::ClearType::All),
     )?;
 
-    let res = run_inner(system, cpu, debugger, show_mapper, show_vram)?;
+    let res = run_inner(
+        system,
+        cpu,
+        debugger,
+        show_mapper,
+        show_vram,
+        show_protection,
+        start_paused,
+    )?;
 
+    if enhanced_keyboard {
+        crossterm::execute!(io::stdout(), crossterm::event::PopKeyboardEnhancementFlags)?;
+    }
     crossterm::terminal::disable_raw_mode()?;
-    crossterm::execute!(io::stdout(), crossterm::terminal::LeaveAlternateScreen,)?;
+    crossterm::execute!(
+        io::stdout(),
+        crossterm::event::DisableBracketedPaste,
+        crossterm::terminal::LeaveAlternateScreen,
+    )?;
     Ok(res)
 }
 
@@ -295,12 +488,16 @@ fn run_inner(
     debugger: Option<Debugger>,
     show_mapper: bool,
     show_vram: bool,
+    show_protection: bool,
+    start_paused: bool,
 ) -> Result<usize, Box<dyn std::error::Error + Send + Sync>> {
-    let mut running = true;
+    let mut running = !start_paused;
     let mut hex = DisplayMode::Normal;
     let mut pc_trace = false;
     let mut keyboard = CrosstermKeyboard::default();
+    let mut memory_viewer: Option<MemoryViewer> = None;
     let mut terminal = ratatui::Terminal::new(CrosstermBackend::new(io::stdout()))?;
+    let blink_start = Instant::now();
     loop {
         if running {
             let pc = cpu.pc_ext(&system);
@@ -325,7 +522,19 @@ fn run_inner(
                 if start.elapsed() > Duration::from_millis(100) {
                     warn!("Event read took too long: {:?}", start.elapsed());
                 }
-                match keyboard.update_keyboard(&event, &system.keyboard.sender()) {
+                // The memory viewer takes the keys while it is open
+                let command = match (&mut memory_viewer, &event) {
+                    (Some(viewer), Event::Key(key)) => {
+                        // The header and LED lines aren't part of the dump
+                        let lines = terminal.size()?.height.saturating_sub(2) as u32;
+                        if !viewer.key(key, &system, lines) {
+                            memory_viewer = None;
+                        }
+                        None
+                    }
+                    _ => keyboard.update_keyboard(&event, &system.keyboard.sender()),
+                };
+                match command {
                     Some(KeyboardCommand::ToggleRun) => {
                         running = !running;
                     }
@@ -333,12 +542,44 @@ fn run_inner(
                         hex = match hex {
                             DisplayMode::Normal => DisplayMode::NibbleTriplet,
                             DisplayMode::NibbleTriplet => DisplayMode::Bytes,
-                            DisplayMode::Bytes => DisplayMode::Normal,
+                            DisplayMode::Bytes => DisplayMode::Attributes,
+                            DisplayMode::Attributes => DisplayMode::Normal,
                         };
                     }
                     Some(KeyboardCommand::DumpVRAM) => {
                         fs::write("/tmp/vram.bin", &system.memory.vram[0..])?;
                     }
+                    Some(KeyboardCommand::ToggleRecording) => {
+                        if system.keyboard.is_recording() {
+                            let recording = system.keyboard.stop_recording();
+                            info!("Saved {} recorded keycodes", recording.len());
+                            fs::write("/tmp/blaze-vt-macro.bin", recording)?;
+                        } else {
+                            info!("Recording keyboard macro");
+                            system.keyboard.start_recording();
+                        }
+                    }
+                    Some(KeyboardCommand::ToggleVideoFreeze) => {
+                        system.freeze_video(!system.is_video_frozen());
+                        let (x, y) = system.raster_position();
+                        info!(
+                            "Video sync generator {} at x={x} y={y}",
+                            if system.is_video_frozen() {
+                                "frozen"
+                            } else {
+                                "running"
+                            }
+                        );
+                    }
+                    Some(KeyboardCommand::ToggleMemoryViewer) => {
+                        memory_viewer = match memory_viewer {
+                            Some(_) => None,
+                            None => Some(MemoryViewer::new()),
+                        };
+                    }
+                    Some(KeyboardCommand::Reset) => {
+                        system.reset(&mut cpu);
+                    }
                     #[cfg(feature = "pc-trace")]
                     Some(KeyboardCommand::TogglePCTrace) => {
                         use std::io::Write;
@@ -366,9 +607,26 @@ fn run_inner(
             let vram = &system.memory.vram[system.memory.mapper.vram_offset_display() as usize..];
             // Skip redrawing if the chargen is disabled
             if system.memory.mapper.get(6) & 0xf0 != 0xf0 {
+                let blink_off =
+                    (blink_start.elapsed().as_millis() / BLINK_PHASE.as_millis()) % 2 == 1;
                 terminal.draw(|f| {
-                    let screen = Screen::new(vram, &system.memory.mapper).display_mode(hex);
-                    f.render_widget(screen, f.area());
+                    // The bottom line is kept for the keyboard LEDs
+                    let area = f.area();
+                    let screen_area = Rect {
+                        height: area.height.saturating_sub(1),
+                        ..area
+                    };
+                    let led_area = Rect {
+                        y: screen_area.bottom(),
+                        height: area.height - screen_area.height,
+                        ..area
+                    };
+                    let screen = Screen::new(vram, &system.memory.mapper)
+                        .display_mode(hex)
+                        .show_protection(show_protection)
+                        .blink_off(blink_off);
+                    f.render_widget(screen, screen_area);
+                    f.render_widget(led_line(system.keyboard.leds()), led_area);
                     let stage = Span::styled(
                         format!(
                             "{:b}/{:02X}",
@@ -379,6 +637,21 @@ fn run_inner(
                     let stage = stage.into_right_aligned_line();
                     f.render_widget(stage, f.area());
 
+                    if let Some(viewer) = &memory_viewer {
+                        f.render_widget(Clear, screen_area);
+                        f.render_widget(viewer.header(&system), screen_area);
+                        let lines = screen_area.height.saturating_sub(1);
+                        for (i, line) in viewer.lines(&system, lines as u32).into_iter().enumerate()
+                        {
+                            let line_area = Rect {
+                                y: screen_area.y + 1 + i as u16,
+                                height: 1,
+                                ..screen_area
+                            };
+                            f.render_widget(line, line_area);
+                        }
+                    }
+
                     if show_mapper {
                         let mut mapper_line = Line::default();
                         for i in 0..16 {
@@ -432,3 +705,189 @@ fn run_inner(
     }
     Ok(system.instruction_count)
 }
+
+#[cfg(test)]
+mod tests {
+    use ratatui::style::Modifier;
+
+    use super::*;
+
+    /// Write a character into a VRAM row: the first 72 go in the first
+    /// segment and the rest in the second.
+    fn put_char(vram: &mut [u8], row_addr: usize, index: usize, ch: u8) {
+        let (row_addr, index) = if index < 72 {
+            (row_addr, index)
+        } else {
+            (row_addr + 128, index - 72)
+        };
+        let base = row_addr + index / 2 * 3;
+        if index % 2 == 0 {
+            vram[base] = ch;
+        } else {
+            vram[base + 1] |= (ch & 0xf) << 4;
+            vram[base + 2] = ch >> 4;
+        }
+    }
+
+    fn buffer_line(buf: &Buffer, y: u16) -> String {
+        (0..buf.area.width)
+            .map(|x| buf.cell((x, y)).unwrap().symbol())
+            .collect()
+    }
+
+    #[test]
+    fn test_double_height_rows() {
+        let mut vram = vec![0_u8; 0x20000];
+        // Both halves of the double-height line point at the same row data
+        vram[0..4].copy_from_slice(&[0x22, 0x08, 0x22, 0x0c]);
+        for (i, ch) in b"HELLO".iter().enumerate() {
+            put_char(&mut vram, 0x1100, i, *ch);
+        }
+        let mut mapper = Mapper::new();
+        mapper.set(3, 0);
+        mapper.set(4, 0);
+
+        let area = Rect::new(0, 0, 80, 3);
+        let mut buf = Buffer::empty(area);
+        Screen::new(&vram, &mapper).render(area, &mut buf);
+
+        assert!(buffer_line(&buf, 0).starts_with("H E L L O "));
+        assert_eq!(buffer_line(&buf, 1).trim(), "");
+    }
+
+    #[test]
+    fn test_double_width_rows() {
+        let mut vram = vec![0_u8; 0x20000];
+        // A double-width row above a single-width one, both full of text
+        vram[0..4].copy_from_slice(&[0x22, 0x04, 0x24, 0x00]);
+        for i in 0..80 {
+            put_char(&mut vram, 0x1100, i, b'W');
+            put_char(&mut vram, 0x1200, i, b'S');
+        }
+        let mut mapper = Mapper::new();
+        mapper.set(3, 0);
+        mapper.set(4, 0);
+
+        let area = Rect::new(0, 0, 100, 2);
+        let mut buf = Buffer::empty(area);
+        Screen::new(&vram, &mapper).render(area, &mut buf);
+
+        // Half as many characters, each spread over two cells, so both rows
+        // end at the same column
+        let double = buffer_line(&buf, 0);
+        let single = buffer_line(&buf, 1);
+        assert_eq!(double.matches('W').count(), 40);
+        assert_eq!(&double[..80], "W ".repeat(40));
+        assert_eq!(&single[..80], "S".repeat(80));
+        assert_eq!(double[80..].trim(), "");
+        assert_eq!(single[80..].trim(), "");
+    }
+
+    /// The text display and `System::dump_screen_text` both decode through
+    /// `decode_vram`, so they must agree on the screen contents.
+    #[test]
+    fn test_matches_dump_screen_text() {
+        let mut system = System::new_deterministic(vec![0; 0x20000], None).unwrap();
+        let lines: [&[u8]; 3] = [b"Hello, world", b"VT420 OK", b"ABC xyz 123"];
+        for (row, line) in lines.iter().enumerate() {
+            let row_addr = 0x22 + row as u8 * 2;
+            system.memory.vram[row * 2] = row_addr;
+            for (i, ch) in line.iter().enumerate() {
+                put_char(
+                    system.memory.vram.as_mut_slice(),
+                    (row_addr as usize >> 1) << 8,
+                    i,
+                    *ch,
+                );
+            }
+        }
+        system.memory.mapper.set(3, 0);
+        system.memory.mapper.set(4, 0);
+
+        let area = Rect::new(0, 0, 80, lines.len() as u16);
+        let mut buf = Buffer::empty(area);
+        Screen::new(system.memory.vram.as_slice(), &system.memory.mapper).render(area, &mut buf);
+
+        let dump = system.dump_screen_text();
+        let dump_lines = dump.lines().skip(1).map(str::trim_end).collect::<Vec<_>>();
+        let rendered = (0..area.height)
+            .map(|y| buffer_line(&buf, y).trim_end().to_string())
+            .collect::<Vec<_>>();
+        assert_eq!(dump_lines, rendered);
+        assert_eq!(rendered[1], "VT420 OK");
+    }
+
+    #[test]
+    fn test_attribute_mode() {
+        let mut vram = vec![0_u8; 0x20000];
+        vram[0..2].copy_from_slice(&[0x22, 0x00]);
+        put_char(&mut vram, 0x1100, 0, b'A');
+        put_char(&mut vram, 0x1100, 1, b'B');
+        // First cell: bold and blink in the character's upper nibble,
+        // underline in the low attribute bits
+        vram[0x1101] |= 0x0a;
+        vram[0x1100 + 0xdd] = 0b0100;
+        let mut mapper = Mapper::new();
+        mapper.set(3, 0);
+        mapper.set(4, 0);
+
+        let area = Rect::new(0, 0, 80, 1);
+        let mut buf = Buffer::empty(area);
+        Screen::new(&vram, &mapper)
+            .display_mode(DisplayMode::Attributes)
+            .render(area, &mut buf);
+
+        assert!(buffer_line(&buf, 0).starts_with("90"));
+        let cell = buf.cell((0, 0)).unwrap();
+        assert_eq!(cell.fg, Color::Magenta);
+        assert!(
+            cell.modifier
+                .contains(Modifier::BOLD | Modifier::UNDERLINED)
+        );
+        assert!(buf.cell((1, 0)).unwrap().modifier.is_empty());
+    }
+
+    #[test]
+    fn test_hexdump_line() {
+        assert_eq!(
+            hexdump_line(0x1230, b"Hello, VT420!\x00\x7f\xff"),
+            "01230  48 65 6C 6C 6F 2C 20 56  54 34 32 30 21 00 7F FF  |Hello, VT420!...|"
+        );
+        assert_eq!(
+            hexdump_line(0x10, b"AB"),
+            format!("00010  41 42{}|AB|", " ".repeat(45))
+        );
+    }
+
+    #[test]
+    fn test_memory_viewer() {
+        let mut system = System::new_deterministic(vec![0; 0x20000], None).unwrap();
+        system.memory.sram[0x1234] = 0x42;
+        let key = |code| KeyEvent::new(code, crossterm::event::KeyModifiers::NONE);
+        let mut viewer = MemoryViewer::new();
+
+        // Go to an SRAM address, which is rounded down to the line
+        assert!(viewer.key(&key(KeyCode::Tab), &system, 10));
+        assert_eq!(viewer.target, MemoryTarget::Sram);
+        for c in "1234".chars() {
+            assert!(viewer.key(&key(KeyCode::Char(c)), &system, 10));
+        }
+        assert!(viewer.key(&key(KeyCode::Enter), &system, 10));
+        assert_eq!(viewer.address, 0x1230);
+        let lines = viewer.lines(&system, 10);
+        assert_eq!(lines.len(), 10);
+        assert!(lines[0].to_string().starts_with("01230  00 00 00 00 42 "));
+
+        // Paging stops at either end
+        viewer.key(&key(KeyCode::PageUp), &system, 10);
+        assert_eq!(viewer.address, 0x1190);
+        viewer.key(&key(KeyCode::End), &system, 10);
+        assert_eq!(viewer.address, system.memory.sram.len() as u32 - 0xa0);
+        viewer.key(&key(KeyCode::PageDown), &system, 10);
+        assert_eq!(viewer.address, system.memory.sram.len() as u32 - 0xa0);
+        viewer.key(&key(KeyCode::Home), &system, 10);
+        assert_eq!(viewer.address, 0);
+
+        assert!(!viewer.key(&key(KeyCode::Esc), &system, 10));
+    }
+}
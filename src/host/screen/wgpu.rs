@@ -1,55 +1,223 @@
 use std::cell::RefCell;
+use std::fs;
+use std::hash::{DefaultHasher, Hash, Hasher};
+use std::io;
+use std::path::Path;
 use std::rc::Rc;
 use std::time::Duration;
 
+use game_loop::{Time, TimeTrait as _};
 use i8051::Cpu;
 #[cfg(feature = "tui")]
 use i8051_debug_tui::{Debugger, DebuggerState};
 #[cfg(feature = "tui")]
 use ratatui::crossterm;
+use tracing::info;
 
 use crate::{
     System,
-    machine::vt420::video::{RowFlags, decode_font, decode_vram},
+    error::BlazeError,
+    host::png,
+    host::wgpu::{Aspect, FPS, LED_STRIP_HEIGHT, Mouse, Request, SCREEN_HEIGHT},
+    machine::generic::lk201::Led,
+    machine::vt420::STEPS_PER_SECOND,
+    machine::vt420::memory::FONT_VRAM,
+    machine::vt420::video::{
+        BLINK_PHASE, CellAttr, FONT_132_OFFSET, RowFlags, STATUS_ROW_TOP, VERTICAL_LINES,
+        decode_font, decode_override_glyph, decode_vram, font_glyph_address,
+    },
 };
 
+/// Bytes per row of the RGBA frame buffer.
+const ROW_BYTES: usize = 800 * 4;
+
+/// Bytes of the frame buffer holding the VT420 display, above the LED strip.
+const SCREEN_BYTES: usize = ROW_BYTES * SCREEN_HEIGHT as usize;
+
+/// Size and spacing of the LED indicator boxes, in pixels.
+const LED_WIDTH: usize = 24;
+const LED_HEIGHT: usize = 6;
+const LED_SPACING: usize = 40;
+const LED_LIT: [u8; 4] = [0x40, 0xff, 0x40, 0xff];
+const LED_UNLIT: [u8; 4] = [0x20, 0x30, 0x20, 0xff];
+
+/// The color of an unlit pixel of the display.
+const UNLIT: [u8; 4] = [0x00, 0x00, 0x00, 0xff];
+
+/// Number of frames in each on/off phase of blinking text.
+const BLINK_FRAMES: u32 = BLINK_PHASE.as_millis() as u32 * FPS / 1000;
+
+#[derive(Default)]
+pub struct WgpuRender {
+    frame_count: u32,
+    /// A hash of everything that went into drawing each row of the last
+    /// frame, so unchanged rows can be skipped.
+    row_hashes: Vec<u64>,
+    /// The keyboard LEDs last drawn into the LED strip.
+    leds: Option<Led>,
+    /// Glyphs to draw in place of the font in VRAM.
+    font: Option<Vec<u8>>,
+    /// Color cells by their attributes instead of drawing them in monochrome.
+    false_color: bool,
+    /// Smooth the edges of glyphs, if enabled.
+    supersample: Option<Supersample>,
+    /// A hash of the font VRAM, and the [`RAM::font_version`](crate::machine::vt420::memory::RAM::font_version) it was taken
+    /// at, so that a font change redraws every row.
+    font_hash: Option<(u64, u64)>,
+}
+
+/// The buffers for smoothing glyph edges, kept between frames.
 #[derive(Default)]
-pub struct WgpuRender {}
+struct Supersample {
+    /// The display as drawn, with hard on/off pixels.
+    crisp: Vec<u8>,
+    /// The display at twice the resolution, with the corners of diagonal
+    /// edges filled in.
+    doubled: Vec<u8>,
+}
+
+/// A decoded cell: column, character and attributes.
+type RenderCell = (u8, u8, CellAttr);
+
+struct Render<'a> {
+    row: usize,
+    row_offset: usize,
+    row_flags: RowFlags,
+    start_row: usize,
+    frame: &'a mut [u8],
+    smooth: (u8, u8, u8),
+    /// The cells of the current row, drawn once the row is complete.
+    cells: Vec<RenderCell>,
+    in_row: bool,
+    rows: usize,
+    rows_drawn: usize,
+    font_hash: u64,
+    blink_off: bool,
+    false_color: bool,
+}
 
 impl WgpuRender {
-    pub fn render(&self, system: &System, frame: &mut [u8]) {
-        // Don't render during vsync
-        if system.memory.mapper.get(6) & 0xf0 == 0xf0 {
-            return;
+    /// Draw characters from `font` instead of the font uploaded by the
+    /// firmware. See [`FONT_GLYPH_BYTES`](crate::machine::vt420::video::FONT_GLYPH_BYTES)
+    /// for the format.
+    pub fn with_font(font: Vec<u8>) -> Self {
+        Self {
+            font: Some(font),
+            ..Default::default()
+        }
+    }
+
+    /// Draw each combination of attributes in its own color, for debugging.
+    /// See [`cell_color`].
+    pub fn false_color(mut self, false_color: bool) -> Self {
+        self.false_color = false_color;
+        self.invalidate();
+        self
+    }
+
+    /// Smooth the edges of glyphs by drawing the display at twice the
+    /// resolution and averaging it back down, instead of drawing hard on/off
+    /// pixels.
+    pub fn smooth_font(mut self, smooth_font: bool) -> Self {
+        self.supersample = smooth_font.then(Supersample::default);
+        self.invalidate();
+        self
+    }
+
+    /// Render the screen into the RGBA frame buffer. Returns false if the frame
+    /// was left untouched.
+    pub fn render(&mut self, system: &System, frame: &mut [u8]) -> bool {
+        let drawn = match self.supersample.take() {
+            Some(mut supersample) => {
+                let len = frame.len().min(SCREEN_BYTES);
+                let screen = &mut frame[..len];
+                supersample.crisp.resize(screen.len(), 0);
+                let rows = self.render_rows(system, &mut supersample.crisp);
+                // Only redrawn rows change the crisp display, unless there
+                // are no rows at all
+                if rows.is_some_and(|rows| rows > 0 || self.row_hashes.is_empty()) {
+                    supersample.resolve(screen);
+                }
+                self.supersample = Some(supersample);
+                rows.is_some()
+            }
+            None => self.render_rows(system, frame).is_some(),
+        };
+        let leds = system.keyboard.leds();
+        if self.leds == Some(leds) {
+            return drawn;
+        }
+        self.leds = Some(leds);
+        if let Some(strip) = frame.get_mut(SCREEN_BYTES..) {
+            draw_leds(strip, leds);
         }
+        true
+    }
+
+    /// Forget what was drawn, so the next frame is drawn in full. Needed when
+    /// something else has modified the frame buffer.
+    pub fn invalidate(&mut self) {
+        self.row_hashes.clear();
+        self.leds = None;
+    }
+
+    /// Render the screen into the RGBA frame buffer, returning the number of
+    /// rows that had to be redrawn, or `None` if the frame was left untouched.
+    fn render_rows(&mut self, system: &System, frame: &mut [u8]) -> Option<usize> {
+        self.frame_count = self.frame_count.wrapping_add(1);
+        let blink_off = (self.frame_count / BLINK_FRAMES) % 2 == 1;
 
-        #[derive(Default)]
-        struct Render<'a> {
-            row: usize,
-            row_offset: usize,
-            row_flags: RowFlags,
-            start_row: usize,
-            frame: &'a mut [u8],
-            smooth: (u8, u8, u8),
+        // Don't render during vsync
+        if in_vsync(system) {
+            return None;
         }
+
+        let font_version = system.memory.font_version;
+        let font_hash = match self.font_hash {
+            Some((version, hash)) if version == font_version => hash,
+            _ => {
+                let mut hasher = DefaultHasher::new();
+                system.memory.vram[FONT_VRAM].hash(&mut hasher);
+                let hash = hasher.finish();
+                self.font_hash = Some((font_version, hash));
+                hash
+            }
+        };
         let render = Render {
+            row: 0,
+            row_offset: 0,
+            row_flags: RowFlags::default(),
+            start_row: 0,
             smooth: (
                 system.memory.mapper.get(0),
                 system.memory.mapper.get(1),
                 system.memory.mapper.get(2),
             ),
             frame,
-            ..Default::default()
+            cells: Vec::with_capacity(132),
+            in_row: false,
+            rows: 0,
+            rows_drawn: 0,
+            font_hash,
+            blink_off,
+            false_color: self.false_color,
         };
-        let mut font = [0_u16; 16];
-        let render = decode_vram(
-            &system.memory.vram[system.memory.mapper.vram_offset_display() as usize..],
+        let vram = system.memory.vram.as_slice();
+        let mut render = decode_vram(
+            &vram[system.memory.mapper.vram_offset_display() as usize..],
             &system.memory.mapper,
-            |render, row, attr, row_flags| {
+            |render, row, _attr, row_flags| {
+                self.flush_row(render, vram);
                 render.row += render.row_flags.row_height as usize;
                 render.row_offset += 800 * 4 * render.row_flags.row_height as usize;
 
+                if row_flags.status_row {
+                    // The status row is always drawn in the same place
+                    render.row = STATUS_ROW_TOP;
+                    render.row_offset = ROW_BYTES * STATUS_ROW_TOP;
+                }
                 render.row_flags = row_flags;
+                render.in_row = true;
                 render.start_row = 0;
                 if render.smooth.2 != 0 {
                     if (render.smooth.0..=render.smooth.1).contains(&row) {
@@ -65,180 +233,1008 @@ impl WgpuRender {
                 }
             },
             |render, column, c, attr| {
-                let c = c as usize | ((((attr >> 2) & 0x01) as usize) << 8);
-                let mut c = c * 2;
-                if render.row_flags.status_row && attr >> 2 & 0x8 == 0 {
-                    c = c.saturating_add(1);
+                render.cells.push((column, c, attr));
+            },
+            render,
+        );
+        self.flush_row(&mut render, vram);
+        self.row_hashes.truncate(render.rows);
+
+        // Stopgap to fix the leftover pixels at the end of the frame
+        let rows_end = render.row_offset + ROW_BYTES * render.row_flags.row_height as usize;
+        let screen_end = render.frame.len().min(SCREEN_BYTES);
+        if rows_end < screen_end {
+            render.frame[rows_end..screen_end].fill(0);
+        }
+        Some(render.rows_drawn)
+    }
+
+    /// Draw the cells collected for the current row, unless the row looks the
+    /// same as it did in the last frame.
+    fn flush_row(&mut self, render: &mut Render, vram: &[u8]) {
+        if !std::mem::replace(&mut render.in_row, false) {
+            return;
+        }
+        let index = render.rows;
+        render.rows += 1;
+
+        let blinking =
+            !render.row_flags.status_row && render.cells.iter().any(|&(_, _, attr)| attr.blink());
+        let mut hasher = DefaultHasher::new();
+        (
+            render.font_hash,
+            render.row,
+            render.row_flags,
+            render.start_row,
+            blinking && render.blink_off,
+            &render.cells,
+        )
+            .hash(&mut hasher);
+        let hash = hasher.finish();
+
+        if self.row_hashes.get(index) == Some(&hash) {
+            render.cells.clear();
+            return;
+        }
+        if index < self.row_hashes.len() {
+            self.row_hashes[index] = hash;
+        } else {
+            self.row_hashes.push(hash);
+        }
+
+        render.rows_drawn += 1;
+        let mut font = [0_u16; 16];
+        let mut cells = std::mem::take(&mut render.cells);
+        for &(column, c, attr) in &cells {
+            draw_cell(
+                render,
+                vram,
+                self.font.as_deref(),
+                &mut font,
+                column,
+                c,
+                attr,
+            );
+        }
+        cells.clear();
+        render.cells = cells;
+        clear_right_margin(render);
+    }
+}
+
+impl Supersample {
+    /// Draw the crisp display into `frame`. Each pixel is doubled into a 2x2
+    /// block, with the corners of diagonal edges taken from the neighbouring
+    /// pixels as Scale2x does. The blocks are then averaged back down, so
+    /// those edges are drawn in intermediate shades.
+    fn resolve(&mut self, frame: &mut [u8]) {
+        let width = ROW_BYTES / 4;
+        let height = self.crisp.len() / ROW_BYTES;
+        let crisp = &self.crisp;
+        let pixel = |x: usize, y: usize| -> [u8; 4] {
+            crisp[(y * width + x) * 4..][..4].try_into().unwrap()
+        };
+
+        self.doubled.resize(crisp.len() * 4, 0);
+        for y in 0..height {
+            for x in 0..width {
+                let p = pixel(x, y);
+                let above = if y > 0 { pixel(x, y - 1) } else { p };
+                let left = if x > 0 { pixel(x - 1, y) } else { p };
+                let right = if x + 1 < width { pixel(x + 1, y) } else { p };
+                let below = if y + 1 < height { pixel(x, y + 1) } else { p };
+                let corner = |a: [u8; 4], b: [u8; 4], c: [u8; 4], d: [u8; 4]| {
+                    if a == b && a != c && b != d { a } else { p }
+                };
+                let corners = [
+                    corner(left, above, below, right),
+                    corner(above, right, left, below),
+                    corner(below, left, right, above),
+                    corner(right, below, above, left),
+                ];
+                for (i, color) in corners.iter().enumerate() {
+                    let offset = ((y * 2 + i / 2) * width * 2 + x * 2 + i % 2) * 4;
+                    self.doubled[offset..][..4].copy_from_slice(color);
                 }
-                let bold = attr & 0x08 != 0;
-                let underline = attr & 1 != 0;
-                let color = if bold { 0xff } else { 0x80 };
-                let font_address_base = c * 16 + 0x8000 + render.row_flags.font as usize;
-                decode_font(
-                    system.memory.vram.as_ref(),
-                    font_address_base as _,
-                    render.row_flags.is_80,
-                    &mut font,
+            }
+        }
+
+        for (i, pixel) in frame.chunks_exact_mut(4).take(width * height).enumerate() {
+            let offset = ((i / width) * width * 4 + (i % width) * 2) * 4;
+            for (channel, value) in pixel.iter_mut().enumerate() {
+                let sum = [0, 4, width * 8, width * 8 + 4]
+                    .iter()
+                    .map(|&sub| self.doubled[offset + sub + channel] as u16)
+                    .sum::<u16>();
+                *value = (sum / 4) as u8;
+            }
+        }
+    }
+}
+
+/// Whether the display is in vertical sync, when VRAM is being updated and
+/// the frame shouldn't be drawn.
+pub fn in_vsync(system: &System) -> bool {
+    system.memory.mapper.get(6) & 0xf0 == 0xf0
+}
+
+/// Render the display from scratch into an RGBA frame buffer, without a
+/// window. The buffer is 800 pixels wide, and the LED strip is drawn below the
+/// display if there is room for it. Returns false, leaving the frame untouched,
+/// while the display is in vertical sync.
+pub fn render_offscreen(system: &System, frame: &mut [u8]) -> bool {
+    if in_vsync(system) {
+        return false;
+    }
+    WgpuRender::default().render(system, frame)
+}
+
+/// Render the display without a window and write it to `path` as a PNG.
+/// Returns false, writing nothing, while the display is in vertical sync.
+pub fn write_png(system: &System, path: &Path) -> io::Result<bool> {
+    let mut frame = vec![0_u8; SCREEN_BYTES];
+    if !render_offscreen(system, &mut frame) {
+        return Ok(false);
+    }
+    let file = io::BufWriter::new(fs::File::create(path)?);
+    png::write_rgba(file, 800, SCREEN_HEIGHT, &frame)?;
+    Ok(true)
+}
+
+/// The line that a row is clipped at: only the status row is drawn over the
+/// bottom of the display.
+fn row_bottom(row_flags: RowFlags) -> usize {
+    if row_flags.status_row {
+        VERTICAL_LINES
+    } else {
+        STATUS_ROW_TOP
+    }
+}
+
+/// Blank the pixels to the right of the current row's text. 132 columns of 6
+/// pixels don't fill the 800 pixel frame, and double-width rows span the same
+/// width as single-width ones.
+fn clear_right_margin(render: &mut Render) {
+    let extent = if render.row_flags.is_80 {
+        80 * 10
+    } else {
+        132 * 6
+    };
+    let lines = (render.row_flags.row_height as usize)
+        .min(row_bottom(render.row_flags).saturating_sub(render.row));
+    for y in 0..lines {
+        let offset = render.row_offset + y * ROW_BYTES;
+        if let Some(margin) = render
+            .frame
+            .get_mut(offset + extent * 4..offset + ROW_BYTES)
+        {
+            margin.fill(0);
+        }
+    }
+}
+
+/// The 1-based row and column of the text cell under a pixel of the frame
+/// buffer, or `None` if the pixel isn't over the text or is over the status
+/// row.
+fn cell_at(system: &System, x: usize, y: usize) -> Option<(u16, u16)> {
+    let mapper = &system.memory.mapper;
+    let vram = &system.memory.vram[mapper.vram_offset_display() as usize..];
+    let (cell, ..) = decode_vram(
+        vram,
+        mapper,
+        |(cell, top, height, rows): &mut (Option<(u16, u16)>, usize, usize, u16),
+         _,
+         _,
+         row_flags: RowFlags| {
+            *top += *height;
+            *height = row_flags.row_height as usize;
+            if row_flags.status_row {
+                return;
+            }
+            *rows += 1;
+            if !(*top..*top + *height).contains(&y) || y >= STATUS_ROW_TOP {
+                return;
+            }
+            let (width, columns) = if row_flags.is_80 { (10, 80) } else { (6, 132) };
+            let (width, columns) = if row_flags.double_width {
+                (width * 2, columns / 2)
+            } else {
+                (width, columns)
+            };
+            let column = x / width;
+            if column < columns {
+                *cell = Some((*rows, column as u16 + 1));
+            }
+        },
+        |_, _, _, _| {},
+        (None, 0, 0, 0),
+    );
+    cell
+}
+
+/// Feed the mouse to the DEC Locator on comm1, if there is one.
+fn locator_mouse(system: Rc<RefCell<System>>) -> impl FnMut(Mouse) + 'static {
+    move |mouse| {
+        let system = system.borrow();
+        let Some(locator) = &system.locator else {
+            return;
+        };
+        match mouse {
+            Mouse::Moved(pixel) => {
+                locator.move_to(pixel.and_then(|(x, y)| cell_at(&system, x, y)));
+            }
+            Mouse::Button(button, pressed) => locator.button(button, pressed),
+        }
+    }
+}
+
+/// Handle the window's requests. A reset happens before the terminal's next
+/// step, as the stepper holds the CPU. Closing the window saves the NVR and
+/// writes the logs, as the event loop doesn't always return, so the system
+/// may never be dropped.
+fn handle_request(system: Rc<RefCell<System>>) -> impl FnMut(Request) + 'static {
+    move |request| match request {
+        Request::Reset => system.borrow_mut().request_reset(),
+        Request::Close => system.borrow_mut().shutdown(),
+    }
+}
+
+fn draw_cell(
+    render: &mut Render,
+    vram: &[u8],
+    font_override: Option<&[u8]>,
+    font: &mut [u16; 16],
+    column: u8,
+    c: u8,
+    attr: CellAttr,
+) {
+    let glyph = c as usize | ((attr.alternate_glyph() as usize) << 8);
+    // The status row doesn't blink
+    let blanked = render.blink_off && attr.blink() && !render.row_flags.status_row;
+    let underline = attr.underline() && !blanked;
+    let lit = cell_color(attr, render.false_color);
+    if let Some(font_override) = font_override {
+        decode_override_glyph(font_override, glyph, render.row_flags.is_80, font);
+    } else {
+        let mut address = font_glyph_address(render.row_flags.font, glyph);
+        if render.row_flags.status_row && attr.blink() {
+            // The status row's blink bit takes the six pixels from the first
+            // half of the glyph instead
+            address -= FONT_132_OFFSET;
+        }
+        decode_font(vram, address, render.row_flags.is_80, font);
+    }
+    let width = if render.row_flags.is_80 { 10 } else { 6 };
+    let mut offset = render.row_offset;
+    let bottom = row_bottom(render.row_flags);
+    for mut y in 0..render.row_flags.row_height as usize {
+        if render.row + y >= bottom {
+            break;
+        }
+        if render.row_flags.double_width {
+            if render.row_flags.double_height_top {
+                y /= 2;
+            } else if render.row_flags.double_height_bottom {
+                y /= 2;
+                y += render.row_flags.row_height as usize / 2;
+            }
+            for x in 0..width {
+                let x_offset = (column as usize * width + x) * 8;
+                let pixel = cell_pixel(
+                    !blanked && font[y + render.start_row] & (1 << x) != 0,
+                    underline && y == render.row_flags.row_height as usize - 1,
+                    attr,
+                );
+                let color = if pixel ^ render.row_flags.invert {
+                    lit
+                } else {
+                    UNLIT
+                };
+                render.frame[offset + x_offset..][..4].copy_from_slice(&color);
+                render.frame[offset + x_offset + 4..][..4].copy_from_slice(&color);
+            }
+        } else {
+            for x in 0..width {
+                let x_offset = (column as usize * width + x) * 4;
+                let pixel = cell_pixel(
+                    !blanked && font[y + render.start_row] & (1 << x) != 0,
+                    underline && y == render.row_flags.row_height as usize - 1,
+                    attr,
                 );
-                let width = if render.row_flags.is_80 { 10 } else { 6 };
-                let mut offset = render.row_offset;
-                for mut y in 0..render.row_flags.row_height as usize {
-                    if render.row + y >= 416 {
-                        break;
+                let color = if pixel ^ render.row_flags.invert {
+                    lit
+                } else {
+                    UNLIT
+                };
+                render.frame[offset + x_offset..][..4].copy_from_slice(&color);
+            }
+        }
+        offset += 800 * 4;
+    }
+}
+
+/// Whether a pixel of a cell is lit, given whether the glyph and the underline
+/// cover it. As on the terminal, the underline is drawn into the glyph before
+/// reverse video inverts the whole cell, so a reversed, underlined cell has a
+/// dark underline.
+fn cell_pixel(glyph: bool, underline: bool, attr: CellAttr) -> bool {
+    (glyph || underline) ^ attr.reverse()
+}
+
+/// The color of a lit pixel in a cell with the given attributes.
+///
+/// In monochrome, bold cells are brighter. In false color, each attribute
+/// raises one channel by its own step, so that every combination of underline,
+/// protected, bold, reverse and blink gets a distinct color: reverse and blink
+/// in red, underline and bold in green, and protected in blue.
+fn cell_color(attr: CellAttr, false_color: bool) -> [u8; 4] {
+    let bit = |set: bool, step: u8| if set { step } else { 0 };
+    if !false_color {
+        let level = if attr.bold() { 0xff } else { 0x80 };
+        return [level, level, level, 0xff];
+    }
+    [
+        0x3f + bit(attr.reverse(), 0x80) + bit(attr.blink(), 0x40),
+        0x3f + bit(attr.underline(), 0x80) + bit(attr.bold(), 0x40),
+        0x3f + bit(attr.protected(), 0x80),
+        0xff,
+    ]
+}
+
+/// Draw a box for each keyboard LED, right-aligned in the strip below the
+/// display.
+fn draw_leds(strip: &mut [u8], leds: Led) {
+    strip.fill(0);
+    let top = (LED_STRIP_HEIGHT as usize - LED_HEIGHT) / 2;
+    let indicators = leds.indicators();
+    let left = ROW_BYTES / 4 - LED_SPACING * indicators.len();
+    for pixels in strip.chunks_exact_mut(ROW_BYTES).skip(top).take(LED_HEIGHT) {
+        for (i, (_, lit)) in indicators.iter().enumerate() {
+            let x = left + i * LED_SPACING;
+            let color = if *lit { LED_LIT } else { LED_UNLIT };
+            for pixel in pixels[x * 4..(x + LED_WIDTH) * 4].chunks_exact_mut(4) {
+                pixel.copy_from_slice(&color);
+            }
+        }
+    }
+}
+
+/// The individual passes of the CRT effect, as selected by `--crt`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum CrtOption {
+    Scanlines,
+    Tint,
+    Persistence,
+}
+
+/// A CPU post-process over the RGBA frame buffer that approximates the look of
+/// the original monitor.
+#[derive(Default)]
+pub struct CrtEffect {
+    /// Darken every other row.
+    pub scanlines: bool,
+    /// Tint the output towards green phosphor.
+    pub tint: bool,
+    /// Blend in a decayed copy of the previous frame.
+    pub persistence: bool,
+    previous: Vec<u8>,
+}
+
+impl CrtEffect {
+    pub fn new(options: &[CrtOption]) -> Self {
+        Self {
+            scanlines: options.contains(&CrtOption::Scanlines),
+            tint: options.contains(&CrtOption::Tint),
+            persistence: options.contains(&CrtOption::Persistence),
+            previous: Vec::new(),
+        }
+    }
+
+    pub fn apply(&mut self, frame: &mut [u8]) {
+        if self.persistence {
+            if self.previous.len() != frame.len() {
+                self.previous = frame.to_vec();
+            }
+            for (pixel, previous) in frame.iter_mut().zip(self.previous.iter_mut()) {
+                // Phosphor decays to ~60% per frame
+                *pixel = (*pixel).max((*previous as u16 * 3 / 5) as u8);
+                *previous = *pixel;
+            }
+        }
+
+        for (row, row_pixels) in frame.chunks_exact_mut(ROW_BYTES).enumerate() {
+            let dim = self.scanlines && row % 2 == 1;
+            for pixel in row_pixels.chunks_exact_mut(4) {
+                if self.tint {
+                    pixel[0] = (pixel[0] as u16 * 7 / 8) as u8;
+                    pixel[2] = (pixel[2] as u16 * 3 / 4) as u8;
+                }
+                if dim {
+                    for channel in &mut pixel[..3] {
+                        *channel = (*channel as u16 * 3 / 5) as u8;
                     }
-                    if c == 0 && !render.row_flags.is_80 {
-                        // Stopgap to fix the leftover pixels at the end of the frame
-                        const LEFTOVER_132_PIXELS: usize = 80 * 10 - 132 * 6;
-                        for i in 0..LEFTOVER_132_PIXELS * 4 {
-                            render.frame[offset + 800 * 4 - LEFTOVER_132_PIXELS * 4 + i] = 0;
-                        }
+                }
+            }
+        }
+    }
+}
+
+/// Paces the emulated CPU against the host clock, returning how many steps to
+/// run each time the game loop updates.
+struct Governor {
+    /// Target steps per second, or zero to run unthrottled.
+    steps_per_second: f64,
+    /// The refresh rate selected by the firmware, which sets the length of a
+    /// frame and how often the game loop runs.
+    refresh_hz: u8,
+    last: Option<Time>,
+    remainder: f64,
+}
+
+impl Governor {
+    /// Don't try to catch up on more than this many seconds at once, ie: after
+    /// being paused.
+    const MAX_ELAPSED: f64 = 0.1;
+
+    fn new(speed: f64) -> Self {
+        Self {
+            steps_per_second: STEPS_PER_SECOND as f64 * speed.max(0.0),
+            refresh_hz: FPS as u8,
+            last: None,
+            remainder: 0.0,
+        }
+    }
+
+    /// Follow the firmware's 60Hz/70Hz selection.
+    fn set_refresh_hz(&mut self, refresh_hz: u8) {
+        if refresh_hz != self.refresh_hz {
+            info!("Display refresh changed to {refresh_hz}Hz");
+            self.refresh_hz = refresh_hz;
+        }
+    }
+
+    /// The length of one display frame, in seconds.
+    fn frame_time(&self) -> f64 {
+        1.0 / self.refresh_hz as f64
+    }
+
+    /// The number of steps to run now, or `None` to run as many as fit in the
+    /// frame.
+    fn steps(&mut self) -> Option<usize> {
+        if self.steps_per_second == 0.0 {
+            return None;
+        }
+        let now = Time::now();
+        let elapsed = self
+            .last
+            .as_ref()
+            .map(|last| now.sub(last).min(Self::MAX_ELAPSED))
+            .unwrap_or(self.frame_time());
+        self.last = Some(now);
+        Some(self.take(elapsed))
+    }
+
+    /// Convert elapsed seconds into whole steps, carrying the fraction over.
+    fn take(&mut self, elapsed: f64) -> usize {
+        let steps = self.steps_per_second * elapsed + self.remainder;
+        self.remainder = steps.fract();
+        steps as usize
+    }
+
+    /// Run `step` at the governed rate. Unthrottled, keep stepping until most
+    /// of the frame's time has been used.
+    fn run(&mut self, mut step: impl FnMut() -> bool) {
+        match self.steps() {
+            Some(steps) => {
+                for _ in 0..steps {
+                    if !step() {
+                        return;
                     }
-                    if render.row_flags.double_width {
-                        if render.row_flags.double_height_top {
-                            y /= 2;
-                        } else if render.row_flags.double_height_bottom {
-                            y /= 2;
-                            y += render.row_flags.row_height as usize / 2;
-                        }
-                        for x in 0..width {
-                            let x_offset = (column as usize * width + x) * 8;
-                            let mut pixel = font[y + render.start_row] & (1 << x) != 0;
-                            if underline && y == render.row_flags.row_height as usize - 1 {
-                                pixel = true;
-                            }
-                            if attr & 16 != 0 {
-                                pixel = !pixel;
-                            }
-                            let color = if pixel ^ render.row_flags.invert {
-                                color
-                            } else {
-                                0x00
-                            };
-                            render.frame[offset + x_offset] = color;
-                            render.frame[offset + x_offset + 1] = color;
-                            render.frame[offset + x_offset + 2] = color;
-                            render.frame[offset + x_offset + 3] = 0xff;
-                            render.frame[offset + x_offset + 4] = color;
-                            render.frame[offset + x_offset + 5] = color;
-                            render.frame[offset + x_offset + 6] = color;
-                            render.frame[offset + x_offset + 7] = 0xff;
-                        }
-                    } else {
-                        for x in 0..width {
-                            let x_offset = (column as usize * width + x) * 4;
-                            let mut pixel = font[y + render.start_row] & (1 << x) != 0;
-                            if underline && y == render.row_flags.row_height as usize - 1 {
-                                pixel = true;
-                            }
-                            if attr & 16 != 0 {
-                                pixel = !pixel;
-                            }
-                            let color = if pixel ^ render.row_flags.invert {
-                                color
-                            } else {
-                                0x00
-                            };
-                            render.frame[offset + x_offset] = color;
-                            render.frame[offset + x_offset + 1] = color;
-                            render.frame[offset + x_offset + 2] = color;
-                            render.frame[offset + x_offset + 3] = 0xff;
+                }
+            }
+            None => {
+                let start = Time::now();
+                let budget = self.frame_time() * 0.8;
+                while Time::now().sub(&start) < budget {
+                    for _ in 0..1000 {
+                        if !step() {
+                            return;
                         }
                     }
-                    offset += 800 * 4;
                 }
-            },
-            render,
-        );
-
-        // Stopgap to fix the leftover pixels at the end of the frame
-        if render.row_offset < render.frame.len() {
-            render.frame[render.row_offset..].fill(0);
+            }
         }
     }
 }
 
+/// How to draw and pace the graphical display.
+pub struct GraphicsOptions {
+    /// How to scale the display to the window
+    pub aspect: Aspect,
+    /// The monitor effects to apply, if any
+    pub crt: Option<CrtEffect>,
+    /// Glyphs to draw in place of the font in VRAM
+    pub font: Option<Vec<u8>>,
+    /// Color cells by their attributes instead of drawing them in monochrome
+    pub false_color: bool,
+    /// Smooth the edges of glyphs
+    pub smooth_font: bool,
+    /// The CPU speed, relative to the real terminal
+    pub speed: f64,
+    /// Boot with the CPU paused
+    pub start_paused: bool,
+}
+
 pub fn run(
     system: System,
     mut cpu: Cpu,
+    options: GraphicsOptions,
     #[cfg(feature = "tui")] debugger: Option<Debugger>,
 ) -> Result<usize, Box<dyn std::error::Error + Send + Sync>> {
+    let GraphicsOptions {
+        aspect,
+        crt,
+        font,
+        false_color,
+        smooth_font,
+        speed,
+        start_paused,
+    } = options;
+    let render = font
+        .map(WgpuRender::with_font)
+        .unwrap_or_default()
+        .false_color(false_color)
+        .smooth_font(smooth_font);
     #[cfg(feature = "tui")]
-    if let Some(debugger) = debugger {
-        return run_debugger(system, cpu, debugger);
+    if let Some(mut debugger) = debugger {
+        if start_paused {
+            debugger.pause();
+        }
+        return run_debugger(system, cpu, aspect, crt, render, speed, debugger);
     }
 
     let sender = system.keyboard.sender();
     let system = Rc::new(RefCell::new(system));
-    let render = crate::host::screen::wgpu::WgpuRender::default();
 
     let system_clone = system.clone();
+    let mut governor = Governor::new(speed);
     let stepper = move || {
         let mut system = system_clone.borrow_mut();
-        for _ in 0..20000 {
+        governor.set_refresh_hz(system.refresh_hz());
+        governor.run(|| {
             system.step(&mut cpu);
-        }
+            true
+        });
+        governor.refresh_hz as u32
     };
 
     let system_clone = system.clone();
     crate::host::wgpu::main(
         sender,
-        move |frame| render.render(&system_clone.borrow(), frame),
+        aspect,
+        render_frame(render, crt, system_clone),
         stepper,
+        locator_mouse(system.clone()),
+        handle_request(system.clone()),
+        start_paused,
     )
-    .map_err(|e| format!("Graphics error: {}", e))?;
+    .map_err(BlazeError::Graphics)?;
 
     return Ok(system.borrow().instruction_count);
 }
 
+fn render_frame(
+    mut render: WgpuRender,
+    mut crt: Option<CrtEffect>,
+    system: Rc<RefCell<System>>,
+) -> impl FnMut(&mut [u8]) + 'static {
+    move |frame| {
+        if render.render(&system.borrow(), frame) {
+            if let Some(crt) = &mut crt {
+                crt.apply(frame);
+                // The effects are applied in place, so rows can't be reused
+                render.invalidate();
+            }
+        }
+    }
+}
+
 #[cfg(feature = "tui")]
 fn run_debugger(
     system: System,
     mut cpu: Cpu,
+    aspect: Aspect,
+    crt: Option<CrtEffect>,
+    render: WgpuRender,
+    speed: f64,
     mut debugger: Debugger,
 ) -> Result<usize, Box<dyn std::error::Error + Send + Sync>> {
     debugger.enter()?;
 
     let sender = system.keyboard.sender();
     let system = Rc::new(RefCell::new(system));
-    let render = crate::host::screen::wgpu::WgpuRender::default();
 
     let system_clone = system.clone();
+    let mut governor = Governor::new(speed);
     let stepper = move || {
         let system = &mut *system_clone.borrow_mut();
         debugger.render(&cpu, system).unwrap();
         if crossterm::event::poll(Duration::from_millis(0)).unwrap() {
             let Ok(event) = crossterm::event::read() else {
-                return;
+                return governor.refresh_hz as u32;
             };
             if debugger.handle_event(event, &mut cpu, system) {
                 system.step(&mut cpu);
             }
             debugger.render(&cpu, system).unwrap();
         }
-        for _ in 0..20000 {
+        governor.set_refresh_hz(system.refresh_hz());
+        governor.run(|| {
             match debugger.debugger_state() {
                 DebuggerState::Running => {
                     system.step(&mut cpu);
                 }
                 DebuggerState::Paused => {
-                    return;
+                    return false;
                 }
                 DebuggerState::Quit => {
-                    return;
+                    return false;
                 }
             }
-            if debugger.breakpoints().contains(&cpu.pc_ext(system)) {
+            if system.watch_hit || debugger.breakpoints().contains(&cpu.pc_ext(system)) {
                 debugger.pause();
             }
-        }
+            true
+        });
+        governor.refresh_hz as u32
     };
 
     let system_clone = system.clone();
     crate::host::wgpu::main(
         sender,
-        move |frame| render.render(&system_clone.borrow(), frame),
+        aspect,
+        render_frame(render, crt, system_clone),
         stepper,
-    )?;
+        locator_mouse(system.clone()),
+        handle_request(system.clone()),
+        false,
+    )
+    .map_err(BlazeError::Graphics)?;
 
     return Ok(system.borrow().instruction_count);
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::machine::vt420::harness::Harness;
+    use crate::machine::vt420::video::FONT_GLYPH_BYTES;
+
+    #[test]
+    fn test_crt_scanlines() {
+        let mut frame = vec![0xff_u8; ROW_BYTES * 4];
+        let mut crt = CrtEffect {
+            scanlines: true,
+            ..Default::default()
+        };
+        crt.apply(&mut frame);
+
+        for (row, row_pixels) in frame.chunks_exact(ROW_BYTES).enumerate() {
+            let expected = if row % 2 == 1 {
+                [0x99, 0x99, 0x99, 0xff]
+            } else {
+                [0xff, 0xff, 0xff, 0xff]
+            };
+            for pixel in row_pixels.chunks_exact(4) {
+                assert_eq!(pixel, expected, "row {row}");
+            }
+        }
+    }
+
+    #[test]
+    fn test_governor_carries_remainder() {
+        let mut governor = Governor {
+            steps_per_second: 5.0,
+            refresh_hz: 60,
+            last: None,
+            remainder: 0.0,
+        };
+        // 5 steps/s over 0.5s is 2.5 steps per update
+        let steps: Vec<_> = (0..4).map(|_| governor.take(0.5)).collect();
+        assert_eq!(steps, vec![2, 3, 2, 3]);
+    }
+
+    #[test]
+    fn test_render_skips_unchanged_rows() {
+        let Harness {
+            mut system,
+            mut cpu,
+        } = Harness::boot();
+        // Stop outside of vsync
+        while system.memory.mapper.get(6) & 0xf0 == 0xf0 {
+            system.step(&mut cpu);
+        }
+
+        let mut render = WgpuRender::default();
+        let mut frame = vec![0_u8; ROW_BYTES * 417];
+        let rows = render.render_rows(&system, &mut frame).unwrap();
+        assert!(rows > 1);
+        assert_eq!(render.render_rows(&system, &mut frame), Some(0));
+
+        // Change the first character of the first row
+        let base = system.memory.mapper.vram_offset_display() as usize;
+        let row_addr = (system.memory.vram[base] >> 1) as usize * 0x100;
+        system.memory.vram[base + row_addr] ^= 0x01;
+        assert_eq!(render.render_rows(&system, &mut frame), Some(1));
+
+        render.invalidate();
+        assert_eq!(render.render_rows(&system, &mut frame), Some(rows));
+    }
+
+    #[test]
+    fn test_render_offscreen() {
+        let mut harness = Harness::boot();
+        while in_vsync(&harness.system) {
+            harness.run(1);
+        }
+
+        let mut frame = vec![0_u8; SCREEN_BYTES];
+        assert!(render_offscreen(&harness.system, &mut frame));
+        let lit = frame
+            .chunks_exact(4)
+            .filter(|pixel| pixel[..3] != UNLIT[..3])
+            .count();
+        assert!(lit > 100, "{lit} pixels lit");
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("boot.png");
+        assert!(write_png(&harness.system, &path).unwrap());
+        assert!(std::fs::read(&path).unwrap().starts_with(b"\x89PNG"));
+    }
+
+    #[test]
+    fn test_font_override() {
+        let mut system = System::new_deterministic(vec![0; 0x20000], None).unwrap();
+        system.memory.mapper.set(3, 0);
+        system.memory.mapper.set(4, 0);
+        // A single row of character 0x00
+        system.memory.vram[0] = 0x22;
+
+        // The firmware hasn't uploaded a font
+        let mut frame = vec![0_u8; ROW_BYTES * 417];
+        assert!(WgpuRender::default().render(&system, &mut frame));
+        assert_eq!(frame[..4], UNLIT);
+
+        // A solid block for glyph 0
+        let mut glyph = [0xff_u8; FONT_GLYPH_BYTES];
+        glyph[16..].fill(0x03);
+        let mut render = WgpuRender::with_font(glyph.to_vec());
+        assert!(render.render(&system, &mut frame));
+        for x in [0, 9, 10] {
+            assert_eq!(frame[x * 4..][..4], [0x80, 0x80, 0x80, 0xff], "x={x}");
+        }
+        assert_eq!(frame[ROW_BYTES * 15..][..4], [0x80, 0x80, 0x80, 0xff]);
+    }
+
+    /// Whatever the row height, the status row is drawn over the bottom 16
+    /// lines of the display.
+    #[test]
+    fn test_status_row_position() {
+        // Glyph 0x41 is a solid block, and everything else is blank
+        let mut font = vec![0_u8; FONT_GLYPH_BYTES * 0x42];
+        font[FONT_GLYPH_BYTES * 0x41..].fill(0xff);
+
+        for (font_reg, status_idx) in [(0x00, 25), (0x0c, 34)] {
+            let mut system = System::new_deterministic(vec![0; 0x20000], None).unwrap();
+            system.memory.mapper.set(3, 0);
+            system.memory.mapper.set(4, 0);
+            system.memory.mapper.set(6, font_reg);
+            system.memory.mapper.set(6, font_reg);
+            for i in 0..60 {
+                system.memory.vram[i * 2] = if i == status_idx { 0x24 } else { 0x22 };
+            }
+            // 'A' at the start of the row that lands on the status row
+            system.memory.vram[0x1200] = 0x41;
+
+            let mut frame = vec![0_u8; ROW_BYTES * 417];
+            WgpuRender::with_font(font.clone()).render(&system, &mut frame);
+            let lit = |y: usize| frame[ROW_BYTES * y] != 0;
+            assert!(!lit(STATUS_ROW_TOP - 1), "{font_reg:02X}");
+            assert!(lit(STATUS_ROW_TOP), "{font_reg:02X}");
+            assert!(lit(VERTICAL_LINES - 1), "{font_reg:02X}");
+        }
+    }
+
+    /// 132 columns of 6 pixels leave an 8 pixel margin on the right, which
+    /// must be blanked rather than left with whatever was drawn before.
+    #[test]
+    fn test_132_column_right_margin() {
+        // Glyph 0x41 is a solid block in 132 columns, which only uses the
+        // second half of the glyph
+        let mut font = vec![0_u8; FONT_GLYPH_BYTES * 0x42];
+        font[FONT_GLYPH_BYTES * 0x41 + FONT_132_OFFSET as usize..].fill(0xfc);
+
+        let mut system = System::new_deterministic(vec![0; 0x20000], None).unwrap();
+        system.memory.mapper.set(3, 0x01);
+        system.memory.mapper.set(4, 0);
+        system.memory.mapper.set(6, 0);
+        system.memory.mapper.set(6, 0);
+        system.memory.vram[0] = 0x22;
+        system.memory.vram[0x1100] = 0x41;
+
+        let mut frame = vec![0xff_u8; ROW_BYTES * 417];
+        WgpuRender::with_font(font).render(&system, &mut frame);
+        for y in 0..16 {
+            let line = &frame[ROW_BYTES * y..][..ROW_BYTES];
+            assert_ne!(line[0], 0, "line {y}");
+            assert!(line[132 * 6 * 4..].iter().all(|&b| b == 0), "line {y}");
+        }
+    }
+
+    #[test]
+    fn test_cell_at() {
+        let mut system = System::new_deterministic(vec![0; 0x20000], None).unwrap();
+        system.memory.mapper.set(3, 0);
+        system.memory.mapper.set(4, 0);
+        system.memory.mapper.set(6, 0);
+        system.memory.mapper.set(6, 0);
+        for i in 0..30 {
+            system.memory.vram[i * 2] = 0x22;
+        }
+
+        assert_eq!(cell_at(&system, 0, 0), Some((1, 1)));
+        assert_eq!(cell_at(&system, 9, 15), Some((1, 1)));
+        assert_eq!(cell_at(&system, 10, 16), Some((2, 2)));
+        assert_eq!(cell_at(&system, 799, 100), Some((7, 80)));
+        assert_eq!(cell_at(&system, 0, STATUS_ROW_TOP), None);
+
+        // 132 columns of 6 pixels, with a margin on the right
+        system.memory.mapper.set(3, 0x01);
+        assert_eq!(cell_at(&system, 6, 0), Some((1, 2)));
+        assert_eq!(cell_at(&system, 791, 0), Some((1, 132)));
+        assert_eq!(cell_at(&system, 792, 0), None);
+    }
+
+    #[test]
+    fn test_false_color() {
+        // Glyph 0 has a solid top line and is otherwise blank
+        let mut glyph = [0_u8; FONT_GLYPH_BYTES];
+        glyph[0] = 0xff;
+        glyph[16] = 0x03;
+
+        let mut system = System::new_deterministic(vec![0; 0x20000], None).unwrap();
+        system.memory.mapper.set(3, 0);
+        system.memory.mapper.set(4, 0);
+        system.memory.vram[0] = 0x22;
+        // Column 0 is reversed and column 1 is bold
+        system.memory.vram[0x1101] = 0x04;
+        system.memory.vram[0x1102] = 0x20;
+
+        let mut frame = vec![0_u8; ROW_BYTES * 417];
+        let pixel = |frame: &[u8], x: usize, y: usize| -> [u8; 4] {
+            frame[ROW_BYTES * y + x * 4..][..4].try_into().unwrap()
+        };
+
+        WgpuRender::with_font(glyph.to_vec())
+            .false_color(true)
+            .render(&system, &mut frame);
+        let reversed = pixel(&frame, 0, 1);
+        let bold = pixel(&frame, 10, 0);
+        let normal = pixel(&frame, 20, 0);
+        assert_eq!(pixel(&frame, 0, 0), UNLIT);
+        assert_ne!(reversed, UNLIT);
+        assert_ne!(reversed, bold);
+        assert_ne!(reversed, normal);
+        assert_ne!(bold, normal);
+
+        // Monochrome by default
+        WgpuRender::with_font(glyph.to_vec()).render(&system, &mut frame);
+        assert_eq!(pixel(&frame, 0, 1), [0x80, 0x80, 0x80, 0xff]);
+        assert_eq!(pixel(&frame, 10, 0), [0xff, 0xff, 0xff, 0xff]);
+    }
+
+    #[test]
+    fn test_smooth_font() {
+        // Glyph 0 is a diagonal line from the top left
+        let mut glyph = [0_u8; FONT_GLYPH_BYTES];
+        for (y, line) in glyph[..8].iter_mut().enumerate() {
+            *line = 1 << y;
+        }
+
+        let mut system = System::new_deterministic(vec![0; 0x20000], None).unwrap();
+        system.memory.mapper.set(3, 0);
+        system.memory.mapper.set(4, 0);
+        system.memory.vram[0] = 0x22;
+
+        let mut frame = vec![0_u8; ROW_BYTES * 417];
+        let pixel = |frame: &[u8], x: usize, y: usize| -> [u8; 4] {
+            frame[ROW_BYTES * y + x * 4..][..4].try_into().unwrap()
+        };
+
+        WgpuRender::with_font(glyph.to_vec()).render(&system, &mut frame);
+        assert_eq!(pixel(&frame, 1, 0), UNLIT);
+        assert_eq!(pixel(&frame, 1, 1), [0x80, 0x80, 0x80, 0xff]);
+
+        let mut render = WgpuRender::with_font(glyph.to_vec()).smooth_font(true);
+        assert!(render.render(&system, &mut frame));
+        // The pixels either side of the diagonal are partly lit
+        let edge = pixel(&frame, 1, 0);
+        assert!(edge[0] > 0 && edge[0] < 0x80, "{edge:?}");
+        assert_eq!(edge[3], 0xff);
+        assert_eq!(pixel(&frame, 0, 1), edge);
+        // Away from the glyph, the display is unchanged
+        assert_eq!(pixel(&frame, 5, 0), UNLIT);
+        assert_eq!(pixel(&frame, 4, 4), [0x80, 0x80, 0x80, 0xff]);
+
+        // The crisp display is kept, so unchanged rows aren't smoothed twice
+        render.render(&system, &mut frame);
+        assert_eq!(pixel(&frame, 1, 0), edge);
+    }
+
+    #[test]
+    fn test_reverse_underline() {
+        let mut system = System::new_deterministic(vec![0; 0x20000], None).unwrap();
+        system.memory.mapper.set(3, 0);
+        system.memory.mapper.set(4, 0);
+        system.memory.vram[0] = 0x22;
+        // Column 0 is reversed and underlined, column 1 is underlined and
+        // column 2 is reversed
+        system.memory.vram[0x1101] = 0x04;
+        system.memory.vram[0x1104] = 0x04;
+        system.memory.vram[0x11dd] = 0x14;
+        let bottom = system.memory.mapper.row_height_screen_1() as usize - 1;
+
+        let mut frame = vec![0_u8; ROW_BYTES * 417];
+        let lit = |frame: &[u8], x: usize, y: usize| frame[ROW_BYTES * y + x * 4..][..4] != UNLIT;
+        WgpuRender::with_font(vec![0; FONT_GLYPH_BYTES]).render(&system, &mut frame);
+
+        // The underline is inverted along with the rest of the cell
+        assert!(lit(&frame, 0, 0));
+        assert!(!lit(&frame, 0, bottom));
+        assert!(!lit(&frame, 10, 0));
+        assert!(lit(&frame, 10, bottom));
+        assert!(lit(&frame, 20, 0));
+        assert!(lit(&frame, 20, bottom));
+    }
+
+    #[test]
+    fn test_distinct_false_colors() {
+        let colors = (0..0x40_u16)
+            .map(CellAttr::from_bits)
+            .filter(|attr| !attr.alternate_glyph())
+            .map(|attr| cell_color(attr, true))
+            .collect::<std::collections::HashSet<_>>();
+        assert_eq!(colors.len(), 32);
+    }
+
+    #[test]
+    fn test_draw_leds() {
+        let mut strip = vec![0xff_u8; ROW_BYTES * LED_STRIP_HEIGHT as usize];
+        draw_leds(&mut strip, Led::new(0x84));
+        let pixel = |x: usize, y: usize| &strip[y * ROW_BYTES + x * 4..][..4];
+        let left = 800 - LED_SPACING * 4;
+        let y = (LED_STRIP_HEIGHT as usize - LED_HEIGHT) / 2;
+        assert_eq!(pixel(0, 0), [0, 0, 0, 0]);
+        // Hold, Lock, Compose, Wait
+        assert_eq!(pixel(left, y), LED_UNLIT);
+        assert_eq!(pixel(left + LED_SPACING, y), LED_LIT);
+        assert_eq!(pixel(left + LED_SPACING * 2, y + LED_HEIGHT - 1), LED_UNLIT);
+        assert_eq!(pixel(left + LED_SPACING, y + LED_HEIGHT), [0, 0, 0, 0]);
+    }
+
+    #[test]
+    fn test_crt_persistence() {
+        let mut crt = CrtEffect {
+            persistence: true,
+            ..Default::default()
+        };
+        let mut frame = vec![0xff_u8; ROW_BYTES];
+        crt.apply(&mut frame);
+        let mut frame = vec![0_u8; ROW_BYTES];
+        crt.apply(&mut frame);
+        assert_eq!(frame[0], 0x99);
+        let mut frame = vec![0_u8; ROW_BYTES];
+        crt.apply(&mut frame);
+        assert_eq!(frame[0], 0x5b);
+    }
+}
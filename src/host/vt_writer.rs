@@ -0,0 +1,207 @@
+//! Typed builders for the control sequences we send to the terminal, so that
+//! host-side code doesn't need to spell out escape sequences by hand.
+//!
+//! Numeric parameters are written in decimal and are 1-based where the DEC
+//! documentation says so; no range checking is done.
+
+const ESC: u8 = 0x1b;
+
+#[derive(Default, Clone, Debug, PartialEq, Eq)]
+pub struct VtWriter {
+    bytes: Vec<u8>,
+}
+
+impl VtWriter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.bytes
+    }
+
+    /// Plain text.
+    #[cfg(test)]
+    pub fn text(&mut self, text: &str) -> &mut Self {
+        self.bytes.extend(text.as_bytes());
+        self
+    }
+
+    /// A control sequence: `CSI params intermediates final`.
+    pub fn csi(&mut self, params: &[u16], intermediates: &[u8], final_byte: u8) -> &mut Self {
+        self.csi_private(None, params, intermediates, final_byte)
+    }
+
+    /// A control sequence with a private parameter prefix such as `?`.
+    fn csi_private(
+        &mut self,
+        prefix: Option<u8>,
+        params: &[u16],
+        intermediates: &[u8],
+        final_byte: u8,
+    ) -> &mut Self {
+        self.bytes.extend([ESC, b'[']);
+        self.bytes.extend(prefix);
+        for (i, param) in params.iter().enumerate() {
+            if i > 0 {
+                self.bytes.push(b';');
+            }
+            self.bytes.extend(param.to_string().into_bytes());
+        }
+        self.bytes.extend(intermediates);
+        self.bytes.push(final_byte);
+        self
+    }
+
+    /// DECSCPP: select 80 or 132 columns per page (`CSI Pn $ |`).
+    pub fn decscpp(&mut self, columns: u16) -> &mut Self {
+        self.csi(&[columns], b"$", b'|')
+    }
+
+    /// DECSNLS: set the number of lines on the screen (`CSI Pn * |`).
+    pub fn set_rows(&mut self, rows: u16) -> &mut Self {
+        self.csi(&[rows], b"*", b'|')
+    }
+
+    /// DECSLPP: set the number of lines per page (`CSI Pn t`).
+    pub fn set_page_size(&mut self, lines: u16) -> &mut Self {
+        self.csi(&[lines], b"", b't')
+    }
+
+    /// CUP: move the cursor to a 1-based row and column (`CSI Pl ; Pc H`).
+    pub fn cursor_position(&mut self, row: u16, column: u16) -> &mut Self {
+        self.csi(&[row, column], b"", b'H')
+    }
+
+    /// CPR: report a 1-based cursor position (`CSI Pl ; Pc R`).
+    pub fn cursor_position_report(&mut self, row: u16, column: u16) -> &mut Self {
+        self.csi(&[row, column], b"", b'R')
+    }
+
+    /// SGR: select graphic rendition (`CSI Ps ; ... m`).
+    pub fn sgr(&mut self, attributes: &[u8]) -> &mut Self {
+        let params = attributes.iter().map(|&a| a as u16).collect::<Vec<_>>();
+        self.csi(&params, b"", b'm')
+    }
+
+    /// ED: erase in display (`CSI Ps J`), 0 after the cursor, 1 before it and
+    /// 2 for the whole display.
+    pub fn erase_display(&mut self, mode: u8) -> &mut Self {
+        self.csi(&[mode as u16], b"", b'J')
+    }
+
+    /// EL: erase in line (`CSI Ps K`), 0 after the cursor, 1 before it and 2
+    /// for the whole line.
+    pub fn erase_line(&mut self, mode: u8) -> &mut Self {
+        self.csi(&[mode as u16], b"", b'K')
+    }
+
+    /// SM: set an ANSI mode (`CSI Pn h`).
+    pub fn set_mode(&mut self, mode: u16) -> &mut Self {
+        self.csi(&[mode], b"", b'h')
+    }
+
+    /// RM: reset an ANSI mode (`CSI Pn l`).
+    pub fn reset_mode(&mut self, mode: u16) -> &mut Self {
+        self.csi(&[mode], b"", b'l')
+    }
+
+    /// DECSET: set a DEC private mode (`CSI ? Pn h`).
+    pub fn set_private_mode(&mut self, mode: u16) -> &mut Self {
+        self.csi_private(Some(b'?'), &[mode], b"", b'h')
+    }
+
+    /// DECRST: reset a DEC private mode (`CSI ? Pn l`).
+    pub fn reset_private_mode(&mut self, mode: u16) -> &mut Self {
+        self.csi_private(Some(b'?'), &[mode], b"", b'l')
+    }
+
+    /// DECRQM: request the state of a DEC private mode (`CSI ? Pn $ p`).
+    pub fn request_private_mode(&mut self, mode: u16) -> &mut Self {
+        self.csi_private(Some(b'?'), &[mode], b"$", b'p')
+    }
+
+    /// DSR: device status report (`CSI Ps n`), eg: 5 for status and 6 for
+    /// the cursor position. The same sequence with 0 reports no malfunction.
+    pub fn device_status(&mut self, request: u16) -> &mut Self {
+        self.csi(&[request], b"", b'n')
+    }
+
+    /// DA1: request the primary device attributes (`CSI c`).
+    pub fn device_attributes(&mut self) -> &mut Self {
+        self.csi(&[], b"", b'c')
+    }
+
+    /// DECRQDE: request the displayed extent (`CSI " v`).
+    pub fn request_displayed_extent(&mut self) -> &mut Self {
+        self.csi(&[], b"\"", b'v')
+    }
+
+    /// DECDWL: make the cursor line double width (`ESC # 6`).
+    pub fn double_width_line(&mut self) -> &mut Self {
+        self.bytes.extend([ESC, b'#', b'6']);
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn bytes(f: impl FnOnce(&mut VtWriter) -> &mut VtWriter) -> Vec<u8> {
+        let mut writer = VtWriter::new();
+        f(&mut writer);
+        writer.into_bytes()
+    }
+
+    #[test]
+    fn test_display_sequences() {
+        assert_eq!(bytes(|w| w.decscpp(132)), b"\x1b[132$|");
+        assert_eq!(bytes(|w| w.set_rows(48)), b"\x1b[48*|");
+        assert_eq!(bytes(|w| w.set_page_size(72)), b"\x1b[72t");
+        assert_eq!(bytes(|w| w.double_width_line()), b"\x1b#6");
+        assert_eq!(bytes(|w| w.request_displayed_extent()), b"\x1b[\"v");
+    }
+
+    #[test]
+    fn test_cursor_sequences() {
+        assert_eq!(bytes(|w| w.cursor_position(3, 5)), b"\x1b[3;5H");
+        assert_eq!(bytes(|w| w.cursor_position_report(24, 80)), b"\x1b[24;80R");
+        assert_eq!(bytes(|w| w.erase_display(2)), b"\x1b[2J");
+        assert_eq!(bytes(|w| w.erase_line(0)), b"\x1b[0K");
+    }
+
+    #[test]
+    fn test_sgr() {
+        assert_eq!(bytes(|w| w.sgr(&[0, 1, 7])), b"\x1b[0;1;7m");
+        assert_eq!(bytes(|w| w.sgr(&[])), b"\x1b[m");
+    }
+
+    #[test]
+    fn test_mode_sequences() {
+        assert_eq!(bytes(|w| w.set_mode(20)), b"\x1b[20h");
+        assert_eq!(bytes(|w| w.reset_mode(20)), b"\x1b[20l");
+        assert_eq!(bytes(|w| w.set_private_mode(7)), b"\x1b[?7h");
+        assert_eq!(bytes(|w| w.reset_private_mode(25)), b"\x1b[?25l");
+        assert_eq!(bytes(|w| w.request_private_mode(7)), b"\x1b[?7$p");
+    }
+
+    #[test]
+    fn test_reports() {
+        assert_eq!(bytes(|w| w.device_status(6)), b"\x1b[6n");
+        assert_eq!(bytes(|w| w.device_status(0)), b"\x1b[0n");
+        assert_eq!(bytes(|w| w.device_attributes()), b"\x1b[c");
+    }
+
+    #[test]
+    fn test_chaining() {
+        assert_eq!(
+            bytes(|w| w.cursor_position(1, 1).double_width_line().text("Blaze")),
+            b"\x1b[1;1H\x1b#6Blaze"
+        );
+    }
+}
@@ -0,0 +1,104 @@
+//! Connects a DUART channel to a WebSocket in the browser, so that a page can
+//! proxy the terminal to a real host.
+//!
+//! There are no threads in the browser, so bytes are moved between the socket
+//! and the DUART from a timer on the main thread.
+
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::rc::Rc;
+
+use js_sys::{ArrayBuffer, Uint8Array};
+use tracing::{error, info};
+use wasm_bindgen::closure::Closure;
+use wasm_bindgen::{JsCast, JsValue};
+use web_sys::{BinaryType, CloseEvent, MessageEvent, WebSocket};
+
+use crate::host::comm::CommConnection;
+use crate::machine::generic::duart::DUARTChannel;
+
+/// How often bytes are moved between the socket and the DUART.
+const PUMP_INTERVAL_MS: i32 = 5;
+
+fn js_error(e: JsValue) -> std::io::Error {
+    std::io::Error::other(format!("{e:?}"))
+}
+
+pub fn connect_websocket(
+    channel: DUARTChannel,
+    url: &str,
+) -> Result<CommConnection, std::io::Error> {
+    info!("Connecting DUART to WebSocket {url}");
+    let socket = WebSocket::new(url).map_err(js_error)?;
+    socket.set_binary_type(BinaryType::Arraybuffer);
+
+    let received = Rc::new(RefCell::new(VecDeque::new()));
+    let onmessage = Closure::<dyn FnMut(MessageEvent)>::new({
+        let received = received.clone();
+        move |event: MessageEvent| {
+            let data = event.data();
+            if let Some(buffer) = data.dyn_ref::<ArrayBuffer>() {
+                received
+                    .borrow_mut()
+                    .extend(Uint8Array::new(buffer).to_vec());
+            } else if let Some(text) = data.as_string() {
+                received.borrow_mut().extend(text.into_bytes());
+            }
+        }
+    });
+    socket.set_onmessage(Some(onmessage.as_ref().unchecked_ref()));
+    onmessage.forget();
+
+    let onclose = Closure::<dyn FnMut(CloseEvent)>::new(|event: CloseEvent| {
+        info!("WebSocket closed ({})", event.code());
+    });
+    socket.set_onclose(Some(onclose.as_ref().unchecked_ref()));
+    onclose.forget();
+
+    let dtr = channel.dtr.clone();
+    let mut software_flow_control = true;
+    let mut outgoing = vec![];
+    let pump = Closure::<dyn FnMut()>::new(move || {
+        while let Ok(b) = channel.rx.try_recv() {
+            match b {
+                // XON
+                0x11 => software_flow_control = true,
+                // XOFF
+                0x13 => software_flow_control = false,
+                b => outgoing.push(b),
+            }
+        }
+        match socket.ready_state() {
+            WebSocket::CONNECTING => {}
+            WebSocket::OPEN => {
+                if !outgoing.is_empty() {
+                    if let Err(e) = socket.send_with_u8_array(&outgoing) {
+                        error!("WebSocket send failed: {e:?}");
+                    }
+                    outgoing.clear();
+                }
+            }
+            _ => outgoing.clear(),
+        }
+
+        if software_flow_control {
+            let mut received = received.borrow_mut();
+            while let Some(&b) = received.front() {
+                if channel.tx.try_send(b).is_err() {
+                    break;
+                }
+                received.pop_front();
+            }
+        }
+    });
+    web_sys::window()
+        .ok_or_else(|| std::io::Error::other("No window"))?
+        .set_interval_with_callback_and_timeout_and_arguments_0(
+            pump.as_ref().unchecked_ref(),
+            PUMP_INTERVAL_MS,
+        )
+        .map_err(js_error)?;
+    pump.forget();
+
+    Ok(CommConnection::new(dtr, None))
+}
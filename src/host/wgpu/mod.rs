@@ -2,9 +2,13 @@
 #![forbid(unsafe_code)]
 
 const WIDTH: u32 = 800;
-const HEIGHT: u32 = 417;
-const FPS: u32 = 60;
-const TIME_STEP: Duration = Duration::from_micros(1_000_000 / FPS as u64);
+/// Height of the VT420 display in the frame buffer.
+pub(crate) const SCREEN_HEIGHT: u32 = 417;
+/// Height of the strip below the display that shows the keyboard LEDs.
+pub(crate) const LED_STRIP_HEIGHT: u32 = 12;
+const HEIGHT: u32 = SCREEN_HEIGHT + LED_STRIP_HEIGHT;
+/// The initial frame rate, until the emulated display selects its own.
+pub(crate) const FPS: u32 = 60;
 
 use game_loop::winit;
 
@@ -12,14 +16,104 @@ use game_loop::{Time, TimeTrait as _, game_loop};
 use pixels::{Error, Pixels, PixelsBuilder, SurfaceTexture};
 use std::sync::Arc;
 use std::time::Duration;
-use winit::{dpi::LogicalSize, event_loop::EventLoop, window::WindowBuilder};
+use winit::{
+    dpi::{LogicalSize, PhysicalSize},
+    event_loop::EventLoop,
+    keyboard::KeyCode,
+    window::WindowBuilder,
+};
 use winit_input_helper::WinitInputHelper;
 
-use crate::host::lk201::winit::update_keyboard;
+use crate::host::comm::LocatorButton;
+use crate::host::lk201::winit::{KeyboardCommand, WinitKeyboard};
 use crate::machine::generic::lk201::LK201Sender;
 
 use tracing::{error, info};
 
+/// How the 800x429 frame buffer is scaled to fit the window.
+#[derive(Default, Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum Aspect {
+    /// Stretch the frame buffer to fill the whole window.
+    #[default]
+    Fill,
+    /// Scale by the largest integer factor that fits, letterboxing the rest.
+    Integer,
+    /// Stretch the display to the 4:3 aspect ratio of the original monitor,
+    /// with the LED strip below it.
+    Correct,
+}
+
+impl Aspect {
+    fn scaling_mode(self) -> pixels::ScalingMode {
+        match self {
+            Aspect::Fill | Aspect::Correct => pixels::ScalingMode::Fill,
+            Aspect::Integer => pixels::ScalingMode::PixelPerfect,
+        }
+    }
+
+    /// The initial window size for this mode.
+    fn initial_size(self) -> LogicalSize<f64> {
+        match self {
+            Aspect::Fill | Aspect::Integer => {
+                LogicalSize::new(WIDTH as f64 * 2.0, HEIGHT as f64 * 2.0)
+            }
+            Aspect::Correct => {
+                LogicalSize::new(WIDTH as f64 * 2.0, Self::correct_height(WIDTH * 2) as f64)
+            }
+        }
+    }
+
+    /// The window height that gives the display, not counting the LED strip,
+    /// a 4:3 aspect ratio at `width`.
+    fn correct_height(width: u32) -> u32 {
+        width * 3 * HEIGHT / (4 * SCREEN_HEIGHT)
+    }
+
+    /// The window size this mode wants given the size the user resized to, if
+    /// it differs.
+    fn corrected_size(self, size: PhysicalSize<u32>) -> Option<PhysicalSize<u32>> {
+        match self {
+            Aspect::Fill | Aspect::Integer => None,
+            Aspect::Correct => {
+                let height = Self::correct_height(size.width);
+                if height.abs_diff(size.height) > 1 {
+                    Some(PhysicalSize::new(size.width, height))
+                } else {
+                    None
+                }
+            }
+        }
+    }
+}
+
+/// Mouse input, in frame buffer pixels.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mouse {
+    /// The mouse moved, and is over this pixel if it's over the frame buffer
+    Moved(Option<(usize, usize)>),
+    Button(LocatorButton, bool),
+}
+
+/// Requests from the window to the emulator.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Request {
+    /// Power cycle the terminal
+    Reset,
+    /// The window is closing
+    Close,
+}
+
+/// The input helper's numbers for the mouse buttons, and their locator
+/// buttons.
+const MOUSE_BUTTONS: [(usize, LocatorButton); 3] = [
+    (0, LocatorButton::Left),
+    (2, LocatorButton::Middle),
+    (1, LocatorButton::Right),
+];
+
+/// The host key that pauses and resumes the emulated CPU.
+const PAUSE_KEY: KeyCode = KeyCode::Pause;
+
 /// Uber-struct representing the entire game.
 struct Terminal {
     /// Software renderer.
@@ -30,20 +124,68 @@ struct Terminal {
     paused: bool,
     /// LK201 keyboard sender.
     sender: LK201Sender,
+    /// Host keyboard state.
+    keyboard: WinitKeyboard,
+    /// The frame buffer pixel last reported under the mouse.
+    mouse_pixel: Option<(usize, usize)>,
 }
 
 impl Terminal {
-    fn new(pixels: Pixels<'static>, sender: LK201Sender) -> Self {
+    fn new(pixels: Pixels<'static>, sender: LK201Sender, paused: bool) -> Self {
         Self {
             pixels,
             input: WinitInputHelper::new(),
-            paused: false,
+            paused,
             sender,
+            keyboard: WinitKeyboard::default(),
+            mouse_pixel: None,
         }
     }
 
-    fn update_controls(&mut self) {
-        update_keyboard(&self.input, &self.sender);
+    /// Type the text on the host clipboard.
+    #[cfg(not(target_arch = "wasm32"))]
+    fn paste_clipboard(&self) {
+        match arboard::Clipboard::new().and_then(|mut clipboard| clipboard.get_text()) {
+            Ok(text) => self.sender.paste(&text),
+            Err(e) => tracing::warn!("Failed to read the clipboard: {e}"),
+        }
+    }
+
+    /// The browser sends pasted text through the page's paste event instead.
+    #[cfg(target_arch = "wasm32")]
+    fn paste_clipboard(&self) {}
+
+    fn update_controls(
+        &mut self,
+        mouse: &mut impl FnMut(Mouse),
+        request: &mut impl FnMut(Request),
+    ) {
+        if self.input.key_pressed(PAUSE_KEY) {
+            self.paused = !self.paused;
+            info!("CPU {}", if self.paused { "paused" } else { "running" });
+            return;
+        }
+        match self.keyboard.update(&self.input, &self.sender) {
+            Some(KeyboardCommand::Reset) => request(Request::Reset),
+            Some(KeyboardCommand::Paste) => self.paste_clipboard(),
+            None => {}
+        }
+
+        if let Some(cursor) = self.input.cursor() {
+            let pixel = self.pixels.window_pos_to_pixel(cursor).ok();
+            if pixel != self.mouse_pixel {
+                self.mouse_pixel = pixel;
+                mouse(Mouse::Moved(pixel));
+            }
+        }
+        for (button, locator_button) in MOUSE_BUTTONS {
+            if self.input.mouse_pressed(button) {
+                mouse(Mouse::Button(locator_button, true));
+            }
+            if self.input.mouse_released(button) {
+                mouse(Mouse::Button(locator_button, false));
+            }
+        }
     }
 }
 
@@ -62,10 +204,14 @@ fn get_window_size() -> LogicalSize<f64> {
 
 pub fn main(
     sender: LK201Sender,
+    aspect: Aspect,
     render: impl FnMut(&mut [u8]) + 'static,
-    step: impl FnMut() + 'static,
+    step: impl FnMut() -> u32 + 'static,
+    mouse: impl FnMut(Mouse) + 'static,
+    request: impl FnMut(Request) + 'static,
+    paused: bool,
 ) -> Result<(), Error> {
-    let future = main_async(sender, render, step);
+    let future = main_async(sender, aspect, render, step, mouse, request, paused);
     #[cfg(target_arch = "wasm32")]
     {
         wasm_bindgen_futures::spawn_local(async {
@@ -87,8 +233,12 @@ pub fn get_canvas(#[allow(unused)] window: &winit::window::Window) -> web_sys::H
 
 pub async fn main_async(
     sender: LK201Sender,
+    aspect: Aspect,
     mut render: impl FnMut(&mut [u8]) + 'static,
-    mut step: impl FnMut() + 'static,
+    mut step: impl FnMut() -> u32 + 'static,
+    mut mouse: impl FnMut(Mouse) + 'static,
+    mut request: impl FnMut(Request) + 'static,
+    paused: bool,
 ) -> Result<(), Error> {
     let event_loop = EventLoop::new().unwrap();
 
@@ -117,7 +267,7 @@ pub async fn main_async(
 
     let window = {
         let size = LogicalSize::new(WIDTH as f64, HEIGHT as f64);
-        let scaled_size = LogicalSize::new(WIDTH as f64 * 2.0, HEIGHT as f64 * 2.0);
+        let scaled_size = aspect.initial_size();
         let window = WindowBuilder::new()
             .with_title("VT420")
             .with_inner_size(scaled_size)
@@ -164,6 +314,25 @@ pub async fn main_async(
         // Trigger initial resize event
         let _ = window.request_inner_size(get_window_size());
 
+        // winit doesn't see the clipboard, so type whatever is pasted into the
+        // page
+        let closure = Closure::wrap(Box::new({
+            let sender = sender.clone();
+            move |e: web_sys::ClipboardEvent| {
+                if let Some(text) = e
+                    .clipboard_data()
+                    .and_then(|data| data.get_data("text/plain").ok())
+                {
+                    sender.paste(&text);
+                }
+            }
+        }) as Box<dyn FnMut(_)>);
+        web_sys::window()
+            .unwrap()
+            .add_event_listener_with_callback("paste", closure.as_ref().unchecked_ref())
+            .unwrap();
+        closure.forget();
+
         let promise = Promise::new(&mut |resolve, _reject| {
             let closure = Closure::wrap(Box::new(move |_timestamp: f64| {
                 resolve.call0(&wasm_bindgen::JsValue::UNDEFINED).unwrap();
@@ -209,10 +378,9 @@ pub async fn main_async(
         pixel_builder.build_async().await?
     };
 
-    // Use the fill scaling mode which supports non-integer scaling.
-    pixels.set_scaling_mode(pixels::ScalingMode::Fill);
+    pixels.set_scaling_mode(aspect.scaling_mode());
 
-    let terminal = Terminal::new(pixels, sender);
+    let terminal = Terminal::new(pixels, sender, paused);
 
     let res = game_loop(
         event_loop,
@@ -221,9 +389,13 @@ pub async fn main_async(
         FPS as u32,
         0.1,
         move |g| {
-            // Update the world
+            // Update the world, following the refresh rate of the emulated
+            // display
             if !g.game.paused {
-                step();
+                let refresh_hz = step();
+                if refresh_hz != g.updates_per_second {
+                    g.set_updates_per_second(refresh_hz);
+                }
             }
         },
         move |g| {
@@ -239,26 +411,34 @@ pub async fn main_async(
             // See: https://github.com/parasyte/pixels/issues/174
             #[cfg(not(target_arch = "wasm32"))]
             {
-                let dt = TIME_STEP.as_secs_f64() - Time::now().sub(&g.current_instant());
+                let time_step = 1.0 / g.updates_per_second as f64;
+                let dt = time_step - Time::now().sub(&g.current_instant());
                 if dt > 0.0 {
                     std::thread::sleep(Duration::from_secs_f64(dt));
                 }
             }
         },
-        |g, event| {
+        move |g, event| {
             // Let winit_input_helper collect events to build its state.
             if g.game.input.update(event) {
                 // Update controls
-                g.game.update_controls();
+                g.game.update_controls(&mut mouse, &mut request);
 
                 // Close events
                 if g.game.input.close_requested() {
+                    request(Request::Close);
                     g.exit();
                     return;
                 }
 
                 // Resize the window
                 if let Some(size) = g.game.input.window_resized() {
+                    // Snap the window back to the requested aspect ratio; we'll
+                    // get another resize event once it has been applied
+                    if let Some(corrected) = aspect.corrected_size(size) {
+                        _ = g.window.request_inner_size(corrected);
+                    }
+
                     // window_resized() returns physical size, but clamp to reasonable maximum
                     // texture size (most GPUs support up to 16384, but we'll use 8192 to be safe)
                     const MAX_TEXTURE_SIZE: u32 = 8192;
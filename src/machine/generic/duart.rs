@@ -1,11 +1,89 @@
-use std::sync::mpsc;
-use std::{cell::Cell, rc::Rc};
+use std::cell::Cell;
+use std::collections::VecDeque;
+use std::mem;
+use std::ops::BitOr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, mpsc};
 
 use tracing::{trace, warn};
 
 /// Slow down ticks to allow XON/XOFF to take effect
 const DUART_COOLDOWN_TICKS: u16 = 100;
 
+/// Most bytes taken from a host channel ahead of the firmware reading them.
+/// Beyond this the channel fills up and the host waits.
+const RX_STAGING_LIMIT: usize = 4096;
+
+/// How long a locally echoed byte waits for the host to echo it too, in
+/// ticks (about half a second). An echo from the host within this window is
+/// dropped rather than shown twice.
+const HOST_ECHO_TICKS: u64 = 460_800;
+
+/// The inputs (IP0 to IP3) that are watched for changes by the IPCR.
+const INPUT_CHANGE_MASK: u8 = 0b0000_1111;
+/// ISR and IMR bit for an input port change.
+const INPUT_CHANGE_INTERRUPT: u8 = 0b1000_0000;
+/// MR2 channel mode bit that loops the transmitter back into the receiver.
+const LOCAL_LOOPBACK: u8 = 0b1000_0000;
+/// ISR and IMR bit for the counter/timer reaching its terminal count.
+const COUNTER_READY_INTERRUPT: u8 = 0b0000_1000;
+/// Flow control bytes from the host, honoured by the transmitter when enabled.
+const XON: u8 = 0x11;
+const XOFF: u8 = 0x13;
+/// ACR bit that runs the counter/timer as a timer rather than a counter.
+const ACR_TIMER_MODE: u8 = 0b0100_0000;
+/// The DUART's 3.6864MHz crystal (X1/CLK) runs four times as fast as the steps
+/// that tick it.
+const X1_CLOCKS_PER_TICK: u32 = 4;
+
+/// A channel's status register (SRA or SRB). Bit 1, FIFO full, is never set:
+/// the receiver holds a single byte rather than the 2681's three byte FIFO.
+/// Parity, framing and break errors can't happen on the emulated connections,
+/// so only overruns are ever reported.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct StatusRegister(u8);
+
+impl StatusRegister {
+    /// A received byte is waiting in the holding register.
+    pub const RX_READY: Self = Self(1 << 0);
+    /// The transmit holding register can take another byte.
+    pub const TX_READY: Self = Self(1 << 2);
+    /// Nothing is waiting to be transmitted.
+    pub const TX_EMPTY: Self = Self(1 << 3);
+    /// A byte arrived while the last one was still unread, and was lost.
+    pub const OVERRUN_ERROR: Self = Self(1 << 4);
+    /// Never set, since the emulated connections have no parity.
+    #[allow(dead_code)]
+    pub const PARITY_ERROR: Self = Self(1 << 5);
+    /// Never set, since the emulated connections have no framing.
+    #[allow(dead_code)]
+    pub const FRAMING_ERROR: Self = Self(1 << 6);
+    /// Never set, since the emulated connections can't send a break.
+    #[allow(dead_code)]
+    pub const RECEIVED_BREAK: Self = Self(1 << 7);
+
+    pub const fn bits(self) -> u8 {
+        self.0
+    }
+
+    /// Whether all of the flags in `other` are set.
+    pub const fn contains(self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    pub fn insert(&mut self, other: Self) {
+        self.0 |= other.0;
+    }
+}
+
+impl BitOr for StatusRegister {
+    type Output = Self;
+
+    fn bitor(self, other: Self) -> Self {
+        Self(self.0 | other.0)
+    }
+}
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 #[repr(u8)]
 pub enum ReadRegister {
@@ -139,14 +217,16 @@ const WRITE_2681: &[&str] = &[
 pub struct DUARTChannel {
     pub rx: mpsc::Receiver<u8>,
     pub tx: mpsc::SyncSender<u8>,
-    pub dtr: Rc<Cell<bool>>,
+    /// Data terminal ready, driven by the emulator and observed by the host
+    /// connection threads.
+    pub dtr: Arc<AtomicBool>,
 }
 
 impl DUARTChannel {
     pub fn new() -> (DUARTChannel, DUARTChannel) {
         let (tx, rx2) = mpsc::sync_channel(16);
         let (tx2, rx) = mpsc::sync_channel(16);
-        let dtr = Rc::new(Cell::new(true));
+        let dtr = Arc::new(AtomicBool::new(true));
         (
             Self {
                 rx,
@@ -162,26 +242,86 @@ impl DUARTChannel {
     }
 }
 
+/// The DUART's end of a [`DUARTChannel`]. The sender is dropped when the
+/// DUART is closed, so the host sees the end of the stream.
+struct Link {
+    rx: mpsc::Receiver<u8>,
+    tx: Option<mpsc::SyncSender<u8>>,
+    dtr: Arc<AtomicBool>,
+}
+
+impl From<DUARTChannel> for Link {
+    fn from(channel: DUARTChannel) -> Self {
+        Self {
+            rx: channel.rx,
+            tx: Some(channel.tx),
+            dtr: channel.dtr,
+        }
+    }
+}
+
+impl Link {
+    /// Send a byte to the host, unless the link has been closed.
+    fn send(&self, byte: u8) {
+        if let Some(tx) = &self.tx {
+            _ = tx.send(byte);
+        }
+    }
+}
+
 pub struct DUART {
-    channel_a: DUARTChannel,
+    channel_a: Link,
     channel_a_cooldown: u16,
-    channel_b: DUARTChannel,
+    channel_b: Link,
     channel_b_cooldown: u16,
     mode_register_a: (u8, u8),
     mr_a: Cell<bool>,
     mode_register_b: (u8, u8),
     mr_b: Cell<bool>,
+    /// MR2 bits held set regardless of what the firmware writes.
+    forced_mode_a: u8,
+    forced_mode_b: u8,
     channel_a_rx_pending: Cell<Option<u8>>,
     channel_a_tx_pending: Option<u8>,
     channel_b_rx_pending: Cell<Option<u8>>,
     channel_b_tx_pending: Option<u8>,
+    /// Set when a received byte was lost because the last one hadn't been
+    /// read, until the firmware resets the error status.
+    channel_a_overrun: bool,
+    channel_b_overrun: bool,
+    /// Bytes taken from the host ahead of the emulated cadence while DTR is
+    /// up, so the connection rarely waits on the channel, and received at
+    /// the emulated cadence. Bounded by [`RX_STAGING_LIMIT`].
+    channel_a_rx_staging: VecDeque<u8>,
+    channel_b_rx_staging: VecDeque<u8>,
+    /// Bytes echoed locally on channel A and the tick each stops waiting for
+    /// the host's echo.
+    local_echo_a: VecDeque<(u8, u64)>,
+    ticks: u64,
+    /// Whether the transmitter stops when the host sends XOFF.
+    host_xoff_a: bool,
+    host_xoff_b: bool,
+    /// Set while the host has sent XOFF and not yet XON.
+    channel_a_tx_held: bool,
+    channel_b_tx_held: bool,
     clock_select_warned: bool,
     reset_sleep: u16,
     interrupt_mask: u8,
+    aux_control: u8,
+    counter_preset: u16,
+    counter: Cell<u16>,
+    counter_running: Cell<bool>,
+    counter_ready: Cell<bool>,
+    /// X1 clocks accumulated towards the next count.
+    counter_clocks: u32,
 
     pub interrupt: bool,
     first_interrupt: bool,
     pub input_bits: u8,
+    /// The inputs as of the last tick, to detect changes.
+    previous_input_bits: u8,
+    /// Inputs that have changed since the IPCR was last read.
+    input_change: Cell<u8>,
     pub output_bits_inv: u8,
 }
 
@@ -191,22 +331,42 @@ impl DUART {
         let (channel_b, channel_b2) = DUARTChannel::new();
         (
             Self {
-                channel_a,
+                channel_a: channel_a.into(),
                 channel_a_cooldown: 0,
-                channel_b,
+                channel_b: channel_b.into(),
                 channel_b_cooldown: 0,
                 mode_register_a: (0, 0),
                 mode_register_b: (0, 0),
                 mr_a: Cell::new(false),
                 mr_b: Cell::new(false),
+                forced_mode_a: 0,
+                forced_mode_b: 0,
                 channel_a_rx_pending: Cell::new(None),
                 channel_a_tx_pending: None,
                 channel_b_rx_pending: Cell::new(None),
                 channel_b_tx_pending: None,
+                channel_a_overrun: false,
+                channel_b_overrun: false,
+                channel_a_rx_staging: VecDeque::new(),
+                channel_b_rx_staging: VecDeque::new(),
+                local_echo_a: VecDeque::new(),
+                ticks: 0,
+                host_xoff_a: false,
+                host_xoff_b: false,
+                channel_a_tx_held: false,
+                channel_b_tx_held: false,
                 input_bits: 0,
+                previous_input_bits: 0,
+                input_change: Cell::new(0),
                 output_bits_inv: 0,
                 interrupt: false,
                 interrupt_mask: 0,
+                aux_control: 0,
+                counter_preset: 0,
+                counter: Cell::new(0),
+                counter_running: Cell::new(false),
+                counter_ready: Cell::new(false),
+                counter_clocks: 0,
                 clock_select_warned: false,
                 first_interrupt: true,
                 reset_sleep: 0xffff,
@@ -216,33 +376,37 @@ impl DUART {
         )
     }
 
+    /// Channel A's status register, without the side effects of reading it.
+    pub fn status_a(&self) -> StatusRegister {
+        status(
+            self.channel_a_rx_pending.get(),
+            self.channel_a_tx_pending,
+            self.channel_a_overrun,
+        )
+    }
+
+    /// Channel B's status register, without the side effects of reading it.
+    pub fn status_b(&self) -> StatusRegister {
+        status(
+            self.channel_b_rx_pending.get(),
+            self.channel_b_tx_pending,
+            self.channel_b_overrun,
+        )
+    }
+
     pub fn read(&self, register: ReadRegister) -> u8 {
         match register {
-            ReadRegister::InterruptStatusRegister => {
-                let mut status = 0;
-                if self.channel_a_tx_pending.is_none() {
-                    status |= 0b0001;
-                }
-                if self.channel_a_rx_pending.get().is_some() {
-                    status |= 0b0010;
-                }
-                if self.channel_b_tx_pending.is_none() {
-                    status |= 0b0001_0000;
-                }
-                if self.channel_b_rx_pending.get().is_some() {
-                    status |= 0b0010_0000;
-                }
-                status
+            ReadRegister::InterruptStatusRegister => self.interrupt_status(),
+            ReadRegister::InputPortChangeRegister => {
+                // Change bits in the upper nibble, current state in the lower
+                self.input_change.replace(0) << 4 | self.input_bits & INPUT_CHANGE_MASK
             }
             ReadRegister::StatusRegisterA => {
-                let mut status = 0;
-                if self.channel_a_rx_pending.get().is_some() {
-                    status |= 0b0001;
+                let status = self.status_a();
+                if status.contains(StatusRegister::OVERRUN_ERROR) {
+                    trace!("DUART read SRA with an overrun pending");
                 }
-                if self.channel_a_tx_pending.is_none() {
-                    status |= 0b1100;
-                }
-                status
+                status.bits()
             }
             ReadRegister::ModeRegisterA => {
                 if !self.mr_a.replace(true) {
@@ -257,14 +421,11 @@ impl DUART {
                 self.channel_a_rx_pending.replace(None).take().unwrap_or(0)
             }
             ReadRegister::StatusRegisterB => {
-                let mut status = 0;
-                if self.channel_b_rx_pending.get().is_some() {
-                    status |= 0b0001;
-                }
-                if self.channel_b_tx_pending.is_none() {
-                    status |= 0b1100;
+                let status = self.status_b();
+                if status.contains(StatusRegister::OVERRUN_ERROR) {
+                    trace!("DUART read SRB with an overrun pending");
                 }
-                status
+                status.bits()
             }
             ReadRegister::ModeRegisterB => {
                 if !self.mr_b.replace(true) {
@@ -279,6 +440,20 @@ impl DUART {
                 self.channel_b_rx_pending.replace(None).take().unwrap_or(0)
             }
             ReadRegister::InputPortsIP0ToIP6 => self.input_bits,
+            ReadRegister::CounterTimerUpperValue => (self.counter.get() >> 8) as u8,
+            ReadRegister::CounterTimerLowerValue => self.counter.get() as u8,
+            ReadRegister::StartCounterCommand => {
+                trace!("DUART counter start, preset = {:04X}", self.counter_preset);
+                self.counter.set(self.counter_preset);
+                self.counter_running.set(true);
+                0
+            }
+            ReadRegister::StopCounterCommand => {
+                trace!("DUART counter stop, value = {:04X}", self.counter.get());
+                self.counter_running.set(false);
+                self.counter_ready.set(false);
+                0
+            }
             _ => {
                 warn!("DUART read from unhandled register: {:?}", register);
                 0
@@ -298,6 +473,9 @@ impl DUART {
                 0b0011 => {
                     self.channel_a_tx_pending.take();
                 }
+                0b0100 => {
+                    self.channel_a_overrun = false;
+                }
                 _ => {}
             },
             WriteRegister::ModeRegisterA => {
@@ -306,7 +484,7 @@ impl DUART {
                     self.mode_register_a.0 = value;
                 } else {
                     trace!("DUART write MRA2");
-                    self.mode_register_a.1 = value;
+                    self.mode_register_a.1 = value | self.forced_mode_a;
                 }
             }
             WriteRegister::TxHoldingRegisterA => {
@@ -322,6 +500,9 @@ impl DUART {
                 0b0011 => {
                     self.channel_b_tx_pending.take();
                 }
+                0b0100 => {
+                    self.channel_b_overrun = false;
+                }
                 _ => {}
             },
             WriteRegister::ModeRegisterB => {
@@ -330,7 +511,7 @@ impl DUART {
                     self.mode_register_b.0 = value;
                 } else {
                     trace!("DUART write MRB2");
-                    self.mode_register_b.1 = value;
+                    self.mode_register_b.1 = value | self.forced_mode_b;
                 }
             }
             WriteRegister::SetOutputPortBitsCommand => {
@@ -348,13 +529,19 @@ impl DUART {
                     self.clock_select_warned = true;
                 }
             }
+            WriteRegister::CounterTimerUpperPreset => {
+                self.counter_preset = self.counter_preset & 0x00ff | (value as u16) << 8;
+            }
+            WriteRegister::CounterTimerLowerPreset => {
+                self.counter_preset = self.counter_preset & 0xff00 | value as u16;
+            }
+            WriteRegister::AuxControlRegister => {
+                trace!("DUART ACR = {value:02X}");
+                self.aux_control = value;
+            }
             WriteRegister::InterruptMaskRegister => {
+                trace!("DUART IMR = {value:02X}");
                 self.interrupt_mask = value;
-                if value != 0 && value != 0x22 {
-                    warn!(
-                        "DUART interrupt mask write only handles 0 and 0x22, other values are ignored: {value:02X}"
-                    );
-                }
             }
             _ => {
                 warn!(
@@ -365,71 +552,704 @@ impl DUART {
         }
     }
 
+    /// Return to the power-on state, as after a hardware reset. The host
+    /// connections and the options set by the emulator (forced loopback and
+    /// host XOFF) are kept, but anything waiting to be sent or received is
+    /// dropped.
+    pub fn reset(&mut self) {
+        let (mut reset, _, _) = DUART::new();
+        mem::swap(&mut reset.channel_a, &mut self.channel_a);
+        mem::swap(&mut reset.channel_b, &mut self.channel_b);
+        reset.force_local_loopback(
+            self.forced_mode_a & LOCAL_LOOPBACK != 0,
+            self.forced_mode_b & LOCAL_LOOPBACK != 0,
+        );
+        reset.honour_host_xoff(self.host_xoff_a, self.host_xoff_b);
+        *self = reset;
+    }
+
+    /// Hold a channel in local loopback, as if the firmware had selected it in
+    /// MR2, so everything it transmits is received straight back and nothing
+    /// reaches the host connection.
+    pub fn force_local_loopback(&mut self, channel_a: bool, channel_b: bool) {
+        if channel_a {
+            self.forced_mode_a |= LOCAL_LOOPBACK;
+            self.mode_register_a.1 |= LOCAL_LOOPBACK;
+        }
+        if channel_b {
+            self.forced_mode_b |= LOCAL_LOOPBACK;
+            self.mode_register_b.1 |= LOCAL_LOOPBACK;
+        }
+    }
+
+    /// Stop a channel's transmitter while the host has sent XOFF, until it
+    /// sends XON. The byte in the transmit holding register waits, so the
+    /// firmware sees the transmitter as busy. The flow control bytes are still
+    /// received by the firmware.
+    pub fn honour_host_xoff(&mut self, channel_a: bool, channel_b: bool) {
+        self.host_xoff_a = channel_a;
+        self.host_xoff_b = channel_b;
+    }
+
+    /// Queue bytes on channel A's receiver, as if they had arrived from the
+    /// host. They are received at the same cadence as bytes from the channel.
+    pub fn inject_rx_a(&mut self, bytes: &[u8]) {
+        self.channel_a_rx_staging.extend(bytes);
+    }
+
+    /// Queue typed bytes on channel A's receiver for local echo. If the host
+    /// echoes the same bytes shortly afterwards, its copies are dropped.
+    pub fn local_echo_a(&mut self, bytes: &[u8]) {
+        self.channel_a_rx_staging.extend(bytes);
+        let deadline = self.ticks + HOST_ECHO_TICKS;
+        self.local_echo_a
+            .extend(bytes.iter().map(|&byte| (byte, deadline)));
+    }
+
+    /// Queue bytes on channel B's receiver, as if they had arrived from the
+    /// host.
+    pub fn inject_rx_b(&mut self, bytes: &[u8]) {
+        self.channel_b_rx_staging.extend(bytes);
+    }
+
+    /// Deliver any byte waiting to be transmitted and disconnect both
+    /// channels, so the host side sees the end of the stream once it has
+    /// drained what is already queued.
+    pub fn close(&mut self) {
+        for (channel, pending) in [
+            (&mut self.channel_a, &mut self.channel_a_tx_pending),
+            (&mut self.channel_b, &mut self.channel_b_tx_pending),
+        ] {
+            if let Some(tx) = pending.take() {
+                channel.send(tx);
+            }
+            channel.tx = None;
+        }
+    }
+
+    /// The ISR: every interrupt source that is active, whether or not the IMR
+    /// enables it. The change-in-break bits are never set.
+    fn interrupt_status(&self) -> u8 {
+        let mut status = 0;
+        if self.channel_a_tx_pending.is_none() {
+            status |= 0b0001;
+        }
+        if self.channel_a_rx_pending.get().is_some() {
+            status |= 0b0010;
+        }
+        if self.channel_b_tx_pending.is_none() {
+            status |= 0b0001_0000;
+        }
+        if self.channel_b_rx_pending.get().is_some() {
+            status |= 0b0010_0000;
+        }
+        if self.counter_ready.get() {
+            status |= COUNTER_READY_INTERRUPT;
+        }
+        if self.input_change_pending() {
+            status |= INPUT_CHANGE_INTERRUPT;
+        }
+        status
+    }
+
+    /// The number of X1 clocks per count of the counter/timer, from the clock
+    /// source selected in the ACR. IP2 and the transmitter clocks aren't
+    /// emulated, so they count once per tick.
+    fn counter_divider(&self) -> u32 {
+        match self.aux_control >> 4 & 0b111 {
+            // Timer, X1/CLK
+            0b110 => 1,
+            // Counter or timer, X1/CLK divided by 16
+            0b011 | 0b111 => 16,
+            // Timer, IP2 divided by 16
+            0b101 => X1_CLOCKS_PER_TICK * 16,
+            _ => X1_CLOCKS_PER_TICK,
+        }
+    }
+
+    /// Count the counter/timer down by one. At the terminal count a timer
+    /// starts again from the preset, and a counter carries on from 0xFFFF.
+    fn count(&mut self) {
+        let counter = self.counter.get().wrapping_sub(1);
+        if counter == 0 {
+            trace!("DUART counter ready");
+            self.counter_ready.set(true);
+            if self.aux_control & ACR_TIMER_MODE != 0 {
+                self.counter.set(self.counter_preset);
+                return;
+            }
+        }
+        self.counter.set(counter);
+    }
+
+    /// True if an input change has been latched that the ACR enables as an
+    /// interrupt source.
+    fn input_change_pending(&self) -> bool {
+        self.input_change.get() & self.aux_control & INPUT_CHANGE_MASK != 0
+    }
+
     pub fn tick(&mut self) {
         if self.reset_sleep != 0 {
             self.reset_sleep = self.reset_sleep.saturating_sub(1);
             return;
         }
+        self.ticks += 1;
 
-        if self.mode_register_a.1 & 0b1000_0000 != 0 {
+        if self.mode_register_a.1 & LOCAL_LOOPBACK != 0 {
             if let Some(tx) = self.channel_a_tx_pending.take() {
                 trace!(
                     "DUART pipe local loopback (channel A) {tx:02X} {:?}",
                     tx as char
                 );
-                self.channel_a_rx_pending.replace(Some(tx));
+                receive(&self.channel_a_rx_pending, &mut self.channel_a_overrun, tx);
             }
         } else {
-            if let Some(tx) = self.channel_a_tx_pending.take() {
-                trace!("DUART pipe send (channel A) {tx:02X} {:?}", tx as char);
-                _ = self.channel_a.tx.send(tx);
+            if !self.channel_a_tx_held {
+                if let Some(tx) = self.channel_a_tx_pending.take() {
+                    trace!("DUART pipe send (channel A) {tx:02X} {:?}", tx as char);
+                    self.channel_a.send(tx);
+                }
+            }
+            // Leave bytes in the channel while DTR is down or the staging
+            // buffer is full, so the host is held off
+            let dtr = self.channel_a.dtr.load(Ordering::Relaxed);
+            let room = RX_STAGING_LIMIT.saturating_sub(self.channel_a_rx_staging.len());
+            let room = if dtr { room } else { 0 };
+            for rx in self.channel_a.rx.try_iter().take(room) {
+                while self
+                    .local_echo_a
+                    .front()
+                    .is_some_and(|&(_, deadline)| deadline < self.ticks)
+                {
+                    self.local_echo_a.pop_front();
+                }
+                if self
+                    .local_echo_a
+                    .front()
+                    .is_some_and(|&(byte, _)| byte == rx)
+                {
+                    trace!("DUART host echo dropped (channel A) {rx:02X}");
+                    self.local_echo_a.pop_front();
+                } else {
+                    self.channel_a_rx_staging.push_back(rx);
+                }
             }
-            let dtr = self.channel_a.dtr.get();
             self.channel_a_cooldown = self.channel_a_cooldown.saturating_sub(1);
             if self.channel_a_rx_pending.get().is_none() && dtr && self.channel_a_cooldown == 0 {
-                if let Ok(tx) = self.channel_a.rx.try_recv() {
+                if let Some(tx) = self.channel_a_rx_staging.pop_front() {
                     trace!(
                         "DUART pipe receive (channel A, dtr = {dtr}) {tx:02X} {:?}",
                         tx as char
                     );
                     self.channel_a_rx_pending.replace(Some(tx));
                     self.channel_a_cooldown = DUART_COOLDOWN_TICKS;
+                    if self.host_xoff_a && (tx == XON || tx == XOFF) {
+                        trace!(
+                            "DUART host {} (channel A)",
+                            if tx == XOFF { "XOFF" } else { "XON" }
+                        );
+                        self.channel_a_tx_held = tx == XOFF;
+                    }
                 }
             }
         }
-        if self.mode_register_b.1 & 0b1000_0000 != 0 {
+        if self.mode_register_b.1 & LOCAL_LOOPBACK != 0 {
             if let Some(tx) = self.channel_b_tx_pending.take() {
                 trace!(
                     "DUART pipe local loopback (channel B) {tx:02X} {:?}",
                     tx as char
                 );
-                self.channel_b_rx_pending.replace(Some(tx));
+                receive(&self.channel_b_rx_pending, &mut self.channel_b_overrun, tx);
             }
         } else {
-            if let Some(tx) = self.channel_b_tx_pending.take() {
-                trace!("DUART pipe send (channel B) {tx:02X} {:?}", tx as char);
-                _ = self.channel_b.tx.send(tx);
+            if !self.channel_b_tx_held {
+                if let Some(tx) = self.channel_b_tx_pending.take() {
+                    trace!("DUART pipe send (channel B) {tx:02X} {:?}", tx as char);
+                    self.channel_b.send(tx);
+                }
             }
-            let dtr = self.channel_b.dtr.get();
+            let dtr = self.channel_b.dtr.load(Ordering::Relaxed);
+            let room = RX_STAGING_LIMIT.saturating_sub(self.channel_b_rx_staging.len());
+            let room = if dtr { room } else { 0 };
+            self.channel_b_rx_staging
+                .extend(self.channel_b.rx.try_iter().take(room));
             self.channel_b_cooldown = self.channel_b_cooldown.saturating_sub(1);
             if self.channel_b_rx_pending.get().is_none() && dtr && self.channel_b_cooldown == 0 {
-                if let Ok(tx) = self.channel_b.rx.try_recv() {
+                if let Some(tx) = self.channel_b_rx_staging.pop_front() {
                     trace!(
                         "DUART pipe receive (channel B, dtr = {dtr}) {tx:02X} {:?}",
                         tx as char
                     );
                     self.channel_b_rx_pending.replace(Some(tx));
                     self.channel_b_cooldown = DUART_COOLDOWN_TICKS;
+                    if self.host_xoff_b && (tx == XON || tx == XOFF) {
+                        trace!(
+                            "DUART host {} (channel B)",
+                            if tx == XOFF { "XOFF" } else { "XON" }
+                        );
+                        self.channel_b_tx_held = tx == XOFF;
+                    }
                 }
             }
         }
 
-        self.interrupt = self.interrupt_mask != 0
-            && (self.channel_a_rx_pending.get().is_some()
-                || self.channel_b_rx_pending.get().is_some());
+        let changed = (self.input_bits ^ self.previous_input_bits) & INPUT_CHANGE_MASK;
+        if changed != 0 {
+            trace!("DUART input change {changed:02X}");
+            self.input_change.set(self.input_change.get() | changed);
+        }
+        self.previous_input_bits = self.input_bits;
+
+        if self.counter_running.get() {
+            self.counter_clocks += X1_CLOCKS_PER_TICK;
+            let divider = self.counter_divider();
+            while self.counter_clocks >= divider {
+                self.counter_clocks -= divider;
+                self.count();
+            }
+        }
+
+        self.interrupt = self.interrupt_status() & self.interrupt_mask != 0;
         if self.interrupt && self.first_interrupt {
             warn!("First DUART interrupt fired");
             self.first_interrupt = false;
         }
     }
 }
+
+/// A channel's status register, from its holding registers and error state.
+fn status(rx_pending: Option<u8>, tx_pending: Option<u8>, overrun: bool) -> StatusRegister {
+    let mut status = StatusRegister::default();
+    if rx_pending.is_some() {
+        status.insert(StatusRegister::RX_READY);
+    }
+    if tx_pending.is_none() {
+        status.insert(StatusRegister::TX_READY | StatusRegister::TX_EMPTY);
+    }
+    if overrun {
+        status.insert(StatusRegister::OVERRUN_ERROR);
+    }
+    status
+}
+
+/// Put a received byte in a channel's holding register. As on the 2681, a
+/// byte that arrives while the last one is still unread is lost, and the
+/// overrun is flagged.
+fn receive(rx_pending: &Cell<Option<u8>>, overrun: &mut bool, byte: u8) {
+    if rx_pending.get().is_some() {
+        trace!("DUART overrun, {byte:02X} lost");
+        *overrun = true;
+    } else {
+        rx_pending.set(Some(byte));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_counter_timer() {
+        let (mut duart, _channel_a, _channel_b) = DUART::new();
+        duart.reset_sleep = 0;
+        // Timer mode, clocked by IP2, which counts once per tick
+        duart.write(WriteRegister::AuxControlRegister, 0b0100_0000);
+
+        duart.write(WriteRegister::CounterTimerUpperPreset, 0x01);
+        duart.write(WriteRegister::CounterTimerLowerPreset, 0x04);
+        duart.read(ReadRegister::StartCounterCommand);
+        assert_eq!(duart.read(ReadRegister::CounterTimerUpperValue), 0x01);
+        assert_eq!(duart.read(ReadRegister::CounterTimerLowerValue), 0x04);
+
+        for _ in 0..4 {
+            duart.tick();
+        }
+        assert_eq!(duart.read(ReadRegister::CounterTimerUpperValue), 0x01);
+        assert_eq!(duart.read(ReadRegister::CounterTimerLowerValue), 0x00);
+        assert_eq!(
+            duart.read(ReadRegister::InterruptStatusRegister) & 0b1000,
+            0
+        );
+
+        for _ in 0..0x100 {
+            duart.tick();
+        }
+        assert_ne!(
+            duart.read(ReadRegister::InterruptStatusRegister) & 0b1000,
+            0
+        );
+        // Counter wraps back to the preset
+        assert_eq!(duart.read(ReadRegister::CounterTimerUpperValue), 0x01);
+        assert_eq!(duart.read(ReadRegister::CounterTimerLowerValue), 0x04);
+
+        duart.read(ReadRegister::StopCounterCommand);
+        assert_eq!(
+            duart.read(ReadRegister::InterruptStatusRegister) & 0b1000,
+            0
+        );
+        duart.tick();
+        assert_eq!(duart.read(ReadRegister::CounterTimerLowerValue), 0x04);
+    }
+
+    #[test]
+    fn test_counter_clock_source() {
+        let (mut duart, _channel_a, _channel_b) = DUART::new();
+        duart.reset_sleep = 0;
+        duart.write(WriteRegister::CounterTimerUpperPreset, 0x00);
+        duart.write(WriteRegister::CounterTimerLowerPreset, 0x10);
+
+        // Counter, X1/CLK divided by 16: one count every four ticks
+        duart.write(WriteRegister::AuxControlRegister, 0b0011_0000);
+        duart.read(ReadRegister::StartCounterCommand);
+        for _ in 0..8 {
+            duart.tick();
+        }
+        assert_eq!(duart.read(ReadRegister::CounterTimerLowerValue), 0x0e);
+
+        // A counter carries on past the terminal count
+        for _ in 0..0x0e * 4 {
+            duart.tick();
+        }
+        assert!(duart.counter_ready.get());
+        assert_eq!(duart.read(ReadRegister::CounterTimerLowerValue), 0x00);
+        for _ in 0..4 {
+            duart.tick();
+        }
+        assert_eq!(duart.read(ReadRegister::CounterTimerUpperValue), 0xff);
+        assert_eq!(duart.read(ReadRegister::CounterTimerLowerValue), 0xff);
+        duart.read(ReadRegister::StopCounterCommand);
+
+        // Timer, X1/CLK: four counts a tick
+        duart.write(WriteRegister::AuxControlRegister, 0b0110_0000);
+        duart.read(ReadRegister::StartCounterCommand);
+        duart.tick();
+        assert_eq!(duart.read(ReadRegister::CounterTimerLowerValue), 0x0c);
+        for _ in 0..3 {
+            duart.tick();
+        }
+        assert!(duart.counter_ready.get());
+        assert_eq!(duart.read(ReadRegister::CounterTimerLowerValue), 0x10);
+        duart.read(ReadRegister::StopCounterCommand);
+
+        // Timer, IP2 divided by 16: one count every 16 ticks
+        duart.write(WriteRegister::AuxControlRegister, 0b0101_0000);
+        duart.read(ReadRegister::StartCounterCommand);
+        for _ in 0..15 {
+            duart.tick();
+        }
+        assert_eq!(duart.read(ReadRegister::CounterTimerLowerValue), 0x10);
+        duart.tick();
+        assert_eq!(duart.read(ReadRegister::CounterTimerLowerValue), 0x0f);
+    }
+
+    #[test]
+    fn test_interrupt_mask() {
+        let (mut duart, _channel_a, channel_b) = DUART::new();
+        duart.reset_sleep = 0;
+
+        // Both transmitters are idle
+        for (mask, fires) in [(0x00, false), (0x01, true), (0x10, true), (0x22, false)] {
+            duart.write(WriteRegister::InterruptMaskRegister, mask);
+            duart.tick();
+            assert_eq!(duart.interrupt, fires, "{mask:02X}");
+        }
+
+        // A byte received on channel B only fires when RxRDYB is enabled
+        channel_b.tx.send(0x41).unwrap();
+        for (mask, fires) in [(0x02, false), (0x08, false), (0x20, true), (0x22, true)] {
+            duart.write(WriteRegister::InterruptMaskRegister, mask);
+            duart.tick();
+            assert_eq!(duart.interrupt, fires, "{mask:02X}");
+        }
+        assert_eq!(duart.read(ReadRegister::RxHoldingRegisterB), 0x41);
+        duart.tick();
+        assert!(!duart.interrupt);
+
+        // The counter only fires when its bit is enabled
+        duart.write(WriteRegister::CounterTimerLowerPreset, 0x01);
+        duart.read(ReadRegister::StartCounterCommand);
+        duart.tick();
+        assert!(!duart.interrupt);
+        duart.write(
+            WriteRegister::InterruptMaskRegister,
+            COUNTER_READY_INTERRUPT,
+        );
+        duart.tick();
+        assert!(duart.interrupt);
+    }
+
+    #[test]
+    fn test_input_port_change() {
+        let (mut duart, _channel_a, _channel_b) = DUART::new();
+        duart.reset_sleep = 0;
+        duart.tick();
+        assert_eq!(duart.read(ReadRegister::InputPortChangeRegister), 0x00);
+
+        duart.input_bits = 0b0100;
+        duart.tick();
+        duart.tick();
+        assert_eq!(duart.read(ReadRegister::InputPortChangeRegister), 0x44);
+        // Change bits clear on read, the current state remains
+        assert_eq!(duart.read(ReadRegister::InputPortChangeRegister), 0x04);
+
+        // Inputs above IP3 aren't reported
+        duart.input_bits = 0b1_0100;
+        duart.tick();
+        assert_eq!(duart.read(ReadRegister::InputPortChangeRegister), 0x04);
+    }
+
+    #[test]
+    fn test_input_port_change_interrupt() {
+        let (mut duart, _channel_a, _channel_b) = DUART::new();
+        duart.reset_sleep = 0;
+        duart.write(WriteRegister::InterruptMaskRegister, INPUT_CHANGE_INTERRUPT);
+
+        // IP0 changes aren't enabled in the ACR
+        duart.input_bits = 0b0001;
+        duart.tick();
+        assert!(!duart.interrupt);
+        duart.read(ReadRegister::InputPortChangeRegister);
+
+        duart.write(WriteRegister::AuxControlRegister, 0b0001);
+        duart.input_bits = 0b0000;
+        duart.tick();
+        assert!(duart.interrupt);
+        assert_eq!(
+            duart.read(ReadRegister::InterruptStatusRegister) & INPUT_CHANGE_INTERRUPT,
+            INPUT_CHANGE_INTERRUPT
+        );
+
+        duart.read(ReadRegister::InputPortChangeRegister);
+        duart.tick();
+        assert!(!duart.interrupt);
+    }
+
+    #[test]
+    fn test_rx_staging() {
+        let (mut duart, channel_a, _channel_b) = DUART::new();
+        duart.reset_sleep = 0;
+
+        // The host finishes sending long before the firmware has read
+        // anything, far more than the channel holds
+        let sender = std::thread::spawn(move || {
+            for i in 0..1000 {
+                channel_a.tx.send(i as u8).unwrap();
+            }
+            channel_a
+        });
+        let deadline = std::time::Instant::now() + std::time::Duration::from_secs(5);
+        while !sender.is_finished() {
+            assert!(std::time::Instant::now() < deadline, "host blocked");
+            duart.tick();
+        }
+        let _channel_a = sender.join().unwrap();
+
+        let mut received = vec![];
+        for _ in 0..1000 * (DUART_COOLDOWN_TICKS as usize + 1) {
+            duart.tick();
+            if duart.read(ReadRegister::StatusRegisterA) & 0b0001 != 0 {
+                received.push(duart.read(ReadRegister::RxHoldingRegisterA));
+            }
+        }
+        let expected = (0..1000).map(|i| i as u8).collect::<Vec<_>>();
+        assert_eq!(received, expected);
+    }
+
+    #[test]
+    fn test_rx_staging_backpressure() {
+        let (mut duart, channel_a, _channel_b) = DUART::new();
+        duart.reset_sleep = 0;
+
+        // Nothing is taken from the host while DTR is down
+        channel_a.dtr.store(false, Ordering::Relaxed);
+        while channel_a.tx.try_send(0).is_ok() {}
+        duart.tick();
+        assert!(duart.channel_a_rx_staging.is_empty());
+        assert!(channel_a.tx.try_send(0).is_err());
+
+        // Nor more than the staging limit while the firmware isn't reading
+        channel_a.dtr.store(true, Ordering::Relaxed);
+        for _ in 0..RX_STAGING_LIMIT {
+            while channel_a.tx.try_send(0).is_ok() {}
+            duart.tick();
+        }
+        assert_eq!(duart.channel_a_rx_staging.len(), RX_STAGING_LIMIT);
+        assert!(channel_a.tx.try_send(0).is_err());
+    }
+
+    #[test]
+    fn test_host_xoff() {
+        let (mut duart, channel_a, channel_b) = DUART::new();
+        duart.reset_sleep = 0;
+        duart.honour_host_xoff(true, false);
+
+        let receive = |duart: &mut DUART| {
+            for _ in 0..=DUART_COOLDOWN_TICKS {
+                duart.tick();
+            }
+            duart.read(ReadRegister::RxHoldingRegisterA)
+        };
+
+        channel_a.tx.send(XOFF).unwrap();
+        assert_eq!(receive(&mut duart), XOFF);
+        duart.write(WriteRegister::TxHoldingRegisterA, b'x');
+        for _ in 0..1000 {
+            duart.tick();
+        }
+        assert!(channel_a.rx.try_recv().is_err());
+        // The transmitter stays busy
+        assert_eq!(duart.read(ReadRegister::StatusRegisterA) & 0b0100, 0);
+
+        channel_a.tx.send(XON).unwrap();
+        assert_eq!(receive(&mut duart), XON);
+        duart.tick();
+        assert_eq!(channel_a.rx.try_recv(), Ok(b'x'));
+        assert_ne!(duart.read(ReadRegister::StatusRegisterA) & 0b0100, 0);
+
+        // Channel B doesn't honour XOFF
+        channel_b.tx.send(XOFF).unwrap();
+        for _ in 0..=DUART_COOLDOWN_TICKS {
+            duart.tick();
+        }
+        duart.write(WriteRegister::TxHoldingRegisterB, b'y');
+        duart.tick();
+        assert_eq!(channel_b.rx.try_recv(), Ok(b'y'));
+    }
+
+    #[test]
+    fn test_status_register() {
+        let (mut duart, channel_a, _channel_b) = DUART::new();
+        duart.reset_sleep = 0;
+        let idle = StatusRegister::TX_READY | StatusRegister::TX_EMPTY;
+        assert_eq!(duart.status_a(), idle);
+        assert_eq!(duart.read(ReadRegister::StatusRegisterA), idle.bits());
+
+        // The transmitter is busy until the byte has gone to the host
+        duart.write(WriteRegister::TxHoldingRegisterA, b'x');
+        assert!(!duart.status_a().contains(StatusRegister::TX_READY));
+        assert!(!duart.status_a().contains(StatusRegister::TX_EMPTY));
+        duart.tick();
+        assert_eq!(channel_a.rx.try_recv(), Ok(b'x'));
+        assert_eq!(duart.status_a(), idle);
+
+        channel_a.tx.send(b'y').unwrap();
+        duart.tick();
+        assert_eq!(duart.status_a(), idle | StatusRegister::RX_READY);
+        assert_eq!(duart.read(ReadRegister::RxHoldingRegisterA), b'y');
+        assert_eq!(duart.status_b(), idle);
+    }
+
+    #[test]
+    fn test_overrun() {
+        let (mut duart, _channel_a, _channel_b) = DUART::new();
+        duart.reset_sleep = 0;
+        duart.force_local_loopback(true, false);
+
+        // The second byte arrives before the first has been read
+        duart.write(WriteRegister::TxHoldingRegisterA, b'1');
+        duart.tick();
+        duart.write(WriteRegister::TxHoldingRegisterA, b'2');
+        duart.tick();
+        let status = duart.status_a();
+        assert!(status.contains(StatusRegister::RX_READY | StatusRegister::OVERRUN_ERROR));
+        assert!(!status.contains(StatusRegister::FRAMING_ERROR));
+        assert_eq!(duart.read(ReadRegister::RxHoldingRegisterA), b'1');
+
+        // Reading doesn't clear the overrun, resetting the error status does
+        assert!(duart.status_a().contains(StatusRegister::OVERRUN_ERROR));
+        duart.write(WriteRegister::CommandRegisterA, 0b0100_0000);
+        assert!(!duart.status_a().contains(StatusRegister::OVERRUN_ERROR));
+        assert!(!duart.status_b().contains(StatusRegister::OVERRUN_ERROR));
+    }
+
+    #[test]
+    fn test_local_echo() {
+        let (mut duart, channel_a, _channel_b) = DUART::new();
+        duart.reset_sleep = 0;
+
+        let receive = |duart: &mut DUART, ticks: u64| {
+            let mut received = vec![];
+            for _ in 0..ticks {
+                duart.tick();
+                if duart.read(ReadRegister::StatusRegisterA) & 0b0001 != 0 {
+                    received.push(duart.read(ReadRegister::RxHoldingRegisterA));
+                }
+            }
+            received
+        };
+
+        // The host's echo is dropped, but not what it sends after it
+        duart.local_echo_a(b"ab");
+        channel_a.tx.send(b'a').unwrap();
+        channel_a.tx.send(b'b').unwrap();
+        channel_a.tx.send(b'c').unwrap();
+        assert_eq!(receive(&mut duart, 1000), b"abc");
+
+        // A host that doesn't echo doesn't lose its next bytes
+        duart.local_echo_a(b"d");
+        assert_eq!(receive(&mut duart, HOST_ECHO_TICKS + 1), b"d");
+        channel_a.tx.send(b'd').unwrap();
+        assert_eq!(receive(&mut duart, 1000), b"d");
+    }
+
+    #[test]
+    fn test_forced_local_loopback() {
+        let (mut duart, channel_a, channel_b) = DUART::new();
+        duart.reset_sleep = 0;
+        duart.force_local_loopback(true, false);
+
+        // The firmware selecting normal mode doesn't undo it
+        duart.write(WriteRegister::CommandRegisterA, 0b0001_0000);
+        duart.write(WriteRegister::ModeRegisterA, 0x13);
+        duart.write(WriteRegister::ModeRegisterA, 0x07);
+
+        duart.write(WriteRegister::TxHoldingRegisterA, 0x5a);
+        duart.tick();
+        assert_eq!(duart.read(ReadRegister::StatusRegisterA) & 0b0001, 0b0001);
+        assert_eq!(duart.read(ReadRegister::RxHoldingRegisterA), 0x5a);
+        assert!(channel_a.rx.try_recv().is_err());
+
+        // Channel B still talks to the host
+        duart.write(WriteRegister::TxHoldingRegisterB, 0xa5);
+        duart.tick();
+        assert_eq!(duart.read(ReadRegister::StatusRegisterB) & 0b0001, 0);
+        assert_eq!(channel_b.rx.try_recv(), Ok(0xa5));
+    }
+
+    #[test]
+    fn test_close() {
+        let (mut duart, channel_a, channel_b) = DUART::new();
+        duart.reset_sleep = 0;
+
+        // A byte still in the holding register is delivered before the
+        // channel disconnects.
+        duart.write(WriteRegister::TxHoldingRegisterA, b'z');
+        duart.close();
+        assert_eq!(channel_a.rx.try_recv(), Ok(b'z'));
+        assert_eq!(
+            channel_a.rx.try_recv(),
+            Err(mpsc::TryRecvError::Disconnected)
+        );
+        assert_eq!(
+            channel_b.rx.try_recv(),
+            Err(mpsc::TryRecvError::Disconnected)
+        );
+
+        // The firmware can keep transmitting, but nothing reaches the host,
+        // even after a reset.
+        duart.write(WriteRegister::TxHoldingRegisterB, b'y');
+        duart.tick();
+        duart.reset();
+        duart.reset_sleep = 0;
+        duart.write(WriteRegister::TxHoldingRegisterA, b'x');
+        duart.tick();
+        assert_eq!(
+            channel_a.rx.try_recv(),
+            Err(mpsc::TryRecvError::Disconnected)
+        );
+        assert_eq!(
+            channel_b.rx.try_recv(),
+            Err(mpsc::TryRecvError::Disconnected)
+        );
+    }
+}
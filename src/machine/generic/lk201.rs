@@ -5,9 +5,14 @@
 //! bootup sequences are documented at <https://vt100.net/keyboard.html>.
 #![allow(unused)]
 
-use std::{collections::VecDeque, fmt, sync::mpsc};
+use std::{
+    collections::VecDeque,
+    fmt,
+    io::{self, Write},
+    sync::{Arc, Mutex, mpsc},
+};
 
-use tracing::trace;
+use tracing::{trace, warn};
 
 /// LED indicators on the LK201 keyboard
 ///
@@ -47,6 +52,17 @@ impl Led {
     pub fn is_all(&self) -> bool {
         self.0 & 0x0F == 0x0F
     }
+
+    /// Each LED and whether it is lit, in the order they appear on the
+    /// keyboard from left to right.
+    pub fn indicators(&self) -> [(&'static str, bool); 4] {
+        [
+            ("Hold", self.is_hold()),
+            ("Lock", self.is_lock()),
+            ("Compose", self.is_compose()),
+            ("Wait", self.is_wait()),
+        ]
+    }
 }
 
 impl fmt::Debug for Led {
@@ -172,6 +188,14 @@ pub enum LK201Command {
     DisableRepeat { division: Division },
     /// Temporarily disable auto-repeat for currently pressed key
     TempNoRepeat,
+    /// Undocumented 3-byte repeat command (0xE9) for a division. Its
+    /// parameters aren't understood, so they are kept without the LK_PARAM
+    /// bit and otherwise ignored.
+    DivisionRepeatControl {
+        division: Division,
+        param1: u8,
+        param2: u8,
+    },
 
     // Auto-repeat Control
     /// Set auto-repeat parameters for a register
@@ -203,10 +227,9 @@ pub enum LK201Command {
 
     /// Unknown initial byte
     Unknown(u8),
-    /// Unknown 2-byte command
-    Unknown2(u8, u8),
-    /// Unknown 3-byte command (e.g., 0xE9 xx xx - possibly division-specific repeat control)
-    Unknown3(u8, u8, u8),
+    /// A known command whose parameter bytes are present but invalid. The
+    /// `len` bytes up to and including the first bad parameter are consumed.
+    Malformed { command: u8, len: usize },
 }
 
 impl LK201Command {
@@ -229,6 +252,7 @@ impl LK201Command {
             LK201Command::EnableRepeat { .. } => 1,
             LK201Command::DisableRepeat { .. } => 1,
             LK201Command::TempNoRepeat => 1,
+            LK201Command::DivisionRepeatControl { .. } => 3,
             LK201Command::SetAutoRepeat { .. } => 3,
             LK201Command::PowerUp => 1,
             LK201Command::RequestId => 1,
@@ -238,8 +262,7 @@ impl LK201Command {
             LK201Command::Inhibit => 1,
             LK201Command::Resume => 1,
             LK201Command::Unknown(_) => 1,
-            LK201Command::Unknown2(_, _) => 2,
-            LK201Command::Unknown3(_, _, _) => 3,
+            LK201Command::Malformed { len, .. } => *len,
         }
     }
 
@@ -252,13 +275,19 @@ impl LK201Command {
     /// - Invalid commands return InputError (0xB6)
     /// - Most other commands (LED, bell, click) have no response
     pub fn response(&self) -> Option<LK201Response> {
+        self.response_with_self_test(SelfTestResult::default())
+    }
+
+    /// The response to this command from a keyboard whose power-up self test
+    /// gave `self_test`.
+    pub fn response_with_self_test(&self, self_test: SelfTestResult) -> Option<LK201Response> {
         Some(match self {
             // Power-up and ID requests return multi-byte responses
             LK201Command::PowerUp => LK201Response::PowerUpSelfTest {
                 keyboard_id_firmware: 0x01, // Standard LK201 firmware ID
                 keyboard_id_hardware: 0x00, // Hardware ID from jumpers
-                error: PowerUpError::NoError,
-                keycode: 0,
+                error: self_test.error,
+                keycode: self_test.keycode,
             },
             LK201Command::RequestId => LK201Response::KeyboardId {
                 firmware_id: 0x01, // Firmware version
@@ -276,6 +305,7 @@ impl LK201Command {
             LK201Command::TempNoRepeat => LK201Response::ModeChangeAck,
             LK201Command::EnableRepeat { .. } => LK201Response::ModeChangeAck,
             LK201Command::DisableRepeat { .. } => LK201Response::ModeChangeAck,
+            LK201Command::DivisionRepeatControl { .. } => LK201Response::ModeChangeAck,
 
             // Special control commands with specific acks
             LK201Command::TestMode => LK201Response::TestModeAck,
@@ -283,6 +313,7 @@ impl LK201Command {
 
             // Invalid commands return InputError
             LK201Command::Unknown(_) => LK201Response::InputError,
+            LK201Command::Malformed { .. } => LK201Response::InputError,
 
             // All other commands (LED, bell, click, autorepeat rate, etc.) have no response
             _ => return None,
@@ -298,30 +329,44 @@ impl TryFrom<&VecDeque<u8>> for LK201Command {
             return Err(());
         };
 
+        // Fetch the parameter byte at an index. The last parameter of a command
+        // has bit 7 (LK_PARAM) set and any earlier ones have it clear.
+        macro_rules! param {
+            ($index:expr, last = $last:expr) => {
+                match value.get($index) {
+                    None => return Err(()),
+                    Some(&b) if (b & 0x80 != 0) == $last => b,
+                    Some(_) => {
+                        return Ok(LK201Command::Malformed {
+                            command: byte0,
+                            len: $index + 1,
+                        });
+                    }
+                }
+            };
+        }
+
         match byte0 {
             // LED Control
             0x13 => {
-                let Some(&led_byte) = value.get(1) else {
-                    return Err(());
-                };
+                let led_byte = param!(1, last = true);
                 let led = Led::new(led_byte);
                 Ok(LK201Command::LedEnable(led))
             }
             0x11 => {
-                let Some(&led_byte) = value.get(1) else {
-                    return Err(());
-                };
+                let led_byte = param!(1, last = true);
                 let led = Led::new(led_byte);
                 Ok(LK201Command::LedDisable(led))
             }
 
             // Key Click Control
             0x1B => {
-                let Some(&vol_byte) = value.get(1) else {
-                    return Err(());
-                };
+                let vol_byte = param!(1, last = true);
                 let Some(volume) = Volume::new((vol_byte & 0x7) as u8) else {
-                    return Ok(LK201Command::Unknown2(0x1B, vol_byte));
+                    return Ok(LK201Command::Malformed {
+                        command: byte0,
+                        len: 2,
+                    });
                 };
                 Ok(LK201Command::KeyClickEnable(volume))
             }
@@ -332,11 +377,12 @@ impl TryFrom<&VecDeque<u8>> for LK201Command {
 
             // Bell Control
             0x23 => {
-                let Some(&vol_byte) = value.get(1) else {
-                    return Err(());
-                };
+                let vol_byte = param!(1, last = true);
                 let Some(volume) = Volume::new((vol_byte & 0x7) as u8) else {
-                    return Ok(LK201Command::Unknown2(0x23, vol_byte));
+                    return Ok(LK201Command::Malformed {
+                        command: byte0,
+                        len: 2,
+                    });
                 };
                 Ok(LK201Command::BellEnable(volume))
             }
@@ -345,14 +391,13 @@ impl TryFrom<&VecDeque<u8>> for LK201Command {
 
             // Autorepeat rate commands (0x78-0x7F range: bits 6-3 = 1111, bits 2-1 = register)
             0x78..=0x7F if (byte0 >> 3) & 0xF == 0xF => {
-                let Some(&timeout) = value.get(1) else {
-                    return Err(());
-                };
-                let Some(&rate) = value.get(2) else {
-                    return Err(());
-                };
+                let timeout = param!(1, last = false);
+                let rate = param!(2, last = true);
                 let Some(register) = AutoRepeatRegister::new((byte0 >> 1) & 0x3) else {
-                    return Ok(LK201Command::Unknown2(0x78, byte0));
+                    return Ok(LK201Command::Malformed {
+                        command: byte0,
+                        len: 3,
+                    });
                 };
                 Ok(LK201Command::SetAutoRepeat {
                     register,
@@ -386,11 +431,9 @@ impl TryFrom<&VecDeque<u8>> for LK201Command {
                     // Check PARAM bit (bit 7)
                     if has_param {
                         // PARAM = 0, parameter follows with autorepeat register
-                        let Some(&param_byte) = value.get(1) else {
-                            return Err(());
-                        };
+                        let param_byte = param!(1, last = true);
                         let Some(register) = AutoRepeatRegister::new(param_byte & 0x3) else {
-                            return Ok(LK201Command::Unknown2(b, param_byte));
+                            return Ok(LK201Command::Malformed { command: b, len: 2 });
                         };
                         return Ok(LK201Command::SetModeWithAutoRepeat {
                             mode,
@@ -411,6 +454,20 @@ impl TryFrom<&VecDeque<u8>> for LK201Command {
             0xD9 => Ok(LK201Command::RepeatToDown),
             0xD1 => Ok(LK201Command::TempNoRepeat),
 
+            // Decoded like the 1-byte repeat commands below, but followed by
+            // two parameters
+            0xE9 => {
+                let param1 = param!(1, last = false);
+                let param2 = param!(2, last = true);
+                let Some(division) = Division::new((byte0 >> 3) & 0b111) else {
+                    return Ok(LK201Command::Unknown(byte0));
+                };
+                Ok(LK201Command::DivisionRepeatControl {
+                    division,
+                    param1: param1 & 0x7F,
+                    param2: param2 & 0x7F,
+                })
+            }
             0xE1..0xEF => {
                 let division_bits = (byte0 >> 3) & 0b111;
                 let Some(division) = Division::new(division_bits) else {
@@ -586,72 +643,403 @@ impl From<PowerUpError> for u8 {
     }
 }
 
+/// The outcome of the keyboard's power-up self test.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SelfTestResult {
+    pub error: PowerUpError,
+    /// The key held down, for [`PowerUpError::KeyDownError`]
+    pub keycode: u8,
+}
+
+impl Default for SelfTestResult {
+    fn default() -> Self {
+        Self {
+            error: PowerUpError::NoError,
+            keycode: 0,
+        }
+    }
+}
+
+/// Keycodes captured from [`LK201Sender`] while recording is active.
+type Recording = Arc<Mutex<Option<Vec<u8>>>>;
+
+/// Where [`LK201Sender`] echoes typed characters while local echo is on.
+type LocalEcho = Arc<Mutex<Option<mpsc::Sender<u8>>>>;
+
+/// Whether the host has inhibited the keyboard, shared with every
+/// [`LK201Sender`].
+type Inhibit = Arc<Mutex<InhibitState>>;
+
+#[derive(Debug, Default)]
+struct InhibitState {
+    /// Keystrokes are dropped until the host resumes the keyboard.
+    inhibited: bool,
+    /// A keystroke was dropped since the keyboard was inhibited, which is
+    /// reported with [`LK201Response::OutputError`] on resume.
+    lost: bool,
+    /// Whether the Lock LED was lit before the keyboard was inhibited.
+    lock_led: bool,
+}
+
+/// A host key and the keycodes it was translated to, for debugging the
+/// keyboard mapping.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KeyLogEntry {
+    /// The host's name for the key
+    pub key: String,
+    /// The host modifiers held with the key
+    pub modifiers: String,
+    pub keycodes: Vec<u8>,
+}
+
+impl fmt::Display for KeyLogEntry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} [{}] ->", self.key, self.modifiers)?;
+        for keycode in &self.keycodes {
+            write!(f, " {keycode:02X}")?;
+        }
+        Ok(())
+    }
+}
+
+/// Host keys logged through [`LK201Sender::log_key`], when enabled.
+type KeyLog = Arc<Mutex<Vec<KeyLogEntry>>>;
+
+/// The serial line to the terminal, shared with every [`LK201Sender`] so it
+/// can be reconnected when the terminal is reset.
+type Line = Arc<Mutex<mpsc::Sender<u8>>>;
+
+fn send_line(line: &Line, byte: u8) {
+    if let Ok(send) = line.lock() {
+        _ = send.send(byte);
+    }
+}
+
+#[derive(Clone)]
 pub struct LK201Sender {
-    send: mpsc::Sender<u8>,
+    send: Line,
+    paste: mpsc::Sender<u8>,
+    recording: Recording,
+    local_echo: LocalEcho,
+    inhibit: Inhibit,
+    key_log: Option<KeyLog>,
+    layout: KeyboardLayout,
 }
 
 impl LK201Sender {
-    fn new(send: mpsc::Sender<u8>) -> Self {
-        Self { send }
+    fn new(
+        send: Line,
+        paste: mpsc::Sender<u8>,
+        recording: Recording,
+        local_echo: LocalEcho,
+        inhibit: Inhibit,
+        key_log: Option<KeyLog>,
+        layout: KeyboardLayout,
+    ) -> Self {
+        Self {
+            send,
+            paste,
+            recording,
+            local_echo,
+            inhibit,
+            key_log,
+            layout,
+        }
+    }
+
+    /// Send the keycodes for a host key with `f`, and if the key log is
+    /// enabled, record them against the key and modifiers it was translated
+    /// from. The names are only built when logging.
+    pub fn log_key<R>(
+        &self,
+        names: impl FnOnce() -> (String, String),
+        f: impl FnOnce(&Self) -> R,
+    ) -> R {
+        let Some(key_log) = &self.key_log else {
+            return f(self);
+        };
+        let (send, recv) = mpsc::channel();
+        let capture = Self::new(
+            Arc::new(Mutex::new(send)),
+            self.paste.clone(),
+            Recording::default(),
+            self.local_echo.clone(),
+            Inhibit::default(),
+            None,
+            self.layout,
+        );
+        let result = f(&capture);
+        let keycodes = recv.try_iter().collect::<Vec<_>>();
+        for &keycode in &keycodes {
+            self.send_byte(keycode);
+        }
+        let (key, modifiers) = names();
+        let entry = KeyLogEntry {
+            key,
+            modifiers,
+            keycodes,
+        };
+        trace!("KBD: {entry}");
+        key_log.lock().unwrap().push(entry);
+        result
+    }
+
+    /// Type pasted text, one keystroke at a time at a pace the firmware can
+    /// keep up with. Characters that aren't on the keyboard are skipped with
+    /// a warning.
+    pub fn paste(&self, text: &str) {
+        // Terminals and clipboards end lines with any of CR, LF and CRLF
+        let text = text.replace("\r\n", "\n").replace('\r', "\n");
+        for key in text_keycodes(&text, self.layout) {
+            _ = self.paste.send(key);
+        }
+    }
+
+    /// Send a keycode, unless the host has inhibited the keyboard, in which
+    /// case it is lost.
+    fn send_byte(&self, byte: u8) {
+        if let Ok(mut inhibit) = self.inhibit.lock() {
+            if inhibit.inhibited {
+                trace!("KBD: Inhibited, dropping {byte:02X}");
+                inhibit.lost = true;
+                return;
+            }
+        }
+        if let Ok(mut recording) = self.recording.lock() {
+            if let Some(recording) = recording.as_mut() {
+                recording.push(byte);
+            }
+        }
+        send_line(&self.send, byte);
+    }
+
+    /// Echo a typed character to the screen if local echo is on. Only
+    /// Latin-1 characters can be echoed, as the firmware would transmit them,
+    /// and nothing is echoed while the keyboard is inhibited.
+    pub fn echo(&self, c: char) {
+        let Ok(byte) = u8::try_from(c) else {
+            return;
+        };
+        if self.inhibit.lock().unwrap().inhibited {
+            return;
+        }
+        if let Some(local_echo) = self.local_echo.lock().unwrap().as_ref() {
+            _ = local_echo.send(byte);
+        }
     }
 
+    /// Press a special key. Return and Tab are echoed as CR and HT when
+    /// local echo is on.
     pub fn send_special_key(&self, key: SpecialKey) {
-        _ = self.send.send(key as u8);
+        self.send_byte(key as u8);
+        match key {
+            SpecialKey::Return => self.echo('\r'),
+            SpecialKey::Tab => self.echo('\t'),
+            _ => {}
+        }
     }
 
-    pub fn send_ctrl_char(&self, c: char) {
-        _ = self.send.send(0xaf); // ctrl
-        _ = self.send_char(c);
-        _ = self.send.send(0xb3); // all up
+    /// Type a character on the keyboard layout, with shift if needed.
+    pub fn send_char(&self, c: char) -> Result<(), ()> {
+        self.send_char_keys(c)?;
+        self.echo(c);
+        Ok(())
+    }
+
+    /// The keycode that types a character on the keyboard layout, and
+    /// whether it needs shift.
+    pub fn char_key(&self, c: char) -> Option<(u8, bool)> {
+        self.layout.key(c)
+    }
+
+    /// Release every key that is held down, including shift and ctrl.
+    pub fn send_all_up(&self) {
+        self.send_byte(0xb3);
     }
 
-    pub fn send_ctrl_special_key(&self, key: SpecialKey) {
-        _ = self.send.send(0xaf); // ctrl
-        _ = self.send.send(key as u8);
-        _ = self.send.send(0xb3); // all up
+    /// Press the keys for a character without echoing it.
+    fn send_char_keys(&self, c: char) -> Result<(), ()> {
+        let (keycode, shift) = self.layout.key(c).ok_or(())?;
+        if shift {
+            self.send_byte(0xae); // shift
+            self.send_byte(keycode);
+            self.send_byte(0xb3); // all up
+        } else {
+            self.send_byte(keycode);
+        }
+        Ok(())
     }
 
-    pub fn send_shift_special_key(&self, key: SpecialKey) {
-        _ = self.send.send(0xae); // shift
-        _ = self.send.send(key as u8);
-        _ = self.send.send(0xb3); // all up
+    /// Send raw keycodes, unchanged.
+    pub fn send_keycodes(&self, keycodes: &[u8]) {
+        for &keycode in keycodes {
+            self.send_byte(keycode);
+        }
     }
 
-    pub fn send_shift_ctrl_special_key(&self, key: SpecialKey) {
-        _ = self.send.send(0xaf); // ctrl
-        _ = self.send.send(0xae); // shift
-        _ = self.send.send(key as u8);
-        _ = self.send.send(0xb3); // all up
+    pub fn send_ctrl_char(&self, c: char) {
+        self.send_byte(0xaf); // ctrl
+        _ = self.send_char_keys(c);
+        self.send_byte(0xb3); // all up
     }
 
     pub fn send_escape(&self) {
-        _ = self.send.send(0xaf); // ctrl
-        _ = self.send.send(0xcb); // 3
-        _ = self.send.send(0xb3); // all up
+        self.send_byte(0xaf); // ctrl
+        self.send_byte(0xcb); // 3
+        self.send_byte(0xb3); // all up
     }
 }
 
-macro_rules! def_char_keys {
-    ($($keycode:literal => $char:literal $( $char_shift:literal )?;)*) => {
-        impl LK201Sender {
-            pub fn send_char(&self, c: char) -> Result<(), ()> {
-                match c {
-                $(
-                    $char => Ok(_ = (self.send.send($keycode))),
-                    $(
-                        $char_shift => Ok(_ = (
-                            (self.send.send(0xae), self.send.send($keycode), self.send.send(0xb3))
-                        )),
-                    )?
-                )*
-                _ => Err(()),
+/// The two-key compose sequences the VT420 firmware uses for the DEC
+/// Multinational characters that are also in Latin-1. The firmware accepts the
+/// keys in either order.
+const COMPOSE_SEQUENCES: &[(char, char, char)] = &[
+    ('`', 'A', 'À'),
+    ('\'', 'A', 'Á'),
+    ('^', 'A', 'Â'),
+    ('~', 'A', 'Ã'),
+    ('"', 'A', 'Ä'),
+    ('*', 'A', 'Å'),
+    ('A', 'E', 'Æ'),
+    (',', 'C', 'Ç'),
+    ('`', 'E', 'È'),
+    ('\'', 'E', 'É'),
+    ('^', 'E', 'Ê'),
+    ('"', 'E', 'Ë'),
+    ('`', 'I', 'Ì'),
+    ('\'', 'I', 'Í'),
+    ('^', 'I', 'Î'),
+    ('"', 'I', 'Ï'),
+    ('~', 'N', 'Ñ'),
+    ('`', 'O', 'Ò'),
+    ('\'', 'O', 'Ó'),
+    ('^', 'O', 'Ô'),
+    ('~', 'O', 'Õ'),
+    ('"', 'O', 'Ö'),
+    ('/', 'O', 'Ø'),
+    ('`', 'U', 'Ù'),
+    ('\'', 'U', 'Ú'),
+    ('^', 'U', 'Û'),
+    ('"', 'U', 'Ü'),
+    ('`', 'a', 'à'),
+    ('\'', 'a', 'á'),
+    ('^', 'a', 'â'),
+    ('~', 'a', 'ã'),
+    ('"', 'a', 'ä'),
+    ('*', 'a', 'å'),
+    ('a', 'e', 'æ'),
+    (',', 'c', 'ç'),
+    ('`', 'e', 'è'),
+    ('\'', 'e', 'é'),
+    ('^', 'e', 'ê'),
+    ('"', 'e', 'ë'),
+    ('`', 'i', 'ì'),
+    ('\'', 'i', 'í'),
+    ('^', 'i', 'î'),
+    ('"', 'i', 'ï'),
+    ('~', 'n', 'ñ'),
+    ('`', 'o', 'ò'),
+    ('\'', 'o', 'ó'),
+    ('^', 'o', 'ô'),
+    ('~', 'o', 'õ'),
+    ('"', 'o', 'ö'),
+    ('/', 'o', 'ø'),
+    ('`', 'u', 'ù'),
+    ('\'', 'u', 'ú'),
+    ('^', 'u', 'û'),
+    ('"', 'u', 'ü'),
+    ('"', 'y', 'ÿ'),
+    ('s', 's', 'ß'),
+    ('!', '!', '¡'),
+    ('?', '?', '¿'),
+    ('c', '/', '¢'),
+    ('L', '-', '£'),
+    ('Y', '=', '¥'),
+    ('s', 'o', '§'),
+    ('c', 'o', '©'),
+    ('<', '<', '«'),
+    ('>', '>', '»'),
+    ('0', '^', '°'),
+    ('+', '-', '±'),
+    ('1', '2', '½'),
+    ('1', '4', '¼'),
+];
+
+/// The character the firmware composes from two keys, if any.
+pub fn compose(first: char, second: char) -> Option<char> {
+    COMPOSE_SEQUENCES
+        .iter()
+        .find(|&&(a, b, _)| (a, b) == (first, second) || (b, a) == (first, second))
+        .map(|&(_, _, c)| c)
+}
+
+impl LK201Sender {
+    /// Press Compose followed by two keys, for the firmware to combine into a
+    /// single character. Fails without sending anything if the keys don't
+    /// form a sequence.
+    pub fn send_compose(&self, first: char, second: char) -> Result<(), ()> {
+        let composed = compose(first, second).ok_or(())?;
+        self.send_special_key(SpecialKey::Meta);
+        self.send_char_keys(first)?;
+        self.send_char_keys(second)?;
+        self.echo(composed);
+        Ok(())
+    }
+
+    /// Type a character that isn't on the keyboard with a compose sequence.
+    pub fn send_composed_char(&self, c: char) -> Result<(), ()> {
+        let &(first, second, _) = COMPOSE_SEQUENCES
+            .iter()
+            .find(|&&(_, _, composed)| composed == c)
+            .ok_or(())?;
+        self.send_compose(first, second)
+    }
+}
+
+/// The keycodes for typing `text`. Characters that aren't on the keyboard
+/// are skipped with a warning.
+fn text_keycodes(text: &str, layout: KeyboardLayout) -> Vec<u8> {
+    let (send, recv) = mpsc::channel();
+    let sender = LK201Sender::new(
+        Arc::new(Mutex::new(send.clone())),
+        send,
+        Recording::default(),
+        LocalEcho::default(),
+        Inhibit::default(),
+        None,
+        layout,
+    );
+    for c in text.chars() {
+        match c {
+            '\n' => sender.send_special_key(SpecialKey::Return),
+            '\t' => sender.send_special_key(SpecialKey::Tab),
+            '\r' => {}
+            c => {
+                if sender.send_char(c).is_err() {
+                    warn!("KBD: Can't type {c:?}, skipping");
                 }
             }
         }
+    }
+    recv.try_iter().collect()
+}
+
+/// Define a layout as a table of keycodes with the characters typed with and
+/// without shift.
+macro_rules! def_char_keys {
+    ($name:ident; $($keycode:literal => $char:literal $( $char_shift:literal )?;)*) => {
+        const $name: &[(u8, char, Option<char>)] = &[
+        $(
+            ($keycode, $char, def_char_keys!(@shifted $($char_shift)?)),
+        )*
+        ];
     };
+    (@shifted) => { None };
+    (@shifted $char_shift:literal) => { Some($char_shift) };
 }
 
 def_char_keys!(
+US_KEYS;
 0xbf => '`' '~';
 0xc0 => '1' '!';
 0xc5 => '2' '@';
@@ -707,13 +1095,131 @@ def_char_keys!(
 0xd4 => ' ';
 );
 
+// The national layouts only list the keys whose legends differ from US, and
+// follow the usual typewriter layout for each language. The German and French
+// tables haven't been checked against the firmware's keyboard tables.
+def_char_keys!(
+UK_KEYS;
+0xcb => '3' '£';
+);
+
+def_char_keys!(
+GERMAN_KEYS;
+0xbf => '^' '°';
+0xc5 => '2' '"';
+0xcb => '3' '§';
+0xdb => '6' '&';
+0xe0 => '7' '/';
+0xe5 => '8' '(';
+0xea => '9' ')';
+0xef => '0' '=';
+0xf9 => 'ß' '?';
+0xf5 => '´' '`';
+0xdc => 'z' 'Z';
+0xfa => 'ü' 'Ü';
+0xf6 => '+' '*';
+0xf2 => 'ö' 'Ö';
+0xfb => 'ä' 'Ä';
+0xf7 => '#' '\'';
+0xc3 => 'y' 'Y';
+0xe8 => ',' ';';
+0xed => '.' ':';
+0xf3 => '-' '_';
+);
+
+def_char_keys!(
+FRENCH_KEYS;
+0xbf => '²';
+0xc0 => '&' '1';
+0xc5 => 'é' '2';
+0xcb => '"' '3';
+0xd0 => '\'' '4';
+0xd6 => '(' '5';
+0xdb => '-' '6';
+0xe0 => 'è' '7';
+0xe5 => '_' '8';
+0xea => 'ç' '9';
+0xef => 'à' '0';
+0xf9 => ')' '°';
+0xc1 => 'a' 'A';
+0xc6 => 'z' 'Z';
+0xfa => '^' '¨';
+0xf6 => '$' '£';
+0xc2 => 'q' 'Q';
+0xf2 => 'm' 'M';
+0xfb => 'ù' '%';
+0xf7 => '*' 'µ';
+0xc3 => 'w' 'W';
+0xe3 => ',' '?';
+0xe8 => ';' '.';
+0xed => ':' '/';
+0xf3 => '!' '§';
+);
+
+/// The national keyboard layout, which decides the keys typed for each
+/// character. It must match the keyboard selected in Set-Up, since the
+/// firmware translates keycodes to characters by that setting.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum KeyboardLayout {
+    #[default]
+    Us,
+    Uk,
+    German,
+    French,
+}
+
+impl KeyboardLayout {
+    /// The keycode for a character, and whether it is typed with shift. Keys
+    /// that a layout doesn't list keep their US legends.
+    pub fn key(self, c: char) -> Option<(u8, bool)> {
+        let national = match self {
+            Self::Us => return table_key(US_KEYS, c),
+            Self::Uk => UK_KEYS,
+            Self::German => GERMAN_KEYS,
+            Self::French => FRENCH_KEYS,
+        };
+        table_key(national, c).or_else(|| {
+            table_key(US_KEYS, c)
+                .filter(|&(keycode, _)| !national.iter().any(|&(key, ..)| key == keycode))
+        })
+    }
+}
+
+fn table_key(table: &[(u8, char, Option<char>)], c: char) -> Option<(u8, bool)> {
+    table.iter().find_map(|&(keycode, plain, shifted)| {
+        if plain == c {
+            Some((keycode, false))
+        } else if shifted == Some(c) {
+            Some((keycode, true))
+        } else {
+            None
+        }
+    })
+}
+
+/// Number of ticks between keystrokes when playing back a recording, so the
+/// firmware has time to process each one.
+const PLAYBACK_KEYSTROKE_TICKS: usize = 20_000;
+
 pub struct LK201 {
     recv: mpsc::Receiver<u8>,
-    send: mpsc::Sender<u8>,
+    send: Line,
+    /// Keycodes pasted through an [`LK201Sender`], waiting to join the playback
+    paste_send: mpsc::Sender<u8>,
+    paste_recv: mpsc::Receiver<u8>,
     kbd_queue: VecDeque<u8>,
     collect_commands: bool,
     collected_bytes: Vec<u8>,
     collected_commands: Vec<LK201Command>,
+    recording: Recording,
+    local_echo: LocalEcho,
+    inhibit: Inhibit,
+    key_log: Option<KeyLog>,
+    playback: VecDeque<u8>,
+    playback_delay: usize,
+    leds: Led,
+    self_test: SelfTestResult,
+    layout: KeyboardLayout,
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -777,16 +1283,179 @@ pub enum SpecialKey {
     F20 = 0x83,
 }
 
+impl SpecialKey {
+    /// Look up a key by its name, eg: `F3` or `KpEnter`, ignoring case.
+    pub fn from_name(name: &str) -> Option<Self> {
+        let key = match name.to_ascii_lowercase().as_str() {
+            "kp0" => Self::Kp0,
+            "kpperiod" => Self::KpPeriod,
+            "kpenter" => Self::KpEnter,
+            "kp1" => Self::Kp1,
+            "kp2" => Self::Kp2,
+            "kp3" => Self::Kp3,
+            "kp4" => Self::Kp4,
+            "kp5" => Self::Kp5,
+            "kp6" => Self::Kp6,
+            "kpcomma" => Self::KpComma,
+            "kp7" => Self::Kp7,
+            "kp8" => Self::Kp8,
+            "kp9" => Self::Kp9,
+            "kphyphen" => Self::KpHyphen,
+            "kppf1" => Self::KpPf1,
+            "kppf2" => Self::KpPf2,
+            "kppf3" => Self::KpPf3,
+            "kppf4" => Self::KpPf4,
+            "delete" => Self::Delete,
+            "return" => Self::Return,
+            "tab" => Self::Tab,
+            "lock" => Self::Lock,
+            "meta" => Self::Meta,
+            "shift" => Self::Shift,
+            "ctrl" => Self::Ctrl,
+            "left" => Self::Left,
+            "right" => Self::Right,
+            "down" => Self::Down,
+            "up" => Self::Up,
+            "rshift" => Self::RShift,
+            "find" => Self::Find,
+            "inserthere" => Self::InsertHere,
+            "remove" => Self::Remove,
+            "select" => Self::Select,
+            "prevscreen" => Self::PrevScreen,
+            "nextscreen" => Self::NextScreen,
+            "f1" => Self::F1,
+            "f2" => Self::F2,
+            "f3" => Self::F3,
+            "f4" => Self::F4,
+            "f5" => Self::F5,
+            "f6" => Self::F6,
+            "f7" => Self::F7,
+            "f8" => Self::F8,
+            "f9" => Self::F9,
+            "f10" => Self::F10,
+            "f11" => Self::F11,
+            "f12" => Self::F12,
+            "f13" => Self::F13,
+            "f14" => Self::F14,
+            "help" => Self::Help,
+            "menu" => Self::Menu,
+            "f17" => Self::F17,
+            "f18" => Self::F18,
+            "f19" => Self::F19,
+            "f20" => Self::F20,
+            _ => return None,
+        };
+        Some(key)
+    }
+}
+
 impl LK201 {
     pub fn new(send: mpsc::Sender<u8>, recv: mpsc::Receiver<u8>) -> Self {
+        let (paste_send, paste_recv) = mpsc::channel();
         Self {
-            send,
+            send: Arc::new(Mutex::new(send)),
             recv,
+            paste_send,
+            paste_recv,
             kbd_queue: VecDeque::new(),
             collect_commands: false,
             collected_bytes: Vec::new(),
             collected_commands: Vec::new(),
+            recording: Arc::new(Mutex::new(None)),
+            local_echo: LocalEcho::default(),
+            inhibit: Inhibit::default(),
+            key_log: None,
+            playback: VecDeque::new(),
+            playback_delay: 0,
+            leds: Led(0),
+            self_test: SelfTestResult::default(),
+            layout: KeyboardLayout::default(),
+        }
+    }
+
+    /// Connect to a new serial line, after the terminal is reset. Existing
+    /// senders follow, and bytes the terminal sent on the old line are lost.
+    pub fn reconnect(&mut self, send: mpsc::Sender<u8>, recv: mpsc::Receiver<u8>) {
+        if let Ok(mut line) = self.send.lock() {
+            *line = send;
+        }
+        self.recv = recv;
+        self.kbd_queue.clear();
+    }
+
+    /// Type characters on `layout` from now on, for senders created after
+    /// this and text queued with [`LK201::type_after`].
+    pub fn set_layout(&mut self, layout: KeyboardLayout) {
+        self.layout = layout;
+    }
+
+    /// Report `result` from the power-up self test, to exercise the
+    /// firmware's handling of keyboard errors.
+    pub fn set_self_test(&mut self, result: SelfTestResult) {
+        self.self_test = result;
+    }
+
+    /// Start capturing the raw keycodes sent through any [`LK201Sender`].
+    pub fn start_recording(&mut self) {
+        *self.recording.lock().unwrap() = Some(Vec::new());
+    }
+
+    /// Stop capturing keycodes and return everything captured so far.
+    pub fn stop_recording(&mut self) -> Vec<u8> {
+        self.recording.lock().unwrap().take().unwrap_or_default()
+    }
+
+    pub fn is_recording(&self) -> bool {
+        self.recording.lock().unwrap().is_some()
+    }
+
+    /// Echo the characters typed through any [`LK201Sender`] from now on,
+    /// for a half-duplex host that doesn't echo them itself. Returns the
+    /// bytes to show as if received from the host. Pasted text and keys
+    /// played back from text or recordings aren't echoed.
+    pub fn enable_local_echo(&mut self) -> mpsc::Receiver<u8> {
+        let (send, recv) = mpsc::channel();
+        *self.local_echo.lock().unwrap() = Some(send);
+        recv
+    }
+
+    /// Log every host key sent through an [`LK201Sender`] from now on, along
+    /// with the keycodes it was translated to.
+    pub fn enable_key_log(&mut self) {
+        self.key_log.get_or_insert_default();
+    }
+
+    /// The host keys logged so far, oldest first.
+    pub fn key_log(&self) -> Vec<KeyLogEntry> {
+        self.key_log
+            .as_ref()
+            .map(|key_log| key_log.lock().unwrap().clone())
+            .unwrap_or_default()
+    }
+
+    /// Write the key log, one key per line.
+    pub fn write_key_log(&self, mut writer: impl Write) -> io::Result<()> {
+        for entry in self.key_log() {
+            writeln!(writer, "{entry}")?;
         }
+        writer.flush()
+    }
+
+    /// Queue raw keycodes for playback, one keystroke at a time.
+    pub fn play(&mut self, keys: &[u8]) {
+        self.playback.extend(keys);
+    }
+
+    /// Queue raw keycodes for playback after waiting for `ticks` ticks.
+    pub fn play_after(&mut self, ticks: usize, keys: &[u8]) {
+        self.playback_delay = self.playback_delay.max(ticks);
+        self.play(keys);
+    }
+
+    /// Queue text to be typed after waiting for `ticks` ticks. Characters
+    /// that aren't on the keyboard are skipped with a warning.
+    pub fn type_after(&mut self, ticks: usize, text: &str) {
+        self.play_after(ticks, &text_keycodes(text, self.layout));
     }
 
     pub fn start_collecting_commands(&mut self) {
@@ -801,11 +1470,43 @@ impl LK201 {
         )
     }
 
+    /// The LEDs currently lit by the host.
+    pub fn leds(&self) -> Led {
+        self.leds
+    }
+
+    /// Whether the host has inhibited the keyboard, so that keystrokes are
+    /// lost.
+    pub fn is_inhibited(&self) -> bool {
+        self.inhibit.lock().unwrap().inhibited
+    }
+
     pub fn sender(&self) -> LK201Sender {
-        LK201Sender::new(self.send.clone())
+        LK201Sender::new(
+            self.send.clone(),
+            self.paste_send.clone(),
+            self.recording.clone(),
+            self.local_echo.clone(),
+            self.inhibit.clone(),
+            self.key_log.clone(),
+            self.layout,
+        )
     }
 
     pub fn tick(&mut self) {
+        self.playback.extend(self.paste_recv.try_iter());
+        // Hold playback while the host has locked the keyboard, eg: when it
+        // can't send because of XOFF, or has inhibited it
+        if !self.playback.is_empty() && !self.leds.is_wait() && !self.is_inhibited() {
+            if self.playback_delay > 0 {
+                self.playback_delay -= 1;
+            } else if let Some(key) = self.playback.pop_front() {
+                trace!("KBD: Playback {key:02X}");
+                send_line(&self.send, key);
+                self.playback_delay = PLAYBACK_KEYSTROKE_TICKS;
+            }
+        }
+
         // Accumulate incoming bytes
         let mut received = false;
         while let Ok(byte) = self.recv.try_recv() {
@@ -835,20 +1536,52 @@ impl LK201 {
 
         trace!("KBD: Command {:?}", command);
 
+        let mut lost = false;
+        match command {
+            LK201Command::LedEnable(led) => self.leds = Led(self.leds.0 | led.0 & 0x0f),
+            LK201Command::LedDisable(led) => self.leds = Led(self.leds.0 & !led.0 & 0x0f),
+            LK201Command::PowerUp => {
+                self.leds = Led(0);
+                *self.inhibit.lock().unwrap() = InhibitState::default();
+            }
+            LK201Command::Inhibit => {
+                let mut inhibit = self.inhibit.lock().unwrap();
+                if !inhibit.inhibited {
+                    inhibit.inhibited = true;
+                    inhibit.lock_led = self.leds.is_lock();
+                    self.leds = Led(self.leds.0 | 0x04);
+                }
+            }
+            LK201Command::Resume => {
+                let inhibit = std::mem::take(&mut *self.inhibit.lock().unwrap());
+                if inhibit.inhibited && !inhibit.lock_led {
+                    self.leds = Led(self.leds.0 & !0x04);
+                }
+                lost = inhibit.lost;
+            }
+            _ => {}
+        }
+
         // Remove the command bytes from the queue
         for _ in 0..cmd_len {
             self.kbd_queue.pop_front();
         }
 
         // Send response if the command has one
-        if let Some(response) = command.response() {
+        if let Some(response) = command.response_with_self_test(self.self_test) {
             trace!(
                 "KBD: Sending response {:?} = {:02X?}",
                 response,
                 response.to_bytes()
             );
             for byte in response.to_bytes() {
-                _ = self.send.send(byte);
+                send_line(&self.send, byte);
+            }
+        }
+        if lost {
+            trace!("KBD: Keystrokes were lost while inhibited");
+            for byte in LK201Response::OutputError.to_bytes() {
+                send_line(&self.send, byte);
             }
         }
     }
@@ -997,6 +1730,14 @@ mod tests {
                 division: Division(4),
             },
         );
+        test_parse(
+            &[0xE9, 0x12, 0x85],
+            LK201Command::DivisionRepeatControl {
+                division: Division(5),
+                param1: 0x12,
+                param2: 0x05,
+            },
+        );
         test_parse(&[0xD9], LK201Command::RepeatToDown);
         test_parse(&[0xD1], LK201Command::TempNoRepeat);
         test_parse(&[0xD3], LK201Command::SetDefaults);
@@ -1015,6 +1756,72 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_malformed_commands() {
+        // Final parameter without LK_PARAM set
+        test_parse(
+            &[0x13, 0x04],
+            LK201Command::Malformed {
+                command: 0x13,
+                len: 2,
+            },
+        );
+        // Intermediate parameter with LK_PARAM set
+        test_parse(
+            &[0x7A, 0xE4],
+            LK201Command::Malformed {
+                command: 0x7A,
+                len: 2,
+            },
+        );
+        test_parse(
+            &[0x7A, 0x64, 0x1E],
+            LK201Command::Malformed {
+                command: 0x7A,
+                len: 3,
+            },
+        );
+        test_parse(
+            &[0xE9, 0x92],
+            LK201Command::Malformed {
+                command: 0xE9,
+                len: 2,
+            },
+        );
+        assert_eq!(
+            LK201Command::Malformed {
+                command: 0x13,
+                len: 2
+            }
+            .response(),
+            Some(LK201Response::InputError)
+        );
+    }
+
+    #[test]
+    fn test_parse_all_short_sequences() {
+        let mut queue = VecDeque::with_capacity(3);
+        let mut check = |bytes: &[u8]| {
+            queue.clear();
+            queue.extend(bytes);
+            if let Ok(command) = LK201Command::try_from(&queue) {
+                assert!(
+                    (1..=bytes.len()).contains(&command.len()),
+                    "input: {bytes:02X?}, command: {command:?}"
+                );
+            }
+        };
+        for a in 0..=255 {
+            check(&[a]);
+            for b in 0..=255 {
+                check(&[a, b]);
+                for c in 0..=255 {
+                    check(&[a, b, c]);
+                }
+            }
+        }
+    }
+
     #[test]
     fn test_unknown_commands() {
         // Test that invalid command bytes are parsed as Unknown
@@ -1074,6 +1881,14 @@ mod tests {
         let resp = cmd.response().unwrap();
         assert_eq!(resp.to_bytes(), vec![0xBA]);
 
+        let cmd = LK201Command::DivisionRepeatControl {
+            division: Division(5),
+            param1: 0x12,
+            param2: 0x05,
+        };
+        let resp = cmd.response().unwrap();
+        assert_eq!(resp.to_bytes(), vec![0xBA]);
+
         // Special acks
         let cmd = LK201Command::TestMode;
         let resp = cmd.response().unwrap();
@@ -1127,6 +1942,246 @@ mod tests {
         assert_eq!(resp.to_bytes(), vec![0xB9, 0x42]);
     }
 
+    #[test]
+    fn test_record_and_play() {
+        let (in_tx, in_rx) = mpsc::channel();
+        let (_out_tx, out_rx) = mpsc::channel();
+        let mut lk201 = LK201::new(in_tx, out_rx);
+
+        lk201.start_recording();
+        lk201.sender().send_char('A').unwrap();
+        lk201.sender().send_special_key(SpecialKey::F3);
+        let recording = lk201.stop_recording();
+        assert_eq!(recording, vec![0xae, 0xc2, 0xb3, 0x58]);
+        assert!(!lk201.is_recording());
+        assert_eq!(in_rx.try_iter().collect::<Vec<_>>(), recording);
+
+        lk201.play(&recording);
+        for _ in 0..PLAYBACK_KEYSTROKE_TICKS * 4 + 1 {
+            lk201.tick();
+        }
+        assert_eq!(in_rx.try_iter().collect::<Vec<_>>(), recording);
+    }
+
+    #[test]
+    fn test_type_text() {
+        let (in_tx, in_rx) = mpsc::channel();
+        let (_out_tx, out_rx) = mpsc::channel();
+        let mut lk201 = LK201::new(in_tx, out_rx);
+
+        lk201.type_after(10, "Hi\r\n\u{e9}?");
+        let keys = [0xae, 0xdd, 0xb3, 0xe6, 0xbd, 0xae, 0xf3, 0xb3];
+        for _ in 0..10 {
+            lk201.tick();
+        }
+        assert_eq!(in_rx.try_iter().count(), 0);
+        for _ in 0..PLAYBACK_KEYSTROKE_TICKS * keys.len() + 1 {
+            lk201.tick();
+        }
+        assert_eq!(in_rx.try_iter().collect::<Vec<_>>(), keys);
+    }
+
+    #[test]
+    fn test_self_test_result() {
+        for (error, keycode, expected) in [
+            (PowerUpError::NoError, 0x00, [0x01, 0x00, 0x00, 0x00]),
+            (PowerUpError::KeyDownError, 0xc2, [0x01, 0x00, 0x3d, 0xc2]),
+            (PowerUpError::PowerError, 0x00, [0x01, 0x00, 0x3e, 0x00]),
+        ] {
+            let (in_tx, in_rx) = mpsc::channel();
+            let (out_tx, out_rx) = mpsc::channel();
+            let mut lk201 = LK201::new(in_tx, out_rx);
+            lk201.set_self_test(SelfTestResult { error, keycode });
+
+            out_tx.send(0xfd).unwrap();
+            lk201.tick();
+            assert_eq!(in_rx.try_iter().collect::<Vec<_>>(), expected, "{error:?}");
+        }
+    }
+
+    #[test]
+    fn test_paste() {
+        let (in_tx, in_rx) = mpsc::channel();
+        let (out_tx, out_rx) = mpsc::channel();
+        let mut lk201 = LK201::new(in_tx, out_rx);
+
+        let text = "echo pasted line one\necho line two\n".repeat(4);
+        let keys = text_keycodes(&text, KeyboardLayout::Us);
+        lk201.sender().paste(&text);
+        assert_eq!(in_rx.try_iter().count(), 0);
+
+        // The host locks the keyboard partway through
+        let mut received = vec![];
+        for _ in 0..keys.len() / 2 * PLAYBACK_KEYSTROKE_TICKS {
+            lk201.tick();
+            received.extend(in_rx.try_iter());
+        }
+        out_tx.send(0x13).unwrap();
+        out_tx.send(0x81).unwrap();
+        lk201.tick();
+        received.extend(in_rx.try_iter());
+        let locked_at = received.len();
+        for _ in 0..PLAYBACK_KEYSTROKE_TICKS * 4 {
+            lk201.tick();
+        }
+        assert_eq!(in_rx.try_iter().count(), 0);
+        assert!(locked_at < keys.len());
+
+        // Nothing is lost once it's unlocked again
+        out_tx.send(0x11).unwrap();
+        out_tx.send(0x81).unwrap();
+        for _ in 0..(keys.len() + 1) * PLAYBACK_KEYSTROKE_TICKS {
+            lk201.tick();
+            received.extend(in_rx.try_iter());
+        }
+        assert_eq!(received, keys);
+    }
+
+    #[test]
+    fn test_compose() {
+        let (in_tx, in_rx) = mpsc::channel();
+        let (_out_tx, out_rx) = mpsc::channel();
+        let lk201 = LK201::new(in_tx, out_rx);
+
+        assert_eq!(compose('\'', 'e'), Some('é'));
+        assert_eq!(compose('e', '\''), Some('é'));
+        assert_eq!(compose('q', 'q'), None);
+
+        lk201.sender().send_composed_char('é').unwrap();
+        lk201.sender().send_compose('"', 'U').unwrap();
+        assert!(lk201.sender().send_compose('q', 'q').is_err());
+        assert!(lk201.sender().send_composed_char('\u{263a}').is_err());
+        assert_eq!(
+            in_rx.try_iter().collect::<Vec<_>>(),
+            [
+                0xb1, 0xfb, 0xcc, // Compose ' e
+                0xb1, 0xae, 0xfb, 0xb3, 0xae, 0xe1, 0xb3, // Compose " U
+            ]
+        );
+    }
+
+    #[test]
+    fn test_local_echo() {
+        let (in_tx, _in_rx) = mpsc::channel();
+        let (_out_tx, out_rx) = mpsc::channel();
+        let mut lk201 = LK201::new(in_tx, out_rx);
+
+        lk201.sender().send_char('x').unwrap();
+        let sender = lk201.sender();
+        let echo = lk201.enable_local_echo();
+        // Senders created before local echo was enabled echo too
+        sender.send_char('A').unwrap();
+        lk201.sender().send_ctrl_char('c');
+        lk201.sender().send_special_key(SpecialKey::F3);
+        lk201.sender().send_special_key(SpecialKey::Return);
+        lk201.sender().send_composed_char('é').unwrap();
+        lk201.sender().paste("pasted");
+        assert_eq!(echo.try_iter().collect::<Vec<_>>(), b"A\r\xe9");
+    }
+
+    #[test]
+    fn test_layouts() {
+        use KeyboardLayout::*;
+
+        // Z and Y swap places on a German keyboard
+        assert_eq!(Us.key('z'), Some((0xc3, false)));
+        assert_eq!(Us.key('y'), Some((0xdc, false)));
+        assert_eq!(German.key('z'), Some((0xdc, false)));
+        assert_eq!(German.key('Y'), Some((0xc3, true)));
+        // Keys that aren't listed keep their US legends, unless they moved
+        assert_eq!(German.key('q'), Some((0xc1, false)));
+        assert_eq!(German.key('ö'), Some((0xf2, false)));
+        assert_eq!(German.key('['), None);
+        assert_eq!(Us.key('ö'), None);
+
+        assert_eq!(Uk.key('£'), Some((0xcb, true)));
+        assert_eq!(Uk.key('#'), None);
+        assert_eq!(French.key('a'), Some((0xc1, false)));
+        assert_eq!(French.key('1'), Some((0xc0, true)));
+        assert_eq!(French.key('m'), Some((0xf2, false)));
+
+        // No character is typed by two keys
+        for layout in [Us, Uk, German, French] {
+            let mut keys = std::collections::HashSet::new();
+            for c in (' '..='~').chain("£§°´ßäöüÄÖÜ²éèçàù¨µ".chars()) {
+                if let Some(key) = layout.key(c) {
+                    assert!(keys.insert(key), "{layout:?} {c:?}");
+                }
+            }
+        }
+
+        let (in_tx, in_rx) = mpsc::channel();
+        let (_out_tx, out_rx) = mpsc::channel();
+        let mut lk201 = LK201::new(in_tx, out_rx);
+        lk201.set_layout(German);
+        lk201.sender().send_char('Z').unwrap();
+        assert_eq!(in_rx.try_iter().collect::<Vec<_>>(), [0xae, 0xdc, 0xb3]);
+    }
+
+    #[test]
+    fn test_leds() {
+        let (in_tx, _in_rx) = mpsc::channel();
+        let (out_tx, out_rx) = mpsc::channel();
+        let mut lk201 = LK201::new(in_tx, out_rx);
+        assert_eq!(lk201.leds(), Led::new(0));
+
+        for byte in [0x13, 0x84] {
+            out_tx.send(byte).unwrap();
+        }
+        lk201.tick();
+        assert!(lk201.leds().is_lock());
+        assert!(!lk201.leds().is_hold());
+
+        for byte in [0x13, 0x89, 0x11, 0x84] {
+            out_tx.send(byte).unwrap();
+            lk201.tick();
+        }
+        let leds = lk201.leds();
+        assert!(!leds.is_lock());
+        assert!(leds.is_wait() && leds.is_hold());
+    }
+
+    #[test]
+    fn test_inhibit() {
+        let (in_tx, in_rx) = mpsc::channel();
+        let (out_tx, out_rx) = mpsc::channel();
+        let mut lk201 = LK201::new(in_tx, out_rx);
+        let sender = lk201.sender();
+
+        // Inhibit turns on the Lock LED and is acknowledged
+        out_tx.send(0x89).unwrap();
+        lk201.tick();
+        assert!(lk201.is_inhibited());
+        assert!(lk201.leds().is_lock());
+        assert_eq!(in_rx.try_iter().collect::<Vec<_>>(), [0xb7]);
+
+        // The key is lost
+        sender.send_char('a').unwrap();
+        assert_eq!(in_rx.try_iter().count(), 0);
+
+        // Resume reports the lost key and turns off the Lock LED
+        out_tx.send(0x8b).unwrap();
+        lk201.tick();
+        assert!(!lk201.is_inhibited());
+        assert!(!lk201.leds().is_lock());
+        assert_eq!(in_rx.try_iter().collect::<Vec<_>>(), [0xb5]);
+
+        // Keys are sent again, and a second resume has nothing to report
+        sender.send_char('a').unwrap();
+        assert_eq!(in_rx.try_iter().collect::<Vec<_>>(), [0xc2]);
+        out_tx.send(0x8b).unwrap();
+        lk201.tick();
+        assert_eq!(in_rx.try_iter().count(), 0);
+
+        // A Lock LED lit by the host stays lit after resume
+        for byte in [0x13, 0x84, 0x89, 0x8b] {
+            out_tx.send(byte).unwrap();
+            lk201.tick();
+        }
+        assert!(lk201.leds().is_lock());
+        assert_eq!(in_rx.try_iter().collect::<Vec<_>>(), [0xb7]);
+    }
+
     #[test]
     fn test_full_sequence() {
         // Test parsing a complete initialization sequence
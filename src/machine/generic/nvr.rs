@@ -46,6 +46,16 @@ impl Nvr {
         }
     }
 
+    /// Return the serial interface to idle, as after a power cycle. The
+    /// contents are nonvolatile, so they are kept.
+    pub fn reset(&mut self) {
+        *self = Self {
+            mem: self.mem,
+            write_count: self.write_count,
+            ..Self::new()
+        };
+    }
+
     /// Tick the NVR with current bus lines and return (DO, READY).
     ///
     /// - `cs`: chip select (active high)
@@ -0,0 +1,64 @@
+//! Unicode equivalents for the VT420's built-in character sets.
+
+/// Unicode equivalents of the DEC Special Graphics (line drawing) set, for the
+/// characters 0x5F to 0x7E. The font stores these glyphs at codes 0x00 to
+/// 0x1F, in the same order.
+const SPECIAL_GRAPHICS: [char; 32] = [
+    ' ', '◆', '▒', '␉', '␌', '␍', '␊', '°', '±', '␤', '␋', '┘', '┐', '┌', '└', '┼', //
+    '⎺', '⎻', '─', '⎼', '⎽', '├', '┤', '┴', '┬', '│', '≤', '≥', 'π', '≠', '£', '·',
+];
+
+/// The Unicode character for a line drawing glyph in the font (0x00 to 0x1F).
+pub fn line_drawing_glyph(glyph: u8) -> Option<char> {
+    SPECIAL_GRAPHICS.get(glyph as usize).copied()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_special_graphics() {
+        // Each glyph alongside the DEC Special Graphics character it displays
+        let expected = [
+            ('_', ' '),
+            ('`', '◆'),
+            ('a', '▒'),
+            ('b', '␉'),
+            ('c', '␌'),
+            ('d', '␍'),
+            ('e', '␊'),
+            ('f', '°'),
+            ('g', '±'),
+            ('h', '␤'),
+            ('i', '␋'),
+            ('j', '┘'),
+            ('k', '┐'),
+            ('l', '┌'),
+            ('m', '└'),
+            ('n', '┼'),
+            ('o', '⎺'),
+            ('p', '⎻'),
+            ('q', '─'),
+            ('r', '⎼'),
+            ('s', '⎽'),
+            ('t', '├'),
+            ('u', '┤'),
+            ('v', '┴'),
+            ('w', '┬'),
+            ('x', '│'),
+            ('y', '≤'),
+            ('z', '≥'),
+            ('{', 'π'),
+            ('|', '≠'),
+            ('}', '£'),
+            ('~', '·'),
+        ];
+        for (i, (ch, unicode)) in expected.into_iter().enumerate() {
+            assert_eq!(ch as usize, 0x5f + i);
+            assert_eq!(line_drawing_glyph(i as u8), Some(unicode), "{ch}");
+        }
+        assert_eq!(line_drawing_glyph(0x20), None);
+        assert_eq!(line_drawing_glyph(0xff), None);
+    }
+}
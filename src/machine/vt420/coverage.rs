@@ -0,0 +1,120 @@
+//! Coverage of the ROM by the firmware as it runs, for finding code that a
+//! session never reached.
+//!
+//! Only the address of each executed instruction is recorded, so the operand
+//! bytes that follow are worked out by decoding the instruction.
+
+use std::collections::BTreeMap;
+use std::io::{self, Write};
+
+use i8051::Cpu;
+
+use crate::machine::vt420::System;
+
+/// Mark every ROM byte that was part of an instruction the system has
+/// executed.
+pub fn executed_bytes(system: &System) -> Vec<bool> {
+    let rom = system.rom.bytes();
+    let cpu = Cpu::new();
+    let mut executed = vec![false; rom.len()];
+    for pc in system.pc_bitset.iter().filter(|&pc| pc < rom.len()) {
+        let end = (pc + cpu.decode(system, pc as u32).len()).min(rom.len());
+        executed[pc..end].fill(true);
+    }
+    executed
+}
+
+/// Write a summary of the executed bytes, the ranges of the ROM that were
+/// never executed and a histogram of the opcodes that were.
+///
+/// The histogram counts distinct addresses, not how often each one ran.
+pub fn write_report(system: &System, mut writer: impl Write) -> io::Result<()> {
+    let rom = system.rom.bytes();
+    let coverage = system.pc_coverage();
+    let count = coverage.iter().filter(|(_, e)| *e).count();
+    writeln!(
+        writer,
+        "Executed {count} of {} ROM bytes ({:.1}%)",
+        rom.len(),
+        count as f64 * 100.0 / rom.len().max(1) as f64
+    )?;
+
+    writeln!(writer)?;
+    writeln!(writer, "Never executed:")?;
+    let mut start = None;
+    let end = (rom.len() as u32, true);
+    for (addr, e) in coverage.into_iter().chain([end]) {
+        match (start, e) {
+            (None, false) => start = Some(addr),
+            (Some(from), true) => {
+                writeln!(
+                    writer,
+                    "  0x{from:05X}-0x{:05X} ({} bytes)",
+                    addr - 1,
+                    addr - from
+                )?;
+                start = None;
+            }
+            _ => {}
+        }
+    }
+
+    let cpu = Cpu::new();
+    let mut histogram = BTreeMap::<u8, (String, usize)>::new();
+    for pc in system.pc_bitset.iter().filter(|&pc| pc < rom.len()) {
+        let (_, count) = histogram.entry(rom[pc]).or_insert_with(|| {
+            let mnemonic = cpu.decode(system, pc as u32).mnemonic();
+            (format!("{mnemonic:?}"), 0)
+        });
+        *count += 1;
+    }
+    let mut histogram = histogram.into_iter().collect::<Vec<_>>();
+    histogram.sort_by(|a, b| b.1.1.cmp(&a.1.1).then(a.0.cmp(&b.0)));
+    writeln!(writer)?;
+    writeln!(writer, "Executed opcodes:")?;
+    for (op, (mnemonic, count)) in histogram {
+        writeln!(writer, "  0x{op:02X} {mnemonic:<8} {count}")?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use bit_set::BitSet;
+
+    #[test]
+    fn test_report() {
+        // MOV A,#12; LJMP 0000; two bytes of data; RET
+        let mut rom = vec![0xff; 0x10000];
+        rom[..8].copy_from_slice(&[0x74, 0x12, 0x02, 0x00, 0x00, 0xff, 0xff, 0x22]);
+        let mut system = System::new_deterministic(rom, None).unwrap();
+        system.pc_bitset = BitSet::from_iter([0, 2, 7]);
+        let coverage = system.pc_coverage();
+        assert_eq!(coverage.len(), 0x10000);
+        assert_eq!(
+            coverage[..8],
+            [
+                (0, true),
+                (1, true),
+                (2, true),
+                (3, true),
+                (4, true),
+                (5, false),
+                (6, false),
+                (7, true)
+            ]
+        );
+        assert!(coverage[8..].iter().all(|&(_, executed)| !executed));
+
+        let mut report = vec![];
+        write_report(&system, &mut report).unwrap();
+        let report = String::from_utf8(report).unwrap();
+        assert!(report.starts_with("Executed 6 of 65536 ROM bytes ("));
+        assert!(report.contains("  0x00005-0x00006 (2 bytes)\n"));
+        assert!(report.contains("  0x00008-0x0FFFF (65528 bytes)\n"));
+        assert!(report.contains("  0x74 MOV      1\n"));
+        assert!(report.contains("  0x22 RET      1\n"));
+    }
+}
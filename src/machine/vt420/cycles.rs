@@ -0,0 +1,68 @@
+//! Machine cycles taken by each 8051 instruction, for clocking the peripherals
+//! by cycle rather than by instruction.
+
+/// The number of machine cycles (12 clocks each) an 8051 instruction takes,
+/// given its opcode and its length in bytes as decoded by the CPU. Every
+/// three-byte instruction takes two cycles, so only the shorter instructions
+/// that take more than one are listed.
+pub fn machine_cycles(op: u8, len: usize) -> usize {
+    match op {
+        // MUL AB, DIV AB
+        0x84 | 0xa4 => 4,
+        _ if len >= 3 => 2,
+        // AJMP, ACALL
+        _ if op & 0x0f == 0x01 => 2,
+        // RET, RETI
+        0x22 | 0x32 => 2,
+        // JC, JNC, JZ, JNZ, SJMP, JMP @A+DPTR
+        0x40 | 0x50 | 0x60 | 0x70 | 0x80 | 0x73 => 2,
+        // ORL C,bit, ANL C,bit, ORL C,/bit, ANL C,/bit, MOV bit,C
+        0x72 | 0x82 | 0xa0 | 0xb0 | 0x92 => 2,
+        // MOVC, INC DPTR
+        0x83 | 0x93 | 0xa3 => 2,
+        // MOV direct,@Ri, MOV direct,Rn
+        0x86..=0x8f => 2,
+        // MOV @Ri,direct, MOV Rn,direct
+        0xa6..=0xaf => 2,
+        // PUSH, POP
+        0xc0 | 0xd0 => 2,
+        // DJNZ Rn,rel
+        0xd8..=0xdf => 2,
+        // MOVX
+        0xe0 | 0xe2 | 0xe3 | 0xf0 | 0xf2 | 0xf3 => 2,
+        _ => 1,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use i8051::Cpu;
+
+    use super::*;
+    use crate::machine::vt420::System;
+
+    #[test]
+    fn test_machine_cycles() {
+        // Every opcode, with room for its operands
+        let mut rom = vec![0; 0x10000];
+        for op in 0..=0xff {
+            rom[op * 4] = op as u8;
+        }
+        let system = System::new_deterministic(rom, None).unwrap();
+        let cpu = Cpu::new();
+        let cycles = |op: u8| machine_cycles(op, cpu.decode(&system, op as u32 * 4).len());
+
+        assert_eq!(cycles(0x00), 1); // NOP
+        assert_eq!(cycles(0x74), 1); // MOV A,#data
+        assert_eq!(cycles(0xe1), 2); // AJMP
+        assert_eq!(cycles(0xf1), 2); // ACALL
+        assert_eq!(cycles(0x80), 2); // SJMP
+        assert_eq!(cycles(0x02), 2); // LJMP
+        assert_eq!(cycles(0xb4), 2); // CJNE A,#data,rel
+        assert_eq!(cycles(0xe0), 2); // MOVX A,@DPTR
+        assert_eq!(cycles(0xa5), 1); // reserved
+        assert_eq!(cycles(0xa4), 4); // MUL AB
+        let two = (0..=0xff_u8).filter(|&op| cycles(op) == 2).count();
+        assert_eq!(two, 92);
+    }
+}
@@ -0,0 +1,300 @@
+//! Golden VRAM tests: VRAM dumps under `tests/vram/` are decoded and compared
+//! against the expected text checked in next to them, so that changes to the
+//! video decode show up as a diff.
+//!
+//! Each fixture is a set of files sharing a name:
+//!
+//! - `NAME.bin`: the 128kB VRAM, as dumped by the text display's `D` key
+//! - `NAME.mapper`: the mapper registers as two lines of 16 hex bytes, the
+//!   current values followed by the values before the last write
+//! - `NAME.txt`: the expected decode, one line per row
+//!
+//! Run the tests with `BLAZE_BLESS_VRAM=1` to rewrite the expected text after
+//! an intentional change, and run the ignored `capture_golden_vram` test to
+//! capture fresh dumps from the ROM:
+//!
+//! ```text
+//! cargo test capture_golden_vram -- --ignored
+//! ```
+//!
+//! `split` is built by hand to cover a split screen. Fixtures written by
+//! `capture_golden_vram` should be recaptured rather than edited.
+
+use std::fmt::Write as _;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::machine::generic::lk201::SpecialKey;
+use crate::machine::vt420::harness::Harness;
+use crate::machine::vt420::video::{Mapper, RowFlags, decode_vram};
+
+fn fixture_dir() -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/vram")
+}
+
+fn read_mapper(path: &Path) -> Mapper {
+    let text = fs::read_to_string(path).unwrap();
+    let mut lines = text.lines().map(|line| {
+        let bytes = line
+            .split_whitespace()
+            .map(|b| u8::from_str_radix(b, 16).unwrap())
+            .collect::<Vec<_>>();
+        <[u8; 16]>::try_from(bytes).unwrap()
+    });
+    Mapper {
+        mapper: lines.next().unwrap(),
+        mapper2: lines.next().unwrap(),
+    }
+}
+
+fn write_mapper(path: &Path, mapper: &Mapper) {
+    let line = |regs: &[u8; 16]| {
+        regs.iter()
+            .map(|b| format!("{b:02x}"))
+            .collect::<Vec<_>>()
+            .join(" ")
+    };
+    fs::write(
+        path,
+        format!("{}\n{}\n", line(&mapper.mapper), line(&mapper.mapper2)),
+    )
+    .unwrap();
+}
+
+struct DecodedRow {
+    index: u8,
+    flags: RowFlags,
+    text: String,
+    attrs: Vec<u16>,
+}
+
+/// Describe every decoded row: where it comes from, its size, its text and
+/// the runs of cells with attributes.
+fn describe(vram: &[u8], mapper: &Mapper) -> String {
+    let rows = decode_vram(
+        vram,
+        mapper,
+        |rows: &mut Vec<DecodedRow>, index, _, flags| {
+            rows.push(DecodedRow {
+                index,
+                flags,
+                text: String::new(),
+                attrs: vec![],
+            });
+        },
+        |rows, _, ch, attr| {
+            let Some(row) = rows.last_mut() else {
+                return;
+            };
+            row.text.push(match ch {
+                0x00 => ' ',
+                0x20..=0x7e => ch as char,
+                _ => '·',
+            });
+            // The column and width flags are described once per row
+            row.attrs.push(attr.bits() & 0xfff);
+        },
+        vec![],
+    );
+
+    let mut out = String::new();
+    for row in rows {
+        let flags = row.flags;
+        let screen = if flags.status_row {
+            "status"
+        } else if flags.screen_2 {
+            "screen 2"
+        } else {
+            "screen 1"
+        };
+        let columns = if flags.is_80 { 80 } else { 132 };
+        let size = if flags.double_height_top {
+            " double-height-top"
+        } else if flags.double_height_bottom {
+            " double-height-bottom"
+        } else if flags.double_width {
+            " double-width"
+        } else {
+            ""
+        };
+        _ = writeln!(
+            out,
+            "{:02} {screen} {columns}{size} h{} |{}|",
+            row.index,
+            flags.row_height,
+            row.text.trim_end()
+        );
+
+        let mut runs = String::new();
+        let mut start = 0;
+        for end in 1..=row.attrs.len() {
+            if end < row.attrs.len() && row.attrs[end] == row.attrs[start] {
+                continue;
+            }
+            if row.attrs[start] != 0 {
+                _ = write!(runs, " {}-{}={:03x}", start, end - 1, row.attrs[start]);
+            }
+            start = end;
+        }
+        if !runs.is_empty() {
+            _ = writeln!(out, "   attributes:{runs}");
+        }
+    }
+    out
+}
+
+#[test]
+fn test_golden_vram() {
+    let bless = std::env::var_os("BLAZE_BLESS_VRAM").is_some();
+    let mut fixtures = fs::read_dir(fixture_dir())
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().is_some_and(|ext| ext == "bin"))
+        .collect::<Vec<_>>();
+    fixtures.sort();
+    assert!(!fixtures.is_empty());
+
+    let mut failures = vec![];
+    for fixture in fixtures {
+        let vram = fs::read(&fixture).unwrap();
+        let mapper = read_mapper(&fixture.with_extension("mapper"));
+        let actual = describe(&vram, &mapper);
+        let expected_path = fixture.with_extension("txt");
+        if bless {
+            fs::write(&expected_path, &actual).unwrap();
+            continue;
+        }
+        let expected = fs::read_to_string(&expected_path).unwrap_or_default();
+        if actual != expected {
+            failures.push(format!(
+                "{}:\n--- expected\n{expected}--- actual\n{actual}",
+                fixture.display()
+            ));
+        }
+    }
+    assert!(
+        failures.is_empty(),
+        "Decoded VRAM doesn't match, rerun with BLAZE_BLESS_VRAM=1 if this is intended\n{}",
+        failures.join("\n")
+    );
+}
+
+/// The row table, as used by the decoder, holds up to 50 rows.
+const ROW_TABLE_BYTES: usize = 50 * 2;
+
+#[test]
+fn test_row_address_low_bit() {
+    let mut fixtures = 0;
+    for entry in fs::read_dir(fixture_dir()).unwrap() {
+        let path = entry.unwrap().path();
+        if path.extension().is_none_or(|ext| ext != "bin") {
+            continue;
+        }
+        let vram = fs::read(&path).unwrap();
+        for (row, address) in vram[..ROW_TABLE_BYTES].iter().step_by(2).enumerate() {
+            assert_eq!(address & 1, 0, "{}: row {row}", path.display());
+        }
+        fixtures += 1;
+    }
+    assert!(fixtures > 1);
+
+    // Setting the bit doesn't change what the captured boot screen shows
+    let path = fixture_dir().join("boot.bin");
+    let mut vram = fs::read(&path).unwrap();
+    let mapper = read_mapper(&path.with_extension("mapper"));
+    let expected = describe(&vram, &mapper);
+    for address in vram[..ROW_TABLE_BYTES].iter_mut().step_by(2) {
+        if *address != 0 {
+            *address |= 1;
+        }
+    }
+    assert_eq!(describe(&vram, &mapper), expected);
+}
+
+#[test]
+fn test_132_column_capture() {
+    let path = fixture_dir().join("132-columns.bin");
+    let vram = fs::read(&path).unwrap();
+    let mapper = read_mapper(&path.with_extension("mapper"));
+    assert!(mapper.screen_1_132_columns());
+    let rows = decode_vram(
+        &vram,
+        &mapper,
+        |rows: &mut Vec<RowFlags>, _, _, flags| rows.push(flags),
+        |_, _, _, _| {},
+        vec![],
+    );
+    assert_eq!(rows.len(), 26);
+    assert!(rows.iter().all(|flags| !flags.is_80));
+}
+
+/// Boot the ROM into each fixture's screen and write its VRAM, mapper
+/// registers and decode into `tests/vram/`.
+#[test]
+#[ignore = "writes fixtures into tests/vram"]
+fn capture_golden_vram() {
+    let captures: &[(&str, &[u8], &str)] = &[
+        ("boot", b"", "VT420 OK"),
+        (
+            "132-columns",
+            b"\x1b[?3h\x1b[H132 columns\x1b[24;120Hbottom right",
+            "bottom right",
+        ),
+        (
+            "double-size",
+            b"\x1b[2J\x1b[H\x1b#6Double width\r\n\x1b#3Double height\r\n\x1b#4Double height\r\n",
+            "Double height",
+        ),
+        (
+            "attributes",
+            b"\x1b[2J\x1b[H\x1b[1mbold\x1b[0m \x1b[4munderline\x1b[0m \x1b[5mblink\x1b[0m \x1b[7mreverse\x1b[0m",
+            "reverse",
+        ),
+        (
+            "status-line",
+            b"\x1b[2$~\x1b[1$}Host status line\x1b[0$}",
+            "Host status line",
+        ),
+    ];
+    for &(name, bytes, pattern) in captures {
+        let mut harness = Harness::boot();
+        harness.send_comm1(bytes);
+        assert!(
+            harness.run_until(pattern, 2_000_000),
+            "{name}: {}",
+            harness.screen()
+        );
+        capture(&mut harness, name);
+    }
+
+    let mut harness = Harness::boot();
+    harness.keyboard().send_special_key(SpecialKey::F3);
+    assert!(
+        harness.run_until("Set-Up=English", 2_000_000),
+        "{}",
+        harness.screen()
+    );
+    capture(&mut harness, "setup");
+}
+
+fn capture(harness: &mut Harness, name: &str) {
+    // Stop outside of vertical refresh, when the rows can be decoded
+    while harness
+        .system
+        .memory
+        .mapper
+        .row_count(&harness.system.memory.vram[..])
+        .is_none()
+    {
+        harness.run(1);
+    }
+    let memory = &harness.system.memory;
+    let path = fixture_dir().join(name);
+    fs::write(path.with_extension("bin"), &memory.vram[..]).unwrap();
+    write_mapper(&path.with_extension("mapper"), &memory.mapper);
+    fs::write(
+        path.with_extension("txt"),
+        describe(&memory.vram[..], &memory.mapper),
+    )
+    .unwrap();
+}
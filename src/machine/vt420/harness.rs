@@ -0,0 +1,154 @@
+//! End-to-end test harness: boots the real ROM with a polled loopback on the
+//! comm ports, drives the keyboard and comm1, and scrapes the screen.
+
+use std::fs;
+
+use i8051::Cpu;
+
+use crate::machine::generic::lk201::LK201Sender;
+use crate::machine::vt420::{FAST_BOOT_MAX_STEPS, System};
+
+/// How often [`Harness::run_until`] checks the screen.
+const POLL_STEPS: usize = 100_000;
+
+/// The V1.3 firmware image shipped in `roms/`.
+pub(crate) fn rom() -> Vec<u8> {
+    let manifest_dir = env!("CARGO_MANIFEST_DIR");
+    fs::read(format!("{manifest_dir}/roms/vt420/23-068E9-00.bin")).unwrap()
+}
+
+pub(crate) struct Harness {
+    pub system: System,
+    pub cpu: Cpu,
+}
+
+impl Harness {
+    /// Boot the terminal until the power-up self test has passed. Anything the
+    /// terminal sends on comm1 is echoed straight back.
+    pub fn boot() -> Self {
+        Self::boot_system(System::new_deterministic(rom(), None).unwrap())
+    }
+
+    /// Boot the terminal with nothing connected to comm1, so nothing it
+    /// sends comes back.
+    pub fn boot_without_host() -> Self {
+        Self::boot_system(System::new_api(rom(), None).unwrap())
+    }
+
+    fn boot_system(system: System) -> Self {
+        let mut harness = Self {
+            system,
+            cpu: Cpu::new(),
+        };
+        assert!(
+            harness
+                .system
+                .fast_boot(&mut harness.cpu, FAST_BOOT_MAX_STEPS)
+                .is_some(),
+            "Didn't boot:\n{}",
+            harness.screen()
+        );
+        harness
+    }
+
+    /// A sender for pressing keys directly.
+    pub fn keyboard(&self) -> LK201Sender {
+        self.system.keyboard.sender()
+    }
+
+    /// Type text on the keyboard, one keystroke at a time, starting now.
+    pub fn type_text(&mut self, text: &str) {
+        self.system.keyboard.type_after(0, text);
+    }
+
+    /// Deliver bytes to the terminal on comm1, as if sent by the host.
+    pub fn send_comm1(&mut self, bytes: &[u8]) {
+        self.system.inject_comm1_after(0, bytes);
+    }
+
+    /// Reset the terminal as if it had been power cycled. It boots again as
+    /// it runs.
+    pub fn reset(&mut self) {
+        self.system.reset(&mut self.cpu);
+    }
+
+    pub fn run(&mut self, steps: usize) {
+        for _ in 0..steps {
+            self.system.step(&mut self.cpu);
+        }
+    }
+
+    /// Run until the screen contains `pattern`, for at most `max_steps`
+    /// steps. Returns false if it never appeared.
+    pub fn run_until(&mut self, pattern: &str, max_steps: usize) -> bool {
+        let mut steps = 0;
+        while steps < max_steps {
+            if self.screen().contains(pattern) {
+                return true;
+            }
+            self.run(POLL_STEPS.min(max_steps - steps));
+            steps += POLL_STEPS;
+        }
+        self.screen().contains(pattern)
+    }
+
+    /// The decoded text of the screen.
+    pub fn screen(&self) -> String {
+        self.system.dump_screen_text()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::machine::generic::lk201::SpecialKey;
+
+    #[test]
+    fn test_typed_text_is_echoed() {
+        let mut harness = Harness::boot();
+        harness.type_text("hello");
+        assert!(
+            harness.run_until("hello", 2_000_000),
+            "{}",
+            harness.screen()
+        );
+
+        harness.keyboard().send_special_key(SpecialKey::F3);
+        assert!(
+            harness.run_until("Set-Up=English", 2_000_000),
+            "{}",
+            harness.screen()
+        );
+    }
+
+    #[test]
+    fn test_local_echo() {
+        let mut harness = Harness::boot_without_host();
+        for c in "zq".chars() {
+            harness.keyboard().send_char(c).unwrap();
+            harness.run(100_000);
+        }
+        harness.run(2_000_000);
+        assert!(!harness.screen().contains("zq"), "{}", harness.screen());
+
+        harness.system.enable_local_echo();
+        for c in "echo".chars() {
+            harness.keyboard().send_char(c).unwrap();
+            harness.run(100_000);
+        }
+        assert!(harness.run_until("echo", 2_000_000), "{}", harness.screen());
+    }
+
+    #[test]
+    fn test_comm1_escape_sequence() {
+        let mut harness = Harness::boot();
+        harness.send_comm1(b"\x1b[2J\x1b[HBlaze harness");
+        assert!(
+            harness.run_until("Blaze harness", 2_000_000),
+            "{}",
+            harness.screen()
+        );
+        let screen = harness.screen();
+        assert!(!screen.contains("VT420 OK"), "{screen}");
+    }
+}
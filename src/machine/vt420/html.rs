@@ -0,0 +1,167 @@
+//! Styled HTML snapshots of the screen, for bug reports and web pages.
+//!
+//! The screen is written as a `<pre>` with one line per row. Runs of cells
+//! with the same attributes share a `<span>` with inline styles, so the
+//! snapshot doesn't need a stylesheet.
+
+use std::fmt::Write;
+
+use crate::machine::vt420::charset::line_drawing_glyph;
+use crate::machine::vt420::video::{CellAttr, Mapper, decode_vram};
+
+const PRE_STYLE: &str = "background:#000;color:#ddd;font-family:monospace";
+/// Double-width rows have half as many cells, spread over the full width.
+const DOUBLE_WIDTH_STYLE: &str = "letter-spacing:1ch";
+
+/// One decoded row: whether it is double width, and the character and style
+/// of each cell.
+type Row = (bool, Vec<(char, &'static str)>);
+
+/// Render the screen as HTML, including the status row.
+pub fn screen_html(vram: &[u8], mapper: &Mapper) -> String {
+    let rows = decode_vram(
+        vram,
+        mapper,
+        |rows: &mut Vec<Row>, _, _, flags| rows.push((flags.double_width, vec![])),
+        |rows, _, ch, attr| {
+            if let Some((_, cells)) = rows.last_mut() {
+                cells.push((cell_char(ch, attr), cell_style(ch, attr)));
+            }
+        },
+        vec![],
+    );
+
+    let mut html = format!("<pre style=\"{PRE_STYLE}\">");
+    for (double_width, cells) in rows {
+        if double_width {
+            _ = write!(html, "<span style=\"{DOUBLE_WIDTH_STYLE}\">");
+        }
+        let mut open = None;
+        for (ch, style) in cells {
+            if open != Some(style) {
+                if open.is_some_and(|style: &str| !style.is_empty()) {
+                    html.push_str("</span>");
+                }
+                if !style.is_empty() {
+                    _ = write!(html, "<span style=\"{style}\">");
+                }
+                open = Some(style);
+            }
+            push_escaped(&mut html, ch);
+        }
+        if open.is_some_and(|style| !style.is_empty()) {
+            html.push_str("</span>");
+        }
+        if double_width {
+            html.push_str("</span>");
+        }
+        html.push('\n');
+    }
+    html.push_str("</pre>\n");
+    html
+}
+
+/// The character shown for a cell. Codes without a printable equivalent are
+/// shown as spaces.
+fn cell_char(ch: u8, attr: CellAttr) -> char {
+    match ch {
+        _ if attr.alternate_glyph() => ' ',
+        0x20..=0x7e => ch as char,
+        0xa9 => '©',
+        _ => line_drawing_glyph(ch).unwrap_or(' '),
+    }
+}
+
+/// The inline style for a cell's attributes, or an empty string for a plain
+/// cell. The fill the firmware leaves in unused cells is drawn plain.
+fn cell_style(ch: u8, attr: CellAttr) -> &'static str {
+    if ch == 0 && attr.is_fill() {
+        return "";
+    }
+    match (attr.bold(), attr.underline(), attr.reverse()) {
+        (false, false, false) => "",
+        (true, false, false) => "font-weight:bold",
+        (false, true, false) => "text-decoration:underline",
+        (true, true, false) => "font-weight:bold;text-decoration:underline",
+        (false, false, true) => "background:#ddd;color:#000",
+        (true, false, true) => "background:#ddd;color:#000;font-weight:bold",
+        (false, true, true) => "background:#ddd;color:#000;text-decoration:underline",
+        (true, true, true) => {
+            "background:#ddd;color:#000;font-weight:bold;text-decoration:underline"
+        }
+    }
+}
+
+fn push_escaped(html: &mut String, ch: char) {
+    match ch {
+        '<' => html.push_str("&lt;"),
+        '>' => html.push_str("&gt;"),
+        '&' => html.push_str("&amp;"),
+        ch => html.push(ch),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::machine::vt420::harness::Harness;
+
+    /// The text of each line, without the markup or escapes.
+    fn line_text(html: &str) -> Vec<String> {
+        let mut text = String::new();
+        let mut in_tag = false;
+        for ch in html.chars() {
+            match ch {
+                '<' => in_tag = true,
+                '>' => in_tag = false,
+                ch if !in_tag => text.push(ch),
+                _ => {}
+            }
+        }
+        text.trim_end_matches('\n')
+            .lines()
+            .map(|line| {
+                line.replace("&lt;", "<")
+                    .replace("&gt;", ">")
+                    .replace("&amp;", "&")
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_screen_html() {
+        let mut harness = Harness::boot();
+        harness.send_comm1(b"\x1b[2J\x1b[Hplain \x1b[7mreversed\x1b[m <&>");
+        assert!(
+            harness.run_until("reversed", 2_000_000),
+            "{}",
+            harness.screen()
+        );
+
+        let html = harness.system.screen_html();
+        assert!(html.starts_with("<pre "), "{html}");
+        assert!(
+            html.contains(
+                "plain <span style=\"background:#ddd;color:#000\">reversed</span> &lt;&amp;&gt;"
+            ),
+            "{html}"
+        );
+        let lines = line_text(&html);
+        assert_eq!(lines[0].trim_end(), "plain reversed <&>");
+        // The status row is always 132 columns
+        let (status, rows) = lines.split_last().unwrap();
+        assert_eq!(status.chars().count(), 132);
+        assert!(
+            rows.iter().all(|line| line.chars().count() == 80),
+            "{lines:?}"
+        );
+
+        // DECCOLM switches to 132 columns
+        harness.send_comm1(b"\x1b[?3h\x1b[Hwide");
+        assert!(harness.run_until("wide", 4_000_000), "{}", harness.screen());
+        let lines = line_text(&harness.system.screen_html());
+        assert!(
+            lines.iter().all(|line| line.chars().count() == 132),
+            "{lines:?}"
+        );
+    }
+}
@@ -1,18 +1,24 @@
 use std::cell::Cell;
 use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::io::{self, Write};
+use std::ops::Range;
 use std::rc::Rc;
+use std::time::Duration;
 
 use i8051::sfr::SFR_P1;
 use i8051::sfr::SFR_P2;
 use i8051::sfr::SFR_P3;
 use i8051::{CpuView, MemoryMapper, PortMapper, ReadOnlyMemoryMapper};
 use tracing::debug;
-use tracing::{info, trace};
+use tracing::{info, trace, warn};
 
+use crate::error::BlazeError;
 use crate::machine::generic::duart::{DUART, ReadRegister, WriteRegister};
 use crate::machine::generic::nvr::Nvr;
 use crate::machine::generic::vsync::SyncGen;
 use crate::machine::vt420::video::{Mapper, TIMING_60HZ, TIMING_70HZ};
+use crate::machine::vt420::{STEPS_PER_SECOND, Watchpoints};
 
 pub struct Bank {
     pub bank: Rc<Cell<bool>>,
@@ -76,6 +82,11 @@ pub struct VideoProcessor {
     pub p3: u8,
     pub p3_read: u8,
     pub sync: SyncHolder,
+    /// When set, the sync generator stops at its current raster position and
+    /// CSYNC holds its last level.
+    pub frozen: bool,
+    /// The number of frames the sync generator has completed.
+    pub frames: usize,
 }
 
 impl VideoProcessor {
@@ -90,12 +101,29 @@ impl VideoProcessor {
             p3: 0xff,
             p3_read: 0b1111_1111,
             sync: SyncHolder::default(),
+            frozen: false,
+            frames: 0,
         }
     }
 
+    /// Return the port latches to 0xFF, as an 8051 reset does.
+    pub fn reset(&mut self) {
+        self.p1 = 0xff;
+        self.p2 = 0xff;
+        self.p3 = 0xff;
+    }
+
     pub fn tick(&mut self) {
+        if self.frozen {
+            return;
+        }
         // Set the T0 bit (bit 4)
-        let csync_low = self.sync.sync_gen.borrow_mut().tick();
+        let mut sync_gen = self.sync.sync_gen.borrow_mut();
+        let csync_low = sync_gen.tick();
+        if sync_gen.x == 0 && sync_gen.y == 0 {
+            self.frames += 1;
+        }
+        drop(sync_gen);
         self.p3_read &= !(1 << 4);
         self.p3_read |= (csync_low as u8) << 4;
 
@@ -183,9 +211,187 @@ impl PortMapper for DiagnosticMonitor {
     }
 }
 
+/// Number of mapper writes kept by [`MapperTrace`].
+pub const MAPPER_TRACE_CAPACITY: usize = 4096;
+
+/// A write to one of the 16 video mapper registers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MapperWrite {
+    /// Emulated time since power-on, counted in machine cycles
+    pub time: Duration,
+    pub offset: u8,
+    pub old: u8,
+    pub new: u8,
+    pub pc: u32,
+}
+
+/// Ring buffer holding the most recent writes to the video mapper registers.
+pub struct MapperTrace {
+    writes: VecDeque<MapperWrite>,
+    capacity: usize,
+    /// Machine cycles since power-on
+    cycles: u64,
+}
+
+impl Default for MapperTrace {
+    fn default() -> Self {
+        Self::new(MAPPER_TRACE_CAPACITY)
+    }
+}
+
+impl MapperTrace {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            writes: VecDeque::with_capacity(capacity),
+            capacity,
+            cycles: 0,
+        }
+    }
+
+    pub fn tick(&mut self) {
+        self.cycles += 1;
+    }
+
+    pub fn record(&mut self, offset: u8, old: u8, new: u8, pc: u32) {
+        if self.capacity == 0 {
+            return;
+        }
+        if self.writes.len() == self.capacity {
+            self.writes.pop_front();
+        }
+        let nanos = self.cycles as u128 * 1_000_000_000 / STEPS_PER_SECOND as u128;
+        self.writes.push_back(MapperWrite {
+            time: Duration::from_nanos(nanos as u64),
+            offset,
+            old,
+            new,
+            pc,
+        });
+    }
+
+    /// The recorded writes, oldest first.
+    pub fn history(&self) -> impl ExactSizeIterator<Item = &MapperWrite> {
+        self.writes.iter()
+    }
+
+    pub fn write_csv(&self, mut writer: impl Write) -> io::Result<()> {
+        writeln!(writer, "time,offset,old,new,pc")?;
+        for write in &self.writes {
+            writeln!(
+                writer,
+                "{:.6},0x{:X},0x{:02X},0x{:02X},0x{:05X}",
+                write.time.as_secs_f64(),
+                write.offset,
+                write.old,
+                write.new,
+                write.pc
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// The peripheral registers at 0x7e00-0x7eff that have been identified, by
+/// offset. The firmware stages video settings here and copies them into the
+/// mapper registers at 0x7ff0-0x7fff (see architecture/ARCH.md).
+const PERIPHERAL_REGISTERS: &[(u8, &str)] = &[
+    (0xe4, "Row Height Low (-> 7FF6)"),
+    (0xe5, "Row Height High (-> 7FF6)"),
+    (0xe6, "Font Offset Low (-> 7FFC)"),
+    (0xe7, "Font Offset High (-> 7FFC)"),
+    (0xf3, "Session 1 Control (-> 7FF3)"),
+    (0xf4, "Session 2 Control (-> 7FF4)"),
+];
+
+/// The name of the peripheral register at `offset`, if it is known.
+pub fn peripheral_name(offset: u8) -> Option<&'static str> {
+    PERIPHERAL_REGISTERS
+        .iter()
+        .find(|(reg, _)| *reg == offset)
+        .map(|(_, name)| *name)
+}
+
+/// The contents of the peripheral region at a point in time.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PeripheralSnapshot {
+    pub values: [u8; 0x100],
+}
+
+impl PeripheralSnapshot {
+    /// The known registers as `(address, name, value)`, in address order.
+    pub fn named(&self) -> impl Iterator<Item = (u16, &'static str, u8)> + '_ {
+        PERIPHERAL_REGISTERS
+            .iter()
+            .map(|&(offset, name)| (0x7e00 | offset as u16, name, self.values[offset as usize]))
+    }
+}
+
+/// What the CPU sees in the upper half of XDATA (0x8000-0xffff).
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum UpperMemory {
+    /// VRAM when the mapper's VRAM-at-0x8000 bit is set, otherwise SRAM, as
+    /// on the VT420.
+    #[default]
+    Mapper,
+    /// Always SRAM, ignoring the mapper.
+    #[cfg(test)]
+    Sram,
+    /// Always VRAM, ignoring the mapper.
+    #[cfg(test)]
+    Vram,
+}
+
+/// The RAM fitted to the board, for experimenting with hardware variants.
+///
+/// The video decoder always sees at least the VT420's 128kB of VRAM. When less
+/// is fitted, the CPU reads 0xFF past the end and its writes are dropped, or
+/// with `mirror` the address wraps as if the upper address lines weren't
+/// decoded.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RamConfig {
+    pub sram_size: usize,
+    pub vram_size: usize,
+    pub upper_memory: UpperMemory,
+    #[cfg(test)]
+    pub mirror: bool,
+}
+
+pub const SRAM_SIZE: usize = 0x8000; // 32kB
+pub const VRAM_SIZE: usize = 0x20000; // 128kB
+/// The VRAM holding the fonts.
+pub const FONT_VRAM: Range<usize> = 0x8000..0x14000;
+
+impl Default for RamConfig {
+    fn default() -> Self {
+        Self {
+            sram_size: SRAM_SIZE,
+            vram_size: VRAM_SIZE,
+            upper_memory: UpperMemory::Mapper,
+            #[cfg(test)]
+            mirror: false,
+        }
+    }
+}
+
+impl RamConfig {
+    /// The offset into a RAM of `size` bytes that `offset` decodes to, if any.
+    fn decode(&self, size: usize, offset: u32) -> Option<usize> {
+        let offset = offset as usize;
+        if offset < size {
+            return Some(offset);
+        }
+        #[cfg(test)]
+        if self.mirror && size > 0 {
+            return Some(offset % size);
+        }
+        None
+    }
+}
+
 pub struct RAM {
-    pub sram: Box<[u8; 0x8000]>,  // 32kB
-    pub vram: Box<[u8; 0x20000]>, // 128kB
+    pub sram: Vec<u8>,
+    pub vram: Vec<u8>,
+    pub config: RamConfig,
     pub mapper: Mapper,
     pub peripheral: [u8; 0x100],
     pub rom_bank: Rc<Cell<bool>>,
@@ -193,17 +399,30 @@ pub struct RAM {
     pub sync: SyncHolder,
     pub nvr: Nvr,
     pub duart: DUART,
+    pub watchpoints: Watchpoints,
+    pub mapper_trace: MapperTrace,
+    /// Writes to the DUART and video mapper, for the watchdog.
+    pub io_writes: usize,
+    /// Changes whenever the font VRAM may have changed, so a renderer only
+    /// needs to look at the font again when this does.
+    pub font_version: u64,
 }
 
 impl RAM {
-    pub fn new(rom_bank: Rc<Cell<bool>>, sync: SyncHolder, duart: DUART) -> Self {
-        let sram = Box::new([0; 0x8000]);
-        let vram = Box::new([0; 0x20000]);
+    pub fn new(
+        config: RamConfig,
+        rom_bank: Rc<Cell<bool>>,
+        sync: SyncHolder,
+        duart: DUART,
+    ) -> Self {
+        let sram = vec![0; config.sram_size];
+        let vram = vec![0; config.vram_size.max(VRAM_SIZE)];
         let mapper = Mapper::new();
         let peripheral = [0; 0x100];
         Self {
             sram,
             vram,
+            config,
             mapper,
             peripheral,
             rom_bank,
@@ -211,8 +430,45 @@ impl RAM {
             sync,
             nvr: Nvr::new(),
             duart,
+            watchpoints: Watchpoints::default(),
+            mapper_trace: MapperTrace::default(),
+            io_writes: 0,
+            font_version: 0,
         }
     }
+
+    /// Return to the power-on state: SRAM and VRAM are cleared and the
+    /// mapper, DUART and ROM bank are reset. The NVR keeps its contents.
+    pub fn reset(&mut self) {
+        self.sram.fill(0);
+        self.vram.fill(0);
+        self.font_version += 1;
+        self.peripheral = [0; 0x100];
+        self.mapper = Mapper::new();
+        self.rom_bank.set(false);
+        self.duart.reset();
+        self.nvr.reset();
+    }
+
+    fn sram_offset(&self, offset: u32) -> Option<usize> {
+        self.config.decode(self.config.sram_size, offset)
+    }
+
+    fn vram_offset(&self, offset: u32) -> Option<usize> {
+        self.config.decode(self.config.vram_size, offset)
+    }
+
+    /// Read SRAM as the CPU would see it, 0xFF where nothing is fitted.
+    fn read_sram(&self, offset: u32) -> u8 {
+        self.sram_offset(offset)
+            .map_or(0xff, |offset| self.sram[offset])
+    }
+
+    /// Read VRAM as the CPU would see it, 0xFF where nothing is fitted.
+    fn read_vram(&self, offset: u32) -> u8 {
+        self.vram_offset(offset)
+            .map_or(0xff, |offset| self.vram[offset])
+    }
 }
 
 fn swizzle_video_ram(addr: u16, bits: u8) -> u16 {
@@ -255,7 +511,14 @@ impl RAM {
             (MemoryTarget::VRAM, vram_offset + addr as u32)
         } else {
             let addr = (addr & 0x7fff) as u32;
-            if self.mapper.map_vram_at_8000() == 1 {
+            let vram = match self.config.upper_memory {
+                UpperMemory::Mapper => self.mapper.map_vram_at_8000() == 1,
+                #[cfg(test)]
+                UpperMemory::Sram => false,
+                #[cfg(test)]
+                UpperMemory::Vram => true,
+            };
+            if vram {
                 let vram_offset = self.mapper.vram_offset();
                 (MemoryTarget::VRAM, addr + vram_offset)
             } else {
@@ -264,6 +527,31 @@ impl RAM {
         }
     }
 
+    /// Read the current value of a target without side effects. The DUART
+    /// registers are write-only and always read as zero here.
+    fn peek(&self, target: MemoryTarget, offset: u32) -> u8 {
+        match target {
+            MemoryTarget::SRAM => self.read_sram(offset),
+            MemoryTarget::VRAM => self.read_vram(offset),
+            MemoryTarget::Peripheral => self.peripheral[offset as usize],
+            MemoryTarget::Mapper => self.mapper.get(offset as _),
+            MemoryTarget::DUART => 0,
+        }
+    }
+
+    /// Read XDATA as the CPU would see it, but without side effects.
+    pub fn peek_xdata(&self, addr: u16) -> u8 {
+        let (target, offset) = self.target_for_addr(addr);
+        self.peek(target, offset)
+    }
+
+    /// Write XDATA as the CPU would, with the same side effects. The write is
+    /// recorded as coming from PC 0.
+    pub fn poke_xdata(&mut self, addr: u16, value: u8) {
+        let (target, offset) = self.target_for_addr(addr);
+        self.write((target, offset, addr as u32, 0, value));
+    }
+
     pub fn tick(&mut self) {
         let nvrtxd = self.duart.output_bits_inv & 1 << 6 == 0;
         let nvrclk = self.duart.output_bits_inv & 1 << 5 == 0;
@@ -274,13 +562,14 @@ impl RAM {
         self.duart.input_bits = self.duart.input_bits & !(1 << 3) | (nvrrxd as u8) << 3;
 
         let int1 = self.duart.tick();
+        self.mapper_trace.tick();
     }
 }
 
 impl MemoryMapper for RAM {
     type WriteValue = (MemoryTarget, u32, u32, u32, u8);
     fn len(&self) -> u32 {
-        self.sram.len() as u32 + self.vram.len() as u32
+        (self.config.sram_size + self.config.vram_size) as u32
     }
     fn read<C: CpuView>(&self, cpu: &C, addr: u32) -> u8 {
         let pc = cpu.pc_ext();
@@ -293,7 +582,7 @@ impl MemoryMapper for RAM {
                     if tracing::enabled!(tracing::Level::TRACE) {
                         debug!("VIDEO VRAM addr = {:02X?}", &self.vram[0..60]);
                     }
-                    self.mapper.read_7ff6(self.vram.as_ref())
+                    self.mapper.read_7ff6(&self.vram)
                 }
                 x => self.mapper.get(x as _),
             },
@@ -304,26 +593,26 @@ impl MemoryMapper for RAM {
                 value
             }
             MemoryTarget::Peripheral => {
-                debug!(
-                    "Peripheral read: 0x{:04X} = 0x{:02X} @ {:05X}",
-                    addr, self.peripheral[offset as usize], pc
-                );
-                // peripheral
-                return self.peripheral[offset as usize];
+                let value = self.peripheral[offset as usize];
+                if let Some(name) = peripheral_name(offset as u8) {
+                    debug!("Peripheral read {name} = {:02X} @ {:05X}", value, pc);
+                } else {
+                    debug!(
+                        "Peripheral read: 0x{:04X} = 0x{:02X} @ {:05X}",
+                        addr, value, pc
+                    );
+                }
+                return value;
             }
             MemoryTarget::VRAM => {
-                trace!(
-                    "VRAM read: 0x{:04X} = 0x{:02X} @ {:05X}",
-                    addr, self.vram[offset as usize], pc
-                );
-                return self.vram[offset as usize];
+                let value = self.read_vram(offset);
+                trace!("VRAM read: 0x{:04X} = 0x{:02X} @ {:05X}", addr, value, pc);
+                value
             }
             MemoryTarget::SRAM => {
-                trace!(
-                    "SRAM read: 0x{:04X} = 0x{:02X} @ {:05X}",
-                    addr, self.sram[offset as usize], pc
-                );
-                return self.sram[offset as usize];
+                let value = self.read_sram(offset);
+                trace!("SRAM read: 0x{:04X} = 0x{:02X} @ {:05X}", addr, value, pc);
+                value
             }
         }
     }
@@ -342,6 +631,15 @@ impl MemoryMapper for RAM {
         //     );
         // }
 
+        if !self.watchpoints.is_empty() {
+            let old = self.peek(target, offset);
+            self.watchpoints.check(addr as u16, old, value, pc);
+        }
+
+        if matches!(target, MemoryTarget::Mapper | MemoryTarget::DUART) {
+            self.io_writes += 1;
+        }
+
         match target {
             MemoryTarget::Mapper => {
                 debug!(
@@ -350,6 +648,8 @@ impl MemoryMapper for RAM {
                     self.mapper.get(offset as _),
                     value
                 );
+                self.mapper_trace
+                    .record(offset as u8, self.mapper.get(offset as _), value, pc);
                 if offset == 0x3
                     && self.mapper.vram_8000_bit() ^ self.mapper.vram_8000_bit_value(value) != 0
                 {
@@ -383,16 +683,34 @@ impl MemoryMapper for RAM {
                 self.duart.write(reg, value);
             }
             MemoryTarget::Peripheral => {
-                debug!("Peripheral write: 0x{:04X} = 0x{:02X}", addr, value);
+                if let Some(name) = peripheral_name(offset as u8) {
+                    debug!("Peripheral write {name} = {:02X} @ {:05X}", value, pc);
+                } else {
+                    debug!(
+                        "Peripheral write: 0x{:04X} = 0x{:02X} @ {:05X}",
+                        addr, value, pc
+                    );
+                }
                 self.peripheral[offset as usize] = value;
             }
             MemoryTarget::VRAM => {
                 debug!("VRAM write: 0x{:04X} = 0x{:02X} @ {:05X}", addr, value, pc);
-                self.vram[offset as usize] = value;
+                match self.vram_offset(offset) {
+                    Some(offset) => {
+                        if FONT_VRAM.contains(&offset) && self.vram[offset] != value {
+                            self.font_version += 1;
+                        }
+                        self.vram[offset] = value;
+                    }
+                    None => debug!("VRAM write past the fitted VRAM: 0x{offset:05X}"),
+                }
             }
             MemoryTarget::SRAM => {
                 debug!("SRAM write: 0x{:04X} = 0x{:02X} @ {:05X}", addr, value, pc);
-                self.sram[offset as usize] = value;
+                match self.sram_offset(offset) {
+                    Some(offset) => self.sram[offset] = value,
+                    None => debug!("SRAM write past the fitted SRAM: 0x{offset:04X}"),
+                }
             }
         }
     }
@@ -405,6 +723,18 @@ pub struct BankDispatch {
     pub target_addr: u32,
 }
 
+/// Each ROM bank is 64kB.
+pub const ROM_BANK_SIZE: usize = 0x10000;
+/// The ROM is mapped as two 64kB banks.
+pub const ROM_MAX_SIZE: usize = 2 * ROM_BANK_SIZE;
+
+/// The CRC-32 and name of each ROM revision known to boot.
+const KNOWN_ROMS: &[(u32, &str)] = &[
+    (0x22c3_f93b, "23-068E9-00 (V1.3)"),
+    (0xca6c_fb18, "23-208E9-00 (V1.4)"),
+    (0xef6e_f48f, "2E-C394A-01 (B1.4)"),
+];
+
 /// Memory mapper for the VT420 emulator
 /// Handles RAM and banked ROM memory regions
 pub struct ROM {
@@ -414,21 +744,49 @@ pub struct ROM {
     rom_size: usize,
     /// Bank size (64KB per bank)
     bank_size: usize,
+    /// The known revision this ROM matches, if any
+    revision: Option<&'static str>,
 }
 
 impl ROM {
     /// Create a new memory mapper with ROM loaded from file
     /// Bank 0: first 64KB of ROM, Bank 1: remaining ROM data
     /// Initializes with bank 0 mapped
-    pub fn new(rom: Vec<u8>) -> Self {
+    ///
+    /// The ROM must be one or two whole banks. It is checked against the known
+    /// revisions, with a warning if it doesn't match any of them.
+    pub fn new(rom: Vec<u8>) -> Result<Self, BlazeError> {
         let rom_size = rom.len();
-        let bank_size = 0x10000; // 64KB per bank
+        if rom_size == 0 || rom_size > ROM_MAX_SIZE || rom_size % ROM_BANK_SIZE != 0 {
+            return Err(BlazeError::RomSize(rom_size));
+        }
 
-        Self {
+        let crc = crc32(&rom);
+        let revision = KNOWN_ROMS
+            .iter()
+            .find(|&&(known, _)| known == crc)
+            .map(|&(_, name)| name);
+        match revision {
+            Some(revision) => info!("Detected ROM revision {revision}"),
+            None => warn!("Unknown ROM (CRC-32 {crc:08X}), it may not boot"),
+        }
+
+        Ok(Self {
             rom,
             rom_size,
-            bank_size,
-        }
+            bank_size: ROM_BANK_SIZE,
+            revision,
+        })
+    }
+
+    /// The name of the known ROM revision this matches, eg: `23-068E9-00 (V1.3)`.
+    #[allow(dead_code)]
+    pub fn revision(&self) -> Option<&str> {
+        self.revision
+    }
+
+    pub fn bytes(&self) -> &[u8] {
+        &self.rom
     }
 
     pub fn banks(&self) -> impl Iterator<Item = &[u8]> {
@@ -438,6 +796,9 @@ impl ROM {
     pub fn find_bank_dispatch(&self) -> Vec<BankDispatch> {
         const BANK_SEARCH_LENGTH: usize = 0x250;
         let banks = self.banks().collect::<Vec<_>>();
+        if banks.len() < 2 {
+            return vec![];
+        }
 
         // Search for 74 <a> 02 00 <b>
         // Address from other bank is at 0x100 + (2 * <a>)
@@ -470,6 +831,23 @@ impl ROM {
     }
 }
 
+/// The CRC-32 used by zlib and PNG, for identifying ROMs and checksumming
+/// PNG chunks.
+pub fn crc32<'a>(data: impl IntoIterator<Item = &'a u8>) -> u32 {
+    let mut crc = !0_u32;
+    for &byte in data {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                crc >> 1 ^ 0xedb8_8320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
+
 impl ReadOnlyMemoryMapper for ROM {
     fn read<C: CpuView>(&self, cpu: &C, addr: u32) -> u8 {
         if addr >= self.rom_size as u32 {
@@ -482,3 +860,82 @@ impl ReadOnlyMemoryMapper for ROM {
         self.rom_size as u32
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::machine::vt420::harness;
+
+    fn ram(config: RamConfig) -> RAM {
+        let (duart, _, _) = DUART::new();
+        RAM::new(config, Rc::default(), SyncHolder::default(), duart)
+    }
+
+    #[test]
+    fn test_reduced_sram() {
+        let mut ram = ram(RamConfig {
+            sram_size: 0x2000,
+            upper_memory: UpperMemory::Sram,
+            ..Default::default()
+        });
+        ram.poke_xdata(0x8000, 0x12);
+        ram.poke_xdata(0x9fff, 0x34);
+        ram.poke_xdata(0xa000, 0x56);
+        assert_eq!(ram.peek_xdata(0x8000), 0x12);
+        assert_eq!(ram.peek_xdata(0x9fff), 0x34);
+        // Past the end of the SRAM, nothing is fitted
+        assert_eq!(ram.peek_xdata(0xa000), 0xff);
+        assert_eq!(ram.peek_xdata(0xffff), 0xff);
+        assert_eq!(ram.sram.len(), 0x2000);
+    }
+
+    #[test]
+    fn test_mirrored_sram() {
+        let mut ram = ram(RamConfig {
+            sram_size: 0x2000,
+            upper_memory: UpperMemory::Sram,
+            mirror: true,
+            ..Default::default()
+        });
+        ram.poke_xdata(0x8001, 0x12);
+        assert_eq!(ram.peek_xdata(0xa001), 0x12);
+        assert_eq!(ram.peek_xdata(0xe001), 0x12);
+        ram.poke_xdata(0xffff, 0x34);
+        assert_eq!(ram.peek_xdata(0x9fff), 0x34);
+    }
+
+    #[test]
+    fn test_upper_memory() {
+        let mut ram = ram(RamConfig {
+            upper_memory: UpperMemory::Vram,
+            ..Default::default()
+        });
+        ram.poke_xdata(0x8000, 0x12);
+        assert_eq!(ram.vram[0x8000], 0x12);
+        assert_eq!(ram.sram[0], 0);
+    }
+
+    #[test]
+    fn test_rom_revision() {
+        let rom = harness::rom();
+        let rom = ROM::new(rom).unwrap();
+        assert_eq!(rom.revision(), Some("23-068E9-00 (V1.3)"));
+
+        let rom = ROM::new(vec![0; ROM_MAX_SIZE]).unwrap();
+        assert_eq!(rom.revision(), None);
+    }
+
+    #[test]
+    fn test_rom_size() {
+        let mut rom = harness::rom();
+        rom.truncate(0x18000);
+        let Err(e) = ROM::new(rom) else {
+            panic!("truncated ROM was accepted");
+        };
+        assert!(matches!(e, BlazeError::RomSize(0x18000)));
+        assert_eq!(e.to_string(), "ROM is 98304 bytes, expected 64kB or 128kB");
+
+        assert!(ROM::new(vec![0; ROM_BANK_SIZE]).is_ok());
+        assert!(ROM::new(vec![0; ROM_MAX_SIZE + ROM_BANK_SIZE]).is_err());
+    }
+}
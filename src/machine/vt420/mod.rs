@@ -1,107 +1,542 @@
 pub mod breakpoints;
+pub mod charset;
+#[cfg(feature = "pc-trace")]
+pub mod coverage;
+pub mod cycles;
+#[cfg(test)]
+mod golden;
+#[cfg(test)]
+pub(crate) mod harness;
+pub mod html;
 pub mod memory;
+pub mod nvr_file;
+pub mod nvr_layout;
+pub mod profile;
+pub mod scrollback;
 pub mod video;
+pub mod watchdog;
 
-use std::cell::Cell;
+use std::collections::VecDeque;
 use std::fs;
+use std::io::{self, Write};
 use std::mem;
 use std::path::{Path, PathBuf};
-use std::rc::Rc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, mpsc};
 #[cfg(not(target_arch = "wasm32"))]
 use std::time::{Duration, Instant};
 
-use hex_literal::hex;
 use i8051::breakpoint::Breakpoints;
 use i8051::peripheral::{P3_INT1, Serial, Timer};
 use i8051::{Cpu, CpuContext, CpuView, DefaultPortMapper, PortMapper};
 use tracing::debug;
 use tracing::{info, trace, warn};
 
-use crate::host::comm::{self, CommConfig};
-use crate::machine::generic::duart::DUART;
+use crate::error::BlazeError;
+use crate::host::comm::{
+    self, CommBackend, CommConfig, CommConnection, LoopbackBackend, PolledComm,
+};
+use crate::host::vt_writer::VtWriter;
+use crate::machine::generic::duart::{DUART, DUARTChannel};
 use crate::machine::generic::lk201::LK201;
 
-use self::memory::{Bank, DiagnosticMonitor, RAM, ROM, VideoProcessor};
+use self::memory::{
+    Bank, DiagnosticMonitor, PeripheralSnapshot, RAM, ROM, RamConfig, VideoProcessor,
+};
+use self::nvr_file::NvrFile;
+use self::profile::{Phase, Profile};
+use self::scrollback::Scrollback;
+use self::watchdog::{Hang, Watchdog};
 
 #[cfg(feature = "pc-trace")]
 use bit_set::BitSet;
 
+/// Nominal number of 8051 machine cycles per second at 11.0592MHz, each of
+/// which we emulate as a single step.
+pub(crate) const STEPS_PER_SECOND: u32 = 11_059_200 / 12;
+
+/// The screen shows this once the power-up self test has passed.
+pub(crate) const BOOT_READY: &str = "VT420 OK";
+
+/// The most steps [`System::fast_boot`] runs before giving up, about twice
+/// as long as the terminal takes to boot.
+pub(crate) const FAST_BOOT_MAX_STEPS: usize = 20_000_000;
+
+/// How often [`System::fast_boot`] checks the screen.
+const FAST_BOOT_POLL_STEPS: usize = 100_000;
+
+/// Number of steps between scrollback snapshots, roughly once per frame.
+const SCROLLBACK_INTERVAL: usize = STEPS_PER_SECOND as usize / 60;
+
+/// Number of steps between row table checks, roughly once per frame.
+const VALIDATE_VIDEO_INTERVAL: usize = STEPS_PER_SECOND as usize / 60;
+
+/// Steps that take longer than this are logged as a warning, by default.
+#[cfg(not(target_arch = "wasm32"))]
+pub(crate) const SLOW_STEP_WARNING: Duration = Duration::from_millis(100);
+
+/// Nominal duration of a single 8051 machine cycle at 11.0592MHz.
+#[cfg(not(target_arch = "wasm32"))]
+const VIRTUAL_STEP_TIME: Duration = Duration::from_nanos(1085);
+
+/// How the comm channels are connected to the host.
+enum Comms {
+    /// Connections serviced by threads, with steps timed by the wall clock.
+    Threaded(CommConfig, CommConfig),
+    /// Backends serviced from [`System::step`], with steps timed virtually.
+    Polled(Box<dyn CommBackend>, Box<dyn CommBackend>),
+    /// No connections: bytes are injected and collected through the
+    /// [`System`] API, with steps timed virtually.
+    Api,
+}
+
+/// The time source used to measure how long each step takes.
+#[cfg(not(target_arch = "wasm32"))]
+enum StepClock {
+    /// Wall-clock time, used by the interactive binary.
+    Wall,
+    /// Virtual time that advances by a fixed amount per step, so that runs
+    /// are fully reproducible.
+    Virtual,
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+impl StepClock {
+    fn start(&self) -> Option<Instant> {
+        match self {
+            StepClock::Wall => Some(Instant::now()),
+            StepClock::Virtual => None,
+        }
+    }
+
+    fn elapsed(&self, start: Option<Instant>) -> Duration {
+        match self {
+            StepClock::Wall => start.map(|start| start.elapsed()).unwrap_or_default(),
+            StepClock::Virtual => VIRTUAL_STEP_TIME,
+        }
+    }
+}
+
+/// A write to a watched XDATA address.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WatchHit {
+    pub addr: u16,
+    pub old: u8,
+    pub new: u8,
+    pub pc: u32,
+}
+
+/// Called for each watchpoint hit.
+type WatchCallback = Box<dyn FnMut(&WatchHit)>;
+
+/// XDATA addresses to watch for writes, optionally only when a specific value
+/// is written. Writes are checked in [`RAM`] and reported from [`System::step`].
+#[derive(Default)]
+pub struct Watchpoints {
+    watches: Vec<(u16, Option<u8>)>,
+    hits: Vec<WatchHit>,
+    callback: Option<WatchCallback>,
+}
+
+impl Watchpoints {
+    pub fn add(&mut self, addr: u16, value: Option<u8>) {
+        self.watches.push((addr, value));
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.watches.is_empty()
+    }
+
+    /// Set the callback invoked for every watchpoint hit.
+    pub fn set_callback(&mut self, callback: impl FnMut(&WatchHit) + 'static) {
+        self.callback = Some(Box::new(callback));
+    }
+
+    pub(crate) fn check(&mut self, addr: u16, old: u8, new: u8, pc: u32) {
+        if self
+            .watches
+            .iter()
+            .any(|&(watch, value)| watch == addr && value.is_none_or(|value| value == new))
+        {
+            self.hits.push(WatchHit { addr, old, new, pc });
+        }
+    }
+
+    /// Deliver pending hits to the callback, returning true if there were any.
+    fn fire(&mut self) -> bool {
+        if self.hits.is_empty() {
+            return false;
+        }
+        for hit in self.hits.drain(..) {
+            if let Some(callback) = &mut self.callback {
+                callback(&hit);
+            }
+        }
+        true
+    }
+}
+
+/// The terminal's current display geometry.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Geometry {
+    /// 80 or 132
+    pub columns: u16,
+    /// Rows in the VRAM row table, or zero during vertical refresh
+    pub rows: u8,
+    /// 60 or 70
+    pub refresh_hz: u8,
+    pub screen_2_active: bool,
+}
+
+/// A display configuration to apply once the terminal has booted, written as
+/// `COLUMNSxROWS` or `COLUMNSxROWSxPAGE`, eg: `132x48` or `80x24x72`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct GeometryPreset {
+    /// 80 or 132
+    pub columns: u16,
+    /// 24, 36 or 48
+    pub rows: u8,
+    /// Lines per page, at least `rows`
+    pub page: u8,
+}
+
+impl GeometryPreset {
+    /// The escape sequences that select this geometry: DECSCPP, DECSLPP and
+    /// DECSNLS.
+    pub fn sequences(&self) -> Vec<u8> {
+        let mut writer = VtWriter::new();
+        writer
+            .decscpp(self.columns)
+            .set_page_size(self.page as u16)
+            .set_rows(self.rows as u16);
+        writer.into_bytes()
+    }
+}
+
+impl std::str::FromStr for GeometryPreset {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let parts = s
+            .split('x')
+            .map(|part| part.parse::<u16>())
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|_| format!("Invalid geometry {s:?}, expected COLUMNSxROWS[xPAGE]"))?;
+        let (columns, rows, page) = match parts[..] {
+            [columns, rows] => (columns, rows, rows),
+            [columns, rows, page] => (columns, rows, page),
+            _ => {
+                return Err(format!(
+                    "Invalid geometry {s:?}, expected COLUMNSxROWS[xPAGE]"
+                ));
+            }
+        };
+        if !matches!(columns, 80 | 132) {
+            return Err(format!(
+                "Unsupported column count {columns}, expected 80 or 132"
+            ));
+        }
+        if !matches!(rows, 24 | 36 | 48) {
+            return Err(format!(
+                "Unsupported row count {rows}, expected 24, 36 or 48"
+            ));
+        }
+        if !matches!(page, 24 | 36 | 48 | 72 | 144) || page < rows {
+            return Err(format!(
+                "Unsupported page size {page}, expected 24, 36, 48, 72 or 144 and at least {rows}"
+            ));
+        }
+        Ok(Self {
+            columns,
+            rows: rows as u8,
+            page: page as u8,
+        })
+    }
+}
+
 pub(crate) struct System {
     pub rom: ROM,
     pub memory: RAM,
     pub instruction_count: usize,
     bank: Bank,
-    nvr_file: Option<PathBuf>,
-    nvr_write: usize,
+    nvr_file: Option<NvrFile>,
 
     video_row: VideoProcessor,
     serial: Serial,
     diagnostic_monitor: DiagnosticMonitor,
     timer: Timer,
     default: DefaultPortMapper,
-    dtr_a: Rc<Cell<bool>>,
-    dtr_b: Rc<Cell<bool>>,
+    comm_a: CommConnection,
+    comm_b: CommConnection,
+    polled_comms: Vec<PolledComm>,
+    /// Host sides of comm1 and comm2 when they are driven through the API,
+    /// with what the firmware has transmitted on each.
+    api_comms: Vec<(DUARTChannel, VecDeque<u8>)>,
+    #[cfg(not(target_arch = "wasm32"))]
+    clock: StepClock,
+    /// Warn about steps that take longer than this. Steps aren't timed at all
+    /// when it is unset.
+    #[cfg(not(target_arch = "wasm32"))]
+    slow_step_warning: Option<Duration>,
+    /// The number of steps that were timed.
+    #[cfg(test)]
+    timed_steps: usize,
 
     #[cfg(feature = "demo")]
     pub(crate) demo_comm: Option<crate::host::demo_comm::DemoComm>,
 
     pub(crate) keyboard: LK201,
     pub(crate) breakpoints: Breakpoints,
+    /// Set when a watchpoint was hit during the last step.
+    pub(crate) watch_hit: bool,
+    /// Set when the video sync generator completed a frame during the last
+    /// step.
+    frame_ready: bool,
+    /// Set by [`System::request_reset`] for the next step.
+    reset_requested: bool,
+    /// Where to write the mapper history as CSV at shutdown.
+    pub(crate) mapper_log: Option<PathBuf>,
+    scrollback: Option<Scrollback>,
+    /// The last row table error found, when validating the video each frame.
+    /// The outer option is set when validation is enabled.
+    row_error: Option<Option<video::RowError>>,
+    watchdog: Option<Watchdog>,
+    /// Where each executed instruction is traced, when enabled.
+    exec_trace: Option<io::BufWriter<fs::File>>,
+    /// Clock the peripherals once per machine cycle of each instruction,
+    /// rather than once per instruction.
+    cycle_timing: bool,
+    /// DEC Locator on comm1, fed by the mouse in the graphical display.
+    #[cfg(feature = "graphics")]
+    pub(crate) locator: Option<comm::Locator>,
+    /// Host side of comm1, for bytes injected by the emulator.
+    comm1_tx: mpsc::SyncSender<u8>,
+    /// Bytes waiting to be injected into comm1 and the step to start at.
+    comm1_inject: Option<(usize, VecDeque<u8>)>,
+    /// Characters typed on the keyboard, to show on comm1 when local echo
+    /// is on.
+    local_echo: Option<mpsc::Receiver<u8>>,
+    /// Where to write the screen transcript at shutdown.
+    pub(crate) screen_log: Option<PathBuf>,
+    /// Where to write the keyboard log at shutdown.
+    pub(crate) keyboard_log: Option<PathBuf>,
+    /// Time spent in each phase of a step, when profiling.
+    pub(crate) profile: Option<Profile>,
 
     #[cfg(feature = "pc-trace")]
     pub(crate) pc_bitset: BitSet,
     #[cfg(feature = "pc-trace")]
     pub(crate) pc_bitset_current: BitSet,
+    /// Where to write the ROM coverage report at shutdown.
+    #[cfg(feature = "pc-trace")]
+    pub(crate) coverage_log: Option<PathBuf>,
 }
 
 impl System {
+    /// Create a system whose comm channels are serviced by threads. With
+    /// `locator`, comm1 also carries DEC Locator reports for the graphical
+    /// display's mouse.
     pub(crate) fn new(
         rom: Vec<u8>,
         nvr: Option<&Path>,
         comm1: CommConfig,
         comm2: CommConfig,
-    ) -> Result<Self, Box<dyn std::error::Error + Send + Sync>> {
+        comm_trace: Option<&Path>,
+        #[cfg(feature = "graphics")] locator: bool,
+    ) -> Result<Self, BlazeError> {
+        Self::build(
+            rom,
+            nvr,
+            Comms::Threaded(comm1, comm2),
+            comm_trace,
+            #[cfg(feature = "graphics")]
+            locator,
+        )
+    }
+
+    /// Create a system whose comm channels are connected to backends that are
+    /// serviced from [`System::step`] rather than threads. Like
+    /// [`System::new_deterministic`], it never reads the wall clock.
+    #[allow(dead_code)]
+    pub fn new_polled(
+        rom: Vec<u8>,
+        nvr: Option<&Path>,
+        comm1: Box<dyn CommBackend>,
+        comm2: Box<dyn CommBackend>,
+    ) -> Result<Self, BlazeError> {
+        Self::build(
+            rom,
+            nvr,
+            Comms::Polled(comm1, comm2),
+            None,
+            #[cfg(feature = "graphics")]
+            false,
+        )
+    }
+
+    /// Create a system that never spawns threads and never reads the wall
+    /// clock. Both comm channels are connected to polled loopbacks that are
+    /// serviced from [`System::step`], so runs are fully reproducible.
+    #[allow(dead_code)]
+    pub fn new_deterministic(rom: Vec<u8>, nvr: Option<&Path>) -> Result<Self, BlazeError> {
+        Self::new_polled(
+            rom,
+            nvr,
+            Box::new(LoopbackBackend::default()),
+            Box::new(LoopbackBackend::default()),
+        )
+    }
+
+    /// Create a deterministic system whose comm channels aren't connected to
+    /// anything. Bytes are delivered to the firmware with
+    /// [`System::inject_comm1`] and [`System::inject_comm2`], and what it
+    /// transmits is collected with [`System::drain_comm1_tx`] and
+    /// [`System::drain_comm2_tx`].
+    #[allow(dead_code)]
+    pub fn new_api(rom: Vec<u8>, nvr: Option<&Path>) -> Result<Self, BlazeError> {
+        Self::build(
+            rom,
+            nvr,
+            Comms::Api,
+            None,
+            #[cfg(feature = "graphics")]
+            false,
+        )
+    }
+
+    fn build(
+        rom: Vec<u8>,
+        nvr: Option<&Path>,
+        comms: Comms,
+        comm_trace: Option<&Path>,
+        #[cfg(feature = "graphics")]
+        #[cfg_attr(target_arch = "wasm32", allow(unused_variables))]
+        locator: bool,
+    ) -> Result<Self, BlazeError> {
+        let deterministic = !matches!(comms, Comms::Threaded(..));
+        let api = matches!(comms, Comms::Api);
         let bank = Bank::default();
         info!("Loading ROM into memory...");
-        let rom = ROM::new(rom);
+        let rom = ROM::new(rom)?;
 
         info!("Configuring video processor...");
         let video_row = VideoProcessor::new();
         info!("Configuring keyboard...");
-        let (serial, in_kbd, out_kbd) = Serial::new(60);
+        let (serial, in_kbd, out_kbd) = keyboard_serial();
 
         info!("Configuring UARTs...");
-        let (duart, channel_a, channel_b) = DUART::new();
+        let (mut duart, channel_a, channel_b) = DUART::new();
+
+        #[cfg(feature = "comm-trace")]
+        let (channel_a, channel_b) = if let Some(comm_trace) = comm_trace {
+            use std::sync::Mutex;
+
+            use crate::host::comm::CommTrace;
+
+            info!("Writing comm trace to {:?}", comm_trace);
+            let file = fs::File::create(comm_trace).map_err(|source| BlazeError::FileCreate {
+                path: comm_trace.to_owned(),
+                source,
+            })?;
+            let file = Arc::new(Mutex::new(file));
+            (
+                comm::trace_duart(channel_a, CommTrace::new("comm1", file.clone())),
+                comm::trace_duart(channel_b, CommTrace::new("comm2", file)),
+            )
+        } else {
+            (channel_a, channel_b)
+        };
+        #[cfg(not(feature = "comm-trace"))]
+        if comm_trace.is_some() {
+            warn!("Comm tracing requires the comm-trace feature, ignoring");
+        }
+
+        // Mouse reports for the graphical display, which needs relay threads
+        #[cfg(all(feature = "graphics", not(target_arch = "wasm32")))]
+        let (channel_a, locator) = if locator {
+            let locator = comm::Locator::default();
+            (
+                comm::locator_duart(channel_a, locator.clone()),
+                Some(locator),
+            )
+        } else {
+            (channel_a, None)
+        };
+        #[cfg(all(feature = "graphics", target_arch = "wasm32"))]
+        let locator = None;
+
+        let comm1_tx = channel_a.tx.clone();
+
+        let mut polled_comms = vec![];
+        let (comm1, comm2, [backend_a, backend_b]) = match comms {
+            Comms::Threaded(comm1, comm2) => (comm1, comm2, [None, None]),
+            Comms::Polled(backend_a, backend_b) => (
+                CommConfig::default(),
+                CommConfig::default(),
+                [Some(backend_a), Some(backend_b)],
+            ),
+            Comms::Api => (CommConfig::default(), CommConfig::default(), [None, None]),
+        };
+        duart.force_local_loopback(comm1 == CommConfig::SelfTest, comm2 == CommConfig::SelfTest);
+
+        let mut api_comms = vec![];
+        let mut connect =
+            |channel: DUARTChannel, config, backend| -> Result<CommConnection, BlazeError> {
+                if api {
+                    let dtr = channel.dtr.clone();
+                    api_comms.push((channel, VecDeque::new()));
+                    return Ok(CommConnection::new(dtr, None));
+                }
+                match backend {
+                    Some(backend) => {
+                        let comm = PolledComm::new(channel, backend);
+                        let dtr = comm.dtr();
+                        polled_comms.push(comm);
+                        Ok(CommConnection::new(dtr, None))
+                    }
+                    None => comm::connect_duart(channel, config),
+                }
+            };
 
         #[cfg(feature = "demo")]
-        let (demo_comm, dtr_a) = if comm1 == CommConfig::Demo {
+        let (demo_comm, comm_a) = if comm1 == CommConfig::Demo {
             (
                 Some(crate::host::demo_comm::DemoComm::new(
                     channel_a.tx,
                     channel_a.rx,
                 )),
-                Rc::new(Cell::new(true)),
+                CommConnection::new(Arc::new(AtomicBool::new(true)), None),
             )
         } else {
-            (None, comm::connect_duart(channel_a, comm1)?)
+            (None, connect(channel_a, comm1, backend_a)?)
         };
 
         #[cfg(not(feature = "demo"))]
-        let dtr_a = comm::connect_duart(channel_a, comm1)?;
+        let comm_a = connect(channel_a, comm1, backend_a)?;
 
-        let dtr_b = comm::connect_duart(channel_b, comm2)?;
+        let comm_b = connect(channel_b, comm2, backend_b)?;
 
-        let mut memory = RAM::new(bank.bank.clone(), video_row.sync.clone(), duart);
+        let mut memory = RAM::new(
+            RamConfig::default(),
+            bank.bank.clone(),
+            video_row.sync.clone(),
+            duart,
+        );
         let mut nvr_file = None;
         info!("Configuring NVR...");
         if let Some(nvr) = nvr {
             info!("Using NVR file: {:?}", nvr);
-            nvr_file = Some(nvr.to_owned());
-            if !nvr.exists() {
+            nvr_file = Some(NvrFile::new(nvr.to_owned()));
+            let nvr_error = |source| BlazeError::Nvr {
+                path: nvr.to_owned(),
+                source,
+            };
+            let created = !nvr.exists();
+            if created {
                 warn!("NVR file does not exist, creating it");
-                fs::write(nvr, vec![0xff; 128])?;
+                fs::write(nvr, vec![0xff; 128]).map_err(nvr_error)?;
             }
-            let mut nvr = fs::read(nvr)?;
+            let mut nvr = fs::read(nvr).map_err(nvr_error)?;
             if nvr.len() < 128 {
                 warn!("NVR file is too small, padding with zeros");
                 nvr.resize(128, 0xff);
@@ -110,22 +545,12 @@ impl System {
                 nvr.truncate(128);
             }
             memory.nvr.mem.copy_from_slice(&nvr);
+            if !created && !nvr_layout::checksums_valid(&memory.nvr.mem) {
+                warn!("NVR file has bad checksums, the firmware may not accept its settings");
+            }
         } else {
             info!("No NVR file provided, using default");
-            // Some checksums hand-modified (0x30, 0x50, 0x70) for tests to pass
-            let initial_nvr = hex!(
-                "65 44 88 1e 1e 85 54 88  85 54 00 00 04 50 00 00"
-                "00 00 00 00 00 00 00 00  00 00 00 00 00 00 00 00"
-                "00 00 00 00 00 00 00 00  00 00 00 00 00 00 00 00"
-                "03 00 c0 25 00 24 01 00  00 00 02 98 00 00 00 00"
-                "01 01 01 01 01 01 01 01  01 01 01 01 01 01 01 01"
-                "4a 00 c0 25 00 24 01 00  00 00 02 98 00 00 00 00"
-                "01 01 01 01 01 01 01 01  01 01 01 01 01 01 01 01"
-                "4a ff ff ff ff ff ff ff  ff ff ff ff ff ff ff ff"
-            );
-
-            memory.nvr.mem.fill(0xff);
-            memory.nvr.mem[..initial_nvr.len()].copy_from_slice(&initial_nvr);
+            memory.nvr.mem = nvr_layout::DEFAULT_NVR;
         }
 
         Ok(Self {
@@ -134,11 +559,22 @@ impl System {
             memory,
             rom,
             nvr_file,
-            nvr_write: 0,
             video_row,
             serial,
-            dtr_a,
-            dtr_b,
+            comm_a,
+            comm_b,
+            polled_comms,
+            api_comms,
+            #[cfg(not(target_arch = "wasm32"))]
+            clock: if deterministic {
+                StepClock::Virtual
+            } else {
+                StepClock::Wall
+            },
+            #[cfg(not(target_arch = "wasm32"))]
+            slow_step_warning: Some(SLOW_STEP_WARNING),
+            #[cfg(test)]
+            timed_steps: 0,
             #[cfg(feature = "demo")]
             demo_comm,
             diagnostic_monitor: DiagnosticMonitor::default(),
@@ -146,21 +582,55 @@ impl System {
             default: DefaultPortMapper::default(),
             keyboard: LK201::new(in_kbd.clone(), out_kbd),
             breakpoints: Breakpoints::new(),
+            watch_hit: false,
+            frame_ready: false,
+            reset_requested: false,
+            mapper_log: None,
+            scrollback: None,
+            row_error: None,
+            watchdog: None,
+            exec_trace: None,
+            cycle_timing: false,
+            #[cfg(feature = "graphics")]
+            locator,
+            comm1_tx,
+            comm1_inject: None,
+            local_echo: None,
+            screen_log: None,
+            keyboard_log: None,
+            profile: None,
             #[cfg(feature = "pc-trace")]
             pc_bitset: BitSet::with_capacity(0x10000),
             #[cfg(feature = "pc-trace")]
             pc_bitset_current: BitSet::with_capacity(0x10000),
+            #[cfg(feature = "pc-trace")]
+            coverage_log: None,
         })
     }
 
     pub(crate) fn step(&mut self, cpu: &mut Cpu) {
+        if mem::take(&mut self.reset_requested) {
+            self.reset(cpu);
+        }
         self.instruction_count += 1;
         #[cfg(not(target_arch = "wasm32"))]
-        let start = Instant::now();
+        let start = if self.slow_step_warning.is_some() {
+            #[cfg(test)]
+            {
+                self.timed_steps += 1;
+            }
+            self.clock.start()
+        } else {
+            None
+        };
+        if let Some(profile) = &mut self.profile {
+            profile.start();
+        }
         let mut breakpoints = Breakpoints::default();
         mem::swap(&mut self.breakpoints, &mut breakpoints);
         breakpoints.run(true, cpu, self);
         mem::swap(&mut self.breakpoints, &mut breakpoints);
+        self.profile_mark(Phase::Breakpoints);
 
         let pc = cpu.pc_ext(self);
         // Trace VSYNC phase for timer interrupts
@@ -169,6 +639,10 @@ impl System {
         //     info!("PC = 0x928, phase = {:?}, flag = {flag}", self.video_row.sync.sync_gen.borrow().phase());
         // }
 
+        if self.exec_trace.is_some() {
+            self.trace_instruction(cpu, pc);
+        }
+
         let prev_0x1f = cpu.internal_ram[0x1f];
         cpu.step(self);
         let new_0x1f = cpu.internal_ram[0x1f];
@@ -183,10 +657,38 @@ impl System {
         {
             self.pc_bitset.insert(pc as usize);
         }
+        if let Some(watchdog) = &mut self.watchdog {
+            watchdog.update(pc, self.memory.io_writes, self.instruction_count);
+        }
+        self.profile_mark(Phase::Cpu);
+
+        self.watch_hit = self.memory.watchpoints.fire();
 
-        self.memory.tick();
-        self.keyboard.tick();
-        self.serial.tick(cpu);
+        let cycles = if self.cycle_timing {
+            let op = self.rom.bytes().get(pc as usize).copied().unwrap_or(0xff);
+            cycles::machine_cycles(op, cpu.decode(self, pc).len())
+        } else {
+            1
+        };
+
+        for _ in 0..cycles {
+            self.memory.tick();
+        }
+        self.profile_mark(Phase::Memory);
+        for _ in 0..cycles {
+            self.keyboard.tick();
+        }
+        if let Some(local_echo) = &self.local_echo {
+            let echoed = local_echo.try_iter().collect::<Vec<_>>();
+            if !echoed.is_empty() {
+                self.memory.duart.local_echo_a(&echoed);
+            }
+        }
+        self.profile_mark(Phase::Keyboard);
+        for _ in 0..cycles {
+            self.serial.tick(cpu);
+        }
+        self.profile_mark(Phase::Serial);
         let prev_p3 = self.video_row.p3_read;
         self.video_row.p3_read &= !P3_INT1;
         if !self.memory.duart.interrupt {
@@ -197,46 +699,456 @@ impl System {
         } else if prev_p3 & P3_INT1 != 0 {
             trace!("DUART interrupt");
         }
+        if let Some((start, pending)) = &mut self.comm1_inject {
+            if self.instruction_count >= *start {
+                while let Some(&b) = pending.front() {
+                    if self.comm1_tx.try_send(b).is_err() {
+                        break;
+                    }
+                    pending.pop_front();
+                }
+                if pending.is_empty() {
+                    self.comm1_inject = None;
+                }
+            }
+        }
         #[cfg(feature = "demo")]
         if let Some(demo_comm) = &mut self.demo_comm {
             demo_comm.tick();
         }
+        for comm in &mut self.polled_comms {
+            comm.tick();
+        }
+        for (channel, sent) in &mut self.api_comms {
+            sent.extend(channel.rx.try_iter());
+        }
         // Set DTR if either DTR1 or DTR2 is set (ideally this should gate on the 232/423 select pin)
         let dtr_a = !self.memory.duart.output_bits_inv & 0b1010 != 0b1010;
         let dtr_b = !self.memory.duart.output_bits_inv & (1 << 7) == 0;
-        if self.dtr_a.replace(dtr_a) != dtr_a {
-            info!("DUART pipe A DTR changed to {}", self.dtr_a.get());
+        if self.comm_a.dtr.swap(dtr_a, Ordering::Relaxed) != dtr_a {
+            info!("DUART pipe A DTR changed to {}", dtr_a);
         }
-        if self.dtr_b.replace(dtr_b) != dtr_b {
-            info!("DUART pipe B DTR changed to {}", self.dtr_b.get());
+        if self.comm_b.dtr.swap(dtr_b, Ordering::Relaxed) != dtr_b {
+            info!("DUART pipe B DTR changed to {}", dtr_b);
         }
-        self.video_row.tick();
-        let tick = self.timer.prepare_tick(cpu, self);
-        self.timer.tick(cpu, tick);
-
-        if self.memory.nvr.write_count > self.nvr_write {
-            if let Some(nvr_file) = &self.nvr_file {
-                fs::write(nvr_file, self.memory.nvr.mem).unwrap();
+        self.profile_mark(Phase::Comm);
+        if self.scrollback.is_some() && self.instruction_count % SCROLLBACK_INTERVAL == 0 {
+            let lines = scrollback::screen_lines(self.decode_screen_split());
+            if let Some(scrollback) = &mut self.scrollback {
+                scrollback.update(lines);
             }
-            self.nvr_write = self.memory.nvr.write_count;
         }
+        if let Some(row_error) = &mut self.row_error {
+            if self.instruction_count % VALIDATE_VIDEO_INTERVAL == 0 {
+                let vram = &self.memory.vram[self.memory.mapper.vram_offset_display() as usize..];
+                let error = self.memory.mapper.validate_rows(vram).err();
+                if error != *row_error {
+                    match error {
+                        Some(e) => warn!(
+                            "Row table invalid at step {} (PC {:05X}): {e}",
+                            self.instruction_count, pc
+                        ),
+                        None => info!("Row table valid at step {}", self.instruction_count),
+                    }
+                    *row_error = error;
+                }
+            }
+        }
+        self.profile_mark(Phase::Other);
+        let frames = self.video_row.frames;
+        for _ in 0..cycles {
+            self.video_row.tick();
+        }
+        self.frame_ready = self.video_row.frames != frames;
+        self.profile_mark(Phase::Video);
+        for _ in 0..cycles {
+            let tick = self.timer.prepare_tick(cpu, self);
+            self.timer.tick(cpu, tick);
+        }
+        self.profile_mark(Phase::Timer);
+
+        if let Some(nvr_file) = &mut self.nvr_file {
+            nvr_file.update(&self.memory.nvr, self.instruction_count);
+        }
+        self.profile_mark(Phase::Other);
 
         mem::swap(&mut self.breakpoints, &mut breakpoints);
         breakpoints.run(false, cpu, self);
         mem::swap(&mut self.breakpoints, &mut breakpoints);
+        self.profile_mark(Phase::Breakpoints);
         #[cfg(not(target_arch = "wasm32"))]
-        if start.elapsed() > Duration::from_millis(100) {
-            warn!("Step took too long: {:?}", start.elapsed());
+        if let Some(limit) = self.slow_step_warning {
+            let elapsed = self.clock.elapsed(start);
+            if elapsed > limit {
+                warn!("Step took too long: {:?}", elapsed);
+            }
         }
     }
 
-    #[cfg(test)]
+    #[inline(always)]
+    fn profile_mark(&mut self, phase: Phase) {
+        if let Some(profile) = &mut self.profile {
+            profile.mark(phase);
+        }
+    }
+
+    /// The current display geometry, as programmed into the video mapper.
+    #[allow(dead_code)]
+    pub fn geometry(&self) -> Geometry {
+        let mapper = &self.memory.mapper;
+        let screen_2_active = mapper.is_screen_2();
+        let is_132 = if screen_2_active {
+            mapper.screen_2_132_columns()
+        } else {
+            mapper.screen_1_132_columns()
+        };
+        let vram = &self.memory.vram[mapper.vram_offset_display() as usize..];
+        Geometry {
+            columns: if is_132 { 132 } else { 80 },
+            rows: mapper.row_count(vram).unwrap_or(0),
+            refresh_hz: self.refresh_hz(),
+            screen_2_active,
+        }
+    }
+
+    /// The vertical refresh rate selected by the firmware: 60 or 70.
+    pub(crate) fn refresh_hz(&self) -> u8 {
+        if self.memory.sync.hz_70.get() { 70 } else { 60 }
+    }
+
+    /// The most recent writes to the video mapper registers, oldest first.
+    pub(crate) fn mapper_history(&self) -> impl ExactSizeIterator<Item = &memory::MapperWrite> {
+        self.memory.mapper_trace.history()
+    }
+
+    /// Read XDATA as the CPU sees it, honouring the mapper's banking and the
+    /// 0x200-0x400 swizzle, without side effects.
+    pub(crate) fn read_xdata(&self, addr: u16) -> u8 {
+        self.memory.peek_xdata(addr)
+    }
+
+    /// Write XDATA as the CPU would, honouring the mapper's banking and the
+    /// 0x200-0x400 swizzle. Writes to the mapper and DUART registers take
+    /// effect.
+    pub(crate) fn write_xdata(&mut self, addr: u16, value: u8) {
+        self.memory.poke_xdata(addr, value);
+    }
+
+    /// Read video RAM by physical offset, regardless of how it is mapped.
+    pub(crate) fn read_vram(&self, offset: u32) -> u8 {
+        self.memory
+            .vram
+            .get(offset as usize)
+            .copied()
+            .unwrap_or(0xff)
+    }
+
+    /// Read static RAM by physical offset, regardless of how it is mapped.
+    pub(crate) fn read_sram(&self, offset: u16) -> u8 {
+        self.memory
+            .sram
+            .get(offset as usize)
+            .copied()
+            .unwrap_or(0xff)
+    }
+
+    /// The current contents of the peripheral region at 0x7e00-0x7eff.
+    pub(crate) fn peripheral_snapshot(&self) -> PeripheralSnapshot {
+        PeripheralSnapshot {
+            values: self.memory.peripheral,
+        }
+    }
+
+    /// Stop or restart the video sync generator, so that VRAM can be examined
+    /// at a fixed raster position while single-stepping. The firmware spins
+    /// in its sync waits while frozen and carries on from the same position
+    /// once unfrozen.
+    pub(crate) fn freeze_video(&mut self, frozen: bool) {
+        self.video_row.frozen = frozen;
+    }
+
+    pub(crate) fn is_video_frozen(&self) -> bool {
+        self.video_row.frozen
+    }
+
+    /// Whether the video sync generator completed a frame during the last
+    /// step, for rendering or logging once per emulated frame.
+    pub(crate) fn frame_ready(&self) -> bool {
+        self.frame_ready
+    }
+
+    /// The number of frames the video sync generator has completed.
+    pub(crate) fn frame_count(&self) -> usize {
+        self.video_row.frames
+    }
+
+    /// The sync generator's current raster position as `(x, y)`.
+    pub(crate) fn raster_position(&self) -> (u16, u16) {
+        let sync_gen = self.memory.sync.sync_gen.borrow();
+        (sync_gen.x, sync_gen.y)
+    }
+
+    /// Every ROM address and whether it was part of an instruction executed
+    /// so far, including the operand bytes.
+    #[cfg(feature = "pc-trace")]
+    pub(crate) fn pc_coverage(&self) -> Vec<(u32, bool)> {
+        coverage::executed_bytes(self)
+            .into_iter()
+            .enumerate()
+            .map(|(addr, executed)| (addr as u32, executed))
+            .collect()
+    }
+
+    /// Deliver `bytes` to the terminal on comm1, as if sent by the host, once
+    /// `ticks` steps have run.
+    pub(crate) fn inject_comm1_after(&mut self, ticks: usize, bytes: &[u8]) {
+        self.comm1_inject = Some((ticks, bytes.iter().copied().collect()));
+    }
+
+    /// Deliver `bytes` to the terminal on comm1 as if sent by the host,
+    /// without going through the comm connection.
+    #[allow(dead_code)]
+    pub fn inject_comm1(&mut self, bytes: &[u8]) {
+        self.memory.duart.inject_rx_a(bytes);
+    }
+
+    /// Show characters typed on the keyboard as if comm1's host had echoed
+    /// them, for a half-duplex host. If the host does echo them, its copies
+    /// are dropped so they aren't shown twice.
+    pub(crate) fn enable_local_echo(&mut self) {
+        self.local_echo = Some(self.keyboard.enable_local_echo());
+    }
+
+    /// Deliver `bytes` to the terminal on comm2 as if sent by the host.
+    #[allow(dead_code)]
+    pub fn inject_comm2(&mut self, bytes: &[u8]) {
+        self.memory.duart.inject_rx_b(bytes);
+    }
+
+    /// Take what the firmware has transmitted on comm1 since the last call.
+    /// Always empty unless the system was created with [`System::new_api`].
+    #[allow(dead_code)]
+    pub fn drain_comm1_tx(&mut self) -> Vec<u8> {
+        self.drain_comm_tx(0)
+    }
+
+    /// Take what the firmware has transmitted on comm2 since the last call.
+    #[allow(dead_code)]
+    pub fn drain_comm2_tx(&mut self) -> Vec<u8> {
+        self.drain_comm_tx(1)
+    }
+
+    fn drain_comm_tx(&mut self, index: usize) -> Vec<u8> {
+        let Some((channel, sent)) = self.api_comms.get_mut(index) else {
+            return vec![];
+        };
+        sent.extend(channel.rx.try_iter());
+        sent.drain(..).collect()
+    }
+
+    /// Start capturing the lines that scroll off the screen, keeping at most
+    /// `max_lines` of them.
+    pub(crate) fn enable_scrollback(&mut self, max_lines: usize) {
+        self.scrollback = Some(Scrollback::new(max_lines));
+    }
+
+    /// Check the row table once per frame, logging a warning when it breaks
+    /// one of the invariants the display relies on.
+    pub(crate) fn enable_video_validation(&mut self) {
+        self.row_error = Some(None);
+    }
+
+    /// Clock the DUART, serial port, video sync generator and timers once for
+    /// each machine cycle an instruction takes, instead of once per step.
+    ///
+    /// The sync timings were worked out with one tick per step, so a frame
+    /// takes fewer steps with this enabled.
+    pub(crate) fn enable_cycle_timing(&mut self) {
+        self.cycle_timing = true;
+    }
+
+    /// Warn about steps that take longer than `limit`, or stop timing steps
+    /// when it is `None`.
+    #[cfg(not(target_arch = "wasm32"))]
+    pub(crate) fn set_slow_step_warning(&mut self, limit: Option<Duration>) {
+        self.slow_step_warning = limit;
+    }
+
+    /// Warn when the firmware spends `limit` steps in a tight loop without
+    /// writing to the DUART or video mapper.
+    pub(crate) fn enable_watchdog(&mut self, limit: usize) {
+        self.watchdog = Some(Watchdog::new(limit));
+    }
+
+    /// Write each instruction to `path` as it is executed, with the registers
+    /// before it runs.
+    pub(crate) fn enable_exec_trace(&mut self, path: &Path) -> io::Result<()> {
+        self.exec_trace = Some(io::BufWriter::new(fs::File::create(path)?));
+        Ok(())
+    }
+
+    fn trace_instruction(&mut self, cpu: &Cpu, pc: u32) {
+        let instruction = cpu.decode(self, pc).to_string();
+        let line = format!(
+            "{pc:05X}: {instruction:<20} A={:02X} B={:02X} DPTR={:02X}{:02X} PSW={:02X} SP={:02X}",
+            cpu.sfr(0xe0, self),
+            cpu.sfr(0xf0, self),
+            cpu.sfr(0x83, self),
+            cpu.sfr(0x82, self),
+            cpu.sfr(0xd0, self),
+            cpu.sfr(0x81, self),
+        );
+        if let Some(writer) = &mut self.exec_trace {
+            if let Err(e) = writeln!(writer, "{line}") {
+                warn!("Failed to write execution trace, stopping: {e}");
+                self.exec_trace = None;
+            }
+        }
+    }
+
+    /// The loop the firmware is stuck in, if the watchdog has found one.
+    pub(crate) fn hang(&self) -> Option<Hang> {
+        self.watchdog.as_ref().and_then(Watchdog::hang)
+    }
+
+    /// Stop sending to the host on both comm ports while the host has sent
+    /// XOFF, until it sends XON.
+    pub(crate) fn enable_host_flow_control(&mut self) {
+        self.memory.duart.honour_host_xoff(true, true);
+    }
+
+    /// The lines captured since [`System::enable_scrollback`], if it was
+    /// called.
+    #[allow(dead_code)]
+    pub fn scrollback(&self) -> Option<&Scrollback> {
+        self.scrollback.as_ref()
+    }
+
+    /// Flush any output still queued for the comm connections and wait up to
+    /// `timeout` for their threads to deliver it. The DUART is disconnected
+    /// afterwards, so this is only useful at shutdown. Returns false if a
+    /// connection timed out.
+    #[cfg(not(target_arch = "wasm32"))]
+    pub(crate) fn drain_comm(&mut self, timeout: Duration) -> bool {
+        self.memory.duart.close();
+        let deadline = Instant::now() + timeout;
+        let drained_a = self.comm_a.join(deadline);
+        let drained_b = self.comm_b.join(deadline);
+        if !drained_a || !drained_b {
+            warn!("Timed out draining comm output");
+        }
+        drained_a && drained_b
+    }
+
+    /// The decoded text of each session's region of the screen. The second
+    /// region is only present when the screen is split.
+    pub(crate) fn decode_screen_split(&self) -> (video::Screen, Option<video::Screen>) {
+        let vram = &self.memory.vram[self.memory.mapper.vram_offset_display() as usize..];
+        video::decode_screen_split(vram, &self.memory.mapper)
+    }
+
+    /// The screen as styled HTML, including the status row.
+    pub(crate) fn screen_html(&self) -> String {
+        let vram = &self.memory.vram[self.memory.mapper.vram_offset_display() as usize..];
+        html::screen_html(vram, &self.memory.mapper)
+    }
+
+    /// Save any NVR writes that are still waiting out the save delay.
+    fn flush_nvr(&mut self) {
+        if let Some(nvr_file) = &mut self.nvr_file {
+            nvr_file.flush(&self.memory.nvr);
+        }
+    }
+
+    /// Save the NVR and write the requested logs, for a host that is about to
+    /// exit. This also runs when the system is dropped, and each log is only
+    /// written once.
+    pub(crate) fn shutdown(&mut self) {
+        self.flush_nvr();
+        if let Some(mapper_log) = self.mapper_log.take() {
+            info!("Writing mapper history to {:?}", mapper_log);
+            let result = fs::File::create(&mapper_log)
+                .and_then(|file| self.memory.mapper_trace.write_csv(io::BufWriter::new(file)));
+            if let Err(e) = result {
+                warn!("Failed to write mapper history: {e}");
+            }
+        }
+        if let (Some(screen_log), Some(scrollback)) = (self.screen_log.take(), &self.scrollback) {
+            info!("Writing screen transcript to {:?}", screen_log);
+            let result = fs::File::create(&screen_log)
+                .and_then(|file| scrollback.write_transcript(io::BufWriter::new(file)));
+            if let Err(e) = result {
+                warn!("Failed to write screen transcript: {e}");
+            }
+        }
+        if let Some(keyboard_log) = self.keyboard_log.take() {
+            info!("Writing keyboard log to {:?}", keyboard_log);
+            let result = fs::File::create(&keyboard_log)
+                .and_then(|file| self.keyboard.write_key_log(io::BufWriter::new(file)));
+            if let Err(e) = result {
+                warn!("Failed to write keyboard log: {e}");
+            }
+        }
+        #[cfg(feature = "pc-trace")]
+        if let Some(coverage_log) = self.coverage_log.take() {
+            info!("Writing ROM coverage to {:?}", coverage_log);
+            let result = fs::File::create(&coverage_log)
+                .and_then(|file| coverage::write_report(self, io::BufWriter::new(file)));
+            if let Err(e) = result {
+                warn!("Failed to write ROM coverage: {e}");
+            }
+        }
+    }
+
+    /// Reset the machine as if it had been power cycled. The CPU restarts at
+    /// the reset vector, SRAM and VRAM are cleared, and the port latches,
+    /// timers, serial port, diagnostic monitor, mapper and DUART return to
+    /// their power-on state. The NVR is nonvolatile, so it keeps its
+    /// contents, and the comm connections and keyboard stay connected.
+    pub(crate) fn reset(&mut self, cpu: &mut Cpu) {
+        info!("Resetting the terminal");
+        *cpu = Cpu::new();
+        self.memory.reset();
+        self.video_row.reset();
+        self.timer = Timer::default();
+        self.diagnostic_monitor = DiagnosticMonitor::default();
+        let (serial, in_kbd, out_kbd) = keyboard_serial();
+        self.serial = serial;
+        self.keyboard.reconnect(in_kbd, out_kbd);
+        self.frame_ready = false;
+    }
+
+    /// Reset the machine at the start of the next step, for hosts that don't
+    /// hold the CPU.
+    pub(crate) fn request_reset(&mut self) {
+        self.reset_requested = true;
+    }
+
+    /// Run flat out until the screen shows [`BOOT_READY`], for at most
+    /// `max_steps` steps. Returns the number of steps run, or `None` if the
+    /// terminal never became ready.
+    pub(crate) fn fast_boot(&mut self, cpu: &mut Cpu, max_steps: usize) -> Option<usize> {
+        let mut steps = 0;
+        while !self.dump_screen_text().contains(BOOT_READY) {
+            if steps >= max_steps {
+                return None;
+            }
+            let poll = FAST_BOOT_POLL_STEPS.min(max_steps - steps);
+            for _ in 0..poll {
+                self.step(cpu);
+            }
+            steps += poll;
+        }
+        Some(steps)
+    }
+
+    /// The decoded text of the screen, one line per row.
     pub(crate) fn dump_screen_text(&self) -> String {
         use crate::machine::vt420::video::decode_vram;
 
         let text = String::with_capacity(132 * 25);
         decode_vram(
-            self.memory.vram.as_ref(),
+            self.memory.vram.as_slice(),
             &self.memory.mapper,
             |text, _, _, _| {
                 text.push_str("\n");
@@ -253,6 +1165,17 @@ impl System {
     }
 }
 
+/// The 8051's serial port, with the keyboard's ends of it.
+fn keyboard_serial() -> (Serial, mpsc::Sender<u8>, mpsc::Receiver<u8>) {
+    Serial::new(60)
+}
+
+impl Drop for System {
+    fn drop(&mut self) {
+        self.shutdown();
+    }
+}
+
 impl PortMapper for System {
     type WriteValue = <(
         VideoProcessor,
@@ -354,6 +1277,9 @@ impl CpuContext for System {
 mod tests {
     use super::*;
     use crate::machine::generic::lk201::SpecialKey;
+    use crate::machine::vt420::harness::{self, Harness};
+    use crate::machine::vt420::memory::MapperWrite;
+    use crate::machine::vt420::video::TIMING_60HZ;
 
     /// Run the ROM and simulation and ensure that we boot to the passed-test screen
     /// and setup comes up.
@@ -363,8 +1289,7 @@ mod tests {
     fn test_boots() {
         let manifest_dir = env!("CARGO_MANIFEST_DIR");
         let rom = fs::read(&format!("{}/roms/vt420/23-068E9-00.bin", manifest_dir)).unwrap();
-        let mut system =
-            System::new(rom, None, CommConfig::default(), CommConfig::default()).unwrap();
+        let mut system = System::new_deterministic(rom, None).unwrap();
 
         system.keyboard.start_collecting_commands();
 
@@ -394,4 +1319,703 @@ mod tests {
         eprintln!("Screen text:\n{screen}\n");
         assert!(screen.contains("Set-Up=English"), "{screen}");
     }
+
+    #[test]
+    fn test_fast_boot() {
+        let mut harness = Harness::boot();
+        assert!(harness.system.instruction_count < FAST_BOOT_MAX_STEPS);
+        assert!(harness.screen().contains(BOOT_READY));
+        // Already booted
+        assert_eq!(
+            harness
+                .system
+                .fast_boot(&mut harness.cpu, FAST_BOOT_MAX_STEPS),
+            Some(0)
+        );
+
+        // A blank ROM never boots, so the cap is hit
+        let mut system = System::new_deterministic(vec![0; 0x20000], None).unwrap();
+        let mut cpu = Cpu::new();
+        assert_eq!(system.fast_boot(&mut cpu, 250_000), None);
+        assert_eq!(system.instruction_count, 250_000);
+    }
+
+    /// Booting flat out beats a run throttled to real time.
+    #[test]
+    #[cfg_attr(debug_assertions, ignore = "needs a release build to outrun real time")]
+    fn test_fast_boot_outruns_real_time() {
+        let start = Instant::now();
+        let harness = Harness::boot();
+        let steps = harness.system.instruction_count;
+        let real_time = Duration::from_secs_f64(steps as f64 / STEPS_PER_SECOND as f64);
+        assert!(
+            start.elapsed() < real_time,
+            "{:?} to boot, {real_time:?} in real time",
+            start.elapsed()
+        );
+    }
+
+    #[test]
+    fn test_setup_errors() {
+        assert!(matches!(
+            System::new_deterministic(vec![], None),
+            Err(BlazeError::RomSize(0))
+        ));
+
+        let dir = tempfile::tempdir().unwrap();
+        let missing = dir.path().join("missing").join("file");
+        assert!(matches!(
+            System::new_deterministic(vec![0; 0x20000], Some(&missing)),
+            Err(BlazeError::Nvr { .. })
+        ));
+        #[cfg(feature = "comm-trace")]
+        assert!(matches!(
+            System::new(
+                vec![0; 0x20000],
+                None,
+                CommConfig::Loopback,
+                CommConfig::Loopback,
+                Some(&missing),
+                #[cfg(feature = "graphics")]
+                false,
+            ),
+            Err(BlazeError::FileCreate { path, .. }) if path == missing
+        ));
+    }
+
+    #[test]
+    fn test_flush_nvr() {
+        let nvr = tempfile::NamedTempFile::new().unwrap();
+        fs::write(nvr.path(), [0xff; 128]).unwrap();
+        let mut system = System::new_deterministic(vec![0; 0x20000], Some(nvr.path())).unwrap();
+        let mut cpu = Cpu::new();
+        system.memory.nvr.mem[0] = 0x42;
+        system.memory.nvr.write_count += 1;
+        for _ in 0..100 {
+            system.step(&mut cpu);
+        }
+        // Still inside the save delay
+        assert_eq!(fs::read(nvr.path()).unwrap()[0], 0xff);
+
+        system.flush_nvr();
+        assert_eq!(fs::read(nvr.path()).unwrap()[0], 0x42);
+    }
+
+    #[test]
+    fn test_shutdown_writes_logs_once() {
+        let dir = tempfile::tempdir().unwrap();
+        let keyboard_log = dir.path().join("keys.log");
+        let mapper_log = dir.path().join("mapper.csv");
+        let mut system = System::new_deterministic(vec![0; 0x20000], None).unwrap();
+        system.keyboard_log = Some(keyboard_log.clone());
+        system.mapper_log = Some(mapper_log.clone());
+        system.shutdown();
+        assert!(keyboard_log.exists());
+        assert!(mapper_log.exists());
+
+        // Dropping the system afterwards doesn't write them again
+        fs::remove_file(&keyboard_log).unwrap();
+        fs::remove_file(&mapper_log).unwrap();
+        drop(system);
+        assert!(!keyboard_log.exists());
+        assert!(!mapper_log.exists());
+    }
+
+    #[test]
+    fn test_reset() {
+        let mut harness = Harness::boot();
+        // Change a setting, with the checksums fixed so the firmware accepts
+        // it on the next boot
+        let mut settings = nvr_layout::NvrSettings::default();
+        settings.sessions[1].columns = 132;
+        let nvr = settings.to_bytes(&harness.system.memory.nvr.mem).unwrap();
+        assert_ne!(nvr, harness.system.memory.nvr.mem);
+        harness.system.memory.nvr.mem = nvr;
+        harness.system.video_row.p2 = 0;
+
+        harness.reset();
+        assert!(harness.system.memory.vram.iter().all(|&b| b == 0));
+        assert!(harness.system.memory.sram.iter().all(|&b| b == 0));
+        assert_eq!(harness.system.memory.mapper.get(4), 0xff);
+        assert_eq!(harness.system.video_row.p2, 0xff);
+        assert_eq!(harness.system.memory.nvr.mem, nvr);
+
+        // The firmware boots again, and the keyboard is still connected
+        assert!(
+            harness
+                .system
+                .fast_boot(&mut harness.cpu, FAST_BOOT_MAX_STEPS)
+                .is_some(),
+            "{}",
+            harness.screen()
+        );
+        harness.type_text("hello");
+        assert!(
+            harness.run_until("hello", 2_000_000),
+            "{}",
+            harness.screen()
+        );
+    }
+
+    #[test]
+    fn test_request_reset() {
+        let mut system = System::new_deterministic(vec![0; 0x20000], None).unwrap();
+        let mut cpu = Cpu::new();
+        for _ in 0..100 {
+            system.step(&mut cpu);
+        }
+        assert_ne!(cpu.pc_ext(&system), 0);
+
+        // A requested reset happens before the next instruction runs
+        system.request_reset();
+        system.step(&mut cpu);
+        assert_eq!(cpu.pc_ext(&system), 1);
+    }
+
+    #[test]
+    fn test_freeze_video() {
+        let mut system = System::new_deterministic(vec![0; 0x20000], None).unwrap();
+        let mut cpu = Cpu::new();
+        let mut run = |system: &mut System| {
+            let start = system.raster_position();
+            for _ in 0..10_000 {
+                system.step(&mut cpu);
+            }
+            (start, system.raster_position())
+        };
+
+        let (start, end) = run(&mut system);
+        assert_ne!(start.1, end.1);
+
+        system.freeze_video(true);
+        let (start, end) = run(&mut system);
+        assert_eq!(start, end);
+
+        system.freeze_video(false);
+        let (start, end) = run(&mut system);
+        assert_ne!(start.1, end.1);
+    }
+
+    #[test]
+    fn test_geometry() {
+        let mut system = System::new_deterministic(vec![0; 0x20000], None).unwrap();
+
+        system.memory.mapper.set(3, 0x01);
+        let geometry = system.geometry();
+        assert_eq!(geometry.columns, 132);
+        assert!(!geometry.screen_2_active);
+        assert_eq!(geometry.refresh_hz, 60);
+
+        system.memory.mapper.set(3, 0x00);
+        system.memory.sync.set_hz_70(true);
+        let geometry = system.geometry();
+        assert_eq!(geometry.columns, 80);
+        assert_eq!(geometry.refresh_hz, 70);
+
+        // Screen 2 has its own column setting
+        system.memory.mapper.set(3, 0x08);
+        system.memory.mapper.set(4, 0x01);
+        let geometry = system.geometry();
+        assert!(geometry.screen_2_active);
+        assert_eq!(geometry.columns, 132);
+    }
+
+    #[test]
+    fn test_refresh_hz() {
+        use i8051::MemoryMapper;
+
+        use crate::machine::vt420::memory::MemoryTarget;
+
+        let mut system = System::new_deterministic(vec![0; 0x20000], None).unwrap();
+        assert_eq!(system.refresh_hz(), 60);
+        system
+            .memory
+            .write((MemoryTarget::Mapper, 0x4, 0x7ff4, 0x01234, 0x10));
+        assert_eq!(system.refresh_hz(), 70);
+        system
+            .memory
+            .write((MemoryTarget::Mapper, 0x4, 0x7ff4, 0x01234, 0x00));
+        assert_eq!(system.refresh_hz(), 60);
+    }
+
+    #[test]
+    fn test_mapper_history() {
+        use i8051::MemoryMapper;
+
+        use crate::machine::vt420::memory::MemoryTarget;
+
+        let mut system = System::new_deterministic(vec![0; 0x20000], None).unwrap();
+        system
+            .memory
+            .write((MemoryTarget::Mapper, 0x3, 0x7ff3, 0x01234, 0x01));
+        system.memory.tick();
+        system.memory.tick();
+        system
+            .memory
+            .write((MemoryTarget::Mapper, 0x3, 0x7ff3, 0x15b33, 0x09));
+        system
+            .memory
+            .write((MemoryTarget::Mapper, 0x4, 0x7ff4, 0x15b40, 0x10));
+
+        let history = system.mapper_history().copied().collect::<Vec<_>>();
+        assert_eq!(
+            history,
+            vec![
+                MapperWrite {
+                    time: Duration::ZERO,
+                    offset: 0x3,
+                    old: 0xFF,
+                    new: 0x01,
+                    pc: 0x01234
+                },
+                MapperWrite {
+                    time: Duration::from_nanos(2170),
+                    offset: 0x3,
+                    old: 0x01,
+                    new: 0x09,
+                    pc: 0x15b33
+                },
+                MapperWrite {
+                    time: Duration::from_nanos(2170),
+                    offset: 0x4,
+                    old: 0xFF,
+                    new: 0x10,
+                    pc: 0x15b40
+                },
+            ]
+        );
+
+        let mut csv = vec![];
+        system.memory.mapper_trace.write_csv(&mut csv).unwrap();
+        assert_eq!(
+            String::from_utf8(csv).unwrap(),
+            "time,offset,old,new,pc\n\
+             0.000000,0x3,0xFF,0x01,0x01234\n\
+             0.000002,0x3,0x01,0x09,0x15B33\n\
+             0.000002,0x4,0xFF,0x10,0x15B40\n"
+        );
+    }
+
+    #[test]
+    fn test_font_version() {
+        use i8051::MemoryMapper;
+
+        use crate::machine::vt420::memory::MemoryTarget;
+
+        let mut system = System::new_deterministic(vec![0; 0x20000], None).unwrap();
+        let version = system.memory.font_version;
+        system
+            .memory
+            .write((MemoryTarget::VRAM, 0x0100, 0x0100, 0x01234, 0x55));
+        assert_eq!(system.memory.font_version, version);
+        system
+            .memory
+            .write((MemoryTarget::VRAM, 0x8000, 0x0000, 0x01234, 0x55));
+        let version = system.memory.font_version;
+        assert_ne!(version, 0);
+        // Rewriting the same value doesn't change the font
+        system
+            .memory
+            .write((MemoryTarget::VRAM, 0x8000, 0x0000, 0x01234, 0x55));
+        assert_eq!(system.memory.font_version, version);
+    }
+
+    #[test]
+    fn test_xdata_swizzle() {
+        let mut system = System::new_deterministic(vec![0; 0x20000], None).unwrap();
+        system.write_xdata(0x7ff3, 0x10);
+        for addr in [0x200, 0x2ff, 0x300, 0x3ff] {
+            system.write_xdata(addr, addr as u8 ^ 0x5a);
+        }
+        for addr in [0x200, 0x2ff, 0x300, 0x3ff] {
+            assert_eq!(system.read_xdata(addr), addr as u8 ^ 0x5a);
+            assert_eq!(system.read_vram(addr as u32 ^ 0x100), addr as u8 ^ 0x5a);
+        }
+
+        // Outside the swizzled range, and SRAM above 0x8000
+        system.write_xdata(0x400, 0x11);
+        assert_eq!(system.read_vram(0x400), 0x11);
+        system.write_xdata(0x7ff5, 0x00);
+        system.write_xdata(0x8123, 0x22);
+        assert_eq!(system.read_xdata(0x8123), 0x22);
+        assert_eq!(system.read_sram(0x0123), 0x22);
+    }
+
+    #[test]
+    fn test_peripheral_snapshot() {
+        use i8051::MemoryMapper;
+
+        use crate::machine::vt420::memory::{MemoryTarget, peripheral_name};
+
+        let mut system = System::new_deterministic(vec![0; 0x20000], None).unwrap();
+        system
+            .memory
+            .write((MemoryTarget::Peripheral, 0xf3, 0x7ef3, 0x01234, 0x09));
+        system
+            .memory
+            .write((MemoryTarget::Peripheral, 0x10, 0x7e10, 0x01234, 0x55));
+
+        let snapshot = system.peripheral_snapshot();
+        assert_eq!(snapshot.values[0xf3], 0x09);
+        assert_eq!(snapshot.values[0x10], 0x55);
+        assert_eq!(peripheral_name(0x10), None);
+        assert_eq!(
+            snapshot.named().find(|(addr, _, _)| *addr == 0x7ef3),
+            Some((0x7ef3, "Session 1 Control (-> 7FF3)", 0x09))
+        );
+        assert!(snapshot.named().all(|(addr, _, _)| addr != 0x7e10));
+    }
+
+    #[test]
+    fn test_mapper_trace_capacity() {
+        use crate::machine::vt420::memory::MapperTrace;
+
+        let mut trace = MapperTrace::new(2);
+        trace.record(0, 0, 1, 0x100);
+        trace.record(1, 0, 2, 0x101);
+        trace.record(2, 0, 3, 0x102);
+        assert_eq!(
+            trace
+                .history()
+                .map(|write| write.offset)
+                .collect::<Vec<_>>(),
+            vec![1, 2]
+        );
+    }
+
+    #[test]
+    fn test_geometry_preset() {
+        let preset = "132x48".parse::<GeometryPreset>().unwrap();
+        assert_eq!(
+            preset,
+            GeometryPreset {
+                columns: 132,
+                rows: 48,
+                page: 48
+            }
+        );
+        assert_eq!(
+            "80x24x72".parse::<GeometryPreset>().unwrap().sequences(),
+            b"\x1b[80$|\x1b[72t\x1b[24*|"
+        );
+
+        for invalid in ["132", "100x24", "80x25", "80x48x36", "80x24x1000", "80xx24"] {
+            assert!(invalid.parse::<GeometryPreset>().is_err(), "{invalid}");
+        }
+    }
+
+    #[test]
+    fn test_inject_comm1() {
+        let mut system = System::new_deterministic(vec![0; 0x20000], None).unwrap();
+        system.inject_comm1_after(2, b"\x1b[80$|");
+        let mut cpu = Cpu::new();
+        system.step(&mut cpu);
+        assert!(system.comm1_inject.is_some());
+        system.step(&mut cpu);
+        assert!(system.comm1_inject.is_none());
+    }
+
+    #[test]
+    fn test_api_comms() {
+        let mut rom = vec![0; 0x20000];
+        #[rustfmt::skip]
+        rom[..0x16].copy_from_slice(&[
+            0x90, 0x7f, 0xee, // MOV DPTR, #7FEE (set output port bits)
+            0x74, 0x0a,       // MOV A, #0A (raise DTR)
+            0xf0,             // MOVX @DPTR, A
+            0x90, 0x7f, 0xe1, // MOV DPTR, #7FE1 (SRA)
+            0xe0,             // MOVX A, @DPTR
+            0x30, 0xe0, 0xfc, // JNB ACC.0, -4 (wait for RxRDY)
+            0x90, 0x7f, 0xe3, // MOV DPTR, #7FE3 (RHRA/THRA)
+            0xe0,             // MOVX A, @DPTR
+            0xf5, 0x30,       // MOV 30, A
+            0xf0,             // MOVX @DPTR, A (echo it back)
+            0x80, 0xfe,       // SJMP $
+        ]);
+        let mut system = System::new_api(rom, None).unwrap();
+        let mut cpu = Cpu::new();
+
+        system.inject_comm1(b"Z");
+        for _ in 0..100_000 {
+            system.step(&mut cpu);
+        }
+        assert_eq!(cpu.internal_ram[0x30], b'Z');
+        assert_eq!(system.drain_comm1_tx(), b"Z");
+        assert_eq!(system.drain_comm1_tx(), b"");
+        assert_eq!(system.drain_comm2_tx(), b"");
+    }
+
+    /// A loop of NOP, MUL AB and SJMP takes 7 machine cycles for every 3
+    /// instructions, so with cycle timing the raster, and the CSYNC pulses
+    /// that come once per line, advance 7/3 as fast.
+    #[test]
+    fn test_cycle_timing() {
+        let mut rom = vec![0; 0x20000];
+        #[rustfmt::skip]
+        rom[..0x04].copy_from_slice(&[
+            0x00,       // NOP
+            0xa4,       // MUL AB
+            0x80, 0xfc, // SJMP -4
+        ]);
+        let htot = TIMING_60HZ.htot() as usize;
+        let steps = 3 * htot * 10;
+
+        let mut ticks = vec![];
+        for cycle_timing in [false, true] {
+            let mut system = System::new_api(rom.clone(), None).unwrap();
+            if cycle_timing {
+                system.enable_cycle_timing();
+            }
+            let mut cpu = Cpu::new();
+            for _ in 0..steps {
+                system.step(&mut cpu);
+            }
+            let sync_gen = system.video_row.sync.sync_gen.borrow();
+            ticks.push((
+                sync_gen.y as usize,
+                sync_gen.y as usize * htot + sync_gen.x as usize,
+            ));
+        }
+        assert_eq!(ticks[0], (10 * 3, steps));
+        assert_eq!(ticks[1], (10 * 7, steps * 7 / 3));
+    }
+
+    /// A ROM that jumps to `setup` at 0x30, then spins, with a timer 0
+    /// interrupt handler that counts its calls in internal RAM 0x30.
+    fn timer_rom(setup: &[u8]) -> Vec<u8> {
+        let mut rom = vec![0; 0x20000];
+        rom[..0x03].copy_from_slice(&[0x02, 0x00, 0x30]); // LJMP 0030
+        #[rustfmt::skip]
+        rom[0x0b..0x0e].copy_from_slice(&[
+            0x05, 0x30, // INC 30h
+            0x32,       // RETI
+        ]);
+        rom[0x30..][..setup.len()].copy_from_slice(setup);
+        rom[0x30 + setup.len()..][..2].copy_from_slice(&[0x80, 0xfe]); // SJMP $
+        rom
+    }
+
+    /// Run until the timer 0 interrupt handler has been called, returning the
+    /// number of steps taken.
+    fn steps_to_timer_0(system: &mut System, cpu: &mut Cpu, limit: usize) -> Option<usize> {
+        (1..=limit).find(|_| {
+            system.step(cpu);
+            cpu.internal_ram[0x30] != 0
+        })
+    }
+
+    /// The firmware counts CSYNC pulses on T0 with timer 0 in 16-bit counter
+    /// mode, preloaded so that it interrupts 0x61 lines later (see 0x2051).
+    #[test]
+    fn test_timer_0_counts_lines() {
+        #[rustfmt::skip]
+        let rom = timer_rom(&[
+            0x75, 0x89, 0x25, // MOV TMOD, #25h (timer 1 mode 2, counter 0 mode 1)
+            0x75, 0x88, 0x40, // MOV TCON, #40h (TR1)
+            0x75, 0x8a, 0x9f, // MOV TL0, #9Fh
+            0x75, 0x8c, 0xff, // MOV TH0, #FFh
+            0xd2, 0x8c,       // SETB TR0
+            0x75, 0xa8, 0x82, // MOV IE, #82h (EA, ET0)
+        ]);
+        let mut system = System::new_deterministic(rom, None).unwrap();
+        let mut cpu = Cpu::new();
+        let htot = TIMING_60HZ.htot() as usize;
+
+        let step = steps_to_timer_0(&mut system, &mut cpu, 200 * htot).unwrap();
+        // One count per line, give or take the serrations around VSYNC, rather
+        // than one per machine cycle
+        let lines = 0x10000 - 0xff9f;
+        assert!(
+            ((lines - 3) * htot..=(lines + 3) * htot).contains(&step),
+            "{step}"
+        );
+        // Mode 1 doesn't reload, so the next overflow is a whole count away
+        for _ in 0..200 * htot {
+            system.step(&mut cpu);
+        }
+        assert_eq!(cpu.internal_ram[0x30], 1);
+    }
+
+    /// In timer mode, timer 0 counts steps instead of T0 pulses. The i8051
+    /// timer only implements mode 1, which is the only mode the firmware uses
+    /// for timer 0, and it doesn't reload on overflow.
+    #[test]
+    fn test_timer_0_counts_cycles() {
+        #[rustfmt::skip]
+        let rom = timer_rom(&[
+            0x75, 0x89, 0x01, // MOV TMOD, #01h (timer 0 mode 1)
+            0x75, 0x8c, 0xff, // MOV TH0, #FFh
+            0x75, 0x8a, 0x9c, // MOV TL0, #9Ch (overflow after 100 counts)
+            0x75, 0xa8, 0x82, // MOV IE, #82h (EA, ET0)
+            0xd2, 0x8c,       // SETB TR0
+        ]);
+        let mut system = System::new_deterministic(rom, None).unwrap();
+        let mut cpu = Cpu::new();
+
+        let first = steps_to_timer_0(&mut system, &mut cpu, 1000).unwrap();
+        assert!((100..=110).contains(&first), "{first}");
+        for _ in first..10_000 {
+            system.step(&mut cpu);
+        }
+        assert_eq!(cpu.internal_ram[0x30], 1);
+    }
+
+    #[test]
+    fn test_watchdog() {
+        let mut rom = vec![0; 0x20000];
+        #[rustfmt::skip]
+        rom[..0x07].copy_from_slice(&[
+            0x90, 0x7f, 0xe1, // MOV DPTR, #7FE1 (SRA)
+            0xe0,             // MOVX A, @DPTR
+            0x30, 0xe0, 0xfc, // JNB ACC.0, -4 (wait for a byte that never comes)
+        ]);
+        let mut system = System::new_api(rom, None).unwrap();
+        let mut cpu = Cpu::new();
+        system.enable_watchdog(10_000);
+
+        for _ in 0..5_000 {
+            system.step(&mut cpu);
+        }
+        assert_eq!(system.hang(), None);
+        for _ in 0..10_000 {
+            system.step(&mut cpu);
+        }
+        let hang = system.hang().unwrap();
+        assert_eq!((hang.low, hang.high), (0x0000, 0x0004));
+    }
+
+    /// Channel B is the printer port: its DTR is the printer's data transmit
+    /// ready output, so bytes written to THRB are what the firmware prints.
+    #[test]
+    fn test_printer_capture() {
+        let mut rom = vec![0; 0x20000];
+        #[rustfmt::skip]
+        rom[..0x15].copy_from_slice(&[
+            0x90, 0x7f, 0xeb, // MOV DPTR, #7FEB (THRB)
+            0x74, b'P',       // MOV A, #'P'
+            0xf0,             // MOVX @DPTR, A
+            0x90, 0x7f, 0xe9, // MOV DPTR, #7FE9 (SRB)
+            0xe0,             // MOVX A, @DPTR
+            0x30, 0xe2, 0xfc, // JNB ACC.2, -4 (wait for TxRDY)
+            0x90, 0x7f, 0xeb, // MOV DPTR, #7FEB (THRB)
+            0x74, b'R',       // MOV A, #'R'
+            0xf0,             // MOVX @DPTR, A
+            0x80, 0xfe,       // SJMP $
+        ]);
+        let capture = tempfile::NamedTempFile::new().unwrap();
+        let mut system = System::new(
+            rom,
+            None,
+            CommConfig::Loopback,
+            CommConfig::Capture(capture.path().to_owned()),
+            None,
+            #[cfg(feature = "graphics")]
+            false,
+        )
+        .unwrap();
+        let mut cpu = Cpu::new();
+        for _ in 0..70_000 {
+            system.step(&mut cpu);
+        }
+        assert!(system.drain_comm(Duration::from_secs(5)));
+        assert_eq!(fs::read(capture.path()).unwrap(), b"PR");
+    }
+
+    #[test]
+    fn test_watchpoints() {
+        use std::cell::RefCell;
+        use std::rc::Rc;
+
+        let mut watchpoints = Watchpoints::default();
+        watchpoints.add(0xb111, None);
+        watchpoints.add(0xb112, Some(0x05));
+        let hits = Rc::new(RefCell::new(vec![]));
+        let hits_clone = hits.clone();
+        watchpoints.set_callback(move |hit| hits_clone.borrow_mut().push(*hit));
+
+        watchpoints.check(0xb111, 0x00, 0x01, 0x1234);
+        watchpoints.check(0xb112, 0x00, 0x04, 0x1235);
+        watchpoints.check(0xb113, 0x00, 0x05, 0x1236);
+        assert!(watchpoints.fire());
+        assert!(!watchpoints.fire());
+        watchpoints.check(0xb112, 0x04, 0x05, 0x1237);
+        assert!(watchpoints.fire());
+
+        assert_eq!(
+            *hits.borrow(),
+            vec![
+                WatchHit {
+                    addr: 0xb111,
+                    old: 0x00,
+                    new: 0x01,
+                    pc: 0x1234
+                },
+                WatchHit {
+                    addr: 0xb112,
+                    old: 0x04,
+                    new: 0x05,
+                    pc: 0x1237
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_exec_trace() {
+        let rom = harness::rom();
+        let trace = tempfile::NamedTempFile::new().unwrap();
+        let mut system = System::new_deterministic(rom, None).unwrap();
+        system.enable_exec_trace(trace.path()).unwrap();
+        let mut cpu = Cpu::new();
+        for _ in 0..10 {
+            system.step(&mut cpu);
+        }
+        drop(system);
+
+        let trace = fs::read_to_string(trace.path()).unwrap();
+        let lines = trace.lines().collect::<Vec<_>>();
+        assert_eq!(lines.len(), 10);
+        // The reset vector jumps to the start of the firmware
+        assert!(lines[0].starts_with("00000: LJMP"), "{trace}");
+        assert!(lines[0].contains(" SP=07"), "{trace}");
+        assert!(lines[1].starts_with("0013E: "), "{trace}");
+    }
+
+    #[test]
+    fn test_slow_step_warning() {
+        let mut system = System::new_deterministic(vec![0; 0x20000], None).unwrap();
+        let mut cpu = Cpu::new();
+        for _ in 0..100 {
+            system.step(&mut cpu);
+        }
+        assert_eq!(system.timed_steps, 100);
+
+        // Nothing is timed once the warning is disabled
+        system.set_slow_step_warning(None);
+        for _ in 0..100 {
+            system.step(&mut cpu);
+        }
+        assert_eq!(system.timed_steps, 100);
+    }
+
+    #[test]
+    fn test_frame_ready() {
+        let mut system = System::new_deterministic(vec![0; 0x20000], None).unwrap();
+        let mut cpu = Cpu::new();
+        let frame_steps = TIMING_60HZ.htot() as usize * TIMING_60HZ.vtot() as usize;
+        // A quarter of a second. The 60Hz timing is tuned to what the
+        // firmware checks for rather than the real pixel clock, so frames
+        // come once every htot * vtot steps.
+        let steps = STEPS_PER_SECOND as usize / 4;
+        let mut frames = 0;
+        for _ in 0..steps {
+            system.step(&mut cpu);
+            if system.frame_ready() {
+                frames += 1;
+            }
+        }
+        assert_eq!(frames, steps / frame_steps);
+        assert!(frames > 0);
+        assert_eq!(system.frame_count(), frames);
+    }
 }
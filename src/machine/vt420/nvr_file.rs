@@ -0,0 +1,157 @@
+//! Persistence of the NVR contents to a file on the host.
+//!
+//! The firmware writes the NVR a byte at a time when settings are saved, so
+//! the file is only rewritten once the writes have stopped for a while. Each
+//! save goes to a temporary file that is renamed over the original, so an
+//! interrupted save never leaves a partial NVR behind.
+
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+use tracing::{info, warn};
+
+use crate::machine::generic::nvr::Nvr;
+
+use super::STEPS_PER_SECOND;
+
+/// Number of steps without an NVR write before the file is saved, about 100ms.
+pub const NVR_SAVE_DELAY: usize = STEPS_PER_SECOND as usize / 10;
+
+pub struct NvrFile {
+    path: PathBuf,
+    /// The NVR write count when the file was last brought up to date.
+    write_count: usize,
+    /// The step at which the NVR was last seen to change, if it hasn't been
+    /// saved since.
+    dirty_since: Option<usize>,
+    #[cfg(test)]
+    saves: usize,
+}
+
+impl NvrFile {
+    pub fn new(path: PathBuf) -> Self {
+        Self {
+            path,
+            write_count: 0,
+            dirty_since: None,
+            #[cfg(test)]
+            saves: 0,
+        }
+    }
+
+    /// Check the NVR for writes at step `now`, saving it once it has been idle
+    /// for [`NVR_SAVE_DELAY`] steps.
+    pub fn update(&mut self, nvr: &Nvr, now: usize) {
+        if nvr.write_count != self.write_count {
+            self.write_count = nvr.write_count;
+            self.dirty_since = Some(now);
+        } else if let Some(since) = self.dirty_since {
+            if now.wrapping_sub(since) >= NVR_SAVE_DELAY {
+                self.flush(nvr);
+            }
+        }
+    }
+
+    /// Save any writes that haven't been saved yet. Errors are logged, and the
+    /// save is retried after the next write.
+    pub fn flush(&mut self, nvr: &Nvr) {
+        if self.dirty_since.take().is_none() && nvr.write_count == self.write_count {
+            return;
+        }
+        self.write_count = nvr.write_count;
+        if let Err(e) = write_atomic(&self.path, &nvr.mem) {
+            warn!("Failed to save NVR to {:?}: {e}", self.path);
+            return;
+        }
+        #[cfg(test)]
+        {
+            self.saves += 1;
+        }
+    }
+
+    /// The number of times the file has been saved.
+    #[cfg(test)]
+    pub fn saves(&self) -> usize {
+        self.saves
+    }
+}
+
+/// Write `contents` to a temporary file next to `path` and rename it over
+/// `path`.
+fn write_atomic(path: &Path, contents: &[u8]) -> io::Result<()> {
+    let mut temp = path.as_os_str().to_owned();
+    temp.push(".tmp");
+    let temp = PathBuf::from(temp);
+    let result = fs::File::create(&temp)
+        .and_then(|mut file| {
+            file.write_all(contents)?;
+            file.sync_all()
+        })
+        .and_then(|()| fs::rename(&temp, path));
+    if result.is_err() {
+        _ = fs::remove_file(&temp);
+    } else {
+        info!("Saved NVR to {:?}", path);
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_debounced_saves() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("nvr.bin");
+        let mut file = NvrFile::new(path.clone());
+        let mut nvr = Nvr::new();
+
+        // A burst of writes, one byte every few hundred steps
+        let mut now = 0;
+        for i in 0..128 {
+            nvr.mem[i] = i as u8;
+            nvr.write_count += 1;
+            for _ in 0..300 {
+                file.update(&nvr, now);
+                now += 1;
+            }
+        }
+        assert_eq!(file.saves(), 0);
+        assert!(!path.exists());
+
+        for _ in 0..=NVR_SAVE_DELAY {
+            file.update(&nvr, now);
+            now += 1;
+        }
+        assert_eq!(file.saves(), 1);
+        let expected = (0..128).collect::<Vec<u8>>();
+        assert_eq!(fs::read(&path).unwrap(), expected);
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 1);
+
+        // Nothing changed, so nothing is saved
+        file.flush(&nvr);
+        assert_eq!(file.saves(), 1);
+
+        // A pending write is saved on flush
+        nvr.mem[0] = 0xff;
+        nvr.write_count += 1;
+        file.update(&nvr, now);
+        file.flush(&nvr);
+        assert_eq!(file.saves(), 2);
+        assert_eq!(fs::read(&path).unwrap()[0], 0xff);
+    }
+
+    #[test]
+    fn test_save_error() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut file = NvrFile::new(dir.path().join("missing/nvr.bin"));
+        let mut nvr = Nvr::new();
+        nvr.write_count += 1;
+        file.update(&nvr, 0);
+        // Logged rather than panicking
+        file.flush(&nvr);
+        assert_eq!(file.saves(), 0);
+    }
+}
@@ -0,0 +1,219 @@
+//! Typed view of the settings the VT420 firmware stores in its 128-byte NVR.
+//!
+//! The NVR is split into three checksummed blocks: a terminal-wide block
+//! followed by one block per session. Each block ends with a checksum byte, and
+//! the firmware accepts the block when all of its bytes (including the
+//! checksum) sum to 0xFE.
+//!
+//! The block layout is well understood, but the positions of the individual
+//! settings are provisional and only cover a few of the fields.
+
+use std::ops::RangeInclusive;
+
+use hex_literal::hex;
+use serde::{Deserialize, Serialize};
+
+pub const NVR_SIZE: usize = 128;
+
+/// The NVR contents used when no NVR file is provided.
+// Some checksums hand-modified (0x30, 0x50, 0x70) for tests to pass
+pub const DEFAULT_NVR: [u8; NVR_SIZE] = hex!(
+    "65 44 88 1e 1e 85 54 88  85 54 00 00 04 50 00 00"
+    "00 00 00 00 00 00 00 00  00 00 00 00 00 00 00 00"
+    "00 00 00 00 00 00 00 00  00 00 00 00 00 00 00 00"
+    "03 00 c0 25 00 24 01 00  00 00 02 98 00 00 00 00"
+    "01 01 01 01 01 01 01 01  01 01 01 01 01 01 01 01"
+    "4a 00 c0 25 00 24 01 00  00 00 02 98 00 00 00 00"
+    "01 01 01 01 01 01 01 01  01 01 01 01 01 01 01 01"
+    "4a ff ff ff ff ff ff ff  ff ff ff ff ff ff ff ff"
+);
+
+/// The checksummed blocks, each ending in its checksum byte.
+const BLOCKS: [RangeInclusive<usize>; 3] = [0x00..=0x30, 0x31..=0x50, 0x51..=0x70];
+const GLOBAL_BLOCK: usize = 0;
+const SESSION_BLOCKS: [usize; 2] = [1, 2];
+
+/// The value each block sums to when its checksum is correct.
+const CHECKSUM_SUM: u8 = 0xfe;
+
+/// Global block: bit 0 selects 70Hz refresh.
+const REFRESH_OFFSET: usize = 0x0b;
+/// Session block: bit 0 selects 132 columns.
+const COLUMNS_OFFSET: usize = 0x00;
+/// Session block: transmit speed code.
+const TRANSMIT_SPEED_OFFSET: usize = 0x02;
+/// Session block: receive speed code.
+const RECEIVE_SPEED_OFFSET: usize = 0x04;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct NvrSettings {
+    /// 60 or 70
+    pub refresh_hz: u8,
+    pub sessions: [SessionSettings; 2],
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SessionSettings {
+    /// 80 or 132
+    pub columns: u16,
+    /// Firmware speed code for the transmit baud rate
+    pub transmit_speed: u8,
+    /// Firmware speed code for the receive baud rate
+    pub receive_speed: u8,
+}
+
+impl Default for NvrSettings {
+    fn default() -> Self {
+        Self::from_bytes(&DEFAULT_NVR)
+    }
+}
+
+impl Default for SessionSettings {
+    fn default() -> Self {
+        NvrSettings::default().sessions[0].clone()
+    }
+}
+
+impl NvrSettings {
+    pub fn from_bytes(bytes: &[u8; NVR_SIZE]) -> Self {
+        let global = block_start(GLOBAL_BLOCK);
+        let session = |block| {
+            let start = block_start(block);
+            SessionSettings {
+                columns: if bytes[start + COLUMNS_OFFSET] & 1 != 0 {
+                    132
+                } else {
+                    80
+                },
+                transmit_speed: bytes[start + TRANSMIT_SPEED_OFFSET],
+                receive_speed: bytes[start + RECEIVE_SPEED_OFFSET],
+            }
+        };
+        Self {
+            refresh_hz: if bytes[global + REFRESH_OFFSET] & 1 != 0 {
+                70
+            } else {
+                60
+            },
+            sessions: SESSION_BLOCKS.map(session),
+        }
+    }
+
+    /// Write these settings over `base`, leaving the bytes for unknown settings
+    /// untouched, and recompute the checksums.
+    pub fn to_bytes(
+        &self,
+        base: &[u8; NVR_SIZE],
+    ) -> Result<[u8; NVR_SIZE], Box<dyn std::error::Error + Send + Sync>> {
+        let mut bytes = *base;
+
+        let global = block_start(GLOBAL_BLOCK);
+        let refresh = match self.refresh_hz {
+            60 => 0,
+            70 => 1,
+            hz => return Err(format!("Unsupported refresh rate: {hz}Hz").into()),
+        };
+        bytes[global + REFRESH_OFFSET] = bytes[global + REFRESH_OFFSET] & !1 | refresh;
+
+        for (session, block) in self.sessions.iter().zip(SESSION_BLOCKS) {
+            let start = block_start(block);
+            let columns = match session.columns {
+                80 => 0,
+                132 => 1,
+                columns => return Err(format!("Unsupported column count: {columns}").into()),
+            };
+            bytes[start + COLUMNS_OFFSET] = bytes[start + COLUMNS_OFFSET] & !1 | columns;
+            bytes[start + TRANSMIT_SPEED_OFFSET] = session.transmit_speed;
+            bytes[start + RECEIVE_SPEED_OFFSET] = session.receive_speed;
+        }
+
+        fix_checksums(&mut bytes);
+        Ok(bytes)
+    }
+
+    pub fn from_toml(toml: &str) -> Result<Self, Box<dyn std::error::Error + Send + Sync>> {
+        Ok(toml::from_str(toml)?)
+    }
+
+    #[allow(dead_code)]
+    pub fn to_toml(&self) -> Result<String, Box<dyn std::error::Error + Send + Sync>> {
+        Ok(toml::to_string(self)?)
+    }
+}
+
+fn block_start(block: usize) -> usize {
+    *BLOCKS[block].start()
+}
+
+/// Rewrite the checksum byte at the end of each block.
+pub fn fix_checksums(bytes: &mut [u8; NVR_SIZE]) {
+    for block in BLOCKS {
+        let checksum = *block.end();
+        let sum = bytes[*block.start()..checksum]
+            .iter()
+            .fold(0_u8, |sum, &b| sum.wrapping_add(b));
+        bytes[checksum] = CHECKSUM_SUM.wrapping_sub(sum);
+    }
+}
+
+/// Returns true if every block's checksum matches what the firmware expects.
+pub fn checksums_valid(bytes: &[u8; NVR_SIZE]) -> bool {
+    BLOCKS.iter().all(|block| {
+        bytes[block.clone()]
+            .iter()
+            .fold(0_u8, |sum, &b| sum.wrapping_add(b))
+            == CHECKSUM_SUM
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_default_checksums() {
+        assert!(checksums_valid(&DEFAULT_NVR));
+        let mut bytes = DEFAULT_NVR;
+        fix_checksums(&mut bytes);
+        assert_eq!(bytes, DEFAULT_NVR);
+    }
+
+    #[test]
+    fn test_toml_round_trip() {
+        let toml = r#"
+            refresh_hz = 70
+
+            [[sessions]]
+            columns = 132
+            transmit_speed = 0x25
+            receive_speed = 0x24
+
+            [[sessions]]
+            columns = 80
+            transmit_speed = 0x21
+            receive_speed = 0x21
+        "#;
+        let settings = NvrSettings::from_toml(toml).unwrap();
+        let bytes = settings.to_bytes(&DEFAULT_NVR).unwrap();
+        assert!(checksums_valid(&bytes));
+        assert_eq!(bytes[0x30], 0x02);
+        assert_eq!(bytes[0x50], 0x49);
+        assert_eq!(bytes[0x70], 0x51);
+
+        let decoded = NvrSettings::from_bytes(&bytes);
+        assert_eq!(decoded, settings);
+        assert_eq!(
+            NvrSettings::from_toml(&decoded.to_toml().unwrap()).unwrap(),
+            settings
+        );
+    }
+
+    #[test]
+    fn test_invalid_settings() {
+        let mut settings = NvrSettings::default();
+        settings.sessions[0].columns = 100;
+        assert!(settings.to_bytes(&DEFAULT_NVR).is_err());
+    }
+}
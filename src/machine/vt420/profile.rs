@@ -0,0 +1,134 @@
+//! Accumulates the time spent in each phase of [`System::step`], for
+//! `--profile`.
+//!
+//! [`System::step`]: super::System::step
+
+use std::io::{self, Write};
+use std::time::{Duration, Instant};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Phase {
+    Breakpoints,
+    Cpu,
+    Memory,
+    Keyboard,
+    Serial,
+    Comm,
+    Video,
+    Timer,
+    Other,
+}
+
+impl Phase {
+    const ALL: [Phase; 9] = [
+        Phase::Breakpoints,
+        Phase::Cpu,
+        Phase::Memory,
+        Phase::Keyboard,
+        Phase::Serial,
+        Phase::Comm,
+        Phase::Video,
+        Phase::Timer,
+        Phase::Other,
+    ];
+
+    fn name(self) -> &'static str {
+        match self {
+            Phase::Breakpoints => "breakpoints",
+            Phase::Cpu => "cpu.step",
+            Phase::Memory => "memory.tick",
+            Phase::Keyboard => "keyboard.tick",
+            Phase::Serial => "serial.tick",
+            Phase::Comm => "comm",
+            Phase::Video => "video_row.tick",
+            Phase::Timer => "timer.tick",
+            Phase::Other => "other",
+        }
+    }
+}
+
+pub struct Profile {
+    phases: [Duration; Phase::ALL.len()],
+    last: Instant,
+}
+
+impl Default for Profile {
+    fn default() -> Self {
+        Self {
+            phases: Default::default(),
+            last: Instant::now(),
+        }
+    }
+}
+
+impl Profile {
+    /// Start timing from now, without charging the time since the last mark to
+    /// any phase.
+    pub fn start(&mut self) {
+        self.last = Instant::now();
+    }
+
+    /// Charge the time since the last mark to `phase`.
+    pub fn mark(&mut self, phase: Phase) {
+        let now = Instant::now();
+        self.phases[phase as usize] += now - self.last;
+        self.last = now;
+    }
+
+    pub fn total(&self) -> Duration {
+        self.phases.iter().sum()
+    }
+
+    /// Write the time spent in each phase and its share of the total.
+    pub fn write_report(&self, mut writer: impl Write) -> io::Result<()> {
+        let total = self.total().as_secs_f64();
+        for phase in Phase::ALL {
+            let time = self.phases[phase as usize];
+            let percent = if total > 0.0 {
+                time.as_secs_f64() / total * 100.0
+            } else {
+                0.0
+            };
+            writeln!(
+                writer,
+                "  {:<16}{:>12.3}ms{:>7.1}%",
+                phase.name(),
+                time.as_secs_f64() * 1000.0,
+                percent
+            )?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_report() {
+        let mut profile = Profile::default();
+        profile.phases[Phase::Cpu as usize] = Duration::from_millis(300);
+        profile.phases[Phase::Memory as usize] = Duration::from_millis(100);
+        assert_eq!(profile.total(), Duration::from_millis(400));
+
+        let mut report = vec![];
+        profile.write_report(&mut report).unwrap();
+        let report = String::from_utf8(report).unwrap();
+        let lines = report.lines().collect::<Vec<_>>();
+        assert_eq!(lines.len(), Phase::ALL.len());
+        assert_eq!(lines[1], "  cpu.step             300.000ms   75.0%");
+        assert_eq!(lines[2], "  memory.tick          100.000ms   25.0%");
+        assert_eq!(lines[0], "  breakpoints            0.000ms    0.0%");
+    }
+
+    #[test]
+    fn test_mark() {
+        let mut profile = Profile::default();
+        profile.start();
+        std::thread::sleep(Duration::from_millis(5));
+        profile.mark(Phase::Video);
+        assert!(profile.phases[Phase::Video as usize] >= Duration::from_millis(5));
+        assert_eq!(profile.total(), profile.phases[Phase::Video as usize]);
+    }
+}
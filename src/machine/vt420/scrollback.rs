@@ -0,0 +1,149 @@
+//! Capture of the lines that scroll off the top of the screen.
+//!
+//! The VT420 doesn't keep any scrollback of its own, so we snapshot the
+//! rendered text periodically and work out how far it moved between snapshots.
+
+use std::collections::VecDeque;
+use std::io::{self, Write};
+
+use crate::machine::vt420::video::Screen;
+
+/// Default number of lines kept by [`Scrollback`].
+pub const DEFAULT_SCROLLBACK_LINES: usize = 10_000;
+
+pub struct Scrollback {
+    lines: VecDeque<String>,
+    max_lines: usize,
+    previous: Vec<String>,
+}
+
+impl Scrollback {
+    pub fn new(max_lines: usize) -> Self {
+        Self {
+            lines: VecDeque::new(),
+            max_lines,
+            previous: vec![],
+        }
+    }
+
+    /// Compare a snapshot of the screen with the previous one and keep any
+    /// lines that scrolled off the top.
+    pub fn update(&mut self, screen: Vec<String>) {
+        if screen.is_empty() || screen == self.previous {
+            return;
+        }
+        let scrolled = scroll_distance(&self.previous, &screen);
+        for line in self.previous.drain(..scrolled) {
+            if self.lines.len() == self.max_lines {
+                self.lines.pop_front();
+            }
+            if self.max_lines > 0 {
+                self.lines.push_back(line);
+            }
+        }
+        self.previous = screen;
+    }
+
+    /// The lines that have scrolled off the screen, oldest first.
+    #[allow(dead_code)]
+    pub fn lines(&self) -> impl ExactSizeIterator<Item = &str> {
+        self.lines.iter().map(String::as_str)
+    }
+
+    /// Write the scrollback followed by the last snapshot of the screen.
+    pub fn write_transcript(&self, mut writer: impl Write) -> io::Result<()> {
+        for line in self.lines.iter().chain(&self.previous) {
+            writeln!(writer, "{line}")?;
+        }
+        Ok(())
+    }
+}
+
+/// The text of every non-status row on screen, from the regions returned
+/// by [`decode_screen_split`](crate::machine::vt420::video::decode_screen_split).
+pub fn screen_lines((top, bottom): (Screen, Option<Screen>)) -> Vec<String> {
+    let mut lines = top.lines;
+    lines.extend(bottom.into_iter().flat_map(|screen| screen.lines));
+    lines
+}
+
+/// The number of rows the screen moved up between two snapshots, or zero if
+/// it doesn't look like a scroll.
+///
+/// The bottom row of the previous snapshot is allowed to differ, since it may
+/// have been written to between the scroll and the snapshot.
+fn scroll_distance(previous: &[String], current: &[String]) -> usize {
+    let rows = previous.len().min(current.len());
+    for distance in 1..rows.saturating_sub(1) {
+        let overlap = &previous[distance..rows - 1];
+        if overlap.iter().any(|line| !line.is_empty()) && overlap == &current[..rows - 1 - distance]
+        {
+            return distance;
+        }
+    }
+    0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn screen(lines: &[&str]) -> Vec<String> {
+        lines.iter().map(|line| line.to_string()).collect()
+    }
+
+    #[test]
+    fn test_scroll() {
+        let mut scrollback = Scrollback::new(100);
+        scrollback.update(screen(&["one", "two", "three", "four", ""]));
+        scrollback.update(screen(&["two", "three", "four", "five", ""]));
+        scrollback.update(screen(&["four", "five", "six", "seven", "$ ec"]));
+        assert_eq!(
+            scrollback.lines().collect::<Vec<_>>(),
+            ["one", "two", "three"]
+        );
+
+        let mut transcript = vec![];
+        scrollback.write_transcript(&mut transcript).unwrap();
+        assert_eq!(
+            String::from_utf8(transcript).unwrap(),
+            "one\ntwo\nthree\nfour\nfive\nsix\nseven\n$ ec\n"
+        );
+    }
+
+    #[test]
+    fn test_no_scroll() {
+        let mut scrollback = Scrollback::new(100);
+        scrollback.update(screen(&["one", "two", "", "", ""]));
+        // Typing on the bottom row
+        scrollback.update(screen(&["one", "two", "t", "", ""]));
+        // Clearing the screen
+        scrollback.update(screen(&["", "", "", "", ""]));
+        assert_eq!(scrollback.lines().len(), 0);
+    }
+
+    #[test]
+    fn test_max_lines() {
+        let mut scrollback = Scrollback::new(2);
+        scrollback.update(screen(&["one", "two", "three", "four", ""]));
+        scrollback.update(screen(&["four", "five", "six", "seven", ""]));
+        assert_eq!(scrollback.lines().collect::<Vec<_>>(), ["two", "three"]);
+    }
+
+    #[test]
+    fn test_screen_lines_split() {
+        let top = Screen {
+            start_row: 0,
+            lines: screen(&["one", "two"]),
+        };
+        let bottom = Screen {
+            start_row: 2,
+            lines: screen(&["three"]),
+        };
+        assert_eq!(
+            screen_lines((top.clone(), Some(bottom))),
+            ["one", "two", "three"]
+        );
+        assert_eq!(screen_lines((top, None)), ["one", "two"]);
+    }
+}
@@ -3,13 +3,39 @@
 //! sync signal passes correctly, and the self-test for number of csync pulses
 //! per frame returns both the correct timing and correct number of pulses.
 
+use std::time::Duration;
+
 use crate::machine::generic::vsync::Timing;
 use hex_literal::hex;
+use thiserror::Error;
 use tracing::trace;
 
 /// The number of vertical lines expected by the ROM
 pub const VERTICAL_LINES: usize = 417;
 
+/// Height of the status row, in lines.
+pub const STATUS_ROW_HEIGHT: usize = 16;
+
+/// The status row always starts this far down the display, 16 lines above the
+/// bottom.
+pub const STATUS_ROW_TOP: usize = VERTICAL_LINES - STATUS_ROW_HEIGHT;
+
+/// The lines available to the rows above the status row, which are followed
+/// by a blank line. The first row that starts below them is the status row,
+/// whichever row of VRAM that is.
+const PAGE_LINES: usize = STATUS_ROW_TOP - 1;
+
+/// The shortest character cell the firmware programs, for 48 rows.
+const MIN_ROW_HEIGHT: u8 = 8;
+
+/// Row addresses below this are shared blank and filler rows, which may
+/// appear any number of times. Text rows start at 0x1100 in VRAM.
+const FIRST_TEXT_ROW: u8 = 0x22;
+
+/// Length of each on/off phase of blinking text, shared by the display
+/// backends.
+pub const BLINK_PHASE: Duration = Duration::from_millis(320);
+
 pub const TIMING_60HZ: Timing = Timing {
     h_active: 20,
     h_fp: 2,
@@ -37,6 +63,24 @@ pub struct Mapper {
     pub mapper2: [u8; 16], // 6, 9, a, b, c can be written twice
 }
 
+/// A row table that breaks an invariant the display relies on, usually
+/// because the firmware has written over it.
+#[derive(Clone, Copy, Debug, Error, PartialEq, Eq)]
+pub enum RowError {
+    /// A font register programs a row height the firmware never uses.
+    #[error("screen {screen} row height is {height} lines")]
+    RowHeight { screen: u8, height: u8 },
+    /// The displayed rows switch screens more than once.
+    #[error("row {row} is a second screen-swap row")]
+    ExtraScreenSwap { row: u8 },
+    /// Two displayed rows show the same text row from VRAM.
+    #[error("row {row} repeats row {first} (address {address:02X})")]
+    DuplicateRow { row: u8, first: u8, address: u8 },
+    /// The row table ran out before reaching the status row.
+    #[error("no status row after {rows} rows")]
+    MissingStatusRow { rows: u8 },
+}
+
 impl Mapper {
     pub fn new() -> Self {
         let mut new = Self {
@@ -94,6 +138,13 @@ impl Mapper {
         self.get(3) & 0x08 != 0
     }
 
+    /// Whether screen 1 is in 132-column mode. This bit alone sets the width
+    /// of the screen's rows: every row in VRAM has room for 132 cells, so a
+    /// row can't decode to fewer columns than the mapper asks for, and the
+    /// low bit of the row address (documented in `ARCH.md` as "force 132
+    /// columns") isn't consulted. In the `132-columns` capture under
+    /// `tests/vram/`, the firmware sets this bit and leaves every row address
+    /// even, so the firmware doesn't use the row bit for 132 columns.
     pub fn screen_1_132_columns(&self) -> bool {
         self.get(3) & 0x01 != 0
     }
@@ -169,6 +220,61 @@ impl Mapper {
         Some(count)
     }
 
+    /// Check the row table against the invariants that [`decode_vram`]
+    /// relies on, walking the rows in the same way. A table that isn't
+    /// displayed because of vertical refresh is always valid.
+    pub fn validate_rows(&self, vram: &[u8]) -> Result<(), RowError> {
+        let Some(rows) = self.row_count(vram) else {
+            return Ok(());
+        };
+        for (screen, height) in [
+            (1, self.row_height_screen_1()),
+            (2, self.row_height_screen_2()),
+        ] {
+            if height < MIN_ROW_HEIGHT {
+                return Err(RowError::RowHeight { screen, height });
+            }
+        }
+
+        let mut screen_2 = self.is_screen_2();
+        let mut swapped = false;
+        let mut first_seen = [None; 128];
+        let mut top = 0;
+        for row_idx in 0..=rows {
+            let row = Row(vram[row_idx as usize * 2], vram[row_idx as usize * 2 + 1]);
+            if row.is_invalid() {
+                continue;
+            }
+            if top >= PAGE_LINES {
+                return Ok(());
+            }
+            if row.is_screen_swap_row() {
+                if swapped {
+                    return Err(RowError::ExtraScreenSwap { row: row_idx });
+                }
+                swapped = true;
+                screen_2 = !screen_2;
+            }
+            if row.0 >= FIRST_TEXT_ROW {
+                let seen = &mut first_seen[row.0 as usize >> 1];
+                if let Some(first) = *seen {
+                    return Err(RowError::DuplicateRow {
+                        row: row_idx,
+                        first,
+                        address: row.0,
+                    });
+                }
+                *seen = Some(row_idx);
+            }
+            top += if screen_2 {
+                self.row_height_screen_2()
+            } else {
+                self.row_height_screen_1()
+            } as usize;
+        }
+        Err(RowError::MissingStatusRow { rows })
+    }
+
     pub fn is_blink(&self) -> bool {
         self.get(3) & 0x40 != 0
     }
@@ -212,7 +318,7 @@ fn row_count(font_regs: [u8; 2], vram: &[u8]) -> Option<u8> {
     debug_assert!(rh1 > 0 && rh1 <= 16);
     debug_assert!(rh2 > 0 && rh2 <= 16);
 
-    let mut remaining = VERTICAL_LINES - 17;
+    let mut remaining = PAGE_LINES;
     let mut rh = rh1 as usize;
     let mut screen = 0;
     let mut count = 0;
@@ -238,7 +344,11 @@ fn row_count(font_regs: [u8; 2], vram: &[u8]) -> Option<u8> {
 
 /// Row VRAM data:
 ///
-/// - Byte 0: Row address (shifted left by 1). Lower bit use unknown.
+/// - Byte 0: Row address (shifted left by 1). The low bit is clear in every
+///   row of the captures under `tests/vram/` (boot, Set-Up, 132 columns,
+///   double size, attributes and the host status line), including the shared
+///   filler rows below the text rows (eg: 0x1C, 0x1E). The firmware has never
+///   been seen to set it, so it is ignored when decoding.
 /// - Byte 1: Row attributes
 #[derive(Clone, Copy, Debug)]
 pub struct Row(u8, u8);
@@ -278,15 +388,9 @@ impl Row {
     pub fn is_invalid(&self) -> bool {
         self.0 == 0
     }
-
-    /// This is not correct, but works for now
-    #[inline(always)]
-    pub fn is_status_row(&self) -> bool {
-        self.0 == 0x1C || self.0 == 0x1E
-    }
 }
 
-#[derive(Clone, Copy, Debug, Default)]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
 pub struct RowFlags {
     pub is_80: bool,
     pub invert: bool,
@@ -296,20 +400,119 @@ pub struct RowFlags {
     pub status_row: bool,
     pub screen_2: bool,
     pub row_height: u8,
+    /// The offset of the row's font from [`FONT_BASE`].
     pub font: u16,
 }
 
+/// The attributes of one decoded cell, as passed to the column callback of
+/// [`decode_vram`].
+///
+/// The low six bits are the cell's own attributes. Bits 0-1 come from the
+/// packed attribute bytes that follow the characters in each row (two bits
+/// per cell, from offset 0xdd), and bits 2-5 are the top nibble of the
+/// cell's 12-bit character code:
+///
+/// - 0: underline
+/// - 1: protected from selective erase (DECSCA), which isn't displayed
+/// - 2: the glyph comes from the second 256 glyphs of the font. The firmware
+///   only uses these for the power-up self-test messages.
+/// - 3: bold
+/// - 4: reverse video
+/// - 5: blink. In the status row, which never blinks, a set bit instead takes
+///   the cell's six pixels from the first half of the glyph rather than from
+///   [`FONT_132_OFFSET`].
+///
+/// The code's top nibble is also kept in bits 8-11, and bits 12 and 13 repeat
+/// the row's double-width and 132-column flags.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub struct CellAttr(u16);
+
+impl CellAttr {
+    const UNDERLINE: u16 = 1 << 0;
+    const PROTECTED: u16 = 1 << 1;
+    const ALTERNATE_GLYPH: u16 = 1 << 2;
+    const BOLD: u16 = 1 << 3;
+    const REVERSE: u16 = 1 << 4;
+    const BLINK: u16 = 1 << 5;
+    const DOUBLE_WIDTH: u16 = 1 << 12;
+    const COLUMNS_132: u16 = 1 << 13;
+
+    #[allow(dead_code)]
+    pub const fn from_bits(bits: u16) -> Self {
+        Self(bits)
+    }
+
+    /// All of the bits, as described on [`CellAttr`].
+    #[allow(dead_code)]
+    pub const fn bits(self) -> u16 {
+        self.0
+    }
+
+    /// The six attribute bits of the cell itself.
+    pub const fn cell_bits(self) -> u8 {
+        (self.0 & 0x3f) as u8
+    }
+
+    /// The top nibble of the cell's 12-bit character code.
+    pub const fn code_nibble(self) -> u8 {
+        ((self.0 >> 8) & 0xf) as u8
+    }
+
+    pub const fn underline(self) -> bool {
+        self.0 & Self::UNDERLINE != 0
+    }
+
+    pub const fn protected(self) -> bool {
+        self.0 & Self::PROTECTED != 0
+    }
+
+    pub const fn alternate_glyph(self) -> bool {
+        self.0 & Self::ALTERNATE_GLYPH != 0
+    }
+
+    pub const fn bold(self) -> bool {
+        self.0 & Self::BOLD != 0
+    }
+
+    pub const fn reverse(self) -> bool {
+        self.0 & Self::REVERSE != 0
+    }
+
+    pub const fn blink(self) -> bool {
+        self.0 & Self::BLINK != 0
+    }
+
+    #[allow(dead_code)]
+    pub const fn double_width(self) -> bool {
+        self.0 & Self::DOUBLE_WIDTH != 0
+    }
+
+    #[allow(dead_code)]
+    pub const fn columns_132(self) -> bool {
+        self.0 & Self::COLUMNS_132 != 0
+    }
+
+    /// Whether this is the bold, reverse, blinking fill that the firmware
+    /// leaves in unused cells, which are drawn blank when they hold character
+    /// 0.
+    pub const fn is_fill(self) -> bool {
+        (self.0 >> 2) & 0xf == 0xe
+    }
+}
+
 struct Cell(u8, u8, u8);
 
 /// Decode the VRAM into a grid of characters and attributes.
 /// The row_callback is called for each row, with the row index and the row attributes.
+/// The status row is the first row that starts past the lines of the page, and
+/// is always the last row decoded.
 /// The column_callback is called for each column, with the column, display character and its attributes.
 #[inline(always)]
 pub fn decode_vram<T>(
     vram: &[u8],
     mapper: &Mapper,
     mut row_callback: impl FnMut(&mut T, u8, Row, RowFlags),
-    mut column_callback: impl FnMut(&mut T, u8, u8, u16),
+    mut column_callback: impl FnMut(&mut T, u8, u8, CellAttr),
     mut data: T,
 ) -> T {
     let vram_base = 0;
@@ -321,8 +524,15 @@ pub fn decode_vram<T>(
     let mut line = [0_u16; 256];
     let mut attr = [0_u8; 256];
     let mut screen_2 = mapper.is_screen_2();
-
-    for row_idx in 0..rows as u16 {
+    let mut top = 0;
+
+    for row_idx in 0..=rows as u16 {
+        // Rows shorter than the ones counted can push the status row one row
+        // past the count
+        let status_row = top >= PAGE_LINES;
+        if row_idx == rows as u16 && !status_row {
+            break;
+        }
         let row = Row(
             vram[vram_base + row_idx as usize * 2],
             vram[vram_base + row_idx as usize * 2 + 1],
@@ -335,7 +545,7 @@ pub fn decode_vram<T>(
             screen_2 = !screen_2;
         }
 
-        let font = if screen_2 && !row.is_status_row() {
+        let font = if screen_2 && !status_row {
             mapper.get(0xc)
         } else {
             mapper.get2(0xc)
@@ -347,12 +557,10 @@ pub fn decode_vram<T>(
             mapper.screen_1_132_columns()
         };
 
-        let mut font = (font & 0xf0) * 0x80;
-        if row.is_status_row() {
+        let font = (font & 0xf0) * 0x80;
+        if status_row {
             is_132 = true;
-        } else if is_132 {
-            font += 16;
-        };
+        }
 
         let row_flags = RowFlags {
             screen_2,
@@ -365,8 +573,10 @@ pub fn decode_vram<T>(
             double_width: !row.is_single_width(),
             double_height_top: row.is_double_height_top(),
             double_height_bottom: row.is_double_height_bottom(),
-            status_row: row.is_status_row(),
-            row_height: if screen_2 {
+            status_row,
+            row_height: if status_row {
+                STATUS_ROW_HEIGHT as u8
+            } else if screen_2 {
                 mapper.row_height_screen_2()
             } else {
                 mapper.row_height_screen_1()
@@ -440,22 +650,99 @@ pub fn decode_vram<T>(
             let value = line[col];
             let char_code = (value & 0xff) as u8;
 
-            let mut combined_attr = (value & 0xf00) as u16 | attr[col] as u16;
+            let mut combined_attr = (value & 0xf00) | attr[col] as u16;
             if row_flags.double_width {
-                combined_attr |= 1 << 12;
+                combined_attr |= CellAttr::DOUBLE_WIDTH;
             }
             if !row_flags.is_80 {
-                combined_attr |= 1 << 13;
+                combined_attr |= CellAttr::COLUMNS_132;
             }
 
-            column_callback(&mut data, col as u8, char_code, combined_attr);
+            column_callback(&mut data, col as u8, char_code, CellAttr(combined_attr));
         }
+
+        if status_row {
+            break;
+        }
+        top += row_flags.row_height as usize;
     }
     data
 }
 
-/// Decode the font into a grid of pixels. For 80-column mode, the font is 10
-/// bytes width. For 132-column mode, the font is 6 bits wide.
+/// The text of one screen region, as decoded from VRAM.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Screen {
+    /// The display row that the region starts on.
+    #[cfg(test)]
+    pub start_row: u8,
+    /// The text of each row, with trailing spaces removed.
+    pub lines: Vec<String>,
+}
+
+/// Decode the text of the two screen regions separately. The region above
+/// the first screen-swap row is returned first, and the region below it is
+/// returned if the screen is split. The status row belongs to neither region.
+pub fn decode_screen_split(vram: &[u8], mapper: &Mapper) -> (Screen, Option<Screen>) {
+    type State = (Screen, Option<Screen>, Option<bool>);
+
+    let top_is_screen_2 = mapper.is_screen_2();
+    let (mut top, mut bottom, _) = decode_vram(
+        vram,
+        mapper,
+        |(top, bottom, current): &mut State, _row_idx, _, flags| {
+            *current = (!flags.status_row).then_some(flags.screen_2 != top_is_screen_2);
+            let screen = match *current {
+                None => return,
+                Some(false) => top,
+                Some(true) => bottom.get_or_insert_with(Screen::default),
+            };
+            #[cfg(test)]
+            if screen.lines.is_empty() {
+                screen.start_row = _row_idx;
+            }
+            screen.lines.push(String::new());
+        },
+        |(top, bottom, current): &mut State, _, ch, _| {
+            let screen = match *current {
+                None => return,
+                Some(false) => Some(top),
+                Some(true) => bottom.as_mut(),
+            };
+            if let Some(line) = screen.and_then(|screen| screen.lines.last_mut()) {
+                line.push(match ch {
+                    0x20..=0x7e => ch as char,
+                    _ => ' ',
+                });
+            }
+        },
+        (Screen::default(), None, None),
+    );
+    for line in top
+        .lines
+        .iter_mut()
+        .chain(bottom.iter_mut().flat_map(|s| &mut s.lines))
+    {
+        line.truncate(line.trim_end().len());
+    }
+    (top, bottom)
+}
+
+/// The VRAM address of the fonts, which [`RowFlags::font`] is relative to.
+pub const FONT_BASE: u32 = 0x8000;
+
+/// The offset of the 132-column pixels within a glyph. They are the top six
+/// bits of the second 16 bytes, whose low two bits are pixels 8 and 9 of the
+/// 80-column glyph.
+pub const FONT_132_OFFSET: u32 = 16;
+
+/// The VRAM address of glyph `glyph` of the font at offset `font`.
+pub fn font_glyph_address(font: u16, glyph: usize) -> u32 {
+    FONT_BASE + font as u32 + (glyph * FONT_GLYPH_BYTES) as u32
+}
+
+/// Decode the glyph at `address` into a grid of pixels. In 80-column mode the
+/// glyph is 10 pixels wide, from both halves of the glyph. In 132-column mode
+/// it is 6 pixels wide, from [`FONT_132_OFFSET`].
 pub fn decode_font(vram: &[u8], address: u32, is_80: bool, char: &mut [u16; 16]) {
     if is_80 {
         for y in 0..16 {
@@ -463,12 +750,33 @@ pub fn decode_font(vram: &[u8], address: u32, is_80: bool, char: &mut [u16; 16])
                 | ((vram[address as usize + y + 16] & 3) as u16) << 8;
         }
     } else {
+        let address = (address + FONT_132_OFFSET) as usize;
         for y in 0..16 {
-            char[y] = (vram[address as usize + y] >> 2) as u16;
+            char[y] = (vram[address + y] >> 2) as u16;
         }
     }
 }
 
+/// Bytes per glyph in a font file that overrides the firmware's font.
+///
+/// Glyph `n` starts at byte `n * 32` and uses the layout of the 80-column
+/// font in VRAM: bytes 0-15 hold pixels 0-7 of each of the 16 lines, with bit
+/// 0 as the leftmost pixel, and the low two bits of bytes 16-31 hold pixels 8
+/// and 9. Glyphs 0x100 and up are the alternate character set. 132-column
+/// cells show the top six bits of bytes 16-31, at [`FONT_132_OFFSET`], as
+/// they do for the firmware's font.
+pub const FONT_GLYPH_BYTES: usize = 32;
+
+/// Decode glyph `glyph` of a font override file into a grid of pixels, as
+/// [`decode_font`] does. Glyphs past the end of the file are blank.
+pub fn decode_override_glyph(font: &[u8], glyph: usize, is_80: bool, char: &mut [u16; 16]) {
+    if (glyph + 1) * FONT_GLYPH_BYTES <= font.len() {
+        decode_font(font, (glyph * FONT_GLYPH_BYTES) as u32, is_80, char);
+    } else {
+        char.fill(0);
+    }
+}
+
 /// This handles a read of 0x7ff6. We don't know what this register does, but it
 /// appears to return something that is a function of 80/132 column mode,
 /// invert, the "screen selection toggle" row attribute (along with double-width
@@ -534,6 +842,173 @@ mod tests {
     use super::*;
     use crate::machine::generic::vsync::SyncGen;
 
+    /// Write a line of text into the first segment of a VRAM row.
+    fn put_line(vram: &mut [u8], row_addr: u8, text: &[u8]) {
+        let base = (row_addr as usize >> 1) << 8;
+        for (i, &ch) in text.iter().enumerate() {
+            let cell = base + i / 2 * 3;
+            if i % 2 == 0 {
+                vram[cell] = ch;
+            } else {
+                vram[cell + 1] |= (ch & 0xf) << 4;
+                vram[cell + 2] = ch >> 4;
+            }
+        }
+    }
+
+    /// The status row starts 16 lines above the bottom of the display,
+    /// whatever the row height and whichever row of VRAM lands there.
+    #[test]
+    fn test_status_row_position() {
+        for (font_reg, status_idx) in [(0xd0, 25), (0x9a, 40), (0x78, 50), (0x0c, 34)] {
+            let mut vram = vec![0_u8; 0x20000];
+            for i in 0..100 {
+                vram[i * 2] = 0x22 + (i as u8 % 0x40) * 2;
+            }
+            let mut mapper = Mapper::new();
+            mapper.set(3, 0);
+            mapper.set(4, 0);
+            mapper.set(6, font_reg);
+            mapper.set(6, font_reg);
+
+            type Decoded = (Vec<(u8, RowFlags)>, Option<(u8, usize, RowFlags)>);
+            let (rows, status) = decode_vram(
+                &vram,
+                &mapper,
+                |(rows, status): &mut Decoded, row_idx, _, flags| {
+                    if flags.status_row {
+                        let top: usize = rows.iter().map(|(_, f)| f.row_height as usize).sum();
+                        *status = Some((row_idx, top, flags));
+                    }
+                    rows.push((row_idx, flags));
+                },
+                |_, _, _, _| {},
+                (vec![], None),
+            );
+            let (row_idx, top, flags) = status.unwrap();
+            assert_eq!(row_idx, status_idx, "{font_reg:02X}");
+            assert_eq!(rows.len(), status_idx as usize + 1, "{font_reg:02X}");
+            // The rows above may end partway into the status row's lines
+            assert!(top >= STATUS_ROW_TOP - 1, "{font_reg:02X}: {top}");
+            assert!(!flags.is_80);
+            assert_eq!(flags.row_height as usize, STATUS_ROW_HEIGHT);
+        }
+    }
+
+    /// A table like the firmware writes for two sessions, 24 rows over 36,
+    /// with the status row at row 29.
+    fn split_row_table() -> (Vec<u8>, Mapper) {
+        let mut vram = vec![0_u8; 0x20000];
+        for i in 0..18 {
+            vram[i * 2] = 0x22 + i as u8 * 2;
+        }
+        vram[36] = 0x16;
+        for i in 0..10 {
+            vram[38 + i * 2] = 0x90 + i as u8 * 2;
+        }
+        vram[39] = 0x02;
+        vram[58] = 0x1c;
+        let mut mapper = Mapper::new();
+        mapper.set(3, 0);
+        mapper.set(4, 0);
+        mapper.set(6, 0xd0);
+        mapper.set(6, 0x9a);
+        (vram, mapper)
+    }
+
+    #[test]
+    fn test_validate_rows() {
+        let (vram, mapper) = split_row_table();
+        assert_eq!(mapper.validate_rows(&vram), Ok(()));
+
+        // Rows past the status row aren't displayed, so aren't checked
+        let mut past_status = vram.clone();
+        past_status[60..64].copy_from_slice(&hex!("22 02 22 02"));
+        assert_eq!(mapper.validate_rows(&past_status), Ok(()));
+
+        // Nothing is displayed during vertical refresh
+        let mut refresh = Mapper::new();
+        refresh.set(6, 0xf0);
+        refresh.set(6, 0xf0);
+        assert_eq!(refresh.validate_rows(&[]), Ok(()));
+    }
+
+    #[test]
+    fn test_validate_corrupted_rows() {
+        let (vram, mapper) = split_row_table();
+
+        let mut duplicate = vram.clone();
+        duplicate[10] = 0x22;
+        assert_eq!(
+            mapper.validate_rows(&duplicate),
+            Err(RowError::DuplicateRow {
+                row: 5,
+                first: 0,
+                address: 0x22
+            })
+        );
+
+        let mut swap = vram.clone();
+        swap[45] = 0x02;
+        assert_eq!(
+            mapper.validate_rows(&swap),
+            Err(RowError::ExtraScreenSwap { row: 22 })
+        );
+
+        let mut height = Mapper::new();
+        height.set(6, 0xd0);
+        height.set(6, 0x94);
+        assert_eq!(
+            height.validate_rows(&vram),
+            Err(RowError::RowHeight {
+                screen: 2,
+                height: 4
+            })
+        );
+
+        // Only zeroed rows after the first screen, so the page never fills
+        let mut short = vram.clone();
+        short[36..].fill(0);
+        assert!(matches!(
+            mapper.validate_rows(&short),
+            Err(RowError::MissingStatusRow { .. })
+        ));
+    }
+
+    #[test]
+    fn test_decode_screen_split() {
+        let mut vram = vec![0_u8; 0x20000];
+        // Screen 2 starts at the third row, which has the swap attribute
+        vram[0..8].copy_from_slice(&hex!("22 00 24 00 26 02 28 00"));
+        put_line(&mut vram, 0x22, b"session one");
+        put_line(&mut vram, 0x24, b"$ ls");
+        put_line(&mut vram, 0x26, b"session two");
+        put_line(&mut vram, 0x28, b"login:");
+        let mut mapper = Mapper::new();
+        mapper.set(3, 0);
+        mapper.set(4, 0);
+
+        let (top, bottom) = decode_screen_split(&vram, &mapper);
+        assert_eq!(top.start_row, 0);
+        assert_eq!(top.lines, ["session one", "$ ls"]);
+        let bottom = bottom.unwrap();
+        assert_eq!(bottom.start_row, 2);
+        assert_eq!(bottom.lines, ["session two", "login:"]);
+
+        // The low bit of the row address doesn't change which row is shown
+        let mut odd = vram.clone();
+        odd[2] |= 1;
+        let (odd_top, odd_bottom) = decode_screen_split(&odd, &mapper);
+        assert_eq!(odd_top.lines, top.lines);
+        assert_eq!(odd_bottom.unwrap().lines, bottom.lines);
+
+        // Without the swap row, everything belongs to one screen
+        vram[5] = 0;
+        let (top, bottom) = decode_screen_split(&vram, &mapper);
+        assert_eq!(top.lines.len(), 4);
+        assert!(bottom.is_none());
+    }
+
     #[test]
     fn test_sync_gen_60hz() {
         let mut sync_gen = SyncGen::new(TIMING_60HZ);
@@ -795,4 +1270,84 @@ mod tests {
         42 00 44 00 46 00 48 00 4A 00 16 00 90 02 92 00 94 00 96 00 98 00 9A 00 9C 00 9E 00 A0 00 A2 00
         A4 00 A6 00 A8 00 AA 00 AC 00 AE 00 B0 00 B2 00 B4 00 B6 00 B8 00 18 00 1E 00 1C 00 1E 00 1E 00"));
     }
+
+    #[test]
+    fn test_cell_attr() {
+        let attr = CellAttr::from_bits(0x01);
+        assert!(attr.underline());
+        assert!(!attr.protected() && !attr.bold() && !attr.reverse() && !attr.blink());
+
+        // Bold, reverse and blink from the code nibble 0xe, protected from the
+        // attribute byte
+        let attr = CellAttr::from_bits(0xe3a);
+        assert!(attr.protected() && attr.bold() && attr.reverse() && attr.blink());
+        assert!(!attr.underline() && !attr.alternate_glyph());
+        assert!(attr.is_fill());
+        assert_eq!(attr.cell_bits(), 0x3a);
+        assert_eq!(attr.code_nibble(), 0xe);
+
+        let attr = CellAttr::from_bits(0x3104);
+        assert!(attr.alternate_glyph());
+        assert!(attr.double_width() && attr.columns_132());
+        assert!(!attr.is_fill());
+    }
+
+    #[test]
+    fn test_decode_cell_attr() {
+        let mut vram = vec![0_u8; 0x20000];
+        vram[0..2].copy_from_slice(&hex!("22 00"));
+        put_line(&mut vram, 0x22, b"abcd");
+        let base = 0x1100;
+        // Code nibbles: bold on the first cell, reverse and blink on the
+        // second, the alternate glyphs on the fourth
+        vram[base + 1] |= 0x2;
+        vram[base + 2] |= 0xc0;
+        vram[base + 5] |= 0x01 << 4;
+        // Attribute bytes: cell 0 underlined, cell 2 protected
+        vram[base + 0xdd] = 0x01 << 2;
+        vram[base + 0xdd] |= 0x02 << 6;
+        let mut mapper = Mapper::new();
+        mapper.set(3, 0);
+        mapper.set(4, 0);
+
+        let attrs = decode_vram(
+            &vram,
+            &mapper,
+            |_, _, _, _| {},
+            |attrs: &mut Vec<(u8, CellAttr)>, _, ch, attr| attrs.push((ch, attr)),
+            vec![],
+        );
+        let attrs = &attrs[..4];
+        assert_eq!(attrs.iter().map(|(ch, _)| *ch).collect::<Vec<_>>(), b"abcd");
+        let cells = attrs
+            .iter()
+            .map(|(_, attr)| attr.cell_bits())
+            .collect::<Vec<_>>();
+        assert_eq!(cells, [0x09, 0x30, 0x02, 0x04]);
+        assert!(attrs[0].1.bold() && attrs[0].1.underline());
+        assert!(attrs[1].1.reverse() && attrs[1].1.blink());
+        assert!(attrs[2].1.protected());
+        assert!(attrs[3].1.alternate_glyph());
+        assert!(!attrs[0].1.double_width());
+    }
+
+    #[test]
+    fn test_decode_132_column_glyph() {
+        let mut vram = vec![0_u8; 0x20000];
+        let font = 0x10 * 0x80;
+        let address = font_glyph_address(font, 0x41);
+        assert_eq!(address, 0x8000 + 0x800 + 0x41 * 32);
+        // An 80-column glyph, with pixels 8 and 9 in the low bits of the
+        // second half, whose top six bits are the 132-column glyph
+        for y in 0..16 {
+            vram[address as usize + y] = 0x81;
+            vram[address as usize + 16 + y] = ((y as u8) << 2) | 0x2;
+        }
+
+        let mut glyph = [0; 16];
+        decode_font(&vram, address, false, &mut glyph);
+        assert_eq!(glyph, std::array::from_fn(|y| y as u16));
+        decode_font(&vram, address, true, &mut glyph);
+        assert_eq!(glyph, [0x281; 16]);
+    }
 }
@@ -0,0 +1,132 @@
+//! Detection of the firmware hanging in a tight loop.
+//!
+//! The watchdog follows the range of addresses the CPU has executed from since
+//! the firmware last wrote to the DUART or the video mapper. Once the range
+//! grows past [`WATCHDOG_WINDOW`] bytes, eg: when an interrupt is serviced, it
+//! starts again from the current PC. A loop that stays inside the window for
+//! too long without any of those writes is reported as a hang.
+
+use std::fmt;
+
+use tracing::{info, warn};
+
+/// The widest range of addresses, in bytes, that still counts as a tight loop.
+pub const WATCHDOG_WINDOW: u32 = 0x40;
+
+/// A tight loop found by the [`Watchdog`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Hang {
+    /// The lowest and highest PC executed in the loop
+    pub low: u32,
+    pub high: u32,
+    /// The step at which the loop was entered
+    pub since: usize,
+}
+
+impl fmt::Display for Hang {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "PC {:05X}-{:05X} since step {}",
+            self.low, self.high, self.since
+        )
+    }
+}
+
+pub struct Watchdog {
+    /// Steps inside the window before a hang is reported
+    limit: usize,
+    low: u32,
+    high: u32,
+    since: usize,
+    /// The firmware's DUART and mapper write count when the window started
+    writes: usize,
+    hang: Option<Hang>,
+}
+
+impl Watchdog {
+    pub fn new(limit: usize) -> Self {
+        Self {
+            limit,
+            low: 0,
+            high: 0,
+            since: 0,
+            writes: 0,
+            hang: None,
+        }
+    }
+
+    /// Record the PC executed at step `now`, with the number of writes the
+    /// firmware has made to the DUART and mapper so far.
+    pub fn update(&mut self, pc: u32, writes: usize, now: usize) {
+        let low = self.low.min(pc);
+        let high = self.high.max(pc);
+        if writes != self.writes || high - low >= WATCHDOG_WINDOW {
+            if let Some(hang) = self.hang.take() {
+                info!("Watchdog: firmware left {hang} at step {now}");
+            }
+            self.low = pc;
+            self.high = pc;
+            self.since = now;
+            self.writes = writes;
+            return;
+        }
+        self.low = low;
+        self.high = high;
+        if self.hang.is_none() && now - self.since >= self.limit {
+            let hang = Hang {
+                low,
+                high,
+                since: self.since,
+            };
+            warn!("Watchdog: firmware hung at {hang}");
+            self.hang = Some(hang);
+        }
+    }
+
+    /// The loop the firmware is stuck in, if any.
+    pub fn hang(&self) -> Option<Hang> {
+        self.hang
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tight_loop() {
+        let mut watchdog = Watchdog::new(100);
+        for now in 0..=100 {
+            watchdog.update(0x1000 + now as u32 % 4, 0, now);
+        }
+        assert_eq!(
+            watchdog.hang(),
+            Some(Hang {
+                low: 0x1000,
+                high: 0x1003,
+                since: 0
+            })
+        );
+
+        // Leaving the window clears it
+        watchdog.update(0x2000, 0, 101);
+        assert_eq!(watchdog.hang(), None);
+    }
+
+    #[test]
+    fn test_serviced_loop() {
+        let mut watchdog = Watchdog::new(100);
+        // Writing to the DUART every so often isn't a hang
+        for now in 0..1000 {
+            watchdog.update(0x1000 + now as u32 % 4, now / 50, now);
+        }
+        assert_eq!(watchdog.hang(), None);
+
+        // Neither is a loop wider than the window
+        for now in 1000..2000 {
+            watchdog.update(0x1000 + now as u32 % 0x100, 0, now);
+        }
+        assert_eq!(watchdog.hang(), None);
+    }
+}
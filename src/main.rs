@@ -1,21 +1,27 @@
-use clap::Parser;
+use clap::{CommandFactory, Parser};
 #[cfg(feature = "tui")]
 use i8051_debug_tui::{Debugger, TracingCollector};
 use std::path::PathBuf;
-use tracing::{Level, info};
+use tracing::{Level, info, warn};
 
 #[cfg(not(target_arch = "wasm32"))]
 use std::time::Instant;
 
+mod error;
 mod host;
 mod machine;
 
-use machine::vt420::System;
+use machine::generic::lk201::KeyboardLayout;
 use machine::vt420::breakpoints::create_breakpoints;
+use machine::vt420::{GeometryPreset, System};
 
 use i8051::Cpu;
 
-use crate::host::comm::CommConfig;
+use crate::host::comm::{CommConfig, EchoFormat, LinkImpairment};
+
+/// Number of ticks to wait before replaying a keyboard macro, typing a file or
+/// applying a geometry preset, enough for the power-up self test to complete.
+const BOOT_TICKS: usize = 10_000_000;
 
 #[derive(Default, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
 enum Display {
@@ -30,6 +36,46 @@ enum Display {
     Graphics,
 }
 
+/// The power-up self test result reported by the keyboard.
+#[derive(Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+enum KeyboardSelfTest {
+    /// The self test passed
+    Ok,
+    /// A key was held down during the self test
+    Keydown,
+    /// The keyboard failed its self test
+    Power,
+}
+
+/// The national keyboard layout used to type characters.
+#[derive(Default, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+enum KbdLayout {
+    /// North American
+    #[default]
+    Us,
+    /// British
+    Uk,
+    /// German
+    German,
+    /// French
+    French,
+}
+
+impl From<KbdLayout> for KeyboardLayout {
+    fn from(layout: KbdLayout) -> Self {
+        match layout {
+            KbdLayout::Us => Self::Us,
+            KbdLayout::Uk => Self::Uk,
+            KbdLayout::German => Self::German,
+            KbdLayout::French => Self::French,
+        }
+    }
+}
+
+/// The key reported as held down by `--keyboard-selftest keydown` unless
+/// `--keyboard-stuck-key` says otherwise ('a').
+const DEFAULT_STUCK_KEY: u8 = 0xc2;
+
 /// VT420 Terminal Emulator
 /// Emulates a VT420 terminal using an 8051 microcontroller
 #[derive(Default, Parser)]
@@ -50,6 +96,15 @@ struct Args {
     #[arg(long)]
     nvr: Option<PathBuf>,
 
+    /// List the ways comm1 and comm2 can be connected in this build, and
+    /// exit
+    #[arg(long, exclusive = true)]
+    list_comm_modes: bool,
+
+    /// Apply the settings in a TOML file to the NVR file before starting
+    #[arg(long, value_name = "PATH", requires = "nvr")]
+    nvr_toml: Option<PathBuf>,
+
     /// Display the video output
     #[arg(long, conflicts_with = "benchmark")]
     display: Option<Display>,
@@ -70,10 +125,29 @@ struct Args {
     #[arg(long = "comm1-exec", value_name = "COMMAND", group = "comm1")]
     comm1_exec: Option<String>,
 
+    /// Comm1: Connect to a serial port, as DEVICE[:BAUD] (default 9600 baud)
+    #[arg(long = "comm1-serial", value_name = "DEVICE[:BAUD]", value_parser = parse_serial_port, group = "comm1")]
+    #[cfg(feature = "serial")]
+    comm1_serial: Option<(PathBuf, u32)>,
+
     /// Comm1: Use loopback mode
     #[arg(long = "comm1-loopback", group = "comm1")]
     comm1_loopback: bool,
 
+    /// Comm1: Receive the bytes of a recording made with --comm1-record, with
+    /// their original timing
+    #[arg(long = "comm1-playback", value_name = "PATH", group = "comm1")]
+    comm1_playback: Option<PathBuf>,
+
+    /// Comm1: Record every byte sent and received, with its time, to a file
+    /// that --comm1-playback can replay
+    #[arg(long = "comm1-record", value_name = "PATH")]
+    comm1_record: Option<PathBuf>,
+
+    /// Comm1: Run the exec command again when it exits, up to this many times
+    #[arg(long = "comm1-restart", value_name = "TIMES", num_args = 0..=1, require_equals = true, default_missing_value = "5")]
+    comm1_restart: Option<u32>,
+
     /// Comm2: Single bidirectional pipe
     #[arg(long = "comm2-pipe", value_name = "PIPE", group = "comm2")]
     comm2_pipe: Option<PathBuf>,
@@ -90,10 +164,160 @@ struct Args {
     #[arg(long = "comm2-exec", value_name = "COMMAND", group = "comm2")]
     comm2_exec: Option<String>,
 
+    /// Comm2: Connect to a serial port, as DEVICE[:BAUD] (default 9600 baud)
+    #[arg(long = "comm2-serial", value_name = "DEVICE[:BAUD]", value_parser = parse_serial_port, group = "comm2")]
+    #[cfg(feature = "serial")]
+    comm2_serial: Option<(PathBuf, u32)>,
+
     /// Comm2: Use loopback mode
     #[arg(long = "comm2-loopback", group = "comm2")]
     comm2_loopback: bool,
 
+    /// Comm2: Capture everything the firmware prints to a file. The printer
+    /// port is the DUART channel used for comm2
+    #[arg(long, value_name = "PATH", group = "comm2")]
+    printer_file: Option<PathBuf>,
+
+    /// Comm2: Run the exec command again when it exits, up to this many times
+    #[arg(long = "comm2-restart", value_name = "TIMES", num_args = 0..=1, require_equals = true, default_missing_value = "5")]
+    comm2_restart: Option<u32>,
+
+    /// Boot with both comm ports in DUART local loopback, as if the self-test
+    /// loopback connectors were fitted
+    #[arg(long, conflicts_with_all = ["comm1", "comm2", "comm1_latency", "comm1_drop_rate", "comm2_latency", "comm2_drop_rate"])]
+    selftest: bool,
+
+    /// Warn when the firmware spends this many steps in a tight loop without
+    /// writing to the DUART or video mapper (default: one second)
+    #[arg(long, value_name = "STEPS", num_args = 0..=1, require_equals = true, default_missing_value = "921600")]
+    watchdog: Option<usize>,
+
+    /// Stop with an error when the watchdog fires (headless display only)
+    #[arg(long, requires = "watchdog")]
+    watchdog_exit: bool,
+
+    /// Clock the peripherals once per machine cycle of each instruction
+    /// instead of once per instruction
+    #[arg(long)]
+    cycle_timing: bool,
+
+    /// Stop sending to the host while it has sent XOFF, until it sends XON,
+    /// as well as passing both to the firmware
+    #[arg(long)]
+    host_xoff: bool,
+
+    /// Show characters typed on the keyboard as if comm1's host had echoed
+    /// them, for a half-duplex host
+    #[arg(long)]
+    local_echo: bool,
+
+    /// Comm1: Delay bytes in both directions by this many milliseconds
+    #[arg(long = "comm1-latency", value_name = "MS")]
+    comm1_latency: Option<u64>,
+
+    /// Comm1: Drop this fraction of bytes (0.0 to 1.0) in both directions
+    #[arg(long = "comm1-drop-rate", value_name = "FLOAT", value_parser = parse_drop_rate)]
+    comm1_drop_rate: Option<f64>,
+
+    /// Comm1: Also write every byte sent and received to stdout, as raw bytes
+    /// or a hex dump
+    #[arg(
+        long,
+        value_name = "FORMAT",
+        num_args = 0..=1,
+        default_missing_value = "raw"
+    )]
+    echo_comm: Option<EchoFormat>,
+
+    /// Comm2: Delay bytes in both directions by this many milliseconds
+    #[arg(long = "comm2-latency", value_name = "MS")]
+    comm2_latency: Option<u64>,
+
+    /// Comm2: Drop this fraction of bytes (0.0 to 1.0) in both directions
+    #[arg(long = "comm2-drop-rate", value_name = "FLOAT", value_parser = parse_drop_rate)]
+    comm2_drop_rate: Option<f64>,
+
+    /// Seed for choosing which bytes are dropped, so runs can be reproduced
+    #[arg(long, default_value_t = 0)]
+    seed: u64,
+
+    /// Append a JSON line for every escape sequence received on comm1/comm2
+    #[arg(long, value_name = "PATH")]
+    #[cfg(feature = "comm-trace")]
+    comm_trace: Option<PathBuf>,
+
+    /// Replay a recorded keyboard macro once the terminal has booted
+    #[arg(long, value_name = "PATH")]
+    replay: Option<PathBuf>,
+
+    /// The national keyboard layout used to type characters, which must match
+    /// the keyboard selected in Set-Up
+    #[arg(long, value_enum, default_value_t)]
+    kbd_layout: KbdLayout,
+
+    /// Make the keyboard report this power-up self test result
+    #[arg(long = "keyboard-selftest", value_name = "RESULT")]
+    keyboard_selftest: Option<KeyboardSelfTest>,
+
+    /// The keycode, in hex, of the key reported as held down by
+    /// `--keyboard-selftest keydown`
+    #[arg(long, value_name = "KEYCODE", value_parser = parse_hex_byte, requires = "keyboard_selftest")]
+    keyboard_stuck_key: Option<u8>,
+
+    /// Type the contents of a text file on the keyboard once the terminal has
+    /// booted
+    #[arg(long, value_name = "PATH")]
+    type_file: Option<PathBuf>,
+
+    /// Switch to a display geometry once the terminal has booted, as
+    /// COLUMNSxROWS[xPAGE] (eg: 132x48)
+    #[arg(long, value_name = "GEOMETRY")]
+    geometry: Option<GeometryPreset>,
+
+    /// How to scale the graphical display to the window
+    #[arg(long, value_enum, default_value_t)]
+    #[cfg(feature = "graphics")]
+    aspect: host::wgpu::Aspect,
+
+    /// Emulate the monitor's scanlines, phosphor tint and persistence
+    /// (all passes if no list is given)
+    #[arg(
+        long,
+        value_enum,
+        value_name = "PASSES",
+        value_delimiter = ',',
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_values = ["scanlines", "tint", "persistence"]
+    )]
+    #[cfg(feature = "graphics")]
+    crt: Option<Vec<host::screen::wgpu::CrtOption>>,
+
+    /// Draw characters in the graphical display with the glyphs in this raw
+    /// font file instead of the font uploaded by the firmware (32 bytes per
+    /// glyph, see the docs for FONT_GLYPH_BYTES)
+    #[arg(long, value_name = "PATH")]
+    #[cfg(feature = "graphics")]
+    font: Option<PathBuf>,
+
+    /// Draw each combination of cell attributes in its own color in the
+    /// graphical display, for debugging
+    #[arg(long)]
+    #[cfg(feature = "graphics")]
+    false_color: bool,
+
+    /// Smooth the edges of characters in the graphical display, for high-DPI
+    /// displays
+    #[arg(long)]
+    #[cfg(feature = "graphics")]
+    smooth_font: bool,
+
+    /// Emulated CPU speed as a multiple of real time (default 1.0, 0 for
+    /// unthrottled)
+    #[arg(long, value_name = "MULTIPLIER")]
+    #[cfg(feature = "graphics")]
+    speed: Option<f64>,
+
     /// Display the video RAM
     #[arg(long, requires = "display")]
     show_vram: bool,
@@ -102,14 +326,108 @@ struct Args {
     #[arg(long, requires = "display")]
     show_mapper: bool,
 
+    /// Highlight cells protected from selective erase in the text display
+    #[arg(long, requires = "display")]
+    show_protection: bool,
+
     /// Enable debugger
     #[arg(long)]
     debug: bool,
 
+    /// Start with the CPU paused, eg: to set breakpoints before the ROM runs.
+    /// Resume with Ctrl+G then Space in the text display, or Pause in the
+    /// graphical display
+    #[arg(long)]
+    start_paused: bool,
+
+    /// Run flat out, without drawing, until the terminal has passed its
+    /// power-up self test, then start the display
+    #[arg(long, conflicts_with_all = ["benchmark", "start_paused"])]
+    fast_boot: bool,
+
     /// Breakpoints for debug mode, repeatable, parsed as hex
     #[arg(value_parser = parse_hex_address, long="bp", alias="breakpoint")]
     breakpoint: Vec<u32>,
 
+    /// Watchpoints on XDATA writes, repeatable, parsed as hex ADDR or ADDR=VALUE
+    #[arg(value_parser = parse_watchpoint, long = "watch", value_name = "ADDR[=VALUE]")]
+    watch: Vec<(u16, Option<u8>)>,
+
+    /// Dump the text screen once it contains this string (headless display
+    /// only)
+    #[arg(long, value_name = "PATTERN")]
+    dump_screen_on: Option<String>,
+
+    /// Where to write the screen for --dump-screen-on, instead of stdout
+    #[arg(long, value_name = "PATH", requires = "dump_screen_on")]
+    dump_screen_out: Option<PathBuf>,
+
+    /// Write the screen as styled HTML when a headless run stops
+    #[arg(long, value_name = "PATH")]
+    html_out: Option<PathBuf>,
+
+    /// Render the display to a PNG when a headless run stops
+    #[arg(long, value_name = "PATH")]
+    #[cfg(feature = "graphics")]
+    render_png: Option<PathBuf>,
+
+    /// Exit once the screen matches --dump-screen-on
+    #[arg(long, requires = "dump_screen_on")]
+    exit_on_match: bool,
+
+    /// Stop after this many steps (headless display only)
+    #[arg(long, value_name = "N")]
+    max_cycles: Option<usize>,
+
+    /// Exit with an error unless the screen contains this text when the
+    /// emulator stops. Stops as soon as it appears (headless display only)
+    #[arg(long, value_name = "STRING")]
+    expect: Option<String>,
+
+    /// Read debugger commands from stdin instead of running freely (headless
+    /// display only)
+    #[arg(long)]
+    repl: bool,
+
+    /// Write a CSV history of video mapper register writes at exit
+    #[arg(long, value_name = "PATH")]
+    mapper_log: Option<PathBuf>,
+
+    /// Write a transcript of every line that scrolled off the screen, followed
+    /// by the final screen, at exit
+    #[arg(long, value_name = "PATH")]
+    log_screen: Option<PathBuf>,
+
+    /// Maximum number of lines kept for --log-screen
+    #[arg(long, value_name = "LINES", requires = "log_screen")]
+    scrollback_lines: Option<usize>,
+
+    /// Write every host key and the LK201 keycodes it was translated to at
+    /// exit
+    #[arg(long, value_name = "PATH")]
+    keyboard_log: Option<PathBuf>,
+
+    /// Listen on a Unix domain socket for keys to type (see README)
+    #[cfg(unix)]
+    #[arg(long, value_name = "PATH")]
+    kbd_socket: Option<PathBuf>,
+
+    /// Check the VRAM row table every frame and log a warning when it looks
+    /// corrupted
+    #[arg(long)]
+    validate_video: bool,
+
+    /// Write which ROM bytes were executed and a histogram of the executed
+    /// opcodes at exit
+    #[arg(long, value_name = "PATH")]
+    #[cfg(feature = "pc-trace")]
+    coverage: Option<PathBuf>,
+
+    /// Write every executed instruction, with the registers before it runs,
+    /// to a file
+    #[arg(long, value_name = "PATH")]
+    trace_exec: Option<PathBuf>,
+
     /// Enable logging
     #[arg(long)]
     log: bool,
@@ -118,15 +436,85 @@ struct Args {
     #[arg(short, long)]
     verbose: bool,
 
+    /// Warn about emulator steps that take longer than this many
+    /// milliseconds, or never time steps with 0. The default is 100, except
+    /// for --benchmark, which doesn't time steps
+    #[arg(long, value_name = "MS")]
+    slow_step_warn_ms: Option<u64>,
+
     /// Run the benchmark mode to see how many cycles we can hit
     #[arg(long, conflicts_with = "display")]
     benchmark: bool,
+
+    /// Run the benchmark mode and report the time spent in each part of the
+    /// emulator
+    #[arg(long, conflicts_with = "display")]
+    profile: bool,
+}
+
+/// Read a font override file, checking that it holds whole glyphs.
+#[cfg(feature = "graphics")]
+fn read_font(path: &std::path::Path) -> Result<Vec<u8>, Box<dyn std::error::Error + Send + Sync>> {
+    use machine::vt420::video::FONT_GLYPH_BYTES;
+
+    let font = std::fs::read(path)?;
+    if font.is_empty() || font.len() % FONT_GLYPH_BYTES != 0 {
+        return Err(format!(
+            "Font file {path:?} is {} bytes, which isn't a whole number of {FONT_GLYPH_BYTES}-byte glyphs",
+            font.len()
+        )
+        .into());
+    }
+    Ok(font)
 }
 
 fn parse_hex_address(s: &str) -> Result<u32, Box<dyn std::error::Error + Send + Sync>> {
     Ok(u32::from_str_radix(s, 16)?)
 }
 
+fn parse_hex_byte(s: &str) -> Result<u8, Box<dyn std::error::Error + Send + Sync>> {
+    Ok(u8::from_str_radix(s, 16)?)
+}
+
+/// Baud rate used when --comm1-serial/--comm2-serial doesn't specify one.
+const DEFAULT_SERIAL_BAUD: u32 = 9600;
+
+/// Parse DEVICE[:BAUD], also accepting DEVICE@BAUD. A ':' suffix is only
+/// taken as the baud rate if it is a number, since ':' can appear in device
+/// paths (eg: Windows' `COM1:`).
+fn parse_serial_port(s: &str) -> Result<(PathBuf, u32), Box<dyn std::error::Error + Send + Sync>> {
+    if let Some((device, baud)) = s.rsplit_once('@') {
+        return Ok((device.into(), baud.parse()?));
+    }
+    match s.rsplit_once(':') {
+        Some((device, baud)) if !device.is_empty() => match baud.parse() {
+            Ok(baud) => Ok((device.into(), baud)),
+            Err(_) => Ok((s.into(), DEFAULT_SERIAL_BAUD)),
+        },
+        _ => Ok((s.into(), DEFAULT_SERIAL_BAUD)),
+    }
+}
+
+fn parse_drop_rate(s: &str) -> Result<f64, Box<dyn std::error::Error + Send + Sync>> {
+    let rate: f64 = s.parse()?;
+    if !(0.0..=1.0).contains(&rate) {
+        return Err(format!("Drop rate must be between 0.0 and 1.0, got {rate}").into());
+    }
+    Ok(rate)
+}
+
+fn parse_watchpoint(
+    s: &str,
+) -> Result<(u16, Option<u8>), Box<dyn std::error::Error + Send + Sync>> {
+    match s.split_once('=') {
+        Some((addr, value)) => Ok((
+            u16::from_str_radix(addr, 16)?,
+            Some(u8::from_str_radix(value, 16)?),
+        )),
+        None => Ok((u16::from_str_radix(s, 16)?, None)),
+    }
+}
+
 fn setup_logging(args: &Args, #[cfg(feature = "tui")] trace_collector: TracingCollector) {
     let level = if args.verbose {
         Level::TRACE
@@ -157,35 +545,109 @@ fn setup_logging(args: &Args, #[cfg(feature = "tui")] trace_collector: TracingCo
     }
 }
 
+#[cfg(target_arch = "wasm32")]
+thread_local! {
+    static COMM1_WEBSOCKET: std::cell::RefCell<Option<String>> = const { std::cell::RefCell::new(None) };
+}
+
+/// Connect comm1 to a WebSocket rather than the demo. The emulator starts on
+/// the first timer tick after the module is initialized, so this must be
+/// called in the same task that awaited initialization.
+#[cfg(target_arch = "wasm32")]
+#[wasm_bindgen::prelude::wasm_bindgen]
+pub fn set_comm1_websocket(url: String) {
+    COMM1_WEBSOCKET.with(|websocket| *websocket.borrow_mut() = Some(url));
+}
+
 #[cfg(target_arch = "wasm32")]
 #[wasm_bindgen::prelude::wasm_bindgen(start)]
 fn start() {
     use tracing::error;
+    use wasm_bindgen::JsCast;
+    use wasm_bindgen::closure::Closure;
 
     console_error_panic_hook::set_once();
     let mut config = tracing_wasm::WASMLayerConfigBuilder::new();
     config.set_max_level(Level::INFO);
     tracing_wasm::set_as_global_default_with_config(config.build());
 
-    if let Err(e) = run(
-        Args {
-            display: Some(Display::Graphics),
-            ..Default::default()
-        },
-        #[cfg(feature = "tui")]
-        TracingCollector::new(1000),
-    ) {
-        error!("Error: {}", e);
+    // Defer starting until the page has had a chance to configure us
+    let run_emulator = Closure::once_into_js(|| {
+        if let Err(e) = run(
+            Args {
+                display: Some(Display::Graphics),
+                ..Default::default()
+            },
+            #[cfg(feature = "tui")]
+            TracingCollector::new(1000),
+        ) {
+            error!("Error: {}", e);
+        }
+    });
+    web_sys::window()
+        .unwrap()
+        .set_timeout_with_callback(run_emulator.unchecked_ref())
+        .unwrap();
+}
+
+/// Reject flags that only a headless run honours when another display is
+/// selected.
+fn check_headless_only(args: &Args) -> Result<(), clap::Error> {
+    if args.display.unwrap_or_default() == Display::Headless {
+        return Ok(());
     }
+    let flags = [
+        ("--max-cycles", args.max_cycles.is_some()),
+        ("--expect", args.expect.is_some()),
+        ("--watchdog-exit", args.watchdog_exit),
+    ];
+    for (flag, set) in flags {
+        if set {
+            return Err(Args::command().error(
+                clap::error::ErrorKind::ArgumentConflict,
+                format!("{flag} can only be used with --display=headless"),
+            ));
+        }
+    }
+    Ok(())
 }
 
 fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
     let mut args = Args::parse();
 
+    if args.list_comm_modes {
+        list_comm_modes();
+        return Ok(());
+    }
+
     // Set display to Headless if benchmark is set
+    if args.profile {
+        args.benchmark = true;
+    }
     if args.benchmark {
         args.display = Some(Display::Headless);
     }
+    if let Err(error) = check_headless_only(&args) {
+        error.exit();
+    }
+    if args.start_paused && args.display.unwrap_or_default() == Display::Headless {
+        return Err("--start-paused needs --display=text or --display=graphics, since a headless run has no way to resume".into());
+    }
+    if args.html_out.is_some()
+        && (args.benchmark || args.display.unwrap_or_default() != Display::Headless)
+    {
+        return Err("--html-out is only written by a headless run".into());
+    }
+    #[cfg(feature = "graphics")]
+    if args.render_png.is_some()
+        && (args.benchmark || args.display.unwrap_or_default() != Display::Headless)
+    {
+        return Err("--render-png is only written by a headless run".into());
+    }
+    #[cfg(feature = "tui")]
+    if args.echo_comm.is_some() && args.display == Some(Display::Text) {
+        return Err("--echo-comm can't be used with --display=text, which draws on stdout".into());
+    }
 
     #[cfg(feature = "tui")]
     let trace_collector = TracingCollector::new(1000);
@@ -202,6 +664,18 @@ fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
     )
 }
 
+/// Print every kind of comm connection compiled in, with the options that
+/// select it.
+fn list_comm_modes() {
+    for config in CommConfig::examples() {
+        let mode = config.mode();
+        println!("{:<10} {}", mode.name, mode.description);
+        if !mode.example.is_empty() {
+            println!("{:<10} {}", "", mode.example);
+        }
+    }
+}
+
 fn run(
     args: Args,
     #[cfg(feature = "tui")] trace_collector: TracingCollector,
@@ -219,7 +693,10 @@ fn run(
             std::process::exit(1);
         }
 
-        fs::read(&args.rom)?
+        fs::read(&args.rom).map_err(|source| error::BlazeError::RomLoad {
+            path: args.rom.clone(),
+            source,
+        })?
     };
 
     #[cfg(feature = "embed-rom")]
@@ -235,7 +712,10 @@ fn run(
             std::process::exit(1);
         }
 
-        rom = fs::read(&rom_path)?;
+        rom = fs::read(&rom_path).map_err(|source| error::BlazeError::RomLoad {
+            path: rom_path.clone(),
+            source,
+        })?;
     };
 
     info!("Configuring system...");
@@ -246,13 +726,36 @@ fn run(
     } else {
         None
     };
-    let comm1_config = CommConfig::from_args(
-        args.comm1_pipe,
-        comm1_pipes,
-        args.comm1_exec_raw,
-        args.comm1_exec,
-        args.comm1_loopback,
-    );
+    #[cfg(feature = "serial")]
+    let (comm1_serial, comm2_serial) = (args.comm1_serial, args.comm2_serial);
+    #[cfg(not(feature = "serial"))]
+    let (comm1_serial, comm2_serial) = (None, None);
+
+    let comm1_config = match args.comm1_playback {
+        Some(path) => CommConfig::Playback(path),
+        None => CommConfig::from_args(
+            args.comm1_pipe,
+            comm1_pipes,
+            args.comm1_exec_raw,
+            args.comm1_exec,
+            comm1_serial,
+            args.comm1_loopback,
+        ),
+    }
+    .restarting(args.comm1_restart.unwrap_or(0))
+    .impaired(LinkImpairment {
+        latency: std::time::Duration::from_millis(args.comm1_latency.unwrap_or(0)),
+        drop_rate: args.comm1_drop_rate.unwrap_or(0.0),
+        seed: args.seed,
+    });
+    let comm1_config = match args.echo_comm {
+        Some(format) => comm1_config.echoed(format),
+        None => comm1_config,
+    };
+    let comm1_config = match args.comm1_record {
+        Some(path) => comm1_config.recorded(path),
+        None => comm1_config,
+    };
 
     // Parse comm2 configuration
     let comm2_pipes = if args.comm2_pipes.len() == 2 {
@@ -260,31 +763,185 @@ fn run(
     } else {
         None
     };
-    let comm2_config = CommConfig::from_args(
-        args.comm2_pipe,
-        comm2_pipes,
-        args.comm2_exec_raw,
-        args.comm2_exec,
-        args.comm2_loopback,
-    );
+    let comm2_config = match args.printer_file {
+        Some(path) => CommConfig::Capture(path),
+        None => CommConfig::from_args(
+            args.comm2_pipe,
+            comm2_pipes,
+            args.comm2_exec_raw,
+            args.comm2_exec,
+            comm2_serial,
+            args.comm2_loopback,
+        ),
+    }
+    .restarting(args.comm2_restart.unwrap_or(0))
+    .impaired(LinkImpairment {
+        latency: std::time::Duration::from_millis(args.comm2_latency.unwrap_or(0)),
+        drop_rate: args.comm2_drop_rate.unwrap_or(0.0),
+        seed: args.seed,
+    });
 
-    let mut system = System::new(rom, args.nvr.as_deref(), comm1_config, comm2_config)?;
+    let (comm1_config, comm2_config) = if args.selftest {
+        (CommConfig::SelfTest, CommConfig::SelfTest)
+    } else {
+        (comm1_config, comm2_config)
+    };
+
+    #[cfg(target_arch = "wasm32")]
+    let comm1_config = match COMM1_WEBSOCKET.with(|websocket| websocket.take()) {
+        Some(url) => CommConfig::WebSocket(url),
+        None => comm1_config,
+    };
+
+    #[cfg(feature = "comm-trace")]
+    let comm_trace = args.comm_trace.as_deref();
+    #[cfg(not(feature = "comm-trace"))]
+    let comm_trace = None;
+
+    if let (Some(nvr_toml), Some(nvr)) = (&args.nvr_toml, &args.nvr) {
+        use machine::vt420::nvr_layout::{DEFAULT_NVR, NvrSettings};
+        use std::fs;
+        info!("Applying NVR settings from {:?}", nvr_toml);
+        let base = fs::read(nvr)
+            .ok()
+            .and_then(|nvr| nvr.try_into().ok())
+            .unwrap_or(DEFAULT_NVR);
+        let settings = NvrSettings::from_toml(&fs::read_to_string(nvr_toml)?)?;
+        fs::write(nvr, settings.to_bytes(&base)?)?;
+    }
+
+    let mut system = System::new(
+        rom,
+        args.nvr.as_deref(),
+        comm1_config,
+        comm2_config,
+        comm_trace,
+        #[cfg(feature = "graphics")]
+        matches!(args.display, Some(Display::Graphics)),
+    )?;
+
+    system.keyboard.set_layout(args.kbd_layout.into());
+    if let Some(self_test) = args.keyboard_selftest {
+        use machine::generic::lk201::{PowerUpError, SelfTestResult};
+        let (error, keycode) = match self_test {
+            KeyboardSelfTest::Ok => (PowerUpError::NoError, 0),
+            KeyboardSelfTest::Keydown => (
+                PowerUpError::KeyDownError,
+                args.keyboard_stuck_key.unwrap_or(DEFAULT_STUCK_KEY),
+            ),
+            KeyboardSelfTest::Power => (PowerUpError::PowerError, 0),
+        };
+        info!("Keyboard self test reports {error:?} ({keycode:02X})");
+        system
+            .keyboard
+            .set_self_test(SelfTestResult { error, keycode });
+    }
+
+    if let Some(replay) = &args.replay {
+        use std::fs;
+        info!("Replaying keyboard macro: {:?}", replay);
+        system.keyboard.play_after(BOOT_TICKS, &fs::read(replay)?);
+    }
+
+    if let Some(type_file) = &args.type_file {
+        info!("Typing {:?}", type_file);
+        system
+            .keyboard
+            .type_after(BOOT_TICKS, &std::fs::read_to_string(type_file)?);
+    }
+
+    if let Some(geometry) = &args.geometry {
+        info!(
+            "Switching to {}x{} with {} lines per page once booted",
+            geometry.columns, geometry.rows, geometry.page
+        );
+        system.inject_comm1_after(BOOT_TICKS, &geometry.sequences());
+    }
 
     let breakpoints = &mut system.breakpoints;
     if args.log {
         create_breakpoints(breakpoints, &system.rom);
     }
 
+    system.mapper_log = args.mapper_log.clone();
+    if let Some(log_screen) = &args.log_screen {
+        system.enable_scrollback(
+            args.scrollback_lines
+                .unwrap_or(machine::vt420::scrollback::DEFAULT_SCROLLBACK_LINES),
+        );
+        system.screen_log = Some(log_screen.clone());
+    }
+    if let Some(keyboard_log) = &args.keyboard_log {
+        system.keyboard.enable_key_log();
+        system.keyboard_log = Some(keyboard_log.clone());
+    }
+    #[cfg(unix)]
+    if let Some(kbd_socket) = &args.kbd_socket {
+        host::kbd_socket::listen(kbd_socket, system.keyboard.sender())?;
+    }
+    if args.host_xoff {
+        system.enable_host_flow_control();
+    }
+    if args.local_echo {
+        system.enable_local_echo();
+    }
+    if args.cycle_timing {
+        system.enable_cycle_timing();
+    }
+    #[cfg(not(target_arch = "wasm32"))]
+    {
+        let slow_step_warning = match args.slow_step_warn_ms {
+            Some(0) => None,
+            Some(ms) => Some(std::time::Duration::from_millis(ms)),
+            None if args.benchmark => None,
+            None => Some(machine::vt420::SLOW_STEP_WARNING),
+        };
+        system.set_slow_step_warning(slow_step_warning);
+    }
+    if let Some(limit) = args.watchdog {
+        system.enable_watchdog(limit);
+    }
+    if let Some(trace_exec) = &args.trace_exec {
+        info!("Tracing execution to {:?}", trace_exec);
+        system.enable_exec_trace(trace_exec)?;
+    }
+    if args.validate_video {
+        system.enable_video_validation();
+    }
+    #[cfg(feature = "pc-trace")]
+    {
+        system.coverage_log = args.coverage.clone();
+    }
+
+    for &(addr, value) in &args.watch {
+        system.memory.watchpoints.add(addr, value);
+    }
+    system.memory.watchpoints.set_callback(|hit| {
+        info!(
+            "Watchpoint: 0x{:04X} changed from 0x{:02X} to 0x{:02X} @ {:05X}",
+            hit.addr, hit.old, hit.new, hit.pc
+        );
+    });
+
     info!("Starting CPU execution...");
     let mut cpu = Cpu::new();
     #[cfg(not(target_arch = "wasm32"))]
     let start_time = Instant::now();
     info!("CPU initialized, PC = 0x{:04X}", cpu.pc_ext(&system));
+    if args.fast_boot {
+        match system.fast_boot(&mut cpu, machine::vt420::FAST_BOOT_MAX_STEPS) {
+            Some(steps) => info!("Booted in {steps} steps"),
+            None => warn!(
+                "The terminal didn't boot within {} steps, starting anyway",
+                machine::vt420::FAST_BOOT_MAX_STEPS
+            ),
+        }
+    }
 
     #[cfg(feature = "tui")]
     let debugger = if args.debug {
         let mut debugger = Debugger::new(Default::default(), trace_collector)?;
-        for breakpoint in args.breakpoint {
+        for &breakpoint in &args.breakpoint {
             debugger.breakpoints_mut().insert(breakpoint);
         }
         Some(debugger)
@@ -293,26 +950,73 @@ fn run(
     };
 
     let instruction_count = if args.benchmark {
-        for _ in 0..100_000_000 {
+        if args.profile {
+            system.profile = Some(Default::default());
+        }
+        for _ in 0..args.max_cycles.unwrap_or(100_000_000) {
             system.step(&mut cpu);
         }
+        if let Some(profile) = &system.profile {
+            println!("Time per phase of a step:");
+            profile.write_report(std::io::stdout())?;
+        }
+        host::screen::headless::RunLimit {
+            max_steps: None,
+            expect: args.expect.map(host::screen::headless::ScreenMatch::expect),
+            exit_on_hang: args.watchdog_exit,
+        }
+        .check(&system)?;
         system.instruction_count
     } else {
         match args.display.unwrap_or(Display::Headless) {
             Display::Headless => host::screen::headless::run(
                 system,
                 cpu,
+                args.dump_screen_on.map(|pattern| {
+                    host::screen::headless::ScreenMatch::new(
+                        pattern,
+                        args.dump_screen_out,
+                        args.exit_on_match,
+                    )
+                }),
+                args.repl
+                    .then(|| host::screen::headless::Repl::new(args.breakpoint)),
+                host::screen::headless::RunLimit {
+                    max_steps: args.max_cycles,
+                    expect: args.expect.map(host::screen::headless::ScreenMatch::expect),
+                    exit_on_hang: args.watchdog_exit,
+                },
+                host::screen::headless::Snapshots {
+                    html: args.html_out,
+                    #[cfg(feature = "graphics")]
+                    png: args.render_png,
+                },
                 #[cfg(feature = "tui")]
                 debugger,
             )?,
             #[cfg(feature = "tui")]
-            Display::Text => {
-                host::screen::ratatui::run(system, cpu, debugger, args.show_mapper, args.show_vram)?
-            }
+            Display::Text => host::screen::ratatui::run(
+                system,
+                cpu,
+                debugger,
+                args.show_mapper,
+                args.show_vram,
+                args.show_protection,
+                args.start_paused,
+            )?,
             #[cfg(feature = "graphics")]
             Display::Graphics => host::screen::wgpu::run(
                 system,
                 cpu,
+                host::screen::wgpu::GraphicsOptions {
+                    aspect: args.aspect,
+                    crt: args.crt.as_deref().map(host::screen::wgpu::CrtEffect::new),
+                    font: args.font.as_deref().map(read_font).transpose()?,
+                    false_color: args.false_color,
+                    smooth_font: args.smooth_font,
+                    speed: args.speed.unwrap_or(1.0),
+                    start_paused: args.start_paused,
+                },
                 #[cfg(feature = "tui")]
                 debugger,
             )?,
@@ -336,3 +1040,23 @@ fn run(
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_serial_port() {
+        let parse = |s| parse_serial_port(s).unwrap();
+        assert_eq!(parse("/dev/ttyUSB0"), ("/dev/ttyUSB0".into(), 9600));
+        assert_eq!(parse("/dev/ttyUSB0:19200"), ("/dev/ttyUSB0".into(), 19200));
+        assert_eq!(parse("/dev/ttyUSB0@19200"), ("/dev/ttyUSB0".into(), 19200));
+        assert_eq!(parse("COM1:"), ("COM1:".into(), 9600));
+        assert_eq!(parse("COM1::4800"), ("COM1:".into(), 4800));
+        assert_eq!(
+            parse("/dev/serial/by-path/pci-0:1.0"),
+            ("/dev/serial/by-path/pci-0:1.0".into(), 9600)
+        );
+        assert!(parse_serial_port("/dev/ttyUSB0@fast").is_err());
+    }
+}